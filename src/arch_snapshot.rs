@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use crate::manifest::ArchSnapshot;
+
+/// Where a local snapshot mirror is mounted inside the build containers
+pub const CONTAINER_MIRROR_PATH: &str = "/mirror";
+
+impl ArchSnapshot {
+	/// The `Server = ...` value pacman should use inside the build containers
+	pub fn pacman_server(&self) -> String {
+		match self {
+			ArchSnapshot::Date(date) => format!(
+				"https://archive.archlinux.org/repos/{}/$repo/os/$arch",
+				date.trim_matches('/')
+			),
+			ArchSnapshot::Local { .. } => format!("file://{CONTAINER_MIRROR_PATH}/$repo/os/$arch"),
+		}
+	}
+
+	/// The host directory that has to be mounted at [`CONTAINER_MIRROR_PATH`], if any
+	pub fn local_mirror_path(&self) -> std::io::Result<Option<PathBuf>> {
+		match self {
+			ArchSnapshot::Date(_) => Ok(None),
+			ArchSnapshot::Local { path } => Ok(Some(path.canonicalize()?)),
+		}
+	}
+
+	/// A short identifier usable as a docker image tag
+	pub fn tag(&self) -> String {
+		match self {
+			ArchSnapshot::Date(date) => format!("snapshot-{}", date.trim_matches('/').replace('/', "-")),
+			ArchSnapshot::Local { path } => {
+				use std::hash::{DefaultHasher, Hash, Hasher};
+				let mut hasher = DefaultHasher::new();
+				path.hash(&mut hasher);
+				format!("local-{:x}", hasher.finish())
+			}
+		}
+	}
+}
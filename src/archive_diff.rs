@@ -0,0 +1,387 @@
+//! Structured comparison of two packages or directory trees, used to explain why
+//! two builds of the same inputs aren't byte for byte identical.
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+	io::{self, Read},
+	os::unix::fs::MetadataExt,
+	path::Path,
+};
+
+use object::{Object, ObjectSection};
+use sha2::{Digest, Sha256};
+
+/// Makepkg's metadata files, compared field by field instead of as a blob
+const METADATA_FILES: [&str; 2] = [".PKGINFO", ".BUILDINFO"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+	kind: char,
+	mode: u32,
+	uid: u64,
+	gid: u64,
+	mtime: u64,
+	sha256: Option<[u8; 32]>,
+	link: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Change {
+	/// Header level differences: mode, owner, mtime, type or link target
+	Attribute {
+		name: &'static str,
+		reference: String,
+		rebuild: String,
+	},
+	/// A `key = value` field of .PKGINFO/.BUILDINFO
+	Field {
+		name: String,
+		reference: Option<String>,
+		rebuild: Option<String>,
+	},
+	/// An ELF section whose contents differ, or that only exists in one build
+	Section {
+		name: String,
+		in_reference: bool,
+		in_rebuild: bool,
+	},
+	/// Contents differ and the member isn't something we know how to look into
+	Content {
+		reference_size: u64,
+		rebuild_size: u64,
+	},
+}
+
+#[derive(Debug)]
+pub enum MemberDiff {
+	OnlyInReference(String),
+	OnlyInRebuild(String),
+	Changed { path: String, changes: Vec<Change> },
+}
+
+impl fmt::Display for Change {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Change::Attribute {
+				name,
+				reference,
+				rebuild,
+			} => write!(f, "{name}: {reference} → {rebuild}"),
+			Change::Field {
+				name,
+				reference,
+				rebuild,
+			} => write!(
+				f,
+				"field {name}: {} → {}",
+				reference.as_deref().unwrap_or("(missing)"),
+				rebuild.as_deref().unwrap_or("(missing)")
+			),
+			Change::Section {
+				name,
+				in_reference,
+				in_rebuild,
+			} => match (in_reference, in_rebuild) {
+				(true, true) => write!(f, "section {name} differs"),
+				(true, false) => write!(f, "section {name} only in reference"),
+				_ => write!(f, "section {name} only in rebuild"),
+			},
+			Change::Content {
+				reference_size,
+				rebuild_size,
+			} => write!(
+				f,
+				"contents differ ({reference_size} → {rebuild_size} bytes)"
+			),
+		}
+	}
+}
+
+fn hash_bytes(data: &[u8]) -> [u8; 32] {
+	Sha256::digest(data).into()
+}
+
+/// Path -> entry for every member of a `.pkg.tar.zst`
+fn index_package(path: &Path) -> io::Result<BTreeMap<String, Entry>> {
+	let decoder = zstd::Decoder::new(std::fs::File::open(path)?)?;
+	let mut archive = tar::Archive::new(decoder);
+	let mut index = BTreeMap::new();
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let member = entry
+			.path()?
+			.to_string_lossy()
+			.trim_end_matches('/')
+			.to_string();
+		let header = entry.header();
+		let kind = match header.entry_type() {
+			tar::EntryType::Directory => 'd',
+			tar::EntryType::Symlink => 'l',
+			tar::EntryType::Link => 'h',
+			_ => 'f',
+		};
+		let mut item = Entry {
+			kind,
+			mode: header.mode()?,
+			uid: header.uid()?,
+			gid: header.gid()?,
+			mtime: header.mtime()?,
+			sha256: None,
+			link: entry.link_name()?.map(|l| l.to_string_lossy().to_string()),
+		};
+		if kind == 'f' {
+			let mut contents = Vec::new();
+			entry.read_to_end(&mut contents)?;
+			item.sha256 = Some(hash_bytes(&contents));
+		}
+		index.insert(member, item);
+	}
+	Ok(index)
+}
+
+/// Reads the given members out of a `.pkg.tar.zst`
+fn read_package_members(
+	path: &Path,
+	members: &BTreeSet<String>,
+) -> io::Result<BTreeMap<String, Vec<u8>>> {
+	let decoder = zstd::Decoder::new(std::fs::File::open(path)?)?;
+	let mut archive = tar::Archive::new(decoder);
+	let mut contents = BTreeMap::new();
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let member = entry.path()?.to_string_lossy().to_string();
+		if members.contains(&member) {
+			let mut data = Vec::new();
+			entry.read_to_end(&mut data)?;
+			contents.insert(member, data);
+		}
+	}
+	Ok(contents)
+}
+
+/// Path -> entry for everything under a directory, relative to it
+fn index_tree(root: &Path) -> io::Result<BTreeMap<String, Entry>> {
+	fn visit(root: &Path, dir: &Path, index: &mut BTreeMap<String, Entry>) -> io::Result<()> {
+		for entry in std::fs::read_dir(dir)? {
+			let path = entry?.path();
+			let metadata = std::fs::symlink_metadata(&path)?;
+			let relative = path
+				.strip_prefix(root)
+				.unwrap()
+				.to_string_lossy()
+				.to_string();
+			let file_type = metadata.file_type();
+			let kind = if file_type.is_dir() {
+				'd'
+			} else if file_type.is_symlink() {
+				'l'
+			} else if file_type.is_file() {
+				'f'
+			} else {
+				// device nodes, fifos and sockets, which can't be read
+				'o'
+			};
+			index.insert(
+				relative,
+				Entry {
+					kind,
+					mode: metadata.mode() & 0o7777,
+					uid: metadata.uid() as u64,
+					gid: metadata.gid() as u64,
+					mtime: metadata.mtime() as u64,
+					sha256: if kind == 'f' {
+						Some(hash_bytes(&std::fs::read(&path)?))
+					} else {
+						None
+					},
+					link: if kind == 'l' {
+						Some(std::fs::read_link(&path)?.to_string_lossy().to_string())
+					} else {
+						None
+					},
+				},
+			);
+			if kind == 'd' {
+				visit(root, &path, index)?;
+			}
+		}
+		Ok(())
+	}
+	let mut index = BTreeMap::new();
+	visit(root, root, &mut index)?;
+	Ok(index)
+}
+
+fn parse_fields(data: &[u8]) -> BTreeMap<String, Vec<String>> {
+	let mut fields: BTreeMap<String, Vec<String>> = BTreeMap::new();
+	for line in String::from_utf8_lossy(data).lines() {
+		if let Some((key, value)) = line.split_once(" = ") {
+			fields
+				.entry(key.trim().to_string())
+				.or_default()
+				.push(value.trim().to_string());
+		}
+	}
+	fields
+}
+
+fn field_changes(reference: &[u8], rebuild: &[u8]) -> Vec<Change> {
+	let reference = parse_fields(reference);
+	let rebuild = parse_fields(rebuild);
+	let keys = reference
+		.keys()
+		.chain(rebuild.keys())
+		.collect::<BTreeSet<_>>();
+	keys
+		.into_iter()
+		.filter(|k| reference.get(*k) != rebuild.get(*k))
+		.map(|k| Change::Field {
+			name: k.clone(),
+			reference: reference.get(k).map(|v| v.join(", ")),
+			rebuild: rebuild.get(k).map(|v| v.join(", ")),
+		})
+		.collect()
+}
+
+fn section_hashes(data: &[u8]) -> Option<BTreeMap<String, [u8; 32]>> {
+	let elf = object::File::parse(data).ok()?;
+	Some(
+		elf
+			.sections()
+			.map(|s| {
+				(
+					s.name().unwrap_or("?").to_string(),
+					hash_bytes(s.data().unwrap_or_default()),
+				)
+			})
+			.collect(),
+	)
+}
+
+/// Explains a content difference: fields for makepkg metadata, sections for ELF files
+fn content_changes(path: &str, reference: &[u8], rebuild: &[u8]) -> Vec<Change> {
+	let file_name = path.rsplit('/').next().unwrap_or(path);
+	if METADATA_FILES.contains(&file_name) {
+		return field_changes(reference, rebuild);
+	}
+	if let (Some(reference), Some(rebuild)) = (section_hashes(reference), section_hashes(rebuild)) {
+		let names = reference
+			.keys()
+			.chain(rebuild.keys())
+			.collect::<BTreeSet<_>>();
+		let changes = names
+			.into_iter()
+			.filter(|n| reference.get(*n) != rebuild.get(*n))
+			.map(|n| Change::Section {
+				name: n.clone(),
+				in_reference: reference.contains_key(n),
+				in_rebuild: rebuild.contains_key(n),
+			})
+			.collect::<Vec<_>>();
+		// identical sections but different bytes means the headers differ
+		if !changes.is_empty() {
+			return changes;
+		}
+	}
+	vec![Change::Content {
+		reference_size: reference.len() as u64,
+		rebuild_size: rebuild.len() as u64,
+	}]
+}
+
+/// Compares two indexes, `read` loads the contents of members whose hashes differ
+fn diff_indexes(
+	reference: &BTreeMap<String, Entry>,
+	rebuild: &BTreeMap<String, Entry>,
+	read: impl Fn(&BTreeSet<String>) -> io::Result<(BTreeMap<String, Vec<u8>>, BTreeMap<String, Vec<u8>>)>,
+) -> io::Result<Vec<MemberDiff>> {
+	let mut diffs = Vec::new();
+	let mut content_differs = BTreeSet::new();
+	let paths = reference
+		.keys()
+		.chain(rebuild.keys())
+		.collect::<BTreeSet<_>>();
+	for path in paths {
+		let (a, b) = match (reference.get(path), rebuild.get(path)) {
+			(Some(_), None) => {
+				diffs.push(MemberDiff::OnlyInReference(path.clone()));
+				continue;
+			}
+			(None, Some(_)) => {
+				diffs.push(MemberDiff::OnlyInRebuild(path.clone()));
+				continue;
+			}
+			(Some(a), Some(b)) if a == b => continue,
+			(Some(a), Some(b)) => (a, b),
+			(None, None) => unreachable!(),
+		};
+		let mut changes = Vec::new();
+		let mut attribute = |name: &'static str, x: String, y: String| {
+			if x != y {
+				changes.push(Change::Attribute {
+					name,
+					reference: x,
+					rebuild: y,
+				});
+			}
+		};
+		attribute("type", a.kind.to_string(), b.kind.to_string());
+		attribute("mode", format!("{:o}", a.mode), format!("{:o}", b.mode));
+		attribute(
+			"owner",
+			format!("{}:{}", a.uid, a.gid),
+			format!("{}:{}", b.uid, b.gid),
+		);
+		attribute("mtime", a.mtime.to_string(), b.mtime.to_string());
+		attribute(
+			"link",
+			a.link.clone().unwrap_or_default(),
+			b.link.clone().unwrap_or_default(),
+		);
+		if a.sha256 != b.sha256 && a.kind == 'f' && b.kind == 'f' {
+			content_differs.insert(path.clone());
+		}
+		diffs.push(MemberDiff::Changed {
+			path: path.clone(),
+			changes,
+		});
+	}
+	if !content_differs.is_empty() {
+		let (reference_contents, rebuild_contents) = read(&content_differs)?;
+		for diff in diffs.iter_mut() {
+			if let MemberDiff::Changed { path, changes } = diff
+				&& let (Some(a), Some(b)) = (reference_contents.get(path), rebuild_contents.get(path))
+			{
+				changes.extend(content_changes(path, a, b));
+			}
+		}
+	}
+	Ok(diffs)
+}
+
+/// Member by member comparison of two `.pkg.tar.zst` files
+pub fn diff_packages(reference: &Path, rebuild: &Path) -> io::Result<Vec<MemberDiff>> {
+	diff_indexes(
+		&index_package(reference)?,
+		&index_package(rebuild)?,
+		|members| {
+			Ok((
+				read_package_members(reference, members)?,
+				read_package_members(rebuild, members)?,
+			))
+		},
+	)
+}
+
+/// File by file comparison of two directory trees, e.g. two sysroots
+pub fn diff_trees(reference: &Path, rebuild: &Path) -> io::Result<Vec<MemberDiff>> {
+	let read = |root: &Path, members: &BTreeSet<String>| -> io::Result<BTreeMap<String, Vec<u8>>> {
+		members
+			.iter()
+			.map(|m| Ok((m.clone(), std::fs::read(root.join(m))?)))
+			.collect()
+	};
+	diff_indexes(&index_tree(reference)?, &index_tree(rebuild)?, |members| {
+		Ok((read(reference, members)?, read(rebuild, members)?))
+	})
+}
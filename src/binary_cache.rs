@@ -0,0 +1,489 @@
+use std::{
+	collections::HashMap,
+	hash::Hash,
+	io::{self, Read},
+	path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+	fs_utils::hash_dir_contents,
+	hash::{Sha256Hash, StableHasher, hash_file},
+	manifest::{BinaryCache, BinaryCacheBackend, DockerSettings, Manifest, Package, Source},
+	patch::hash_patches,
+};
+
+/// Written next to the packages of every cache entry, and uploaded last so
+/// half-uploaded entries are never picked up
+const ENTRY_METADATA: &str = "metadata.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+	pub name: String,
+	pub version: String,
+	pub key: String,
+	pub files: Vec<CachedFile>,
+	/// Unix timestamp of the upload
+	pub uploaded_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedFile {
+	pub name: String,
+	pub sha256: Sha256Hash,
+	pub size: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum BinaryCacheError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("http error: {0}")]
+	Http(#[from] ureq::Error),
+	#[error("invalid cache entry metadata: {0}")]
+	Metadata(#[from] serde_json::Error),
+	#[error("{file} doesn't match the hash in the cache entry")]
+	HashMismatch {
+		file: String,
+		expected: Sha256Hash,
+		actual: Sha256Hash,
+	},
+	#[error("the package hasn't been built yet")]
+	NotBuilt,
+}
+
+impl BinaryCacheBackend {
+	/// Human readable location of a cache entry, for messages
+	pub fn location(&self, key: &str) -> String {
+		match self {
+			BinaryCacheBackend::Directory { path } => path.join(key).display().to_string(),
+			BinaryCacheBackend::Http { url } => format!("{}/{key}", url.trim_end_matches('/')),
+		}
+	}
+
+	/// Opens a file of a cache entry, `None` if it doesn't exist
+	fn open(&self, key: &str, file: &str) -> Result<Option<Box<dyn Read>>, BinaryCacheError> {
+		match self {
+			BinaryCacheBackend::Directory { path } => {
+				match std::fs::File::open(path.join(key).join(file)) {
+					Ok(file) => Ok(Some(Box::new(file))),
+					Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+					Err(e) => Err(e.into()),
+				}
+			}
+			BinaryCacheBackend::Http { .. } => {
+				match ureq::get(format!("{}/{file}", self.location(key))).call() {
+					Ok(response) => Ok(Some(Box::new(response.into_body().into_reader()))),
+					Err(ureq::Error::StatusCode(404)) => Ok(None),
+					Err(e) => Err(e.into()),
+				}
+			}
+		}
+	}
+
+	fn upload(&self, key: &str, file: &str, local_path: &Path) -> Result<(), BinaryCacheError> {
+		match self {
+			BinaryCacheBackend::Directory { path } => {
+				let entry_dir = path.join(key);
+				std::fs::create_dir_all(&entry_dir)?;
+				// copy then rename, other machines may be reading the same directory
+				let partial = entry_dir.join(format!("{file}.partial"));
+				if let Some(parent) = partial.parent() {
+					std::fs::create_dir_all(parent)?;
+				}
+				std::fs::copy(local_path, &partial)?;
+				std::fs::rename(partial, entry_dir.join(file))?;
+				Ok(())
+			}
+			BinaryCacheBackend::Http { .. } => {
+				ureq::put(format!("{}/{file}", self.location(key)))
+					.send(std::fs::File::open(local_path)?)?;
+				Ok(())
+			}
+		}
+	}
+
+	fn entry(&self, key: &str) -> Result<Option<CacheEntry>, BinaryCacheError> {
+		let Some(mut reader) = self.open(key, ENTRY_METADATA)? else {
+			return Ok(None);
+		};
+		let mut contents = String::new();
+		reader.read_to_string(&mut contents)?;
+		Ok(Some(serde_json::from_str(&contents)?))
+	}
+}
+
+impl Manifest {
+	/// Keys of every package, each computed once: a key covers the keys of the package's
+	/// `build_deps`, and local sources are hashed in full
+	fn binary_cache_keys(&self) -> &HashMap<String, String> {
+		self.binary_cache_keys.get_or_init(|| {
+			let mut keys = HashMap::new();
+			// a cycle is reported by whatever asked for the key
+			let Ok(order) = self.build_order() else {
+				return keys;
+			};
+			for pkg in order {
+				// packages whose key fails are left out, asking again reports the error
+				if let Ok(key) = pkg.compute_binary_cache_key(self, &keys) {
+					keys.insert(pkg.name.clone(), key);
+				}
+			}
+			keys
+		})
+	}
+}
+
+impl Package {
+	/// Identifies the package's build inputs across machines: everything that goes into
+	/// its out dir name (the source date epoch included), the contents of local sources and the keys of its `build_deps`
+	pub fn binary_cache_key(&self, manifest: &Manifest) -> io::Result<String> {
+		let keys = manifest.binary_cache_keys();
+		match keys.get(&self.name) {
+			Some(key) => Ok(key.clone()),
+			None => self.compute_binary_cache_key(manifest, keys),
+		}
+	}
+
+	/// `keys` holds the keys of the build deps computed so far
+	fn compute_binary_cache_key(
+		&self,
+		manifest: &Manifest,
+		keys: &HashMap<String, String>,
+	) -> io::Result<String> {
+		let mut hasher = StableHasher::new();
+		self.name.hash(&mut hasher);
+		self.version.hash(&mut hasher);
+		self.source.hash(&mut hasher);
+		match &self.docker {
+			DockerSettings::DockerfilePath { path } => hash_file(path)?.hash(&mut hasher),
+			DockerSettings::ImageName { name } => name.hash(&mut hasher),
+		}
+		manifest.arch_snapshot.hash(&mut hasher);
+		// makepkg writes it into .PKGINFO as the builddate
+		manifest.source_date_epoch().hash(&mut hasher);
+		self.build_options.hash(&mut hasher);
+		if let Some(makepkg_conf) = &self.build_options.makepkg_conf {
+			std::fs::read(makepkg_conf)?.hash(&mut hasher);
+		}
+		hash_patches(&self.patches, &mut hasher);
+		if let Source::PkgBuildLocal { path, .. } = &self.source {
+			hash_dir_contents(path, &mut hasher)?;
+		}
+		let mut deps = manifest
+			.packages
+			.iter()
+			.filter(|p| self.build_deps.contains(&p.name))
+			.collect::<Vec<_>>();
+		deps.sort_by(|a, b| a.name.cmp(&b.name));
+		for dep in deps {
+			match keys.get(&dep.name) {
+				Some(key) => key.hash(&mut hasher),
+				None => dep
+					.compute_binary_cache_key(manifest, keys)?
+					.hash(&mut hasher),
+			}
+		}
+		let hash = hasher.finalize().into_string().to_lowercase();
+		Ok(format!("{}-{}-{}", self.name, self.version, &hash[..32]))
+	}
+
+	/// Downloads the package's cache entry into its out dir, replacing whatever makepkg left there.
+	/// Returns `None` when the cache doesn't have it.
+	pub fn pull_from_binary_cache(
+		&self,
+		manifest: &Manifest,
+		cache: &BinaryCache,
+	) -> Result<Option<CacheEntry>, BinaryCacheError> {
+		let key = self.binary_cache_key(manifest)?;
+		let Some(entry) = cache.backend.entry(&key)? else {
+			return Ok(None);
+		};
+		let out_dir = self.create_out_dir(manifest)?;
+		// everything is downloaded and checked before the packages already there are
+		// replaced, a failed pull leaves them alone
+		let mut downloaded = Vec::new();
+		let result = download_entry(cache, &key, &entry, &out_dir, &mut downloaded);
+		if let Err(e) = result {
+			for (partial, _) in downloaded {
+				std::fs::remove_file(partial).ok();
+			}
+			return Err(e);
+		}
+		for path in built_package_files(&out_dir)? {
+			std::fs::remove_file(path)?;
+		}
+		for (partial, path) in downloaded {
+			std::fs::rename(partial, path)?;
+		}
+		Ok(Some(entry))
+	}
+
+	/// Uploads the packages in the out dir. Returns `false` if the cache already had them.
+	pub fn push_to_binary_cache(
+		&self,
+		manifest: &Manifest,
+		cache: &BinaryCache,
+	) -> Result<bool, BinaryCacheError> {
+		let key = self.binary_cache_key(manifest)?;
+		if cache.backend.entry(&key)?.is_some() {
+			return Ok(false);
+		}
+		let out_dir = self.get_out_dir(manifest);
+		let paths = built_package_files(&out_dir)?;
+		if paths.is_empty() || !out_dir.join("last_successful_build_time").exists() {
+			return Err(BinaryCacheError::NotBuilt);
+		}
+		let mut files = Vec::new();
+		for path in paths {
+			// debug packages are uploaded as `debug/<file>`
+			let name = path
+				.strip_prefix(&out_dir)
+				.unwrap_or(&path)
+				.to_string_lossy()
+				.to_string();
+			cache.backend.upload(&key, &name, &path)?;
+			files.push(CachedFile {
+				name,
+				sha256: hash_file(&path)?,
+				size: path.metadata()?.len(),
+			});
+		}
+		let entry = CacheEntry {
+			name: self.name.clone(),
+			version: self.version.clone(),
+			key: key.clone(),
+			files,
+			uploaded_at: std::time::SystemTime::now()
+				.duration_since(std::time::UNIX_EPOCH)
+				.unwrap()
+				.as_secs(),
+		};
+		let metadata_path = out_dir.join(ENTRY_METADATA);
+		std::fs::write(&metadata_path, serde_json::to_string_pretty(&entry)?)?;
+		cache.backend.upload(&key, ENTRY_METADATA, &metadata_path)?;
+		Ok(true)
+	}
+
+	/// [`Package::pull_from_binary_cache`] for the build loop: cache problems are
+	/// only warnings, the package just gets built instead
+	pub fn restore_from_binary_cache(&self, manifest: &Manifest, cache: &BinaryCache) -> bool {
+		match self.pull_from_binary_cache(manifest, cache) {
+			Ok(Some(entry)) => {
+				println!(
+					"    {} {} {}",
+					"󰆼 Restored".green().bold(),
+					self.name.bold(),
+					format!("from {}", cache.backend.location(&entry.key)).dimmed()
+				);
+				true
+			}
+			Ok(None) => false,
+			Err(e) => {
+				println!(
+					"    {}: could not read {} from the binary cache: {}",
+					"warning".yellow().bold(),
+					self.name.bold(),
+					e.to_string().dimmed()
+				);
+				false
+			}
+		}
+	}
+
+	/// [`Package::push_to_binary_cache`] for the build loop, failing uploads are only warnings
+	pub fn upload_to_binary_cache(&self, manifest: &Manifest, cache: &BinaryCache) {
+		match self.push_to_binary_cache(manifest, cache) {
+			Ok(true) => println!(
+				"    {} {} {}",
+				"󰅧 Uploaded".green().bold(),
+				self.name.bold(),
+				"to the binary cache".dimmed()
+			),
+			Ok(false) => {}
+			Err(e) => println!(
+				"    {}: could not upload {} to the binary cache: {}",
+				"warning".yellow().bold(),
+				self.name.bold(),
+				e.to_string().dimmed()
+			),
+		}
+	}
+}
+
+/// Downloads the files of `entry` next to where they go in `out_dir` and checks their
+/// hashes, pushing `(partial, destination)` for each file written
+fn download_entry(
+	cache: &BinaryCache,
+	key: &str,
+	entry: &CacheEntry,
+	out_dir: &Path,
+	downloaded: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), BinaryCacheError> {
+	for file in &entry.files {
+		let Some(mut reader) = cache.backend.open(key, &file.name)? else {
+			return Err(
+				io::Error::new(
+					io::ErrorKind::NotFound,
+					format!("{} is missing from cache entry {key}", file.name),
+				)
+				.into(),
+			);
+		};
+		let partial = out_dir.join(format!("{}.partial", file.name));
+		if let Some(parent) = partial.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		downloaded.push((partial.clone(), out_dir.join(&file.name)));
+		io::copy(&mut reader, &mut std::fs::File::create(&partial)?)?;
+		let actual = hash_file(&partial)?;
+		if actual != file.sha256 {
+			return Err(BinaryCacheError::HashMismatch {
+				file: file.name.clone(),
+				expected: file.sha256.clone(),
+				actual,
+			});
+		}
+	}
+	Ok(())
+}
+
+/// Every `.pkg.tar.zst` in an out dir, including the debug store in `debug/`
+pub fn built_package_files(out_dir: &Path) -> io::Result<Vec<PathBuf>> {
+	let mut files = Vec::new();
+	for dir in [out_dir.to_path_buf(), out_dir.join("debug")] {
+		if !dir.exists() {
+			continue;
+		}
+		for entry in std::fs::read_dir(dir)? {
+			let path = entry?.path();
+			if path.to_string_lossy().ends_with(".pkg.tar.zst") {
+				files.push(path);
+			}
+		}
+	}
+	files.sort();
+	Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{FileServer, TempDir, test_manifest, write_package};
+
+	/// Builds `pkg` and `other` from local PKGBUILDs into `dir`, with `backend` as the cache
+	fn manifest(dir: &TempDir, backend: &str) -> Manifest {
+		let mut packages = String::new();
+		for name in ["pkg", "other"] {
+			let pkgbuild_dir = dir.path().join(name);
+			std::fs::create_dir_all(&pkgbuild_dir).unwrap();
+			std::fs::write(pkgbuild_dir.join("PKGBUILD"), format!("pkgname={name}\n")).unwrap();
+			packages.push_str(&format!(
+				"[[package]]\nname = \"{name}\"\nversion = \"1.0-1\"\nsource = {{ mode = \"pkgbuildlocal\", path = {pkgbuild_dir:?} }}\n"
+			));
+		}
+		let mut manifest = test_manifest(&format!("binary_cache = {backend}\n{packages}"));
+		manifest.rebuild_dir = Some(dir.path().join("build"));
+		manifest
+	}
+
+	/// Puts a built package with `contents` in the package's out dir
+	fn build(manifest: &Manifest, pkg: &Package, contents: &[u8]) -> PathBuf {
+		let out_dir = pkg.create_out_dir(manifest).unwrap();
+		let path = write_package(&out_dir, &pkg.name, "1.0-1", contents);
+		pkg
+			.install_built_packages(manifest, &pkg.create_out_unpacked_dir(manifest).unwrap())
+			.unwrap();
+		path
+	}
+
+	/// Pushes `pkg`, then checks what pulls get back. `cache_dir` is where the backend
+	/// stores its entries.
+	fn check_cache(backend: &str, cache_dir: &Path) {
+		let dir = TempDir::new();
+		let manifest = manifest(&dir, backend);
+		let cache = manifest.binary_cache.as_ref().unwrap();
+		let (pkg, other) = (&manifest.packages[0], &manifest.packages[1]);
+
+		let built = build(&manifest, pkg, b"built");
+		assert!(pkg.push_to_binary_cache(&manifest, cache).unwrap());
+		// entries are never replaced
+		assert!(!pkg.push_to_binary_cache(&manifest, cache).unwrap());
+
+		let pushed = std::fs::read(&built).unwrap();
+		std::fs::remove_dir_all(pkg.get_out_dir(&manifest)).unwrap();
+		let entry = pkg
+			.pull_from_binary_cache(&manifest, cache)
+			.unwrap()
+			.unwrap();
+		assert_eq!(entry.key, pkg.binary_cache_key(&manifest).unwrap());
+		assert_eq!(entry.files.len(), 1);
+		assert_eq!(std::fs::read(&built).unwrap(), pushed);
+
+		assert!(
+			other
+				.pull_from_binary_cache(&manifest, cache)
+				.unwrap()
+				.is_none()
+		);
+
+		// a corrupted artifact is rejected and the package already built stays
+		let cached = cache_dir.join(&entry.key).join(&entry.files[0].name);
+		std::fs::write(&cached, "corrupted").unwrap();
+		build(&manifest, pkg, b"local");
+		let before = std::fs::read(&built).unwrap();
+		assert!(matches!(
+			pkg.pull_from_binary_cache(&manifest, cache),
+			Err(BinaryCacheError::HashMismatch { .. })
+		));
+		assert_eq!(std::fs::read(&built).unwrap(), before);
+		assert_eq!(
+			built_package_files(&pkg.get_out_dir(&manifest)).unwrap(),
+			vec![built.clone()]
+		);
+		let partial = format!("{}.partial", built.display());
+		assert!(!Path::new(&partial).exists());
+	}
+
+	#[test]
+	fn keys_cover_the_source_date_epoch() {
+		let dir = TempDir::new();
+		let mut manifest = manifest(&dir, "{ path = \"/nonexistent\" }");
+		let keys = |manifest: &Manifest| {
+			let pkg = &manifest.packages[0];
+			(
+				pkg.binary_cache_key(manifest).unwrap(),
+				pkg.get_out_dir(manifest),
+			)
+		};
+		manifest.source_date_epoch = Some(1700000000);
+		let before = keys(&manifest);
+		let mut other = manifest.clone();
+		other.binary_cache_keys.take();
+		other.source_date_epoch = Some(1700000001);
+		let after = keys(&other);
+		assert_ne!(before.0, after.0);
+		assert_ne!(before.1, after.1);
+	}
+
+	#[test]
+	fn directory_cache() {
+		let cache_dir = TempDir::new();
+		check_cache(
+			&format!("{{ path = {:?} }}", cache_dir.path()),
+			cache_dir.path(),
+		);
+	}
+
+	#[test]
+	fn http_cache() {
+		let server = FileServer::start();
+		check_cache(
+			&format!("{{ url = \"{}/cache\" }}", server.url),
+			&server.root.path().join("cache"),
+		);
+	}
+}
@@ -0,0 +1,167 @@
+//! The shared cache root (`--cache-dir` / `HYPRPACKER_CACHE`): sources and package outputs
+//! shared between worktrees and manifests, with one GC root record per project. Without
+//! one, the records are kept in `build/` for the manifests listed in `[gc] roots`.
+use std::{
+	hash::Hash,
+	io,
+	path::{Path, PathBuf},
+	sync::OnceLock,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	hash::StableHasher,
+	manifest::{Manifest, Package},
+};
+
+static CACHE_ROOT: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Sets the shared cache root for the rest of the process, `None` keeps everything in `build/`
+pub fn init(dir: Option<PathBuf>) -> io::Result<()> {
+	let dir = match dir {
+		Some(dir) => {
+			std::fs::create_dir_all(&dir)?;
+			// records of other projects must resolve to the same paths
+			Some(dir.canonicalize()?)
+		}
+		None => None,
+	};
+	CACHE_ROOT.set(dir).ok();
+	Ok(())
+}
+
+pub fn get() -> Option<&'static Path> {
+	CACHE_ROOT.get().and_then(|d| d.as_deref())
+}
+
+/// Downloaded sources and prepared source trees
+pub fn sources_dir() -> PathBuf {
+	match get() {
+		Some(root) => root.join("sources"),
+		None => PathBuf::from("build/sources"),
+	}
+}
+
+/// Package out dirs
+pub fn out_dir() -> PathBuf {
+	match get() {
+		Some(root) => root.join("out"),
+		None => PathBuf::from("build/out"),
+	}
+}
+
+fn roots_dir() -> PathBuf {
+	match get() {
+		Some(root) => root.join("gc-roots"),
+		None => PathBuf::from("build/gc-roots"),
+	}
+}
+
+/// `<roots_dir>/<id>.json`, named by the hash of the manifest's absolute path
+fn record_path(manifest_path: &Path) -> io::Result<PathBuf> {
+	let mut hasher = StableHasher::new();
+	manifest_path.canonicalize()?.hash(&mut hasher);
+	let id = hasher.finalize().into_string().to_lowercase();
+	Ok(roots_dir().join(format!("{id}.json")))
+}
+
+/// Where a path of a record is, see [`GcRoot::paths`]
+fn recorded_path(path: &Path) -> PathBuf {
+	match get() {
+		Some(root) => root.join(path),
+		None => path.to_path_buf(),
+	}
+}
+
+/// What one project uses from the shared cache (or `build/`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GcRoot {
+	/// Absolute path of the project's manifest, the record dies with it
+	pub manifest: PathBuf,
+	/// Unix timestamp of the last registration
+	pub updated_at: u64,
+	/// Relative to the cache root, or as they are (relative to the project) without one
+	pub paths: Vec<PathBuf>,
+}
+
+impl Package {
+	/// Everything the package uses from the sources and out dirs
+	fn cache_paths(&self, manifest: &Manifest) -> Vec<PathBuf> {
+		let mut paths = vec![self.get_package_prepared_dir(), self.get_out_dir(manifest)];
+		paths.extend(self.source_tarball_path().ok());
+		paths
+	}
+}
+
+/// Records what `manifest` uses in the shared cache (or `build/`), so other projects'
+/// garbage collection, and that of manifests listing it in `[gc] roots`, leaves it alone
+pub fn register_gc_root(manifest: &Manifest, manifest_path: &Path) -> io::Result<()> {
+	let record_path = record_path(manifest_path)?;
+	let mut paths = manifest
+		.packages
+		.iter()
+		.flat_map(|p| p.cache_paths(manifest))
+		.filter_map(|p| match get() {
+			Some(root) => p.strip_prefix(root).ok().map(Path::to_path_buf),
+			None => Some(p),
+		})
+		.collect::<Vec<_>>();
+	paths.sort();
+	paths.dedup();
+	let record = GcRoot {
+		manifest: manifest_path.canonicalize()?,
+		updated_at: std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.unwrap()
+			.as_secs(),
+		paths,
+	};
+	std::fs::create_dir_all(roots_dir())?;
+	let partial = record_path.with_extension("json.partial");
+	std::fs::write(&partial, serde_json::to_string_pretty(&record)?)?;
+	std::fs::rename(partial, record_path)
+}
+
+fn read_record(path: &Path) -> io::Result<GcRoot> {
+	serde_json::from_str(&std::fs::read_to_string(path)?)
+		.map_err(|e| io::Error::other(format!("invalid GC root {}: {e}", path.display())))
+}
+
+/// The paths recorded by the last registration of the manifest at `manifest_path`, `None`
+/// if it was never registered
+pub fn recorded_gc_root_paths(manifest_path: &Path) -> io::Result<Option<Vec<PathBuf>>> {
+	let path = record_path(manifest_path)?;
+	if !path.exists() {
+		return Ok(None);
+	}
+	let record = read_record(&path)?;
+	Ok(Some(
+		record.paths.iter().map(|p| recorded_path(p)).collect(),
+	))
+}
+
+/// The absolute paths referenced by every live GC root. Records whose manifest no longer
+/// exists are dead: their paths become garbage, and they're deleted unless `dry_run`.
+pub fn live_gc_root_paths(dry_run: bool) -> io::Result<Vec<PathBuf>> {
+	if get().is_none() {
+		return Ok(vec![]);
+	}
+	let mut paths = Vec::new();
+	for entry in std::fs::read_dir(roots_dir()).into_iter().flatten() {
+		let path = entry?.path();
+		if path.extension().is_none_or(|e| e != "json") {
+			continue;
+		}
+		// an unreadable record could be protecting anything, so it stops the collection
+		let record = read_record(&path)?;
+		if !record.manifest.exists() {
+			if !dry_run {
+				std::fs::remove_file(&path)?;
+			}
+			continue;
+		}
+		paths.extend(record.paths.iter().map(|p| recorded_path(p)));
+	}
+	Ok(paths)
+}
@@ -0,0 +1,161 @@
+use colored::Colorize;
+
+use crate::{
+	binary_cache::BinaryCacheError,
+	manifest::{BinaryCache, Manifest, Package, Source},
+};
+
+pub struct CacheResult {
+	action: &'static str,
+	transferred: Vec<String>,
+	/// Package name and why it was left alone
+	skipped: Vec<(String, &'static str)>,
+	errors: Vec<String>,
+}
+
+impl CacheResult {
+	fn new(action: &'static str) -> Self {
+		CacheResult {
+			action,
+			transferred: vec![],
+			skipped: vec![],
+			errors: vec![],
+		}
+	}
+	pub fn print(&self) {
+		for (name, reason) in &self.skipped {
+			println!("    {} {} {}", "".dimmed(), name.bold(), reason.dimmed());
+		}
+		for error in &self.errors {
+			eprintln!("{}: {}", "ERROR".red().bold(), error);
+		}
+		if self.errors.is_empty() {
+			println!(
+				"{} {} {} {}",
+				"󰆼".green(),
+				self.transferred.len().to_string().cyan(),
+				if self.transferred.len() == 1 {
+					"package"
+				} else {
+					"packages"
+				}
+				.green(),
+				self.action.green().bold()
+			);
+		}
+	}
+	pub fn exit_if_failure(&self) {
+		if !self.errors.is_empty() {
+			std::process::exit(1);
+		}
+	}
+}
+
+/// The PKGBUILD packages named on the command line (or all of them), in build order
+fn selected_packages<'m>(
+	manifest: &'m Manifest,
+	names: &[String],
+	result: &mut CacheResult,
+) -> Vec<&'m Package> {
+	for name in names {
+		if !manifest.packages.iter().any(|p| &p.name == name) {
+			result
+				.errors
+				.push(format!("package {name} is not defined in the manifest"));
+		}
+	}
+	let order = match manifest.build_order() {
+		Ok(order) => order,
+		Err(cycle) => {
+			result.errors.push(cycle.to_string());
+			return vec![];
+		}
+	};
+	order
+		.into_iter()
+		.filter(|p| !matches!(p.source, Source::Binary { .. }))
+		.filter(|p| names.is_empty() || names.contains(&p.name))
+		.collect()
+}
+
+fn configured_cache<'m>(
+	manifest: &'m Manifest,
+	result: &mut CacheResult,
+) -> Option<&'m BinaryCache> {
+	if manifest.binary_cache.is_none() {
+		result
+			.errors
+			.push("the manifest has no [binary_cache] section".to_string());
+	}
+	manifest.binary_cache.as_ref()
+}
+
+/// Uploads already built packages, regardless of the cache's `upload` setting
+pub fn push(manifest: &Manifest, names: &[String]) -> CacheResult {
+	let mut result = CacheResult::new("pushed");
+	let Some(cache) = configured_cache(manifest, &mut result) else {
+		return result;
+	};
+	for pkg in selected_packages(manifest, names, &mut result) {
+		match pkg.push_to_binary_cache(manifest, cache) {
+			Ok(true) => {
+				println!("    {} {}", "󰅧 Pushed".green().bold(), pkg.name.bold());
+				result.transferred.push(pkg.name.clone());
+			}
+			Ok(false) => result
+				.skipped
+				.push((pkg.name.clone(), "is already in the cache")),
+			Err(BinaryCacheError::NotBuilt) => result
+				.skipped
+				.push((pkg.name.clone(), "hasn't been built yet")),
+			Err(e) => result
+				.errors
+				.push(format!("failed to push {}: {e}", pkg.name)),
+		}
+	}
+	result
+}
+
+/// Downloads and unpacks the cached builds of packages that need a rebuild
+pub fn pull(manifest: &Manifest, names: &[String]) -> CacheResult {
+	let mut result = CacheResult::new("pulled");
+	let Some(cache) = configured_cache(manifest, &mut result) else {
+		return result;
+	};
+	for pkg in selected_packages(manifest, names, &mut result) {
+		if !pkg.needs_rebuild(manifest) {
+			result.skipped.push((pkg.name.clone(), "is up-to-date"));
+			continue;
+		}
+		let pulled = pkg
+			.pull_from_binary_cache(manifest, cache)
+			.and_then(|entry| {
+				let Some(entry) = entry else {
+					return Ok(None);
+				};
+				let unpacked_dir = pkg.create_out_unpacked_dir(manifest)?;
+				pkg
+					.install_built_packages(manifest, &unpacked_dir)
+					.map_err(|e| std::io::Error::other(e.to_string()))?;
+				Ok(Some(entry))
+			});
+		match pulled {
+			Ok(Some(entry)) => {
+				println!(
+					"    {} {} {}",
+					"󰇚 Pulled".green().bold(),
+					pkg.name.bold(),
+					format!("from {}", cache.backend.location(&entry.key)).dimmed()
+				);
+				result.transferred.push(pkg.name.clone());
+			}
+			Ok(None) => result
+				.skipped
+				.push((pkg.name.clone(), "is not in the cache")),
+			Err(e) => result
+				.errors
+				.push(format!("failed to pull {}: {e}", pkg.name)),
+		}
+	}
+	result
+}
@@ -1,19 +1,25 @@
 use std::{
-	io::{Write, stdout},
-	path::PathBuf,
+	collections::{BTreeMap, BTreeSet, HashMap},
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
 	process::Command,
 };
 
 use colored::Colorize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use super::{
+	filter::ImageFilter,
+	formats::{self, FormatError, SquashFsError},
+	size_report,
+};
 use crate::{
 	credits, fs_utils,
 	manifest::{Manifest, Package},
-	prefix_commands,
-	privilage_escalation::ensure_root,
+	size, verity,
 };
-fn get_git_commit_hash() -> Option<String> {
+pub fn get_git_commit_hash() -> Option<String> {
 	let output = Command::new("git")
 		.args(["rev-parse", "--short", "HEAD"])
 		.output()
@@ -26,89 +32,551 @@ fn get_git_commit_hash() -> Option<String> {
 	let hash = String::from_utf8(output.stdout).ok()?; // converte bytes em string
 	Some(hash.trim().to_string()) // remove \n e espaços
 }
-#[derive(Debug, Error)]
-pub enum SquashFsError {
-	#[error("Non-zero exit code: {exit_code}")]
-	Non0ExitCode { exit_code: i32 },
-	#[error("Command error: io error: {0}")]
-	CommandError(#[from] std::io::Error),
-}
-
 #[derive(Debug, Error)]
 pub enum AssembleError<'m> {
-	#[error("Failed to copy package {} to sysroot: {error}", package.name)]
+	#[error("Failed to link package {} into the sysroot: {error}", package.name)]
 	CopyError {
 		package: &'m Package,
 		error: std::io::Error,
 	},
 	#[error("Failed to create squashfs image: {0}")]
 	SquashfsError(#[from] SquashFsError),
+	#[error("Failed to create image: {0}")]
+	FormatError(#[from] FormatError),
+	#[error("Failed to generate the dm-verity hash tree: {0}")]
+	Verity(#[from] verity::VerityError),
+	#[error("The image is {} over the {} budget (`[image] max_size`)", size::human_readable_size(size - max_size), size::human_readable_size(*max_size))]
+	TooLarge { size: u64, max_size: u64 },
 	#[error("io error: {0}")]
 	Io(#[from] std::io::Error),
 }
 
-pub fn assemble<'m>(manifest: &'m Manifest) -> Result<PathBuf, AssembleError<'m>> {
-	let sysroot_folder = PathBuf::from("build/sysroot");
-	std::fs::remove_dir_all(&sysroot_folder).ok();
-	let image_file_name = format!(
-		"hyprside-{}-{}.squashfs",
-		manifest.version,
-		get_git_commit_hash().unwrap_or(String::from("unknown"))
-	);
+impl AssembleError<'_> {
+	pub fn print(&self) {
+		match self {
+			AssembleError::TooLarge { .. } => {
+				eprintln!();
+				eprintln!(
+					"    {}: {}",
+					" 󱁥  Image too large".bold().red(),
+					self.to_string().red()
+				);
+				eprintln!();
+			}
+			AssembleError::CopyError {
+				package: pkg,
+				error,
+			} => {
+				eprintln!(
+					"  {} {} {} {}: {}",
+					" 󱁥  Failed copying ".bold().red(),
+					pkg.name.red().bold(),
+					pkg.version.dimmed(),
+					"to sysroot".red(),
+					error.to_string().red()
+				);
+			}
+			AssembleError::SquashfsError(e) => {
+				eprintln!();
+				eprintln!(
+					"    {}: {}",
+					" 󱁥  Failed to create image".bold().red(),
+					e.to_string().red()
+				);
+				eprintln!();
+				if let SquashFsError::CommandError(e) = e
+					&& let std::io::ErrorKind::NotFound = e.kind()
+				{
+					eprintln!(
+						"    {}: This is likely due to {} not being installed. {}",
+						"help".bold().cyan(),
+						hyperlink(
+							"https://github.com/plougher/squashfs-tools",
+							"squashfs-tools".bold().underline()
+						),
+						"Make sure it is installed, or use the builtin writer (`[image.squashfs] backend = \"builtin\"`).".bold()
+					);
+					eprintln!();
+				}
+			}
+			AssembleError::FormatError(e) => {
+				eprintln!();
+				eprintln!(
+					"    {}: {}",
+					" 󱁥  Failed to create image".bold().red(),
+					e.to_string().red()
+				);
+				eprintln!();
+				if let FormatError::CommandError { tool, error } = e
+					&& let std::io::ErrorKind::NotFound = error.kind()
+				{
+					let (link, package) = match *tool {
+						"mkfs.erofs" => (
+							"https://git.kernel.org/pub/scm/linux/kernel/git/xiang/erofs-utils.git",
+							"erofs-utils",
+						),
+						_ => ("https://e2fsprogs.sourceforge.net", "e2fsprogs"),
+					};
+					eprintln!(
+						"    {}: This is likely due to {} not being installed. {}",
+						"help".bold().cyan(),
+						hyperlink(link, package.bold().underline()),
+						"Make sure it is installed, or use another image format (`[image] format`).".bold()
+					);
+					eprintln!();
+				}
+			}
+			AssembleError::Verity(e) => {
+				eprintln!();
+				eprintln!(
+					"    {}: {}",
+					" 󱁥  Failed to generate the dm-verity hash tree"
+						.bold()
+						.red(),
+					e.to_string().red()
+				);
+				eprintln!();
+			}
+			AssembleError::Io(e) => {
+				eprintln!();
+				eprintln!(
+					"    {}: {}",
+					" 󱁥  Failed to create image due to an IO error".bold().red(),
+					e.to_string().red().dimmed()
+				);
+				eprintln!();
+			}
+		}
+	}
+}
 
-	for pkg in manifest.packages.iter() {
-		let unpacked_path = pkg.get_out_unpacked_dir();
-		print!(
-			"    {} {} {}\r",
-			"󱁥  Copying".yellow().bold(),
-			pkg.name,
-			pkg.version.dimmed()
-		);
-		stdout().flush().ok();
-		fs_utils::copy_dir_all_with_filter(unpacked_path, &sysroot_folder, |d| {
-			d.file_name()
-				.into_string()
-				.is_ok_and(|n| !n.starts_with("."))
+/// An OSC 8 terminal hyperlink
+fn hyperlink(link: impl std::fmt::Display, text: impl std::fmt::Display) -> String {
+	format!("\x1b]8;;{link}\x1b\\{text}\x1b]8;;\x1b\\")
+}
+
+/// `YYYYMMDD` of a unix timestamp, in UTC
+fn utc_date(epoch: u64) -> String {
+	let (year, month, day) = civil_date(epoch);
+	format!("{year:04}{month:02}{day:02}")
+}
+
+/// Year, month and day of a unix timestamp, in UTC
+pub fn civil_date(epoch: u64) -> (i64, i64, i64) {
+	// days to civil date, see https://howardhinnant.github.io/date_algorithms.html
+	let days = (epoch / 86400) as i64 + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days.rem_euclid(146097);
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + i64::from(month <= 2);
+	(year, month, day)
+}
+
+/// The image name without extension (the `[image] name` template filled in), shared with
+/// the files generated alongside it
+pub fn image_stem(manifest: &Manifest) -> String {
+	let template = &manifest.image.name;
+	let epoch = || {
+		manifest.source_date_epoch().unwrap_or_else(|| {
+			std::time::SystemTime::now()
+				.duration_since(std::time::UNIX_EPOCH)
+				.map(|d| d.as_secs())
+				.unwrap_or_default()
 		})
-		.map_err(|e| AssembleError::CopyError {
+	};
+	let mut stem = template.replace("{version}", &manifest.version);
+	if stem.contains("{git}") {
+		stem = stem.replace(
+			"{git}",
+			&get_git_commit_hash().unwrap_or(String::from("unknown")),
+		);
+	}
+	if stem.contains("{date}") {
+		stem = stem.replace("{date}", &utc_date(epoch()));
+	}
+	if stem.contains("{epoch}") {
+		stem = stem.replace("{epoch}", &epoch().to_string());
+	}
+	stem
+}
+
+/// Where [`assemble`] writes the image
+pub fn image_path(manifest: &Manifest) -> PathBuf {
+	manifest.image.output_dir.join(format!(
+		"{}.{}",
+		image_stem(manifest),
+		manifest.image.format.extension()
+	))
+}
+
+/// Where the last assembled image's contents are kept
+pub fn sysroot_path(manifest: &Manifest) -> PathBuf {
+	match &manifest.rebuild_dir {
+		Some(dir) => dir.join("sysroot"),
+		None => PathBuf::from("build/sysroot"),
+	}
+}
+
+/// Records what [`sync_sysroot`] put in the sysroot, so the next assembly only touches
+/// what changed. Only valid for the sysroot it was written with.
+pub fn sysroot_state_path(manifest: &Manifest) -> PathBuf {
+	match &manifest.rebuild_dir {
+		Some(dir) => dir.join("sysroot.json"),
+		None => PathBuf::from("build/sysroot.json"),
+	}
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SysrootState {
+	/// Every file and symlink in the sysroot, relative to it
+	files: BTreeMap<PathBuf, InstalledFile>,
+	dirs: BTreeSet<PathBuf>,
+}
+
+/// Where a sysroot entry came from. A rebuild unpacks into new inodes, so the same
+/// source path, inode and length means the entry is still current.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct InstalledFile {
+	package: String,
+	source: PathBuf,
+	dev: u64,
+	ino: u64,
+	len: u64,
+}
+
+/// Adds everything under `dir` to `files` and `dirs`, later packages replacing earlier ones
+fn collect_package_tree<'m>(
+	package: &'m Package,
+	dir: &Path,
+	relative: &Path,
+	files: &mut BTreeMap<PathBuf, (PathBuf, &'m Package)>,
+	dirs: &mut BTreeSet<PathBuf>,
+) -> std::io::Result<()> {
+	for entry in std::fs::read_dir(dir)? {
+		let entry = entry?;
+		let relative = relative.join(entry.file_name());
+		if entry.file_type()?.is_dir() {
+			collect_package_tree(package, &entry.path(), &relative, files, dirs)?;
+			dirs.insert(relative);
+		} else {
+			files.insert(relative, (entry.path(), package));
+		}
+	}
+	Ok(())
+}
+
+/// Leaves out what the manifest's `[image]` include/exclude globs and strip policy don't
+/// want in the image. Returns the files and bytes each rule saved, in the order they
+/// first dropped something.
+fn filter_package_trees(
+	manifest: &Manifest,
+	files: &mut BTreeMap<PathBuf, (PathBuf, &Package)>,
+	dirs: &mut BTreeSet<PathBuf>,
+) -> Vec<(&'static str, (usize, u64))> {
+	let filter = ImageFilter::new(manifest);
+	let image_path = |relative: &Path| Path::new("/").join(relative);
+	let mut dropped: Vec<(&'static str, (usize, u64))> = Vec::new();
+	files.retain(|relative, (source, _)| {
+		let Some(rule) = filter.dropped_by(&image_path(relative), false) else {
+			return true;
+		};
+		let len = std::fs::symlink_metadata(source)
+			.map(|m| m.len())
+			.unwrap_or_default();
+		match dropped.iter_mut().find(|(name, _)| *name == rule) {
+			Some((_, (files, bytes))) => {
+				*files += 1;
+				*bytes += len;
+			}
+			None => dropped.push((rule, (1, len))),
+		}
+		false
+	});
+	// directories holding something that stays, stay too
+	let mut kept_dirs = files
+		.keys()
+		.flat_map(|path| path.ancestors().skip(1))
+		.filter(|dir| !dir.as_os_str().is_empty())
+		.map(Path::to_path_buf)
+		.collect::<BTreeSet<_>>();
+	kept_dirs.extend(
+		dirs
+			.iter()
+			.filter(|dir| filter.dropped_by(&image_path(dir), true).is_none())
+			.cloned(),
+	);
+	*dirs = kept_dirs;
+	dropped
+}
+
+/// Removes a file, symlink or directory tree, if there's anything at `path`
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+	match std::fs::symlink_metadata(path) {
+		Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+		Ok(_) => std::fs::remove_file(path),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		Err(e) => Err(e),
+	}
+}
+
+/// Makes the sysroot hold exactly the manifest's unpacked packages, hardlinking from the
+/// out dirs (copying when they're on another filesystem) and leaving alone whatever
+/// didn't change since the last assembly
+fn sync_sysroot<'m>(
+	manifest: &'m Manifest,
+	sysroot: &Path,
+) -> Result<SysrootState, AssembleError<'m>> {
+	let state_path = sysroot_state_path(manifest);
+	let previous = std::fs::read_to_string(&state_path)
+		.ok()
+		.and_then(|s| serde_json::from_str::<SysrootState>(&s).ok());
+	// an interrupted assembly leaves no state behind, and without one the sysroot is unknown
+	std::fs::remove_file(&state_path).ok();
+	let previous = match previous {
+		Some(previous) if sysroot.exists() => previous,
+		_ => {
+			remove_entry(sysroot)?;
+			SysrootState::default()
+		}
+	};
+	std::fs::create_dir_all(sysroot)?;
+
+	let mut wanted = BTreeMap::new();
+	let mut wanted_dirs = BTreeSet::new();
+	for pkg in &manifest.packages {
+		collect_package_tree(
+			pkg,
+			&pkg.get_out_unpacked_dir(manifest),
+			Path::new(""),
+			&mut wanted,
+			&mut wanted_dirs,
+		)
+		.map_err(|error| AssembleError::CopyError {
 			package: pkg,
-			error: e,
+			error,
 		})?;
+	}
+
+	let dropped = filter_package_trees(manifest, &mut wanted, &mut wanted_dirs);
+
+	let mut removed = 0usize;
+	for path in previous.files.keys().filter(|p| !wanted.contains_key(*p)) {
+		remove_entry(&sysroot.join(path))?;
+		removed += 1;
+	}
+	// deepest first, and only if nothing else ended up in them
+	for dir in previous
+		.dirs
+		.iter()
+		.rev()
+		.filter(|d| !wanted_dirs.contains(*d))
+	{
+		std::fs::remove_dir(sysroot.join(dir)).ok();
+	}
+	for dir in &wanted_dirs {
+		let path = sysroot.join(dir);
+		if !std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+			remove_entry(&path)?;
+			std::fs::create_dir(&path)?;
+		}
+	}
+
+	let mut state = SysrootState {
+		files: BTreeMap::new(),
+		dirs: wanted_dirs,
+	};
+	// (linked, unchanged) per package
+	let mut counts: HashMap<&str, (usize, usize)> = HashMap::new();
+	for (path, (source, pkg)) in wanted {
+		let link_error = |error| AssembleError::CopyError {
+			package: pkg,
+			error,
+		};
+		let metadata = std::fs::symlink_metadata(&source).map_err(link_error)?;
+		let installed = InstalledFile {
+			package: pkg.name.clone(),
+			source: source.clone(),
+			dev: metadata.dev(),
+			ino: metadata.ino(),
+			len: metadata.len(),
+		};
+		let destination = sysroot.join(&path);
+		let count = counts.entry(&pkg.name).or_default();
+		if previous.files.get(&path) == Some(&installed)
+			&& std::fs::symlink_metadata(&destination).is_ok()
+		{
+			count.1 += 1;
+		} else {
+			remove_entry(&destination)
+				.and_then(|_| fs_utils::link_or_copy(&source, &destination))
+				.map_err(link_error)?;
+			count.0 += 1;
+		}
+		state.files.insert(path, installed);
+	}
+
+	for pkg in &manifest.packages {
+		let (linked, unchanged) = counts.get(pkg.name.as_str()).copied().unwrap_or_default();
+		let status = if linked == 0 {
+			"up to date".dimmed().to_string()
+		} else {
+			format!("{linked} files linked, {unchanged} unchanged")
+				.green()
+				.to_string()
+		};
 		println!(
 			"  {} 󱁥  {} {} {}",
-			"  ".blue(),
+			"  ".blue(),
 			pkg.name.bold(),
 			format!("({})", pkg.version).dimmed().italic(),
-			"copied successfully".green()
+			status
 		);
 	}
+	for (rule, (files, bytes)) in &dropped {
+		println!(
+			"  {} ✂  {} {} {}",
+			"  ".blue(),
+			rule.bold(),
+			size::human_readable_size(*bytes).cyan(),
+			format!("saved ({files} file{})", if *files == 1 { "" } else { "s" }).dimmed()
+		);
+	}
+	if removed > 0 {
+		println!(
+			"  {} 󱁥  {}",
+			"  ".blue(),
+			format!("{removed} stale files removed").yellow()
+		);
+	}
+	std::fs::write(&state_path, serde_json::to_string(&state).unwrap())?;
+	Ok(state)
+}
+
+/// Removes an image and the files generated alongside it
+fn remove_image(image_path: &Path) -> std::io::Result<()> {
+	for path in [
+		image_path.to_path_buf(),
+		size_report::size_report_path(image_path),
+		verity::info_path(image_path),
+		image_path.with_extension("verity"),
+	] {
+		match std::fs::remove_file(path) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+pub fn assemble<'m>(manifest: &'m Manifest) -> Result<PathBuf, AssembleError<'m>> {
+	let sysroot_folder = sysroot_path(manifest);
+	let image_path = image_path(manifest);
+
+	let state = sync_sysroot(manifest, &sysroot_folder)?;
 
-	let credits = credits::generate_credits(&manifest.packages);
+	let credits = credits::generate_credits(manifest);
 	let credits_json = serde_json::to_string_pretty(&credits).unwrap();
 	let credits_file = sysroot_folder.join("etc/credits.json");
 	std::fs::create_dir_all(sysroot_folder.join("etc"))?;
-	std::fs::write(&credits_file, credits_json)?;
-	let images_path = PathBuf::from("build/images");
-	std::fs::create_dir_all(images_path)?;
-	let image_path = PathBuf::from("build/images").join(&image_file_name);
+	// never write through a hardlink into a package's out dir
+	remove_entry(&credits_file)?;
+	std::fs::write(&credits_file, &credits_json)?;
+	// the sysroot is hardlinked from the (possibly shared) out dirs, so its mtimes are left
+	// alone: every image backend stamps the source date epoch itself
+	let source_date_epoch = manifest.source_date_epoch();
+	std::fs::create_dir_all(&manifest.image.output_dir)?;
+	let previous = size_report::previous_image(manifest);
 	println!(
 		"     {} {}",
 		"→󰋩← Creating image".yellow().bold(),
-		image_file_name
+		image_path.file_name().unwrap_or_default().to_string_lossy()
 	);
-	let mut command = Command::new("mksquashfs");
-	command
-		.arg(&sysroot_folder)
-		.arg(&image_path)
-		.args(["-comp", "zstd", "-b", "1M", "-noappend"]);
-	let status =
-		prefix_commands::run_command_with_tag(command, "       [ →󰋩← mksquashfs ] ".blue().to_string())
-			.map_err(SquashFsError::CommandError)?;
-	if !status.success() {
-		return Err(AssembleError::SquashfsError(SquashFsError::Non0ExitCode {
-			exit_code: status.code().unwrap_or(-1),
-		}));
+	formats::write_image(manifest, &sysroot_folder, &image_path, source_date_epoch)?;
+	match &manifest.image.verity {
+		Some(options) => {
+			let info = verity::generate(&image_path, options)?;
+			println!(
+				"     {} {}",
+				"󰒃 dm-verity root hash".yellow().bold(),
+				info.root_hash
+			);
+		}
+		None => {
+			// a previous assembly's hash tree doesn't match this image
+			std::fs::remove_file(verity::info_path(&image_path)).ok();
+			std::fs::remove_file(image_path.with_extension("verity")).ok();
+		}
+	}
+	let mut installed = state
+		.files
+		.into_iter()
+		.map(|(path, file)| (path, (file.package, file.len)))
+		.collect::<BTreeMap<_, _>>();
+	installed.insert(
+		PathBuf::from("etc/credits.json"),
+		(
+			size_report::GENERATED.to_string(),
+			credits_json.len() as u64,
+		),
+	);
+	let report = size_report::generate(manifest, &image_path, &installed, previous)?;
+	report.print();
+	std::fs::write(
+		size_report::size_report_path(&image_path),
+		serde_json::to_string_pretty(&report).unwrap(),
+	)?;
+	if let Some(max_size) = report.max_size
+		&& report.is_over_budget()
+	{
+		// sign, push and the next size report must never pick up an image over budget
+		remove_image(&image_path)?;
+		return Err(AssembleError::TooLarge {
+			size: report.size,
+			max_size,
+		});
 	}
 	// rodar comando do squashfs aqui
 	Ok(image_path)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{TempDir, test_manifest};
+
+	fn manifest(dir: &TempDir, max_size: u64) -> Manifest {
+		let mut manifest = test_manifest(&format!(
+			"source_date_epoch = 1700000000\n[image]\nname = \"test\"\noutput_dir = {:?}\nmax_size = {max_size}\nverity = {{ hash_tree = \"separate\" }}\n[image.squashfs]\nbackend = \"builtin\"\n",
+			dir.path().join("image"),
+		));
+		manifest.rebuild_dir = Some(dir.path().join("build"));
+		manifest
+	}
+
+	#[test]
+	fn images_over_budget_are_removed() {
+		let dir = TempDir::new();
+		let manifest = manifest(&dir, 1 << 20);
+		let image = assemble(&manifest).unwrap();
+		let generated = [
+			image.clone(),
+			size_report::size_report_path(&image),
+			verity::info_path(&image),
+			image.with_extension("verity"),
+		];
+		assert!(generated.iter().all(|path| path.exists()));
+
+		let manifest = self::manifest(&dir, 1);
+		assert!(matches!(
+			assemble(&manifest),
+			Err(AssembleError::TooLarge { max_size: 1, .. })
+		));
+		assert!(generated.iter().all(|path| !path.exists()));
+		assert!(size_report::previous_image(&manifest).is_none());
+	}
+}
@@ -0,0 +1,335 @@
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::{self, Read},
+	path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use object::{Object, ObjectSection, ReadCache};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+	commands::{
+		image::assemble::{image_stem, sysroot_path},
+		kernel::build::{locate_tree, tarball_name},
+	},
+	manifest::Manifest,
+};
+
+/// A gdb `debug-file-directory` / debuginfod style tree matching the last assembled image
+pub struct DebugInfoResult {
+	pub tree: PathBuf,
+	pub archive: PathBuf,
+	pub binaries: usize,
+	pub with_debug_info: usize,
+	/// Image binaries (relative to the sysroot) that no debug package had symbols for
+	pub missing: Vec<PathBuf>,
+	pub vmlinux: bool,
+	pub kernel_modules: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum DebugInfoError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("no assembled image found at {}, run `image assemble` first", .0.display())]
+	NotAssembled(PathBuf),
+}
+
+/// One line of `index.json`, so crash dumps can be matched without walking `.build-id/`
+#[derive(Serialize)]
+struct IndexEntry {
+	build_id: String,
+	/// Path inside the image, or inside the kernel tree for the kernel and its modules
+	path: String,
+	/// The package whose debug package provided the symbols
+	package: Option<String>,
+	has_debug_info: bool,
+}
+
+struct ElfInfo {
+	build_id: String,
+	has_debug_info: bool,
+}
+
+/// Reads the GNU build-id of an ELF file without loading all of it, `None` for anything else
+fn read_elf_info(path: &Path) -> Option<ElfInfo> {
+	let mut magic = [0; 4];
+	File::open(path).ok()?.read_exact(&mut magic).ok()?;
+	if magic != *b"\x7fELF" {
+		return None;
+	}
+	let cache = ReadCache::new(File::open(path).ok()?);
+	let elf = object::File::parse(&cache).ok()?;
+	let build_id = elf.build_id().ok()??;
+	Some(ElfInfo {
+		build_id: build_id.iter().map(|b| format!("{b:02x}")).collect(),
+		has_debug_info: elf
+			.section_by_name(".debug_info")
+			.is_some_and(|s| s.size() > 0),
+	})
+}
+
+/// `.build-id/ab/cdef...` (without extension) for a build-id
+fn build_id_path(tree: &Path, build_id: &str) -> PathBuf {
+	tree
+		.join(".build-id")
+		.join(&build_id[..2])
+		.join(&build_id[2..])
+}
+
+/// Hard links `src` to `dst` (copying across filesystems), keeping an existing `dst`
+fn link(src: &Path, dst: &Path) -> io::Result<()> {
+	if dst.exists() {
+		return Ok(());
+	}
+	if let Some(parent) = dst.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	std::fs::hard_link(src, dst).or_else(|_| std::fs::copy(src, dst).map(|_| ()))
+}
+
+/// Every regular file under `dir`, symlinks are skipped
+fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+	if !dir.exists() {
+		return Ok(());
+	}
+	for entry in std::fs::read_dir(dir)? {
+		let entry = entry?;
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			walk_files(&entry.path(), files)?;
+		} else if file_type.is_file() {
+			files.push(entry.path());
+		}
+	}
+	Ok(())
+}
+
+/// build-id -> (debug file, package) from every package's debug store, using the
+/// `usr/lib/debug/.build-id/xx/rest.debug` links makepkg creates
+fn debug_files_by_build_id(manifest: &Manifest) -> io::Result<HashMap<String, (PathBuf, String)>> {
+	let mut debug_files = HashMap::new();
+	for pkg in &manifest.packages {
+		let build_id_dir = pkg
+			.get_out_debug_unpacked_dir(manifest)
+			.join("usr/lib/debug/.build-id");
+		if !build_id_dir.exists() {
+			continue;
+		}
+		for prefix in std::fs::read_dir(build_id_dir)? {
+			let prefix = prefix?;
+			if !prefix.file_type()?.is_dir() {
+				continue;
+			}
+			let prefix_name = prefix.file_name().to_string_lossy().to_string();
+			for entry in std::fs::read_dir(prefix.path())? {
+				let path = entry?.path();
+				let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+				let Some(rest) = file_name.strip_suffix(".debug") else {
+					continue;
+				};
+				// dangling links mean the debug file was split into another package
+				if let Ok(target) = path.canonicalize() {
+					debug_files.insert(format!("{prefix_name}{rest}"), (target, pkg.name.clone()));
+				}
+			}
+		}
+	}
+	Ok(debug_files)
+}
+
+/// The kernel tree the kernel builder unpacked for `[kernel] url`, if that kernel was built
+fn kernel_tree(manifest: &Manifest) -> io::Result<Option<PathBuf>> {
+	locate_tree(
+		Path::new("build/kernel/src"),
+		&tarball_name(&manifest.kernel.url),
+	)
+}
+
+pub fn debuginfo(manifest: &Manifest) -> Result<DebugInfoResult, DebugInfoError> {
+	let sysroot = sysroot_path(manifest);
+	if !sysroot.exists() {
+		return Err(DebugInfoError::NotAssembled(sysroot));
+	}
+	let stem = image_stem(manifest);
+	let tree = PathBuf::from("build/debuginfo").join(&stem);
+	if tree.exists() {
+		std::fs::remove_dir_all(&tree)?;
+	}
+	std::fs::create_dir_all(&tree)?;
+
+	let debug_files = debug_files_by_build_id(manifest)?;
+	let mut index = Vec::new();
+	let mut result = DebugInfoResult {
+		tree: tree.clone(),
+		archive: manifest
+			.image
+			.output_dir
+			.join(format!("{stem}.debuginfo.tar.zst")),
+		binaries: 0,
+		with_debug_info: 0,
+		missing: vec![],
+		vmlinux: false,
+		kernel_modules: 0,
+	};
+
+	let mut image_files = Vec::new();
+	walk_files(&sysroot, &mut image_files)?;
+	image_files.sort();
+	for path in image_files {
+		let Some(info) = read_elf_info(&path) else {
+			continue;
+		};
+		result.binaries += 1;
+		let relative = path.strip_prefix(&sysroot).unwrap_or(&path).to_path_buf();
+		let target = build_id_path(&tree, &info.build_id);
+		// debuginfod serves the executable next to its debug info
+		link(&path, &target)?;
+		let package = match debug_files.get(&info.build_id) {
+			Some((debug_file, package)) => {
+				link(debug_file, &target.with_extension("debug"))?;
+				Some(package.clone())
+			}
+			None if info.has_debug_info => {
+				link(&path, &target.with_extension("debug"))?;
+				None
+			}
+			None => {
+				result.missing.push(relative.clone());
+				index.push(IndexEntry {
+					build_id: info.build_id,
+					path: format!("/{}", relative.display()),
+					package: None,
+					has_debug_info: false,
+				});
+				continue;
+			}
+		};
+		result.with_debug_info += 1;
+		index.push(IndexEntry {
+			build_id: info.build_id,
+			path: format!("/{}", relative.display()),
+			package,
+			has_debug_info: true,
+		});
+	}
+
+	// sources makepkg saved for the debug packages, at the paths the DWARF refers to
+	for pkg in &manifest.packages {
+		let sources = pkg
+			.get_out_debug_unpacked_dir(manifest)
+			.join("usr/src/debug");
+		let mut files = Vec::new();
+		walk_files(&sources, &mut files)?;
+		for file in files {
+			let relative = file.strip_prefix(&sources).unwrap();
+			link(&file, &tree.join("usr/src/debug").join(relative))?;
+		}
+	}
+
+	if let Some(kernel_tree) = kernel_tree(manifest)? {
+		let vmlinux = kernel_tree.join("vmlinux");
+		if let Some(info) = read_elf_info(&vmlinux) {
+			link(&vmlinux, &tree.join("vmlinux"))?;
+			link(
+				&vmlinux,
+				&build_id_path(&tree, &info.build_id).with_extension("debug"),
+			)?;
+			result.vmlinux = true;
+			index.push(IndexEntry {
+				build_id: info.build_id,
+				path: "vmlinux".to_string(),
+				package: None,
+				has_debug_info: info.has_debug_info,
+			});
+		}
+		let mut files = Vec::new();
+		walk_files(&kernel_tree, &mut files)?;
+		files.retain(|f| f.extension().is_some_and(|e| e == "ko"));
+		files.sort();
+		for module in files {
+			let Some(info) = read_elf_info(&module) else {
+				continue;
+			};
+			let relative = module.strip_prefix(&kernel_tree).unwrap();
+			link(
+				&module,
+				&build_id_path(&tree, &info.build_id).with_extension("debug"),
+			)?;
+			result.kernel_modules += 1;
+			index.push(IndexEntry {
+				build_id: info.build_id,
+				path: relative.display().to_string(),
+				package: None,
+				has_debug_info: info.has_debug_info,
+			});
+		}
+	}
+
+	std::fs::write(
+		tree.join("index.json"),
+		serde_json::to_string_pretty(&index).map_err(io::Error::other)?,
+	)?;
+
+	std::fs::create_dir_all(&manifest.image.output_dir)?;
+	let encoder = zstd::Encoder::new(File::create(&result.archive)?, 0)?;
+	let mut archive = tar::Builder::new(encoder);
+	archive.follow_symlinks(false);
+	archive.append_dir_all(".", &tree)?;
+	archive.into_inner()?.finish()?;
+	Ok(result)
+}
+
+impl DebugInfoResult {
+	pub fn print(&self) {
+		println!(
+			"{} {} {} {} {}",
+			"🐞".green(),
+			self.with_debug_info.to_string().cyan(),
+			"of".green(),
+			self.binaries.to_string().cyan(),
+			"image binaries have debug info".green()
+		);
+		if self.vmlinux {
+			println!(
+				"    {} vmlinux and {} kernel modules",
+				"🐧".blue(),
+				self.kernel_modules.to_string().cyan()
+			);
+		} else {
+			println!(
+				"    {}: no vmlinux found, build the kernel to include its symbols",
+				"warning".yellow().bold()
+			);
+		}
+		if !self.missing.is_empty() {
+			println!(
+				"    {}: {} binaries have no debug info (is `debug` in the makepkg OPTIONS?)",
+				"warning".yellow().bold(),
+				self.missing.len()
+			);
+			for path in self.missing.iter().take(10) {
+				println!("        {}", format!("/{}", path.display()).dimmed());
+			}
+			if self.missing.len() > 10 {
+				println!(
+					"        {}",
+					format!("... and {} more", self.missing.len() - 10).dimmed()
+				);
+			}
+		}
+		println!(
+			"    {} {}",
+			"Symbol tree:".green(),
+			self.tree.display().to_string().bold()
+		);
+		println!(
+			"    {} {}",
+			"Archive:".green(),
+			self.archive.display().to_string().bold()
+		);
+	}
+}
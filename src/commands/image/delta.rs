@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+
+use super::release;
+use crate::{
+	delta::{self, DeltaError, DeltaHeader, DeltaStats},
+	fs_utils,
+	hash::{Sha256Hash, hash_file},
+	size,
+};
+
+/// `<new image>.from-<start of the old image's sha256>.delta`, the name `image push` looks
+/// for deltas under
+pub fn default_delta_path(new: &Path, old_sha256: &Sha256Hash) -> PathBuf {
+	fs_utils::with_suffix(
+		new,
+		&format!(".from-{}.delta", &old_sha256.as_str()[..12].to_lowercase()),
+	)
+}
+
+/// Deltas next to `image` that produce it, as written by [`delta`] with the default name
+pub fn deltas_for(image: &Path, sha256: &Sha256Hash) -> Vec<(PathBuf, DeltaHeader)> {
+	let Some(image_name) = image
+		.file_name()
+		.map(|name| name.to_string_lossy().into_owned())
+	else {
+		return Vec::new();
+	};
+	let dir = image
+		.parent()
+		.filter(|parent| !parent.as_os_str().is_empty())
+		.unwrap_or(Path::new("."));
+	let Ok(entries) = std::fs::read_dir(dir) else {
+		return Vec::new();
+	};
+	let mut deltas = entries
+		.filter_map(|entry| entry.ok())
+		.map(|entry| entry.path())
+		.filter(|path| {
+			let name = path.file_name().unwrap_or_default().to_string_lossy();
+			name.starts_with(&format!("{image_name}.from-")) && name.ends_with(".delta")
+		})
+		.filter_map(|path| {
+			let header = delta::read_header(&path).ok()?;
+			(header.new.sha256 == *sha256).then_some((path, header))
+		})
+		.collect::<Vec<_>>();
+	deltas.sort_by(|a, b| a.0.cmp(&b.0));
+	deltas
+}
+
+pub struct DeltaResult {
+	pub path: PathBuf,
+	pub stats: DeltaStats,
+	pub size: u64,
+}
+
+/// Generates the delta from `old` to `new`, next to `new` unless `output` is given
+pub fn delta(old: &Path, new: &Path, output: Option<PathBuf>) -> Result<DeltaResult, DeltaError> {
+	let version = |image: &Path| release::read_release(image).ok().map(|r| r.version);
+	let output = match output {
+		Some(output) => output,
+		None => default_delta_path(new, &hash_file(old)?),
+	};
+	let stats = delta::generate(old, new, &output, version(old), version(new))?;
+	Ok(DeltaResult {
+		size: std::fs::metadata(&output)?.len(),
+		path: output,
+		stats,
+	})
+}
+
+pub struct ApplyDeltaResult {
+	pub output: PathBuf,
+	pub header: DeltaHeader,
+}
+
+pub fn apply_delta(
+	old: &Path,
+	delta: &Path,
+	output: &Path,
+) -> Result<ApplyDeltaResult, DeltaError> {
+	let header = delta::apply(old, delta, output)?;
+	Ok(ApplyDeltaResult {
+		output: output.to_path_buf(),
+		header,
+	})
+}
+
+impl DeltaResult {
+	pub fn print(&self) {
+		let new_size = self.stats.header.new.size.max(1);
+		println!(
+			"{} {} {}",
+			"󰦓 Delta written to".green().bold(),
+			self.path.display().to_string().green().bold(),
+			size::human_readable_size(self.size).cyan()
+		);
+		println!(
+			"    {} {} {}",
+			"Reused from the old image:".green(),
+			size::human_readable_size(self.stats.copied),
+			format!("({}%)", self.stats.copied * 100 / new_size).dimmed()
+		);
+		println!(
+			"    {} {} {}",
+			"New data:                 ".green(),
+			size::human_readable_size(self.stats.inserted),
+			format!(
+				"({:.1}% of a full download)",
+				self.size as f64 * 100.0 / new_size as f64
+			)
+			.dimmed()
+		);
+	}
+}
+
+impl ApplyDeltaResult {
+	pub fn print(&self) {
+		println!(
+			"{} {} {}",
+			"✔ Rebuilt".green().bold(),
+			self.output.display().to_string().green().bold(),
+			format!("sha256 {}", self.header.new.sha256).dimmed()
+		);
+	}
+}
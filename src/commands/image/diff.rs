@@ -0,0 +1,496 @@
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	io,
+	os::unix::fs::{FileTypeExt, MetadataExt},
+	path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+	credits::PackageCredit,
+	manifest::EntryOverride,
+	size,
+	squashfs::{self, FileLayout, InodeKind, ReadError, Reader},
+};
+
+const CREDITS_PATH: &str = "/etc/credits.json";
+
+#[derive(Debug, Error)]
+pub enum DiffError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{}: {}", .0.display(), .1)]
+	Image(PathBuf, ReadError),
+	#[error("invalid image override: {0}")]
+	Overrides(#[from] squashfs::WriteError),
+}
+
+/// Where a file's contents can be read from, for comparing them
+#[derive(Debug, Clone)]
+enum Contents {
+	Image(FileLayout),
+	Path(PathBuf),
+}
+
+/// What's compared between two entries with the same path. mtimes aren't, since every
+/// image build has its own.
+#[derive(Debug, Clone)]
+struct Entry {
+	kind: char,
+	size: u64,
+	mode: u32,
+	uid: u32,
+	gid: u32,
+	target: Option<PathBuf>,
+	device: Option<u64>,
+	xattrs: Vec<(String, Vec<u8>)>,
+	contents: Option<Contents>,
+}
+
+/// One side of a diff, a squashfs image or a directory like the sysroot
+enum Tree<'m> {
+	Image(PathBuf, Box<Reader>),
+	/// Seen through the image overrides, the way the builtin writer would put it in an image
+	Directory(PathBuf, squashfs::Overrides<'m>),
+}
+
+impl<'m> Tree<'m> {
+	fn open(path: &Path, overrides: Option<&'m [EntryOverride]>) -> Result<Self, DiffError> {
+		if path.is_dir() {
+			return Ok(Tree::Directory(
+				path.to_path_buf(),
+				squashfs::Overrides::new(overrides.unwrap_or_default())?,
+			));
+		}
+		Reader::open(path)
+			.map(|reader| Tree::Image(path.to_path_buf(), Box::new(reader)))
+			.map_err(|e| DiffError::Image(path.to_path_buf(), e))
+	}
+
+	/// Every entry by absolute image path
+	fn entries(&mut self) -> Result<BTreeMap<PathBuf, Entry>, DiffError> {
+		let mut entries = BTreeMap::new();
+		match self {
+			Tree::Image(image, reader) => {
+				let mut inodes = Vec::new();
+				reader
+					.walk(Path::new("/"), &mut |path, inode| {
+						inodes.push((path.to_path_buf(), inode.clone()));
+						Ok(())
+					})
+					.map_err(|e| DiffError::Image(image.clone(), e))?;
+				for (path, inode) in inodes {
+					let mut xattrs = reader
+						.xattrs(&inode)
+						.map_err(|e| DiffError::Image(image.clone(), e))?;
+					xattrs.sort();
+					let (target, device, contents) = match &inode.kind {
+						InodeKind::Symlink(target) => (Some(squashfs::symlink_target(target)), None, None),
+						InodeKind::BlockDev(device) | InodeKind::CharDev(device) => {
+							(None, Some(*device as u64), None)
+						}
+						InodeKind::File(layout) => (None, None, Some(Contents::Image(layout.clone()))),
+						_ => (None, None, None),
+					};
+					entries.insert(
+						path,
+						Entry {
+							kind: inode.type_char(),
+							size: inode.size(),
+							mode: inode.mode as u32,
+							uid: inode.uid,
+							gid: inode.gid,
+							target,
+							device,
+							xattrs,
+							contents,
+						},
+					);
+				}
+			}
+			Tree::Directory(root, overrides) => {
+				walk_directory(root, Path::new("/"), overrides, &mut entries)?;
+			}
+		}
+		Ok(entries)
+	}
+
+	/// sha256 of a file's contents
+	fn hash(&mut self, contents: &Contents) -> Result<[u8; 32], DiffError> {
+		let mut hasher = Sha256::new();
+		match (self, contents) {
+			(Tree::Image(path, reader), Contents::Image(layout)) => reader
+				.read_file(layout, &mut hasher)
+				.map_err(|e| DiffError::Image(path.clone(), e))?,
+			(_, Contents::Path(path)) => {
+				io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+			}
+			(Tree::Directory(..), Contents::Image(_)) => {
+				unreachable!("directories have no image contents")
+			}
+		}
+		Ok(hasher.finalize().into())
+	}
+
+	/// The packages in the tree's credits, `None` when it has none
+	fn packages(&mut self) -> Result<Option<Vec<PackageCredit>>, DiffError> {
+		let contents = match self {
+			Tree::Image(path, reader) => {
+				let inode = match reader.lookup(Path::new(CREDITS_PATH)) {
+					Ok(inode) => inode,
+					Err(ReadError::NotFound(_) | ReadError::NotADirectory(_)) => return Ok(None),
+					Err(e) => return Err(DiffError::Image(path.clone(), e)),
+				};
+				let InodeKind::File(layout) = &inode.kind else {
+					return Ok(None);
+				};
+				let mut contents = Vec::new();
+				reader
+					.read_file(layout, &mut contents)
+					.map_err(|e| DiffError::Image(path.clone(), e))?;
+				contents
+			}
+			Tree::Directory(root, _) => match std::fs::read(root.join(&CREDITS_PATH[1..])) {
+				Ok(contents) => contents,
+				Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+				Err(e) => return Err(e.into()),
+			},
+		};
+		Ok(serde_json::from_slice(&contents).ok())
+	}
+}
+
+fn walk_directory(
+	path: &Path,
+	image_path: &Path,
+	overrides: &squashfs::Overrides,
+	entries: &mut BTreeMap<PathBuf, Entry>,
+) -> Result<(), DiffError> {
+	let metadata = std::fs::symlink_metadata(path)?;
+	let file_type = metadata.file_type();
+	let attributes = overrides.apply(&image_path.to_string_lossy(), metadata.mode() & 0o7777)?;
+	let mut entry = Entry {
+		kind: '-',
+		size: 0,
+		mode: attributes.mode,
+		uid: attributes.uid,
+		gid: attributes.gid,
+		target: None,
+		device: None,
+		xattrs: attributes.xattrs.into_iter().collect(),
+		contents: None,
+	};
+	if file_type.is_dir() {
+		entry.kind = 'd';
+		entries.insert(image_path.to_path_buf(), entry);
+		for child in std::fs::read_dir(path)? {
+			let child = child?;
+			walk_directory(
+				&child.path(),
+				&image_path.join(child.file_name()),
+				overrides,
+				entries,
+			)?;
+		}
+		return Ok(());
+	}
+	if file_type.is_file() {
+		entry.size = metadata.len();
+		entry.contents = Some(Contents::Path(path.to_path_buf()));
+	} else if file_type.is_symlink() {
+		let target = std::fs::read_link(path)?;
+		entry.kind = 'l';
+		entry.size = target.as_os_str().len() as u64;
+		entry.target = Some(target);
+	} else if file_type.is_block_device() || file_type.is_char_device() {
+		entry.kind = if file_type.is_block_device() {
+			'b'
+		} else {
+			'c'
+		};
+		entry.device = Some(squashfs::encode_device(metadata.rdev()) as u64);
+	} else {
+		entry.kind = if file_type.is_fifo() { 'p' } else { 's' };
+	}
+	entries.insert(image_path.to_path_buf(), entry);
+	Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageVersion {
+	pub name: String,
+	pub version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageUpgrade {
+	pub name: String,
+	pub from: String,
+	pub to: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PackageDiff {
+	pub added: Vec<PackageVersion>,
+	pub removed: Vec<PackageVersion>,
+	pub upgraded: Vec<PackageUpgrade>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileChange {
+	pub path: PathBuf,
+	/// `None` when the file doesn't exist on that side
+	pub old_size: Option<u64>,
+	pub new_size: Option<u64>,
+	/// What changed about a file on both sides: type, content, mode, owner, target,
+	/// device or xattrs
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub changes: Vec<&'static str>,
+}
+
+impl FileChange {
+	pub fn size_delta(&self) -> i64 {
+		self.new_size.unwrap_or(0) as i64 - self.old_size.unwrap_or(0) as i64
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffResult {
+	pub old: PathBuf,
+	pub new: PathBuf,
+	/// `None` when one of the sides has no `/etc/credits.json`
+	pub packages: Option<PackageDiff>,
+	pub added: Vec<FileChange>,
+	pub removed: Vec<FileChange>,
+	pub changed: Vec<FileChange>,
+	/// Total size of the files on the new side minus the old one
+	pub size_delta: i64,
+}
+
+fn diff_packages(old: Vec<PackageCredit>, new: Vec<PackageCredit>) -> PackageDiff {
+	let old = old
+		.into_iter()
+		.map(|p| (p.name, p.version))
+		.collect::<BTreeMap<_, _>>();
+	let new = new
+		.into_iter()
+		.map(|p| (p.name, p.version))
+		.collect::<BTreeMap<_, _>>();
+	let mut diff = PackageDiff::default();
+	for (name, version) in &new {
+		match old.get(name) {
+			None => diff.added.push(PackageVersion {
+				name: name.clone(),
+				version: version.clone(),
+			}),
+			// credits from before versions were recorded can't tell
+			Some(Some(old_version)) => {
+				if let Some(version) = version
+					&& old_version != version
+				{
+					diff.upgraded.push(PackageUpgrade {
+						name: name.clone(),
+						from: old_version.clone(),
+						to: version.clone(),
+					});
+				}
+			}
+			Some(None) => {}
+		}
+	}
+	for (name, version) in old {
+		if !new.contains_key(&name) {
+			diff.removed.push(PackageVersion { name, version });
+		}
+	}
+	diff
+}
+
+/// Compares two images (or an image and a directory like the sysroot): the packages in
+/// their credits and every file, by contents and attributes. Directories are seen through
+/// the manifest's image `overrides`, only images can be compared without a manifest.
+pub fn diff(
+	overrides: Option<&[EntryOverride]>,
+	old: &Path,
+	new: &Path,
+) -> Result<DiffResult, DiffError> {
+	let mut old_tree = Tree::open(old, overrides)?;
+	let mut new_tree = Tree::open(new, overrides)?;
+	let old_entries = old_tree.entries()?;
+	let new_entries = new_tree.entries()?;
+
+	let packages = match (old_tree.packages()?, new_tree.packages()?) {
+		(Some(old), Some(new)) => Some(diff_packages(old, new)),
+		_ => None,
+	};
+
+	let mut result = DiffResult {
+		old: old.to_path_buf(),
+		new: new.to_path_buf(),
+		packages,
+		added: vec![],
+		removed: vec![],
+		changed: vec![],
+		size_delta: 0,
+	};
+	let paths = old_entries
+		.keys()
+		.chain(new_entries.keys())
+		.collect::<BTreeSet<_>>();
+	for path in paths {
+		let (old_entry, new_entry) = (old_entries.get(path), new_entries.get(path));
+		let mut change = FileChange {
+			path: path.clone(),
+			old_size: old_entry.map(|e| e.size),
+			new_size: new_entry.map(|e| e.size),
+			changes: vec![],
+		};
+		let (old_entry, new_entry) = match (old_entry, new_entry) {
+			(None, Some(_)) => {
+				result.added.push(change);
+				continue;
+			}
+			(Some(_), None) => {
+				result.removed.push(change);
+				continue;
+			}
+			(Some(old_entry), Some(new_entry)) => (old_entry, new_entry),
+			(None, None) => unreachable!(),
+		};
+		if old_entry.kind != new_entry.kind {
+			change.changes.push("type");
+		} else if let (Some(old_contents), Some(new_contents)) =
+			(&old_entry.contents, &new_entry.contents)
+		{
+			// only files of the same size need reading
+			if old_entry.size != new_entry.size
+				|| old_tree.hash(old_contents)? != new_tree.hash(new_contents)?
+			{
+				change.changes.push("content");
+			}
+		}
+		if old_entry.mode != new_entry.mode {
+			change.changes.push("mode");
+		}
+		if (old_entry.uid, old_entry.gid) != (new_entry.uid, new_entry.gid) {
+			change.changes.push("owner");
+		}
+		if old_entry.target != new_entry.target {
+			change.changes.push("target");
+		}
+		if old_entry.device != new_entry.device {
+			change.changes.push("device");
+		}
+		if old_entry.xattrs != new_entry.xattrs {
+			change.changes.push("xattrs");
+		}
+		if !change.changes.is_empty() {
+			result.changed.push(change);
+		}
+	}
+	result.size_delta = result
+		.added
+		.iter()
+		.chain(&result.removed)
+		.chain(&result.changed)
+		.map(FileChange::size_delta)
+		.sum();
+	Ok(result)
+}
+
+fn signed_size(delta: i64) -> String {
+	let sign = if delta < 0 { '-' } else { '+' };
+	format!("{sign}{}", size::human_readable_size(delta.unsigned_abs()))
+}
+
+impl DiffResult {
+	pub fn print(&self) {
+		println!(
+			"{} {} {} {}",
+			"󰦓 Comparing".green().bold(),
+			self.old.display().to_string().bold(),
+			"→".dimmed(),
+			self.new.display().to_string().bold()
+		);
+		match &self.packages {
+			Some(packages) => {
+				println!("{}", "Packages".green().bold());
+				for package in &packages.added {
+					println!(
+						"    {} {} {}",
+						"+".green().bold(),
+						package.name.bold(),
+						package.version.as_deref().unwrap_or_default().dimmed()
+					);
+				}
+				for package in &packages.removed {
+					println!(
+						"    {} {} {}",
+						"-".red().bold(),
+						package.name.bold(),
+						package.version.as_deref().unwrap_or_default().dimmed()
+					);
+				}
+				for package in &packages.upgraded {
+					println!(
+						"    {} {} {} → {}",
+						"↑".yellow().bold(),
+						package.name.bold(),
+						package.from,
+						package.to
+					);
+				}
+				if packages.added.is_empty() && packages.removed.is_empty() && packages.upgraded.is_empty()
+				{
+					println!("    {}", "no changes".dimmed());
+				}
+			}
+			None => println!(
+				"{} {}",
+				"Packages".green().bold(),
+				format!("unknown, {CREDITS_PATH} is missing on one side").dimmed()
+			),
+		}
+
+		println!("{}", "Files".green().bold());
+		let mut files = self
+			.added
+			.iter()
+			.map(|change| ("+".green().bold(), change))
+			.chain(self.removed.iter().map(|change| ("-".red().bold(), change)))
+			.chain(
+				self
+					.changed
+					.iter()
+					.map(|change| ("~".yellow().bold(), change)),
+			)
+			.collect::<Vec<_>>();
+		files.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+		for (marker, change) in &files {
+			let mut line = format!("    {marker} {}", change.path.display());
+			if !change.changes.is_empty() {
+				line.push_str(&format!(" {}", change.changes.join(", ").dimmed()));
+			}
+			let delta = change.size_delta();
+			if delta != 0 {
+				line.push_str(&format!(" {}", format!("({})", signed_size(delta)).cyan()));
+			}
+			println!("{line}");
+		}
+		if files.is_empty() {
+			println!("    {}", "no changes".dimmed());
+		}
+		println!(
+			"{} {} added, {} removed, {} changed, {}",
+			"Summary:".green().bold(),
+			self.added.len(),
+			self.removed.len(),
+			self.changed.len(),
+			signed_size(self.size_delta).cyan()
+		);
+	}
+}
@@ -0,0 +1,133 @@
+use std::path::Path;
+
+use glob::{MatchOptions, Pattern};
+
+use crate::manifest::Manifest;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+	case_sensitive: true,
+	require_literal_separator: true,
+	require_literal_leading_dot: false,
+};
+
+/// A group of globs matching image paths along with everything under them
+struct Rule {
+	name: &'static str,
+	patterns: Vec<Pattern>,
+}
+
+impl Rule {
+	fn new(name: &'static str, globs: &[impl AsRef<str>]) -> Self {
+		Self {
+			name,
+			patterns: globs
+				.iter()
+				.map(|glob| {
+					Pattern::new(glob.as_ref()).expect("image globs are checked when deserializing")
+				})
+				.collect(),
+		}
+	}
+
+	fn matches(&self, image_path: &Path) -> bool {
+		image_path
+			.ancestors()
+			.filter(|p| p.parent().is_some())
+			.any(|p| {
+				self
+					.patterns
+					.iter()
+					.any(|pattern| pattern.matches_path_with(p, MATCH_OPTIONS))
+			})
+	}
+}
+
+/// Decides which of the files the packages install end up in the image, from the
+/// manifest's `[image]` include/exclude globs and strip policy
+pub struct ImageFilter<'m> {
+	include: Option<Rule>,
+	exclude: Rule,
+	strip: Vec<Rule>,
+	locales: Option<&'m [String]>,
+}
+
+impl<'m> ImageFilter<'m> {
+	pub fn new(manifest: &'m Manifest) -> Self {
+		let image = &manifest.image;
+		let strip = &image.strip;
+		let rules = [
+			(
+				strip.docs,
+				Rule::new(
+					"docs",
+					&["/usr/share/doc", "/usr/share/info", "/usr/share/gtk-doc"],
+				),
+			),
+			(strip.man_pages, Rule::new("man pages", &["/usr/share/man"])),
+			(
+				strip.static_libs,
+				Rule::new("static libs", &["/usr/lib/**/*.a"]),
+			),
+			(strip.headers, Rule::new("headers", &["/usr/include"])),
+			(
+				strip.pkgconfig,
+				Rule::new("pkgconfig", &["/usr/lib/pkgconfig", "/usr/share/pkgconfig"]),
+			),
+		];
+		Self {
+			include: (!image.include.is_empty()).then(|| Rule::new("include", &image.include)),
+			exclude: Rule::new("exclude", &image.exclude),
+			strip: rules
+				.into_iter()
+				.filter(|(enabled, _)| *enabled)
+				.map(|(_, rule)| rule)
+				.collect(),
+			locales: strip.locales.as_deref(),
+		}
+	}
+
+	/// Whether `image_path` is a locale the allowlist doesn't keep (or is inside one).
+	/// `pt` keeps `pt_BR` and `en_US` keeps `en_US.UTF-8`.
+	fn is_stripped_locale(&self, image_path: &Path, is_dir: bool) -> bool {
+		let Some(allowed) = self.locales else {
+			return false;
+		};
+		let Ok(rest) = image_path.strip_prefix("/usr/share/locale") else {
+			return false;
+		};
+		let mut components = rest.components();
+		let Some(locale) = components.next() else {
+			return false;
+		};
+		// files right in /usr/share/locale, like locale.alias, aren't a locale
+		if components.next().is_none() && !is_dir {
+			return false;
+		}
+		let locale = locale.as_os_str().to_string_lossy();
+		!allowed.iter().any(|allowed| {
+			locale
+				.strip_prefix(allowed.as_str())
+				.is_some_and(|rest| rest.is_empty() || rest.starts_with(['_', '.', '@']))
+		})
+	}
+
+	/// The rule leaving the file or directory at the absolute `image_path` out of the
+	/// image, if any
+	pub fn dropped_by(&self, image_path: &Path, is_dir: bool) -> Option<&'static str> {
+		if self.exclude.matches(image_path) {
+			return Some(self.exclude.name);
+		}
+		if let Some(include) = &self.include
+			&& !include.matches(image_path)
+		{
+			return Some(include.name);
+		}
+		if let Some(rule) = self.strip.iter().find(|rule| rule.matches(image_path)) {
+			return Some(rule.name);
+		}
+		if self.is_stripped_locale(image_path, is_dir) {
+			return Some("locales");
+		}
+		None
+	}
+}
@@ -0,0 +1,489 @@
+use std::{
+	io::Write,
+	os::unix::fs::{FileTypeExt, MetadataExt},
+	path::Path,
+	process::Command,
+};
+
+use colored::Colorize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::AssembleError;
+use crate::{
+	commands::image::packages::gc::calculate_folder_size,
+	manifest::{CompressionAlgorithm, ImageFormat, Manifest, SquashfsBackend},
+	prefix_commands, squashfs,
+};
+
+#[derive(Debug, Error)]
+pub enum SquashFsError {
+	#[error("Non-zero exit code: {exit_code}")]
+	Non0ExitCode { exit_code: i32 },
+	#[error("Command error: io error: {0}")]
+	CommandError(#[from] std::io::Error),
+	#[error("{0}")]
+	Writer(#[from] squashfs::WriteError),
+	#[error("{0} compression is not supported by {1}")]
+	UnsupportedCompression(CompressionAlgorithm, &'static str),
+	#[error("{0} compression has no compression level")]
+	UnsupportedLevel(CompressionAlgorithm),
+}
+
+/// Errors of the formats written by external tools from a rootfs tarball
+#[derive(Debug, Error)]
+pub enum FormatError {
+	#[error("{0} compression is not supported by {1}")]
+	UnsupportedCompression(CompressionAlgorithm, ImageFormat),
+	#[error("failed to write the rootfs tarball: {0}")]
+	Tarball(#[from] squashfs::WriteError),
+	#[error("{tool}: io error: {error}")]
+	CommandError {
+		tool: &'static str,
+		error: std::io::Error,
+	},
+	#[error("{tool} exited with non-zero code: {exit_code}")]
+	Non0ExitCode { tool: &'static str, exit_code: i32 },
+}
+
+/// zstd level of both squashfs backends, mksquashfs' default
+const COMPRESSION_LEVEL: i32 = 15;
+
+/// Writes the image of the sysroot in the format the manifest asks for
+pub fn write_image<'m>(
+	manifest: &'m Manifest,
+	sysroot: &Path,
+	image_path: &Path,
+	source_date_epoch: Option<u64>,
+) -> Result<(), AssembleError<'m>> {
+	match manifest.image.format {
+		ImageFormat::Squashfs => write_squashfs(manifest, sysroot, image_path, source_date_epoch)?,
+		ImageFormat::Erofs => run_mkfs_erofs(manifest, sysroot, image_path, source_date_epoch)?,
+		ImageFormat::Ext4 => run_mkfs_ext4(manifest, sysroot, image_path, source_date_epoch)?,
+	}
+	Ok(())
+}
+
+fn write_squashfs(
+	manifest: &Manifest,
+	sysroot: &Path,
+	image_path: &Path,
+	source_date_epoch: Option<u64>,
+) -> Result<(), SquashFsError> {
+	let options = &manifest.image.squashfs;
+	let block_size = options.block_size.unwrap_or(1024 * 1024);
+	match options.backend {
+		SquashfsBackend::Builtin => {
+			if options.compression != CompressionAlgorithm::Zstd {
+				return Err(SquashFsError::UnsupportedCompression(
+					options.compression,
+					"the builtin squashfs writer",
+				));
+			}
+			squashfs::write_image(
+				sysroot,
+				image_path,
+				&squashfs::WriterOptions {
+					block_size,
+					compression_level: options.level.unwrap_or(COMPRESSION_LEVEL),
+					mtime: source_date_epoch.map(|epoch| epoch.min(u32::MAX as u64) as u32),
+					overrides: &manifest.image.overrides,
+				},
+			)?
+		}
+		SquashfsBackend::Mksquashfs => {
+			run_mksquashfs(manifest, sysroot, image_path, block_size, source_date_epoch)?
+		}
+	}
+	Ok(())
+}
+
+/// Quotes a path for a mksquashfs pseudo file
+fn pseudo_quote(path: &str) -> String {
+	format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// mksquashfs pseudo definitions giving every entry the ownership, permissions and xattrs
+/// the builtin writer would
+fn pseudo_definitions(
+	dir: &Path,
+	image_path: &str,
+	overrides: &squashfs::Overrides,
+	definitions: &mut String,
+) -> Result<(), squashfs::WriteError> {
+	let metadata = std::fs::symlink_metadata(dir)?;
+	let attributes = overrides.apply(image_path, metadata.mode() & 0o7777)?;
+	let quoted = pseudo_quote(image_path);
+	definitions.push_str(&format!(
+		"{quoted} m {:o} {} {}\n",
+		attributes.mode, attributes.uid, attributes.gid
+	));
+	for (name, value) in &attributes.xattrs {
+		let hex = value.iter().map(|b| format!("{b:02x}")).collect::<String>();
+		definitions.push_str(&format!("{quoted} x {name}=0x{hex}\n"));
+	}
+	if metadata.is_dir() {
+		let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+		entries.sort_by_key(|e| e.file_name());
+		for entry in entries {
+			let child_path = format!(
+				"{}/{}",
+				image_path.trim_end_matches('/'),
+				entry.file_name().to_string_lossy()
+			);
+			pseudo_definitions(&entry.path(), &child_path, overrides, definitions)?;
+		}
+	}
+	Ok(())
+}
+
+/// mksquashfs arguments selecting the compressor and its level
+fn mksquashfs_compression_args(
+	compression: CompressionAlgorithm,
+	level: Option<i32>,
+) -> Result<Vec<String>, SquashFsError> {
+	let mut args = match compression {
+		CompressionAlgorithm::None => {
+			return match level {
+				Some(_) => Err(SquashFsError::UnsupportedLevel(compression)),
+				None => Ok(
+					["-noI", "-noId", "-noD", "-noF", "-noX"]
+						.map(String::from)
+						.to_vec(),
+				),
+			};
+		}
+		CompressionAlgorithm::Gzip
+		| CompressionAlgorithm::Lzo
+		| CompressionAlgorithm::Lz4
+		| CompressionAlgorithm::Xz
+		| CompressionAlgorithm::Zstd => vec!["-comp".to_string(), compression.to_string()],
+		_ => {
+			return Err(SquashFsError::UnsupportedCompression(
+				compression,
+				"mksquashfs",
+			));
+		}
+	};
+	let level = match compression {
+		CompressionAlgorithm::Zstd => Some(level.unwrap_or(COMPRESSION_LEVEL)),
+		_ => level,
+	};
+	match (compression, level) {
+		(_, None) => {}
+		(
+			CompressionAlgorithm::Gzip | CompressionAlgorithm::Lzo | CompressionAlgorithm::Zstd,
+			Some(level),
+		) => {
+			args.push("-Xcompression-level".to_string());
+			args.push(level.to_string());
+		}
+		(_, Some(_)) => return Err(SquashFsError::UnsupportedLevel(compression)),
+	}
+	Ok(args)
+}
+
+fn run_mksquashfs(
+	manifest: &Manifest,
+	sysroot: &Path,
+	image_path: &Path,
+	block_size: u64,
+	source_date_epoch: Option<u64>,
+) -> Result<(), SquashFsError> {
+	let options = &manifest.image.squashfs;
+	let compression_args = mksquashfs_compression_args(options.compression, options.level)?;
+	let overrides = squashfs::Overrides::new(&manifest.image.overrides)?;
+	let mut definitions = String::new();
+	pseudo_definitions(sysroot, "/", &overrides, &mut definitions)?;
+	let pseudo_file = image_path.with_extension("pseudo");
+	std::fs::write(&pseudo_file, definitions)?;
+
+	let mut command = Command::new("mksquashfs");
+	command
+		.arg(sysroot)
+		.arg(image_path)
+		.args(compression_args)
+		.arg("-b")
+		.arg(block_size.to_string())
+		.arg("-noappend")
+		.arg("-pf")
+		.arg(&pseudo_file);
+	if let Some(epoch) = source_date_epoch {
+		command
+			.arg("-mkfs-time")
+			.arg(epoch.to_string())
+			.arg("-all-time")
+			.arg(epoch.to_string());
+	}
+	let status =
+		prefix_commands::run_command_with_tag(command, "       [ →󰋩← mksquashfs ] ".blue().to_string());
+	std::fs::remove_file(&pseudo_file).ok();
+	let status = status?;
+	if !status.success() {
+		return Err(SquashFsError::Non0ExitCode {
+			exit_code: status.code().unwrap_or(-1),
+		});
+	}
+	Ok(())
+}
+
+/// A pax extended header record, `<length> <key>=<value>\n` where the length counts itself
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+	let rest = 1 + key.len() + 1 + value.len() + 1;
+	let mut len = rest + 1;
+	while len != rest + len.to_string().len() {
+		len = rest + len.to_string().len();
+	}
+	let mut record = format!("{len} {key}=").into_bytes();
+	record.extend_from_slice(value);
+	record.push(b'\n');
+	record
+}
+
+/// Appends the sysroot entry at `path` (and everything below it) to a rootfs tarball, with
+/// the ownership, permissions and xattrs the squashfs writers would give it
+fn append_rootfs_entry(
+	builder: &mut tar::Builder<impl Write>,
+	path: &Path,
+	image_path: &str,
+	overrides: &squashfs::Overrides,
+	source_date_epoch: Option<u64>,
+) -> Result<(), squashfs::WriteError> {
+	let metadata = std::fs::symlink_metadata(path)?;
+	let file_type = metadata.file_type();
+	if file_type.is_socket() {
+		return Ok(());
+	}
+	let attributes = overrides.apply(image_path, metadata.mode() & 0o7777)?;
+	let tar_path = format!(".{image_path}");
+
+	if !attributes.xattrs.is_empty() {
+		let records = attributes
+			.xattrs
+			.iter()
+			.flat_map(|(name, value)| pax_record(&format!("SCHILY.xattr.{name}"), value))
+			.collect::<Vec<_>>();
+		let mut header = tar::Header::new_ustar();
+		header.set_entry_type(tar::EntryType::XHeader);
+		header.set_path("PaxHeader")?;
+		header.set_mode(0o644);
+		header.set_mtime(0);
+		header.set_size(records.len() as u64);
+		header.set_cksum();
+		builder.append(&header, records.as_slice())?;
+	}
+
+	let mut header = tar::Header::new_gnu();
+	header.set_mode(attributes.mode);
+	header.set_uid(attributes.uid as u64);
+	header.set_gid(attributes.gid as u64);
+	header.set_mtime(source_date_epoch.unwrap_or_else(|| metadata.mtime().max(0) as u64));
+	header.set_size(0);
+	if file_type.is_dir() {
+		header.set_entry_type(tar::EntryType::Directory);
+		builder.append_data(&mut header, &tar_path, std::io::empty())?;
+		let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+		entries.sort_by_key(|e| e.file_name());
+		for entry in entries {
+			let child_path = format!(
+				"{}/{}",
+				image_path.trim_end_matches('/'),
+				entry.file_name().to_string_lossy()
+			);
+			append_rootfs_entry(
+				builder,
+				&entry.path(),
+				&child_path,
+				overrides,
+				source_date_epoch,
+			)?;
+		}
+	} else if file_type.is_symlink() {
+		header.set_entry_type(tar::EntryType::Symlink);
+		builder.append_link(&mut header, &tar_path, std::fs::read_link(path)?)?;
+	} else if file_type.is_file() {
+		header.set_entry_type(tar::EntryType::Regular);
+		header.set_size(metadata.len());
+		builder.append_data(&mut header, &tar_path, std::fs::File::open(path)?)?;
+	} else {
+		header.set_entry_type(if file_type.is_block_device() {
+			tar::EntryType::Block
+		} else if file_type.is_char_device() {
+			tar::EntryType::Char
+		} else {
+			tar::EntryType::Fifo
+		});
+		header.set_device_major(libc::major(metadata.rdev()))?;
+		header.set_device_minor(libc::minor(metadata.rdev()))?;
+		builder.append_data(&mut header, &tar_path, std::io::empty())?;
+	}
+	Ok(())
+}
+
+/// Writes the sysroot as a tarball the way it should end up in the image, for the mkfs tools
+/// that can't be told which ownership and xattrs entries get
+fn write_rootfs_tarball(
+	manifest: &Manifest,
+	sysroot: &Path,
+	tarball: &Path,
+	source_date_epoch: Option<u64>,
+) -> Result<(), squashfs::WriteError> {
+	let overrides = squashfs::Overrides::new(&manifest.image.overrides)?;
+	let file = std::io::BufWriter::new(std::fs::File::create(tarball)?);
+	let mut builder = tar::Builder::new(file);
+	builder.mode(tar::HeaderMode::Complete);
+	append_rootfs_entry(&mut builder, sysroot, "/", &overrides, source_date_epoch)?;
+	builder.into_inner()?.flush()?;
+	Ok(())
+}
+
+/// A filesystem UUID derived from the image's file name, so rebuilding an image doesn't
+/// change it
+fn image_uuid(image_path: &Path) -> String {
+	let digest = Sha256::digest(
+		image_path
+			.file_name()
+			.unwrap_or_default()
+			.as_encoded_bytes(),
+	);
+	let mut bytes: [u8; 16] = digest[..16].try_into().unwrap();
+	// version 4, RFC 4122 variant
+	bytes[6] = (bytes[6] & 0x0f) | 0x40;
+	bytes[8] = (bytes[8] & 0x3f) | 0x80;
+	let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+	format!(
+		"{}-{}-{}-{}-{}",
+		&hex[..8],
+		&hex[8..12],
+		&hex[12..16],
+		&hex[16..20],
+		&hex[20..]
+	)
+}
+
+fn run_tool(tool: &'static str, command: Command) -> Result<(), FormatError> {
+	let status = prefix_commands::run_command_with_tag(
+		command,
+		format!("       [ →󰋩← {tool} ] ").blue().to_string(),
+	)
+	.map_err(|error| FormatError::CommandError { tool, error })?;
+	if !status.success() {
+		return Err(FormatError::Non0ExitCode {
+			tool,
+			exit_code: status.code().unwrap_or(-1),
+		});
+	}
+	Ok(())
+}
+
+/// Runs `f` with a rootfs tarball of the sysroot next to the image, removing it afterwards
+fn with_rootfs_tarball(
+	manifest: &Manifest,
+	sysroot: &Path,
+	image_path: &Path,
+	source_date_epoch: Option<u64>,
+	f: impl FnOnce(&Path) -> Result<(), FormatError>,
+) -> Result<(), FormatError> {
+	let tarball = image_path.with_extension("rootfs.tar");
+	let result = write_rootfs_tarball(manifest, sysroot, &tarball, source_date_epoch)
+		.map_err(FormatError::from)
+		.and_then(|()| f(&tarball));
+	std::fs::remove_file(&tarball).ok();
+	result
+}
+
+fn run_mkfs_erofs(
+	manifest: &Manifest,
+	sysroot: &Path,
+	image_path: &Path,
+	source_date_epoch: Option<u64>,
+) -> Result<(), FormatError> {
+	let options = &manifest.image.erofs;
+	let compression = match options.compression {
+		CompressionAlgorithm::None => None,
+		CompressionAlgorithm::Lz4
+		| CompressionAlgorithm::Lz4hc
+		| CompressionAlgorithm::Lzma
+		| CompressionAlgorithm::Deflate
+		| CompressionAlgorithm::Zstd => Some(match options.level {
+			Some(level) => format!("-z{},{level}", options.compression),
+			None => format!("-z{}", options.compression),
+		}),
+		_ => {
+			return Err(FormatError::UnsupportedCompression(
+				options.compression,
+				ImageFormat::Erofs,
+			));
+		}
+	};
+	with_rootfs_tarball(
+		manifest,
+		sysroot,
+		image_path,
+		source_date_epoch,
+		|tarball| {
+			let mut command = Command::new("mkfs.erofs");
+			command
+				.args(compression)
+				.arg("-U")
+				.arg(image_uuid(image_path));
+			if let Some(epoch) = source_date_epoch {
+				command.arg(format!("-T{epoch}"));
+			}
+			command.arg("--tar=f").arg(image_path).arg(tarball);
+			run_tool("mkfs.erofs", command)
+		},
+	)
+}
+
+fn run_mkfs_ext4(
+	manifest: &Manifest,
+	sysroot: &Path,
+	image_path: &Path,
+	source_date_epoch: Option<u64>,
+) -> Result<(), FormatError> {
+	// room for metadata, resize2fs shrinks it back to what's used
+	let size_kib = (calculate_folder_size(sysroot).map_err(squashfs::WriteError::from)? / 4 * 5
+		+ 64 * 1024 * 1024)
+		/ 1024;
+	// mkfs.ext4 doesn't truncate an existing file, a bigger previous image would leave its tail
+	std::fs::remove_file(image_path).ok();
+	let uuid = image_uuid(image_path);
+	with_rootfs_tarball(
+		manifest,
+		sysroot,
+		image_path,
+		source_date_epoch,
+		|tarball| {
+			let with_fake_time = |command: &mut Command| {
+				if let Some(epoch) = source_date_epoch {
+					command.env("E2FSPROGS_FAKE_TIME", epoch.to_string());
+				}
+			};
+			let mut mkfs = Command::new("mkfs.ext4");
+			mkfs
+				.args([
+					"-q", "-F", "-t", "ext4", "-b", "4096", "-L", "hyprside", "-U",
+				])
+				.arg(&uuid)
+				.arg("-E")
+				.arg(format!("root_owner=0:0,hash_seed={uuid}"))
+				.args(["-O", "^has_journal", "-d"])
+				.arg(tarball)
+				.arg(image_path)
+				.arg(format!("{size_kib}k"));
+			with_fake_time(&mut mkfs);
+			run_tool("mkfs.ext4", mkfs)?;
+
+			let mut resize = Command::new("resize2fs");
+			resize.arg("-M").arg(image_path);
+			with_fake_time(&mut resize);
+			run_tool("resize2fs", resize)?;
+
+			let mut tune = Command::new("tune2fs");
+			tune.args(["-O", "read-only"]).arg(image_path);
+			with_fake_time(&mut tune);
+			run_tool("tune2fs", tune)
+		},
+	)
+}
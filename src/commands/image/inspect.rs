@@ -0,0 +1,329 @@
+use std::{
+	fs::File,
+	io::{self, BufWriter, Write},
+	os::unix::fs::PermissionsExt,
+	path::{Component, Path, PathBuf},
+	time::{Duration, UNIX_EPOCH},
+};
+
+use colored::Colorize;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::assemble::civil_date;
+use crate::{
+	size,
+	squashfs::{Inode, InodeKind, ReadError, Reader, symlink_target},
+};
+
+#[derive(Debug, Error)]
+pub enum InspectError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{}: {}", .0.display(), .1)]
+	Image(PathBuf, ReadError),
+	#[error("is a directory in the image: {0}")]
+	IsADirectory(String),
+	#[error("not a regular file in the image: {0}")]
+	NotAFile(String),
+	#[error("{} already exists", .0.display())]
+	Exists(PathBuf),
+	#[error("refusing to extract {}, it would end up outside the destination", .0.display())]
+	UnsafePath(PathBuf),
+}
+
+/// Opens `image` and finds `path` in it, following symlinks in its parent directories but
+/// not in the last component, like `ls` and `cp` do
+fn open(image: &Path, path: &Path) -> Result<(Reader, PathBuf, Inode), InspectError> {
+	let image_error = |e| InspectError::Image(image.to_path_buf(), e);
+	let mut reader = Reader::open(image).map_err(image_error)?;
+	let (path, inode) = match (path.parent(), path.file_name()) {
+		(Some(parent), Some(name)) => {
+			let (parent, _) = reader.resolve(parent).map_err(image_error)?;
+			let path = parent.join(name);
+			let inode = reader.lookup(&path).map_err(image_error)?;
+			(path, inode)
+		}
+		_ => reader.resolve(path).map_err(image_error)?,
+	};
+	Ok((reader, path, inode))
+}
+
+#[derive(Debug, Serialize)]
+pub struct LsEntry {
+	/// The name in a directory listing, the full path in the image otherwise
+	pub path: PathBuf,
+	#[serde(rename = "type")]
+	pub kind: char,
+	pub mode: u16,
+	pub uid: u32,
+	pub gid: u32,
+	pub size: u64,
+	pub mtime: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub target: Option<PathBuf>,
+	/// Major and minor numbers of device nodes
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub device: Option<(u32, u32)>,
+}
+
+impl LsEntry {
+	fn new(path: PathBuf, inode: &Inode) -> Self {
+		let (target, device) = match &inode.kind {
+			InodeKind::Symlink(target) => (Some(symlink_target(target)), None),
+			// old 32 bit encoding, see `squashfs::encode_device`
+			InodeKind::BlockDev(dev) | InodeKind::CharDev(dev) => (
+				None,
+				Some(((dev >> 8) & 0xfff, (dev & 0xff) | ((dev >> 12) & 0xfff00))),
+			),
+			_ => (None, None),
+		};
+		Self {
+			path,
+			kind: inode.type_char(),
+			mode: inode.mode,
+			uid: inode.uid,
+			gid: inode.gid,
+			size: inode.size(),
+			mtime: inode.mtime,
+			target,
+			device,
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct LsResult {
+	pub entries: Vec<LsEntry>,
+}
+
+/// Lists the directory at `path` in `image`, or everything under it when `recursive`. Any
+/// other kind of file is listed by itself.
+pub fn ls(image: &Path, path: &Path, recursive: bool) -> Result<LsResult, InspectError> {
+	let (mut reader, path, inode) = open(image, path)?;
+	let image_error = |e| InspectError::Image(image.to_path_buf(), e);
+	let mut entries = Vec::new();
+	match &inode.kind {
+		InodeKind::Dir(_) if recursive => {
+			reader
+				.walk(&path, &mut |entry_path, entry| {
+					if entry_path != path {
+						entries.push(LsEntry::new(entry_path.to_path_buf(), entry));
+					}
+					Ok(())
+				})
+				.map_err(image_error)?;
+		}
+		InodeKind::Dir(listing) => {
+			for (name, reference) in reader.read_dir(listing).map_err(image_error)? {
+				let entry = reader.inode(reference).map_err(image_error)?;
+				entries.push(LsEntry::new(name.into(), &entry));
+			}
+		}
+		_ => entries.push(LsEntry::new(path, &inode)),
+	}
+	Ok(LsResult { entries })
+}
+
+/// `ls -l` style permissions, with the setuid, setgid and sticky bits
+fn mode_string(kind: char, mode: u16) -> String {
+	let mut string = String::from(kind);
+	for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+		let bits = mode >> shift;
+		string.push(if bits & 4 != 0 { 'r' } else { '-' });
+		string.push(if bits & 2 != 0 { 'w' } else { '-' });
+		string.push(match (mode & special != 0, bits & 1 != 0) {
+			(true, true) => special_char,
+			(true, false) => special_char.to_ascii_uppercase(),
+			(false, true) => 'x',
+			(false, false) => '-',
+		});
+	}
+	string
+}
+
+/// `YYYY-MM-DD HH:MM` of a unix timestamp, in UTC
+fn format_mtime(mtime: u32) -> String {
+	let (year, month, day) = civil_date(mtime as u64);
+	let minutes = mtime % 86400 / 60;
+	format!(
+		"{year:04}-{month:02}-{day:02} {:02}:{:02}",
+		minutes / 60,
+		minutes % 60
+	)
+}
+
+impl LsResult {
+	pub fn print(&self, long: bool) -> io::Result<()> {
+		// plain, this is meant to be piped to grep and friends
+		let mut out = BufWriter::new(io::stdout().lock());
+		for entry in &self.entries {
+			if long {
+				let size = match entry.device {
+					Some((major, minor)) => format!("{major}, {minor}"),
+					None => entry.size.to_string(),
+				};
+				write!(
+					out,
+					"{} {:>5} {:>5} {:>10} {} ",
+					mode_string(entry.kind, entry.mode),
+					entry.uid,
+					entry.gid,
+					size,
+					format_mtime(entry.mtime)
+				)?;
+			}
+			write!(out, "{}", entry.path.display())?;
+			match &entry.target {
+				Some(target) if long => writeln!(out, " -> {}", target.display())?,
+				_ => writeln!(out)?,
+			}
+		}
+		out.flush()
+	}
+}
+
+/// Writes the file at `path` in `image` to stdout, following symlinks
+pub fn cat(image: &Path, path: &Path) -> Result<(), InspectError> {
+	let image_error = |e| InspectError::Image(image.to_path_buf(), e);
+	let mut reader = Reader::open(image).map_err(image_error)?;
+	let (path, inode) = reader.resolve(path).map_err(image_error)?;
+	let file = match &inode.kind {
+		InodeKind::File(file) => file,
+		InodeKind::Dir(_) => return Err(InspectError::IsADirectory(path.display().to_string())),
+		_ => return Err(InspectError::NotAFile(path.display().to_string())),
+	};
+	let mut out = BufWriter::new(io::stdout().lock());
+	match reader
+		.read_file(file, &mut out)
+		.and_then(|()| Ok(out.flush()?))
+	{
+		// `image cat ... | head`
+		Err(ReadError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+		result => result.map_err(image_error),
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractResult {
+	pub dest: PathBuf,
+	pub files: usize,
+	pub dirs: usize,
+	pub symlinks: usize,
+	pub bytes: u64,
+	/// Device nodes, fifos and sockets, which aren't extracted
+	pub skipped: Vec<PathBuf>,
+}
+
+/// Copies the file or directory at `path` in `image` to `dest`, or into it if it's an
+/// existing directory. Modes and mtimes are kept, and owners too when running as root.
+/// Existing files are never overwritten.
+pub fn extract(image: &Path, path: &Path, dest: &Path) -> Result<ExtractResult, InspectError> {
+	let image_error = |e| InspectError::Image(image.to_path_buf(), e);
+	let (mut reader, path, _) = open(image, path)?;
+	let dest = match path.file_name() {
+		Some(name) if dest.is_dir() => dest.join(name),
+		_ => dest.to_path_buf(),
+	};
+	let mut entries = Vec::new();
+	reader
+		.walk(&path, &mut |entry_path, inode| {
+			entries.push((entry_path.to_path_buf(), inode.clone()));
+			Ok(())
+		})
+		.map_err(image_error)?;
+
+	// extracting `/` into an existing directory
+	let dest_exists = dest.is_dir();
+	let as_root = unsafe { libc::geteuid() } == 0;
+	let mut result = ExtractResult {
+		dest: dest.clone(),
+		..Default::default()
+	};
+	// directories get their mode and mtime once their contents are written, in case
+	// they're read-only
+	let mut dirs = Vec::new();
+	for (entry_path, inode) in entries {
+		let target = match entry_path.strip_prefix(&path) {
+			Ok(relative) if relative.as_os_str().is_empty() => dest.clone(),
+			Ok(relative)
+				if relative
+					.components()
+					.all(|c| matches!(c, Component::Normal(_))) =>
+			{
+				dest.join(relative)
+			}
+			_ => return Err(InspectError::UnsafePath(entry_path)),
+		};
+		let exists = |e: io::Error| match e.kind() {
+			io::ErrorKind::AlreadyExists => InspectError::Exists(target.clone()),
+			_ => InspectError::Io(e),
+		};
+		let mtime = UNIX_EPOCH + Duration::from_secs(inode.mtime as u64);
+		match &inode.kind {
+			InodeKind::Dir(_) => {
+				if !(dest_exists && target == dest) {
+					std::fs::create_dir(&target).map_err(exists)?;
+				}
+				result.dirs += 1;
+			}
+			InodeKind::File(file) => {
+				let mut out = BufWriter::new(File::create_new(&target).map_err(exists)?);
+				reader.read_file(file, &mut out).map_err(image_error)?;
+				let out = out.into_inner().map_err(|e| e.into_error())?;
+				out.set_modified(mtime)?;
+				result.files += 1;
+				result.bytes += inode.size();
+			}
+			InodeKind::Symlink(link) => {
+				std::os::unix::fs::symlink(symlink_target(link), &target).map_err(exists)?;
+				result.symlinks += 1;
+			}
+			_ => {
+				result.skipped.push(entry_path);
+				continue;
+			}
+		}
+		if as_root {
+			std::os::unix::fs::lchown(&target, Some(inode.uid), Some(inode.gid))?;
+		}
+		match &inode.kind {
+			InodeKind::Dir(_) => dirs.push((target, inode.mode, mtime)),
+			// symlinks have no mode of their own
+			InodeKind::Symlink(_) => {}
+			// after chown, which clears setuid and setgid
+			_ => std::fs::set_permissions(&target, PermissionsExt::from_mode(inode.mode as u32))?,
+		}
+	}
+	for (dir, mode, mtime) in dirs.into_iter().rev() {
+		std::fs::set_permissions(&dir, PermissionsExt::from_mode(mode as u32))?;
+		File::open(&dir)?.set_modified(mtime)?;
+	}
+	Ok(result)
+}
+
+impl ExtractResult {
+	pub fn print(&self) {
+		println!(
+			"{} {} {}",
+			"✔ Extracted to".green().bold(),
+			self.dest.display().to_string().green().bold(),
+			format!(
+				"({} files, {} directories, {} symlinks, {})",
+				self.files,
+				self.dirs,
+				self.symlinks,
+				size::human_readable_size(self.bytes)
+			)
+			.dimmed()
+		);
+		for path in &self.skipped {
+			println!(
+				"    {} {} {}",
+				"".yellow(),
+				path.display(),
+				"(device node, fifo or socket, not extracted)".dimmed()
+			);
+		}
+	}
+}
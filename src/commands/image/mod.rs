@@ -1,4 +1,14 @@
 pub mod assemble;
+pub mod debuginfo;
+pub mod delta;
+pub mod diff;
+pub mod filter;
+pub mod formats;
+pub mod inspect;
 pub mod packages;
+pub mod push;
+pub mod release;
+pub mod size_report;
 
-pub use assemble::{AssembleError, SquashFsError, assemble};
+pub use assemble::{AssembleError, assemble};
+pub use debuginfo::debuginfo;
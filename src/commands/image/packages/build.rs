@@ -1,6 +1,7 @@
 use std::{
+	collections::HashSet,
 	hash::{DefaultHasher, Hash, Hasher},
-	path::PathBuf,
+	path::{Path, PathBuf},
 	process::Command,
 	time::UNIX_EPOCH,
 };
@@ -9,33 +10,76 @@ use colored::Colorize;
 use thiserror::Error;
 
 use crate::{
-	fs_utils::has_file_newer_than, hash::hash_file, manifest::{DockerSettings, InvalidSourceError, Manifest, Package, Source}, prefix_commands
+	arch_snapshot, cache_root,
+	fs_utils::has_file_newer_than,
+	hash::hash_file,
+	manifest::{DockerSettings, InvalidSourceError, Manifest, Package, Source},
+	patch::hash_patches,
+	pkginfo::PkgInfo,
+	prefix_commands,
 };
 pub struct BuildResult {
 	total_packages: usize,
 	built_packages: usize,
-	errors: usize,
+	failed: Vec<String>,
+	/// Package name and why it wasn't built
+	skipped: Vec<(String, &'static str)>,
+	/// Problems that stopped the whole build before any package, like a dependency cycle
+	error: Option<String>,
 }
 
 impl BuildResult {
+	fn new(total_packages: usize) -> Self {
+		BuildResult {
+			total_packages,
+			built_packages: 0,
+			failed: vec![],
+			skipped: vec![],
+			error: None,
+		}
+	}
+	fn with_error(error: String) -> Self {
+		BuildResult {
+			error: Some(error),
+			..Self::new(0)
+		}
+	}
 	pub fn print(&self) {
-		if self.errors == self.total_packages && self.total_packages > 0 {
-			println!(
-				"{}: {}{} package{} failed to build",
-				"ERROR".red().bold(),
-				if self.errors != 1 { "All of the " } else { "" },
-				self.errors,
-				if self.errors != 1 { "s" } else { "" }
-			);
-		} else if self.errors > 0 {
-			eprintln!(
-				"{}: {} of the {} package{} failed to build",
-				"ERROR".red().bold(),
-				self.errors.to_string().blue(),
-				self.total_packages.to_string().blue(),
-				if self.total_packages != 1 { "s" } else { "" }
-			);
-		} else if self.built_packages == 0 && self.errors == 0 {
+		if let Some(error) = &self.error {
+			eprintln!("{}: {}", "ERROR".red().bold(), error);
+			return;
+		}
+		let unsuccessful = self.failed.len() + self.skipped.len();
+		if unsuccessful > 0 {
+			if unsuccessful == self.total_packages {
+				eprintln!(
+					"{}: {}{} package{} failed to build",
+					"ERROR".red().bold(),
+					if unsuccessful != 1 { "All of the " } else { "" },
+					unsuccessful,
+					if unsuccessful != 1 { "s" } else { "" }
+				);
+			} else {
+				eprintln!(
+					"{}: {} of the {} package{} failed to build",
+					"ERROR".red().bold(),
+					unsuccessful.to_string().blue(),
+					self.total_packages.to_string().blue(),
+					if self.total_packages != 1 { "s" } else { "" }
+				);
+			}
+			for name in &self.failed {
+				eprintln!("    {} {} {}", "".red(), name.bold(), "failed".red());
+			}
+			for (name, reason) in &self.skipped {
+				eprintln!(
+					"    {} {} {}",
+					"".yellow(),
+					name.bold(),
+					format!("skipped ({reason})").yellow()
+				);
+			}
+		} else if self.built_packages == 0 {
 			println!(
 				"{}",
 				"󱌢 No packages to build: already up-to-date"
@@ -65,27 +109,126 @@ impl BuildResult {
 		}
 	}
 	pub fn exit_if_failure(&self) {
-		if self.errors > 0 {
+		if self.error.is_some() || !self.failed.is_empty() || !self.skipped.is_empty() {
 			std::process::exit(1);
 		}
 	}
 }
 
-pub fn build(manifest: &Manifest) -> BuildResult {
+/// Which packages `packages::build` should consider, defaults to all of them
+#[derive(Debug, Default)]
+pub struct BuildRequest {
+	/// Package names or globs, the packages they need are built too
+	pub packages: Vec<String>,
+	/// Rebuild the selected packages even if they're up-to-date
+	pub force: bool,
+	/// Also rebuild every package that (transitively) has a selected package in its `build_deps`
+	pub rebuild_dependents: bool,
+	/// Stop at the first failure instead of building everything that doesn't depend on it
+	pub fail_fast: bool,
+}
+
+impl BuildRequest {
+	/// The manifest packages matching the requested names/globs
+	pub fn selected<'m>(&self, manifest: &'m Manifest) -> Result<Vec<&'m Package>, String> {
+		if self.packages.is_empty() {
+			return Ok(manifest.packages.iter().collect());
+		}
+		let mut selected: Vec<&Package> = Vec::new();
+		for pattern in &self.packages {
+			let glob = glob::Pattern::new(pattern)
+				.map_err(|e| format!("invalid package pattern {pattern}: {e}"))?;
+			let matches = manifest
+				.packages
+				.iter()
+				.filter(|p| glob.matches(&p.name))
+				.collect::<Vec<_>>();
+			if matches.is_empty() {
+				return Err(format!(
+					"{pattern} doesn't match any package in the manifest"
+				));
+			}
+			for pkg in matches {
+				if !selected.iter().any(|p| p.name == pkg.name) {
+					selected.push(pkg);
+				}
+			}
+		}
+		Ok(selected)
+	}
+}
+
+/// Adds every package reachable through `build_deps` (or through reverse `build_deps`
+/// when `dependents` is set) to `names`
+fn add_reachable(manifest: &Manifest, names: &mut HashSet<String>, dependents: bool) {
+	loop {
+		let reachable = manifest
+			.packages
+			.iter()
+			.filter(|p| !names.contains(&p.name))
+			.filter(|p| {
+				if dependents {
+					p.build_deps.iter().any(|d| names.contains(d))
+				} else {
+					manifest
+						.packages
+						.iter()
+						.any(|q| names.contains(&q.name) && q.build_deps.contains(&p.name))
+				}
+			})
+			.map(|p| p.name.clone())
+			.collect::<Vec<_>>();
+		if reachable.is_empty() {
+			return;
+		}
+		names.extend(reachable);
+	}
+}
+
+pub fn build(manifest: &Manifest, request: &BuildRequest) -> BuildResult {
 	println!();
-	let packages = manifest
-		.packages
+	let build_order = match manifest.build_order() {
+		Ok(order) => order,
+		Err(cycle) => return BuildResult::with_error(cycle.to_string()),
+	};
+	let selected = match request.selected(manifest) {
+		Ok(selected) => selected,
+		Err(e) => return BuildResult::with_error(e),
+	};
+
+	let mut forced = HashSet::new();
+	if request.force {
+		forced.extend(selected.iter().map(|p| p.name.clone()));
+	}
+	let mut considered = selected
 		.iter()
-		.filter(|p| p.needs_rebuild(&manifest))
-		.cloned()
-		.collect::<Vec<Package>>();
+		.map(|p| p.name.clone())
+		.collect::<HashSet<_>>();
+	if request.rebuild_dependents {
+		let mut dependents = considered.clone();
+		add_reachable(manifest, &mut dependents, true);
+		forced.extend(dependents.difference(&considered).cloned());
+		considered = dependents;
+	}
+	add_reachable(manifest, &mut considered, false);
+
+	// a package is rebuilt when it's out of date or one of its build_deps gets rebuilt
+	let mut packages: Vec<Package> = Vec::new();
+	for pkg in build_order
+		.into_iter()
+		.filter(|p| considered.contains(&p.name))
+	{
+		if forced.contains(&pkg.name)
+			|| pkg.needs_rebuild(manifest)
+			|| packages.iter().any(|p| pkg.build_deps.contains(&p.name))
+		{
+			packages.push(pkg.clone());
+		}
+	}
+	let mut result = BuildResult::new(considered.len());
 
 	if packages.is_empty() {
-		return BuildResult {
-			total_packages: manifest.packages.len(),
-			built_packages: 0,
-			errors: 0,
-		};
+		return result;
 	}
 	println!(
 		"{} {} {}",
@@ -94,33 +237,40 @@ pub fn build(manifest: &Manifest) -> BuildResult {
 		"packages...".green().bold()
 	);
 
-	let mut built_packages = 0;
-	let mut errors = 0;
 	for pkg in packages {
+		// building against a failed dependency would use its stale or missing output
+		let unsuccessful =
+			|name: &String| result.failed.contains(name) || result.skipped.iter().any(|(n, _)| n == name);
+		if request.fail_fast && !result.failed.is_empty() {
+			result
+				.skipped
+				.push((pkg.name.clone(), "stopped after a failure"));
+			continue;
+		}
+		if pkg.build_deps.iter().any(unsuccessful) {
+			result.skipped.push((pkg.name.clone(), "dependency failed"));
+			continue;
+		}
 		println!(
 			"    {} {} {}",
 			"󱌢  Compiling".green().bold(),
 			pkg.name,
 			pkg.version.dimmed()
 		);
-		match pkg.build(&manifest) {
-			Ok(()) => built_packages += 1,
+		match pkg.build(manifest, forced.contains(&pkg.name)) {
+			Ok(()) => result.built_packages += 1,
 			Err(error) => {
-				errors += 1;
+				result.failed.push(pkg.name.clone());
 				println!(
 					"\n    {} {}: {}\n",
-					"  Error building package".red().bold(),
+					"  Error building package".red().bold(),
 					pkg.name.cyan().bold().italic(),
 					error.to_string().dimmed()
 				);
 			}
 		}
 	}
-	BuildResult {
-		total_packages: manifest.packages.len(),
-		built_packages: built_packages,
-		errors,
-	}
+	result
 }
 
 #[derive(Debug, Error)]
@@ -148,109 +298,180 @@ pub enum BuildDockerImageError {
 	InvalidDockerfilePath(PathBuf),
 }
 impl Package {
-	pub fn get_out_dir(&self) -> PathBuf {
+	pub fn get_out_dir(&self, manifest: &Manifest) -> PathBuf {
+		let out_dir = match &manifest.rebuild_dir {
+			Some(dir) => dir.join("out"),
+			None => cache_root::out_dir(),
+		};
+		// a shared cache is keyed by the package's inputs, local sources included
+		if cache_root::get().is_some()
+			&& let Ok(key) = self.binary_cache_key(manifest)
+		{
+			return out_dir.join(key);
+		}
 		// calculate hash of self using Hash trait
 		let mut hasher = DefaultHasher::new();
 		self.source.hash(&mut hasher);
 		self.docker.hash(&mut hasher);
+		if let Some(snapshot) = &manifest.arch_snapshot {
+			snapshot.hash(&mut hasher);
+		}
+		manifest.source_date_epoch().hash(&mut hasher);
+		self.build_options.hash(&mut hasher);
+		hash_patches(&self.patches, &mut hasher);
+		if let Some(makepkg_conf) = &self.build_options.makepkg_conf {
+			std::fs::read(makepkg_conf).ok().hash(&mut hasher);
+		}
 		let hash = hasher.finish();
-		[
-			"build",
-			"out",
-			format!("{}-{}-{}", self.name, self.version, hash).as_str(),
-		]
-		.iter()
-		.collect()
+		out_dir.join(format!("{}-{}-{}", self.name, self.version, hash))
 	}
-	pub fn create_out_dir(&self) -> Result<PathBuf, std::io::Error> {
-		let build_dir = self.get_out_dir();
+	pub fn create_out_dir(&self, manifest: &Manifest) -> Result<PathBuf, std::io::Error> {
+		let build_dir = self.get_out_dir(manifest);
 		std::fs::create_dir_all(&build_dir)?;
 		Ok(build_dir)
 	}
-	pub fn get_out_unpacked_dir(&self) -> PathBuf {
-		let mut build_dir = self.get_out_dir();
+	pub fn get_out_unpacked_dir(&self, manifest: &Manifest) -> PathBuf {
+		let mut build_dir = self.get_out_dir(manifest);
 		build_dir.push("unpacked");
 		build_dir
 	}
-	pub fn create_out_unpacked_dir(&self) -> Result<PathBuf, std::io::Error> {
-		let build_dir = self.get_out_unpacked_dir();
+	pub fn create_out_unpacked_dir(&self, manifest: &Manifest) -> Result<PathBuf, std::io::Error> {
+		let build_dir = self.get_out_unpacked_dir(manifest);
 		std::fs::create_dir_all(&build_dir)?;
 		Ok(build_dir)
 	}
+	/// The debug store: makepkg's `-debug` packages, kept out of the image
+	pub fn get_out_debug_dir(&self, manifest: &Manifest) -> PathBuf {
+		self.get_out_dir(manifest).join("debug")
+	}
+	pub fn get_out_debug_unpacked_dir(&self, manifest: &Manifest) -> PathBuf {
+		self.get_out_debug_dir(manifest).join("unpacked")
+	}
+	pub fn get_debug_archlinux_pkgs_paths(
+		&self,
+		manifest: &Manifest,
+	) -> std::io::Result<Vec<PathBuf>> {
+		let debug_dir = self.get_out_debug_dir(manifest);
+		if !debug_dir.exists() {
+			return Ok(vec![]);
+		}
+		let mut paths = std::fs::read_dir(debug_dir)?
+			.map(|e| e.map(|e| e.path()))
+			.collect::<std::io::Result<Vec<_>>>()?;
+		paths.retain(|p| p.to_string_lossy().ends_with(".pkg.tar.zst"));
+		paths.sort();
+		Ok(paths)
+	}
+	/// Moves the `-debug` packages makepkg left in the out dir into the debug store
+	/// and unpacks everything in the store
+	fn store_debug_packages(&self, manifest: &Manifest) -> Result<(), BuildError> {
+		let debug_dir = self.get_out_debug_dir(manifest);
+		std::fs::create_dir_all(&debug_dir)?;
+		for entry in std::fs::read_dir(self.get_out_dir(manifest))? {
+			let path = entry?.path();
+			if path.to_string_lossy().ends_with(".pkg.tar.zst")
+				&& PkgInfo::read_from_package(&path)?.is_debug()
+			{
+				std::fs::rename(&path, debug_dir.join(path.file_name().unwrap()))?;
+			}
+		}
+		let unpacked_dir = self.get_out_debug_unpacked_dir(manifest);
+		if unpacked_dir.exists() {
+			std::fs::remove_dir_all(&unpacked_dir)?;
+		}
+		std::fs::create_dir_all(&unpacked_dir)?;
+		for path in self.get_debug_archlinux_pkgs_paths(manifest)? {
+			let file = std::fs::File::open(&path).map_err(BuildError::UnpackBinaryError)?;
+			let zstd = zstd::Decoder::new(file).map_err(BuildError::UnpackBinaryError)?;
+			tar::Archive::new(zstd)
+				.unpack(&unpacked_dir)
+				.map_err(BuildError::UnpackBinaryError)?;
+		}
+		Ok(())
+	}
 	pub fn get_this_package_src_root(&self) -> PathBuf {
-		let pkg_build_root = if let Source::PkgBuildLocal { path, .. } = &self.source {
+		if let Source::PkgBuildLocal { path, .. } = &self.source
+			&& self.patches.is_empty()
+		{
 			path.clone()
 		} else {
 			self.get_package_prepared_dir()
-		};
-		pkg_build_root
-	}
-	pub fn get_built_archlinux_pkgs_paths(&self) -> std::io::Result<Vec<PathBuf>> {
-		let build_dir = self.create_out_dir()?;
-		macro_rules! files_listing {
-			() => {
-				std::fs::read_dir(&build_dir)?
-					.filter_map(|entry| entry.ok())
-					.filter(|entry| {
-						entry
-							.file_name()
-							.to_string_lossy()
-							.ends_with(".pkg.tar.zst")
-					})
-			};
 		}
-
-		let files_to_unpack: Vec<_> = match &self.source {
+	}
+	/// Lists the `.pkg.tar.zst` files makepkg produced that belong in the image, along with
+	/// their `.PKGINFO`. Packages are selected by their real `pkgname`, either from
+	/// `pick_packages_from_group` or everything but the `-debug` package.
+	pub fn get_built_archlinux_pkgs(
+		&self,
+		manifest: &Manifest,
+	) -> std::io::Result<Vec<(PathBuf, PkgInfo)>> {
+		let build_dir = self.create_out_dir(manifest)?;
+		let picked_names = match &self.source {
 			Source::PkgBuildGit {
-				pick_packages_from_group: Some(pkg_names),
+				pick_packages_from_group,
 				..
 			}
 			| Source::PkgBuildLocal {
-				pick_packages_from_group: Some(pkg_names),
+				pick_packages_from_group,
 				..
-			} => files_listing!()
-				.filter(|entry| {
-					let file_name = entry.file_name();
-					let file_name = file_name.to_string_lossy();
-					pkg_names.iter().any(|pkg| {
-						let pattern_prefix = format!("{}-{}-", pkg, self.version);
-						file_name.starts_with(&pattern_prefix)
-					})
-				})
-				.map(|e| e.path())
-				.collect::<Vec<PathBuf>>(),
-			Source::Binary { .. } => self.source_tarball_path().into_iter().collect(),
-			_ => files_listing!()
-				.filter(|entry| {
-					let file_name = entry.file_name();
-					let file_name = file_name.to_string_lossy();
-					let pattern_prefix = format!("{}-debug-{}-", self.name, self.version);
-					!file_name.starts_with(&pattern_prefix)
-				})
-				.map(|e| e.path())
-				.collect::<Vec<PathBuf>>(),
+			} => pick_packages_from_group.as_ref(),
+			Source::Binary { .. } => None,
 		};
-		Ok(files_to_unpack)
+
+		let mut built_pkgs = Vec::new();
+		for entry in std::fs::read_dir(&build_dir)? {
+			let path = entry?.path();
+			if !path.to_string_lossy().ends_with(".pkg.tar.zst") {
+				continue;
+			}
+			let info = PkgInfo::read_from_package(&path)?;
+			let selected = match picked_names {
+				Some(names) => names.contains(&info.pkgname),
+				None => !info.is_debug(),
+			};
+			if selected {
+				built_pkgs.push((path, info));
+			}
+		}
+		built_pkgs.sort_by(|(a, _), (b, _)| a.cmp(b));
+		Ok(built_pkgs)
+	}
+
+	pub fn get_built_archlinux_pkgs_paths(
+		&self,
+		manifest: &Manifest,
+	) -> std::io::Result<Vec<PathBuf>> {
+		if let Source::Binary { .. } = &self.source {
+			return Ok(self.source_tarball_path().into_iter().collect());
+		}
+		Ok(
+			self
+				.get_built_archlinux_pkgs(manifest)?
+				.into_iter()
+				.map(|(path, _)| path)
+				.collect(),
+		)
 	}
 
 	pub fn get_deps_paths(&self, manifest: &Manifest) -> Vec<PathBuf> {
-		let deps_paths = manifest
+		manifest
 			.packages
 			.iter()
 			.filter(|p| self.build_deps.contains(&p.name))
 			.flat_map(|p| {
-				p.get_built_archlinux_pkgs_paths()
+				p.get_built_archlinux_pkgs_paths(manifest)
 					.into_iter()
 					.flatten()
-					.chain(p.get_deps_paths(manifest).into_iter())
+					.chain(p.get_deps_paths(manifest))
 			})
-			.collect::<Vec<_>>();
-		deps_paths
+			.collect::<Vec<_>>()
 	}
 
-	pub fn build(&self, manifest: &Manifest) -> Result<(), BuildError> {
-		let build_dir = self.create_out_dir()?;
-		let unpacked_dir = self.create_out_unpacked_dir()?;
+	/// Builds the package into its out dir. Forced builds always run makepkg, a cache entry
+	/// would just bring the previous build back.
+	pub fn build(&self, manifest: &Manifest, force: bool) -> Result<(), BuildError> {
+		let build_dir = self.create_out_dir(manifest)?;
+		let unpacked_dir = self.create_out_unpacked_dir(manifest)?;
 		match &self.source {
 			Source::Binary { .. } => {
 				let archlinux_pkg_path = self.source_tarball_path()?;
@@ -286,88 +507,215 @@ impl Package {
 				);
 			}
 			Source::PkgBuildGit { .. } | Source::PkgBuildLocal { .. } => {
-				let docker_image_name = self.build_docker_image_if_needed()?;
-				let pkg_src_root = self.get_this_package_src_root();
-				let mut command = Command::new("docker");
-				let deps_paths = self.get_deps_paths(&manifest);
-				let build_script = include_str!("./build_script.sh");
-				command
-					.arg("run")
-					.arg("--rm")
-					.arg("-v")
-					.arg(format!("{}:/src", pkg_src_root.canonicalize()?.display()))
-					.arg("-v")
-					.arg(format!("{}:/out", build_dir.canonicalize()?.display()));
-				// map all dependencies to volumes inside /deps/
-				for dep_path in deps_paths {
-					command.arg("-v").arg(format!(
-						"{}:/deps/{}",
-						dep_path.canonicalize()?.display(),
-						dep_path.file_name().unwrap().to_string_lossy()
-					));
-				}
-				command
-					.arg("-e")
-					.arg("PKGDEST=/out")
-					.arg("-e")
-					.arg("BUILDDIR=/out/makepkg")
-					.arg(docker_image_name)
-					.arg("bash")
-					.arg("-c")
-					.arg(build_script);
-				let exit_status = prefix_commands::run_command_with_tag(
-					command,
-					format!(
-						"{}{}{}{}{}",
-						"[".dimmed(),
-						self.name.bold(),
-						"@".dimmed(),
-						self.version.dimmed(),
-						" | makepkg] ".dimmed()
-					),
-				)
-				.map_err(BuildError::Io)?;
-				if !exit_status.success() {
-					return Err(BuildError::Non0ExitCode(exit_status.code().unwrap_or(-1)));
-				}
-				let files_to_unpack = self.get_built_archlinux_pkgs_paths()?;
-				if files_to_unpack.is_empty() {
-					return Err(BuildError::NoPackageFound);
-				}
+				self.build_pkgbuild(manifest, force, &unpacked_dir, || {
+					self.run_makepkg(manifest, &build_dir)
+				})?;
+			}
+		}
+		Ok(())
+	}
 
-				for path in files_to_unpack {
-					println!(
-						"    {} {}",
-						"  Unpacking".yellow().bold(),
-						path.file_name().unwrap().display().to_string().italic()
-					);
-
-					let file = std::fs::File::open(&path).map_err(BuildError::UnpackBinaryError)?;
-					let zstd = zstd::Decoder::new(file).map_err(BuildError::UnpackBinaryError)?;
-					let mut tar = tar::Archive::new(zstd);
-					tar
-						.unpack(&unpacked_dir)
-						.map_err(BuildError::UnpackBinaryError)?;
-
-					println!(
-						"  {}  {} {}",
-						" ".green().bold(),
-						path.file_name().unwrap().display().to_string().italic(),
-						"unpacked successfully".green().bold()
-					);
-				}
+	/// Restores the package from the binary cache or runs `makepkg`, then installs it
+	fn build_pkgbuild(
+		&self,
+		manifest: &Manifest,
+		force: bool,
+		unpacked_dir: &Path,
+		makepkg: impl FnOnce() -> Result<(), BuildError>,
+	) -> Result<(), BuildError> {
+		let restored = match &manifest.binary_cache {
+			Some(cache) if !force => self.restore_from_binary_cache(manifest, cache),
+			_ => false,
+		};
+		if !restored {
+			makepkg()?;
+		}
+		self.install_built_packages(manifest, unpacked_dir)?;
+		if !restored
+			&& let Some(cache) = &manifest.binary_cache
+			&& cache.upload
+		{
+			self.upload_to_binary_cache(manifest, cache);
+		}
+		Ok(())
+	}
 
-				// save the current time in a "last_successful_build_time" file
-				std::fs::write(
-					build_dir.join("last_successful_build_time"),
-					std::time::SystemTime::now()
-						.duration_since(std::time::UNIX_EPOCH)
-						.unwrap()
-						.as_millis()
-						.to_string(),
-				)?;
+	/// Runs makepkg inside the package's build container, leaving the packages in `build_dir`
+	fn run_makepkg(&self, manifest: &Manifest, build_dir: &Path) -> Result<(), BuildError> {
+		let debug_dir = self.get_out_debug_dir(manifest);
+		if debug_dir.exists() {
+			std::fs::remove_dir_all(debug_dir)?;
+		}
+		let docker_image_name = self.build_docker_image_if_needed()?;
+		let pkg_src_root = self.get_this_package_src_root();
+		let mut command = Command::new("docker");
+		let deps_paths = self.get_deps_paths(manifest);
+		let build_script = include_str!("./build_script.sh");
+		command
+			.arg("run")
+			.arg("--rm")
+			.arg("-v")
+			.arg(format!("{}:/src", pkg_src_root.canonicalize()?.display()))
+			.arg("-v")
+			.arg(format!("{}:/out", build_dir.canonicalize()?.display()));
+		// map all dependencies to volumes inside /deps/
+		for dep_path in deps_paths {
+			command.arg("-v").arg(format!(
+				"{}:/deps/{}",
+				dep_path.canonicalize()?.display(),
+				dep_path.file_name().unwrap().to_string_lossy()
+			));
+		}
+		// point pacman at the pinned snapshot, if the manifest has one
+		if let Some(snapshot) = &manifest.arch_snapshot {
+			if let Some(mirror_path) = snapshot.local_mirror_path()? {
+				command.arg("-v").arg(format!(
+					"{}:{}:ro",
+					mirror_path.display(),
+					arch_snapshot::CONTAINER_MIRROR_PATH
+				));
+			}
+			command.arg("-e").arg(format!(
+				"HYPRPACKER_PACMAN_SERVER={}",
+				snapshot.pacman_server()
+			));
+		}
+		// makepkg uses it for builddate and clamps the package's file times to it
+		if let Some(epoch) = manifest.source_date_epoch() {
+			command.arg("-e").arg(format!("SOURCE_DATE_EPOCH={epoch}"));
+		}
+		self.add_build_options_args(&mut command)?;
+		command
+			.arg("-e")
+			.arg("PKGDEST=/out")
+			.arg("-e")
+			.arg("BUILDDIR=/out/makepkg")
+			.arg(docker_image_name)
+			.arg("bash")
+			.arg("-c")
+			.arg(build_script)
+			// everything after $0 ends up in "$@", which the script forwards to makepkg
+			.arg("build_script.sh")
+			.args(self.makepkg_args());
+		let exit_status = prefix_commands::run_command_with_tag(
+			command,
+			format!(
+				"{}{}{}{}{}",
+				"[".dimmed(),
+				self.name.bold(),
+				"@".dimmed(),
+				self.version.dimmed(),
+				" | makepkg] ".dimmed()
+			),
+		)
+		.map_err(BuildError::Io)?;
+		if !exit_status.success() {
+			return Err(BuildError::Non0ExitCode(exit_status.code().unwrap_or(-1)));
+		}
+		Ok(())
+	}
+
+	/// Unpacks the packages makepkg produced (or the binary cache provided) into `unpacked_dir`
+	/// and marks the build as successful
+	pub fn install_built_packages(
+		&self,
+		manifest: &Manifest,
+		unpacked_dir: &Path,
+	) -> Result<(), BuildError> {
+		let build_dir = self.get_out_dir(manifest);
+		self.store_debug_packages(manifest)?;
+		let built_pkgs = self.get_built_archlinux_pkgs(manifest)?;
+		if built_pkgs.is_empty() {
+			return Err(BuildError::NoPackageFound);
+		}
+		for (_, info) in built_pkgs
+			.iter()
+			.filter(|(_, info)| !info.version_matches(&self.version))
+		{
+			println!(
+				"    {}: {} was built as version {} but the manifest says {}",
+				"warning".yellow().bold(),
+				info.pkgname.bold(),
+				info.pkgver.cyan(),
+				self.version.cyan()
+			);
+		}
+
+		for (path, _) in built_pkgs {
+			println!(
+				"    {} {}",
+				"  Unpacking".yellow().bold(),
+				path.file_name().unwrap().display().to_string().italic()
+			);
+
+			let file = std::fs::File::open(&path).map_err(BuildError::UnpackBinaryError)?;
+			let zstd = zstd::Decoder::new(file).map_err(BuildError::UnpackBinaryError)?;
+			let mut tar = tar::Archive::new(zstd);
+			tar
+				.unpack(unpacked_dir)
+				.map_err(BuildError::UnpackBinaryError)?;
+
+			println!(
+				"  {}  {} {}",
+				" ".green().bold(),
+				path.file_name().unwrap().display().to_string().italic(),
+				"unpacked successfully".green().bold()
+			);
+		}
+
+		// save the current time in a "last_successful_build_time" file
+		std::fs::write(
+			build_dir.join("last_successful_build_time"),
+			std::time::SystemTime::now()
+				.duration_since(std::time::UNIX_EPOCH)
+				.unwrap()
+				.as_millis()
+				.to_string(),
+		)?;
+		Ok(())
+	}
+
+	/// Extra arguments for makepkg, in addition to the ones the build script always passes
+	pub fn makepkg_args(&self) -> Vec<String> {
+		let mut args = self.build_options.makepkg_args.clone();
+		if !self.build_options.check && !args.iter().any(|a| a == "--nocheck") {
+			args.push("--nocheck".to_string());
+		}
+		args
+	}
+
+	/// Adds the `docker run` arguments for the package's [`BuildOptions`]
+	fn add_build_options_args(&self, command: &mut Command) -> std::io::Result<()> {
+		let options = &self.build_options;
+		for (key, value) in &options.env {
+			command.arg("-e").arg(format!("{key}={value}"));
+		}
+		// sudo drops the environment, so the script needs to know what to keep
+		if !options.env.is_empty() {
+			let keys = options.env.keys().cloned().collect::<Vec<_>>().join(" ");
+			command.arg("-e").arg(format!("HYPRPACKER_ENV_KEYS={keys}"));
+		}
+		for (name, value) in [
+			("CFLAGS", &options.cflags),
+			("LDFLAGS", &options.ldflags),
+			("MAKEFLAGS", &options.makeflags),
+		] {
+			if let Some(value) = value {
+				command.arg("-e").arg(format!("HYPRPACKER_{name}={value}"));
 			}
 		}
+		if let Some(makepkg_conf) = &options.makepkg_conf {
+			command.arg("-v").arg(format!(
+				"{}:/hyprpacker/makepkg.conf:ro",
+				makepkg_conf.canonicalize()?.display()
+			));
+		}
+		if let Some(cpus) = options.cpus {
+			command.arg("--cpus").arg(cpus.to_string());
+		}
+		if let Some(memory) = &options.memory {
+			command.arg("--memory").arg(memory);
+		}
 		Ok(())
 	}
 
@@ -380,7 +728,7 @@ impl Package {
 		{
 			return true;
 		}
-		let build_dir = self.get_out_dir();
+		let build_dir = self.get_out_dir(manifest);
 		let last_successful_build_time_path = build_dir.join("last_successful_build_time");
 
 		if !last_successful_build_time_path.exists() {
@@ -395,6 +743,10 @@ impl Package {
 			return true;
 		};
 
+		// shared out dirs are named after the sources' contents, so they can't be stale
+		if cache_root::get().is_some() {
+			return false;
+		}
 		let timestamp =
 			UNIX_EPOCH + std::time::Duration::from_millis(last_successful_build_time as u64);
 
@@ -406,16 +758,18 @@ impl Package {
 				.unwrap_or_else(|| self.get_package_prepared_dir()),
 		};
 
-		let needs_rebuild = has_file_newer_than(&source_path, timestamp).unwrap_or(true);
-
-		needs_rebuild
+		has_file_newer_than(&source_path, timestamp).unwrap_or(true)
 	}
 	pub fn get_docker_image_name(&self) -> Result<String, BuildDockerImageError> {
 		Ok(match &self.docker {
 			DockerSettings::DockerfilePath {
 				path: dockerfile_path,
 			} => {
-				format!("hyprpacker-{}", hash_file(dockerfile_path)?)
+				// Docker repository names must be lowercase
+				format!(
+					"hyprpacker-{}",
+					hash_file(dockerfile_path)?.into_string().to_lowercase()
+				)
 			}
 			DockerSettings::ImageName { name } => name.clone(),
 		})
@@ -466,3 +820,63 @@ impl Package {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{TempDir, test_manifest, write_package};
+
+	/// Builds `pkg` from a local PKGBUILD into `dir`, with a binary cache that already has it
+	fn cached_manifest(dir: &TempDir) -> Manifest {
+		let pkgbuild_dir = dir.path().join("pkgbuild");
+		std::fs::create_dir_all(&pkgbuild_dir).unwrap();
+		std::fs::write(pkgbuild_dir.join("PKGBUILD"), "pkgname=pkg\n").unwrap();
+		let mut manifest = test_manifest(&format!(
+			"binary_cache = {{ path = {:?} }}\n[[package]]\nname = \"pkg\"\nversion = \"1.0-1\"\nsource = {{ mode = \"pkgbuildlocal\", path = {:?} }}\n",
+			dir.path().join("cache"),
+			pkgbuild_dir,
+		));
+		manifest.rebuild_dir = Some(dir.path().join("build"));
+
+		let pkg = &manifest.packages[0];
+		let out_dir = pkg.create_out_dir(&manifest).unwrap();
+		write_package(&out_dir, "pkg", "1.0-1", b"cached");
+		pkg
+			.install_built_packages(&manifest, &pkg.create_out_unpacked_dir(&manifest).unwrap())
+			.unwrap();
+		let cache = manifest.binary_cache.as_ref().unwrap();
+		assert!(pkg.push_to_binary_cache(&manifest, cache).unwrap());
+		std::fs::remove_dir_all(out_dir).unwrap();
+		manifest
+	}
+
+	fn build(manifest: &Manifest, force: bool) -> (bool, Vec<u8>) {
+		let pkg = &manifest.packages[0];
+		let out_dir = pkg.create_out_dir(manifest).unwrap();
+		let unpacked_dir = pkg.create_out_unpacked_dir(manifest).unwrap();
+		let mut ran_makepkg = false;
+		pkg
+			.build_pkgbuild(manifest, force, &unpacked_dir, || {
+				ran_makepkg = true;
+				write_package(&out_dir, "pkg", "1.0-1", b"fresh");
+				Ok(())
+			})
+			.unwrap();
+		let data = std::fs::read(unpacked_dir.join("usr/share/pkg/data")).unwrap();
+		(ran_makepkg, data)
+	}
+
+	#[test]
+	fn restores_from_the_binary_cache() {
+		let dir = TempDir::new();
+		let manifest = cached_manifest(&dir);
+		assert_eq!(build(&manifest, false), (false, b"cached".to_vec()));
+	}
+
+	#[test]
+	fn forced_builds_run_makepkg() {
+		let dir = TempDir::new();
+		let manifest = cached_manifest(&dir);
+		assert_eq!(build(&manifest, true), (true, b"fresh".to_vec()));
+	}
+}
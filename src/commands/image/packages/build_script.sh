@@ -1,7 +1,13 @@
 # install all packages inside /deps/ with pacman
 mkdir /deps -p
 buildDependencies=$(find /deps/ -type f -name "*.pkg.tar.zst")
-pacman -Sy
+# pin pacman to the manifest's arch_snapshot (if any) before installing anything
+if [ -n "$HYPRPACKER_PACMAN_SERVER" ]; then
+	echo "Server = $HYPRPACKER_PACMAN_SERVER" > /etc/pacman.d/mirrorlist
+	pacman -Syyuu --noconfirm # sync (and downgrade) the toolchain to the snapshot
+else
+	pacman -Sy
+fi
 if [ -n "$buildDependencies" ]; then
 	pacman -U --needed --noconfirm $buildDependencies
 fi
@@ -9,10 +15,28 @@ pacman -S --needed --noconfirm sudo # Install sudo
 useradd builduser -m # Create the builduser
 passwd -d builduser # Delete the buildusers password
 printf 'builduser ALL=(ALL) ALL\nDefaults    env_keep += "PKGDEST"\nDefaults    env_keep += "BUILDDIR"\n' | tee -a /etc/sudoers # Allow the builduser passwordless sudo
+for var in $HYPRPACKER_ENV_KEYS; do # Keep the package's custom environment
+	printf 'Defaults    env_keep += "%s"\n' "$var" | tee -a /etc/sudoers
+done
+# custom makepkg.conf and flag overrides from the manifest
+if [ -f /hyprpacker/makepkg.conf ]; then
+	cp /hyprpacker/makepkg.conf /etc/makepkg.conf
+fi
+mkdir -p /home/builduser/.config/pacman
+if [ -n "$HYPRPACKER_CFLAGS" ]; then
+	printf 'CFLAGS=%q\nCXXFLAGS=%q\n' "$HYPRPACKER_CFLAGS" "$HYPRPACKER_CFLAGS" >> /home/builduser/.config/pacman/makepkg.conf
+fi
+if [ -n "$HYPRPACKER_LDFLAGS" ]; then
+	printf 'LDFLAGS=%q\n' "$HYPRPACKER_LDFLAGS" >> /home/builduser/.config/pacman/makepkg.conf
+fi
+if [ -n "$HYPRPACKER_MAKEFLAGS" ]; then
+	printf 'MAKEFLAGS=%q\n' "$HYPRPACKER_MAKEFLAGS" >> /home/builduser/.config/pacman/makepkg.conf
+fi
+chown builduser:builduser /home/builduser/.config -R
 cd /src
 rm -rf /out/makepkg/pkg
 rm -rf /out/makepkg/*.pkg.tar.zst
 rm -rf /out/*.pkg.tar.zst
 mkdir /out/makepkg -p
 chown builduser:builduser /out/ -R
-sudo -u builduser bash -c 'makepkg --noconfirm --noprogressbar -s -C -f'
+sudo -u builduser makepkg --noconfirm --noprogressbar -s -C -f "$@" # "$@" holds the package's makepkg_args
@@ -0,0 +1,159 @@
+use std::{collections::HashSet, process::Command};
+
+use colored::Colorize;
+use thiserror::Error;
+
+use crate::{
+	commands::{image::packages::build::BuildDockerImageError, kernel::build::kernel_image_name},
+	manifest::{DockerSettings, Manifest},
+	size,
+};
+
+/// Repository prefix of every builder image hyprpacker tags, package images
+/// (`hyprpacker-<dockerfile hash>`) and the kernel builder alike
+const IMAGE_PREFIX: &str = "hyprpacker-";
+
+#[derive(Debug, Error)]
+pub enum DockerGcError {
+	#[error("io error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("docker exited with non-zero code: {0}")]
+	Non0ExitCode(i32),
+	#[error("could not name the builder image of {0}: {1}")]
+	ImageName(String, BuildDockerImageError),
+}
+
+pub struct DockerGcStat {
+	/// Removed (or with a dry run, removable) images with their size
+	pub removed: Vec<(String, u64)>,
+	pub failed: Vec<String>,
+	pub dry_run: bool,
+}
+
+/// `repository:tag`, the way `docker image ls` lists it
+fn normalize_image_name(name: &str) -> String {
+	let last_component = name.rsplit('/').next().unwrap_or(name);
+	if last_component.contains(':') || name.contains('@') {
+		name.to_string()
+	} else {
+		format!("{name}:latest")
+	}
+}
+
+/// The builder images the manifests still use
+fn referenced_images(manifests: &[&Manifest]) -> Result<HashSet<String>, DockerGcError> {
+	let mut referenced = HashSet::new();
+	for manifest in manifests {
+		referenced.insert(normalize_image_name(&kernel_image_name(manifest)));
+		for pkg in &manifest.packages {
+			let name = match &pkg.docker {
+				DockerSettings::ImageName { name } => name.clone(),
+				DockerSettings::DockerfilePath { .. } => pkg
+					.get_docker_image_name()
+					.map_err(|e| DockerGcError::ImageName(pkg.name.clone(), e))?,
+			};
+			referenced.insert(normalize_image_name(&name));
+		}
+	}
+	Ok(referenced)
+}
+
+fn docker_output(args: &[&str]) -> Result<String, DockerGcError> {
+	let output = Command::new("docker").args(args).output()?;
+	if !output.status.success() {
+		return Err(DockerGcError::Non0ExitCode(
+			output.status.code().unwrap_or(-1),
+		));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Removes the `hyprpacker-*` images none of the manifests reference: package builder
+/// images of old Dockerfiles and kernel builder images of other snapshots
+fn collect_docker_images(
+	manifests: &[&Manifest],
+	dry_run: bool,
+) -> Result<DockerGcStat, DockerGcError> {
+	let referenced = referenced_images(manifests)?;
+	let mut stat = DockerGcStat {
+		removed: vec![],
+		failed: vec![],
+		dry_run,
+	};
+	let listing = docker_output(&["image", "ls", "--format", "{{.Repository}}:{{.Tag}}"])?;
+	let mut candidates = listing
+		.lines()
+		.map(str::trim)
+		.filter(|image| image.starts_with(IMAGE_PREFIX) && !image.ends_with(":<none>"))
+		.filter(|image| !referenced.contains(*image))
+		.map(str::to_string)
+		.collect::<Vec<_>>();
+	candidates.sort();
+	candidates.dedup();
+
+	for image in candidates {
+		let size = docker_output(&["image", "inspect", "--format", "{{.Size}}", &image])
+			.ok()
+			.and_then(|s| s.trim().parse::<u64>().ok())
+			.unwrap_or_default();
+		if !dry_run {
+			// untagging by name leaves images that are also tagged as something else alone
+			if let Err(e) = docker_output(&["image", "rm", &image]) {
+				eprintln!(
+					"{}: {} {}: {}",
+					"ERROR".red().bold(),
+					"Failed to remove Docker image".white(),
+					image.bright_black(),
+					e.to_string().bright_black()
+				);
+				stat.failed.push(image);
+				continue;
+			}
+		}
+		stat.removed.push((image, size));
+	}
+	Ok(stat)
+}
+
+pub fn docker_gc_command(manifests: &[&Manifest], dry_run: bool) {
+	let stat = match collect_docker_images(manifests, dry_run) {
+		Ok(stat) => stat,
+		Err(e) => {
+			eprintln!(
+				"{}: Failed to collect Docker images: {}",
+				"ERROR".red().bold(),
+				e.to_string().white()
+			);
+			return;
+		}
+	};
+	if stat.removed.is_empty() {
+		println!(
+			"{}",
+			"No Docker images removed during garbage collection.".dimmed()
+		);
+		return;
+	}
+	for (image, size) in &stat.removed {
+		println!("    {} {}", image, size::human_readable_size(*size).cyan());
+	}
+	println!();
+	let freed = stat.removed.iter().map(|(_, size)| size).sum::<u64>();
+	let n = stat.removed.len();
+	// layers shared with images that stay aren't actually freed
+	println!(
+		"🐳 Docker Garbage Collector: {} up to {} ({} image{})",
+		if stat.dry_run { "Would free" } else { "Freed" }.green(),
+		size::human_readable_size(freed).cyan(),
+		n,
+		if n == 1 { "" } else { "s" }
+	);
+	if !stat.failed.is_empty() {
+		println!(
+			"    {} {}",
+			stat.failed.len().to_string().bold(),
+			"images could not be removed".red()
+		);
+	}
+	println!();
+}
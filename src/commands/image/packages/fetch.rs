@@ -1,4 +1,4 @@
-use crate::manifest::{Manifest, Package};
+use crate::manifest::{Manifest, Package, Source};
 use crate::sources::SourceType;
 
 use colored::*;
@@ -71,8 +71,18 @@ pub fn fetch(manifest: &Manifest) -> FetchResult {
 		manifest
 			.packages
 			.iter()
-			.filter(|p| !matches!(p.source_type(), Ok(SourceType::LocalFolder { .. })))
-			.filter(|p| p.assert_source_tarball_matches_hash().is_err())
+			.filter(|p| match p.source_type() {
+				// patched local PKGBUILDs are prepared into a dir named after their contents,
+				// another worktree may be building from it
+				Ok(SourceType::LocalFolder { .. }) => {
+					!p.patches.is_empty() && !p.get_package_prepared_dir().exists()
+				}
+				_ => {
+					p.assert_source_tarball_matches_hash().is_err()
+						|| (matches!(p.source, Source::PkgBuildGit { .. })
+							&& !p.get_package_prepared_dir().exists())
+				}
+			})
 			.cloned()
 			.collect::<Vec<Package>>(),
 	);
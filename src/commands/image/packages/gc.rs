@@ -1,37 +1,123 @@
 use std::{
+	collections::{HashMap, HashSet},
 	fs::read_dir,
 	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
 };
 
 use colored::Colorize;
 
 use crate::{
-	manifest::{GarbageCollectionStat, Manifest, Package},
+	cache_root,
+	commands::image::packages::docker_gc::docker_gc_command,
+	manifest::{
+		ArchSnapshot, DockerSettings, GarbageCollectionStat, GcOptions, Manifest, Package, Source,
+	},
 	size,
 };
 
+/// Recursive size of a file or directory, symlinks count as themselves
 pub fn calculate_folder_size<P>(path: P) -> std::io::Result<u64>
 where
 	P: AsRef<Path>,
 {
+	let metadata = std::fs::symlink_metadata(&path)?;
+	if !metadata.is_dir() {
+		return Ok(metadata.len());
+	}
 	let mut result = 0;
+	for entry in read_dir(&path)? {
+		result += calculate_folder_size(entry?.path())?;
+	}
+	Ok(result)
+}
 
-	if path.as_ref().is_dir() {
-		for entry in read_dir(&path)? {
-			let _path = entry?.path();
-			if _path.is_file() {
-				result += _path.metadata()?.len();
-			} else {
-				result += calculate_folder_size(_path)?;
-			}
+/// Runs the garbage collector before a fetch, build or assemble if the manifest opted in
+pub fn auto_gc(manifest: &Manifest) {
+	if manifest.gc.auto {
+		gc_command(manifest, &manifest.gc, false);
+	}
+}
+
+/// A manifest whose packages should be kept
+pub struct GcRootManifest {
+	/// With its relative paths rebased onto its own directory
+	pub manifest: Manifest,
+	/// What it used the last time it was resolved, see [`cache_root::register_gc_root`]
+	pub paths: Vec<PathBuf>,
+}
+
+/// `path` when it's absolute, `dir/path` otherwise
+fn rebase(dir: &Path, path: &mut PathBuf) {
+	if path.is_relative() {
+		*path = dir.join(&*path);
+	}
+}
+
+fn rebase_globs(dir: &Path, globs: &mut [String]) {
+	for glob in globs {
+		if Path::new(glob).is_relative() {
+			*glob = dir.join(&*glob).to_string_lossy().into_owned();
 		}
-	} else {
-		result = path.as_ref().metadata()?.len();
 	}
-	Ok(result)
 }
-pub fn gc_command(manifest: &Manifest) {
-	match manifest.garbage_collect_sources() {
+
+/// Makes the relative paths of a manifest read from `dir` usable from the current directory
+fn rebase_manifest(dir: &Path, manifest: &mut Manifest) {
+	if let Some(ArchSnapshot::Local { path }) = &mut manifest.arch_snapshot {
+		rebase(dir, path);
+	}
+	rebase_globs(dir, &mut manifest.kernel.patches);
+	rebase(dir, &mut manifest.initrd.build_script);
+	for pkg in &mut manifest.packages {
+		if let Source::PkgBuildLocal { path, .. } = &mut pkg.source {
+			rebase(dir, path);
+		}
+		if let DockerSettings::DockerfilePath { path } = &mut pkg.docker {
+			rebase(dir, path);
+		}
+		if let Some(makepkg_conf) = &mut pkg.build_options.makepkg_conf {
+			rebase(dir, makepkg_conf);
+		}
+		rebase_globs(dir, &mut pkg.patches);
+	}
+}
+
+/// Reads a root manifest and what it recorded. It isn't resolved again: that could mean
+/// fetching sources or starting containers, and it wouldn't see what the root's own runs saw.
+fn load_root(path: &Path) -> Result<GcRootManifest, String> {
+	let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+	let mut manifest = toml::from_str::<Manifest>(&contents).map_err(|e| e.to_string())?;
+	rebase_manifest(path.parent().unwrap_or(Path::new("")), &mut manifest);
+	let paths = cache_root::recorded_gc_root_paths(path)
+		.map_err(|e| e.to_string())?
+		.ok_or_else(|| {
+			format!(
+				"it has no GC root record yet, run a build with `-m {}` once to record what it uses",
+				path.display()
+			)
+		})?;
+	Ok(GcRootManifest { manifest, paths })
+}
+
+pub fn gc_command(manifest: &Manifest, options: &GcOptions, dry_run: bool) {
+	let mut roots = Vec::new();
+	for path in &options.roots {
+		match load_root(path) {
+			Ok(root) => roots.push(root),
+			Err(e) => {
+				// collecting without the root could delete what it needs
+				eprintln!(
+					"{}: Failed to load GC root {}, skipping garbage collection: {}",
+					"ERROR".red().bold(),
+					path.display(),
+					e.white()
+				);
+				return;
+			}
+		}
+	}
+	match manifest.garbage_collect_sources(options, &roots, dry_run) {
 		Err(e) => {
 			eprintln!(
 				"{}: Failed to run garbage collector: {}",
@@ -39,178 +125,519 @@ pub fn gc_command(manifest: &Manifest) {
 				e.to_string().white()
 			);
 		}
-		Ok(GarbageCollectionStat {
-			freed_bytes,
-			removed_out_folders,
-			removed_prepared_packages,
-			removed_sources_packages,
-		}) => {
-			if removed_out_folders == 0 && removed_prepared_packages == 0 && removed_sources_packages == 0
-			{
-				println!(
-					"{}",
-					"No packages removed during garbage collection.".dimmed()
-				);
-				return;
-			}
-			let package_counter = |n: usize| format!(" {} package{}", n, if n == 1 { "" } else { "s" });
-			println!(
-				"{} {} {}",
-				"🧹 Garbage Collector:",
-				"Freed".green(),
-				size::human_readable_size(freed_bytes).to_string().cyan()
-			);
-			println!();
-			println!(
-				"    {} {}",
-				package_counter(removed_out_folders).bold(),
-				"output folders removed".green()
-			);
+		Ok(stat) => print_stat(stat),
+	}
+	if options.docker_images {
+		let manifests = std::iter::once(manifest)
+			.chain(roots.iter().map(|root| &root.manifest))
+			.collect::<Vec<_>>();
+		docker_gc_command(&manifests, dry_run);
+	}
+}
+
+fn print_stat(stat: GarbageCollectionStat) {
+	let GarbageCollectionStat {
+		freed_bytes,
+		removed_out_folders,
+		removed_prepared_packages,
+		removed_sources_packages,
+		removed,
+		dry_run,
+	} = stat;
+	if removed.is_empty() {
+		println!(
+			"{}",
+			"No packages removed during garbage collection.".dimmed()
+		);
+		return;
+	}
+	if dry_run {
+		for (path, size, reason) in &removed {
 			println!(
-				"    {} {}",
-				package_counter(removed_prepared_packages).bold(),
-				"prepared packages removed".green()
+				"    {} {} {}",
+				path.display(),
+				size::human_readable_size(*size).cyan(),
+				format!("({reason})").dimmed()
 			);
-			println!(
-				"    {} {}",
-				package_counter(removed_sources_packages).bold(),
-				"source packages removed".green()
+		}
+		println!();
+	}
+	let package_counter = |n: usize| format!(" {} package{}", n, if n == 1 { "" } else { "s" });
+	println!(
+		"🧹 Garbage Collector: {} {}",
+		if dry_run { "Would free" } else { "Freed" }.green(),
+		size::human_readable_size(freed_bytes).to_string().cyan()
+	);
+	println!();
+	let removed_label = |what: &str| {
+		if dry_run {
+			format!("{what} would be removed")
+		} else {
+			format!("{what} removed")
+		}
+	};
+	println!(
+		"    {} {}",
+		package_counter(removed_out_folders).bold(),
+		removed_label("output folders").green()
+	);
+	println!(
+		"    {} {}",
+		package_counter(removed_prepared_packages).bold(),
+		removed_label("prepared packages").green()
+	);
+	println!(
+		"    {} {}",
+		package_counter(removed_sources_packages).bold(),
+		removed_label("source packages").green()
+	);
+	println!();
+}
+
+/// An out dir no manifest references
+struct StaleOutDir {
+	path: PathBuf,
+	package: String,
+	last_used: SystemTime,
+	size: u64,
+}
+
+/// The package an out dir (`{name}-{version}-{hash}`) belongs to: the longest known
+/// package name it starts with, or everything before the version for unknown packages
+fn out_dir_package(dir_name: &str, known_names: &HashSet<&str>) -> String {
+	if let Some(name) = known_names
+		.iter()
+		.filter(|n| dir_name.starts_with(&format!("{n}-")))
+		.max_by_key(|n| n.len())
+	{
+		return name.to_string();
+	}
+	let version_start = dir_name
+		.match_indices('-')
+		.find(|(i, _)| {
+			dir_name[i + 1..]
+				.chars()
+				.next()
+				.is_some_and(|c| c.is_ascii_digit())
+		})
+		.map(|(i, _)| i);
+	dir_name[..version_start.unwrap_or(dir_name.len())].to_string()
+}
+
+/// When an out dir was last built into, falling back to the directory's mtime
+fn last_used(path: &Path) -> SystemTime {
+	std::fs::metadata(path.join("last_successful_build_time"))
+		.or_else(|_| std::fs::metadata(path))
+		.and_then(|m| m.modified())
+		.unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// Left unreferenced for this long, in-flight downloads (`*.partial`) and patch staging
+/// dirs (`*.partial-<pid>`) are considered interrupted. Other projects sharing the cache
+/// may be writing them, so they don't follow `max_age_days`.
+const PARTIAL_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn is_partial(path: &Path) -> bool {
+	let name = path.file_name().unwrap_or_default().to_string_lossy();
+	name.ends_with(".partial") || name.contains(".partial-")
+}
+
+/// Where the collected files live, the shared cache root or `build/`
+pub struct GcDirs {
+	pub sources: PathBuf,
+	pub prepared: PathBuf,
+	pub out: PathBuf,
+}
+
+impl GcDirs {
+	pub fn current() -> Self {
+		GcDirs {
+			sources: Package::sources_path(),
+			prepared: Package::prepared_sources_dir(),
+			out: cache_root::out_dir(),
+		}
+	}
+}
+
+/// Removes `path` (unless it's a dry run) and records it, `false` if removing failed
+fn remove(
+	stat: &mut GarbageCollectionStat,
+	path: &Path,
+	size: u64,
+	reason: &'static str,
+	what: &str,
+) -> bool {
+	if !stat.dry_run {
+		let removed = if std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+			std::fs::remove_dir_all(path)
+		} else {
+			std::fs::remove_file(path)
+		};
+		if let Err(e) = removed {
+			eprintln!(
+				"{}: {} {:?}: {}",
+				"ERROR".red().bold(),
+				format!("Failed to remove {what}").white(),
+				path.display().to_string().bright_black(),
+				e.to_string().bright_black()
 			);
-			println!();
+			return false;
 		}
 	}
+	stat.freed_bytes += size;
+	stat.removed.push((path.to_path_buf(), size, reason));
+	true
 }
 
 impl Manifest {
-	pub fn garbage_collect_sources(&self) -> std::io::Result<GarbageCollectionStat> {
-		let sources_dir = PathBuf::from(Package::sources_path());
-		if !sources_dir.exists() {
-			return Ok(GarbageCollectionStat {
-				freed_bytes: 0,
-				removed_prepared_packages: 0,
-				removed_sources_packages: 0,
-				removed_out_folders: 0,
-			});
-		}
-		let mut referenced = std::collections::HashSet::new();
+	pub fn garbage_collect_sources(
+		&self,
+		options: &GcOptions,
+		roots: &[GcRootManifest],
+		dry_run: bool,
+	) -> std::io::Result<GarbageCollectionStat> {
+		self.garbage_collect_in(&GcDirs::current(), options, roots, dry_run)
+	}
+
+	pub fn garbage_collect_in(
+		&self,
+		dirs: &GcDirs,
+		options: &GcOptions,
+		roots: &[GcRootManifest],
+		dry_run: bool,
+	) -> std::io::Result<GarbageCollectionStat> {
+		let mut stat = GarbageCollectionStat {
+			freed_bytes: 0,
+			removed_prepared_packages: 0,
+			removed_sources_packages: 0,
+			removed_out_folders: 0,
+			removed: vec![],
+			dry_run,
+		};
+		let max_age = options
+			.max_age_days
+			.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+		let too_old = |modified: SystemTime| {
+			max_age.is_none_or(|max_age| modified.elapsed().is_ok_and(|age| age > max_age))
+		};
+		let stale_reason = if max_age.is_some() {
+			"unreferenced, older than max_age_days"
+		} else {
+			"unreferenced"
+		};
+		let sweep_reason = |path: &Path, modified: SystemTime| {
+			if is_partial(path) {
+				let interrupted = modified
+					.elapsed()
+					.is_ok_and(|age| age > PARTIAL_GRACE_PERIOD);
+				interrupted.then_some("interrupted, unreferenced for a day")
+			} else {
+				too_old(modified).then_some(stale_reason)
+			}
+		};
+		let mut referenced = HashSet::new();
+		let mut prepared_referenced = HashSet::new();
+		let mut referenced_folders = HashSet::new();
+		let mut known_names = HashSet::new();
 		for pkg in &self.packages {
 			if let Ok(path) = pkg.source_tarball_path() {
 				referenced.insert(path);
 			}
+			prepared_referenced.insert(pkg.get_package_prepared_dir());
+			referenced_folders.insert(pkg.get_out_dir(self));
+			known_names.insert(pkg.name.as_str());
 		}
-		let mut prepared_referenced = std::collections::HashSet::new();
-		let prepared_dir = Package::prepared_sources_dir();
-		for pkg in &self.packages {
-			let mut d = prepared_dir.clone();
-			d.push(format!("{}-{}", pkg.name, pkg.version));
-			prepared_referenced.insert(d);
+		for root in roots {
+			known_names.extend(root.manifest.packages.iter().map(|p| p.name.as_str()));
 		}
-
-		let mut freed_bytes = 0u64;
-		let mut removed_sources_packages = 0usize;
-		for entry in std::fs::read_dir(&sources_dir)
+		// whatever the roots, and with a shared cache root other projects, recorded is just
+		// as referenced
+		let recorded = roots.iter().flat_map(|root| root.paths.iter().cloned());
+		for path in cache_root::live_gc_root_paths(dry_run)?
 			.into_iter()
-			.flatten()
-			.flatten()
+			.chain(recorded)
 		{
+			referenced.insert(path.clone());
+			prepared_referenced.insert(path.clone());
+			referenced_folders.insert(path);
+		}
+
+		for entry in read_dir(&dirs.sources).into_iter().flatten().flatten() {
 			let path = entry.path();
-			let metadata = entry.metadata()?;
-			if entry.file_name() == "prepared" && metadata.is_dir() {
-				// Handle prepared directory separately below
+			if path == dirs.prepared || referenced.contains(&path) {
 				continue;
 			}
-			if !referenced.contains(&path) {
-				// Try to remove file, but ignore errors and continue
-				match if path.is_file() {
-					std::fs::remove_file(&path)
-				} else {
-					std::fs::remove_dir_all(&path)
-				} {
-					Ok(()) => {
-						freed_bytes += metadata.len();
-						removed_sources_packages += 1;
-					}
-					Err(e) => {
-						eprintln!(
-							"{}: {} {:?}: {}",
-							"ERROR".red().bold(),
-							"Failed to remove file".white(),
-							path.display().to_string().bright_black(),
-							e.to_string().bright_black()
-						);
-					}
+			if let Some(reason) = sweep_reason(&path, entry.metadata()?.modified()?) {
+				let size = calculate_folder_size(&path).unwrap_or_default();
+				if remove(&mut stat, &path, size, reason, "file") {
+					stat.removed_sources_packages += 1;
 				}
 			}
 		}
-		let mut removed_prepared_packages = 0usize;
-		// Garbage collect prepared folder
-		if prepared_dir.exists() {
-			for entry in std::fs::read_dir(&prepared_dir)
-				.into_iter()
-				.flatten()
-				.flatten()
-			{
-				let path = entry.path();
-				if !prepared_referenced.contains(&path) {
-					let metadata = entry.metadata()?;
-					match std::fs::remove_dir_all(&path) {
-						Ok(()) => {
-							freed_bytes += metadata.len();
-							removed_prepared_packages += 1;
-						}
-						Err(e) => {
-							eprintln!(
-								"{}: {} {:?}: {}",
-								"ERROR".red().bold(),
-								"Failed to remove prepared directory".white(),
-								path.display().to_string().bright_black(),
-								e.to_string().bright_black()
-							);
-						}
-					}
+		for entry in read_dir(&dirs.prepared).into_iter().flatten().flatten() {
+			let path = entry.path();
+			if prepared_referenced.contains(&path) {
+				continue;
+			}
+			if let Some(reason) = sweep_reason(&path, entry.metadata()?.modified()?) {
+				let size = calculate_folder_size(&path).unwrap_or_default();
+				if remove(&mut stat, &path, size, reason, "prepared directory") {
+					stat.removed_prepared_packages += 1;
 				}
 			}
 		}
-		let mut removed_out_folders = 0usize;
-		let referenced_folders = self
-			.packages
-			.iter()
-			.filter_map(|p| {
-				p.get_out_dir()
-					.file_name()
-					.map(|n| n.to_string_lossy().to_string())
-			})
-			.collect::<Vec<_>>();
-		for entry in std::fs::read_dir("build/out")
-			.into_iter()
-			.flatten()
-			.flatten()
-		{
+
+		let mut total_out_size = 0u64;
+		let mut stale_by_package: HashMap<String, Vec<StaleOutDir>> = HashMap::new();
+		for entry in read_dir(&dirs.out).into_iter().flatten().flatten() {
 			let path = entry.path();
-			if !referenced_folders.contains(&path.file_name().unwrap().to_string_lossy().to_string()) {
-				let size_bytes = calculate_folder_size(&path).unwrap_or_default();
-				match std::fs::remove_dir_all(&path) {
-					Ok(()) => {
-						freed_bytes += size_bytes;
-						removed_out_folders += 1;
-					}
-					Err(e) => {
-						eprintln!(
-							"{}: {} {:?}: {}",
-							"ERROR".red().bold(),
-							"Failed to remove output folder".white(),
-							path.display().to_string().bright_black(),
-							e.to_string().bright_black()
-						);
+			let size = calculate_folder_size(&path).unwrap_or_default();
+			total_out_size += size;
+			if referenced_folders.contains(&path) {
+				continue;
+			}
+			let dir_name = entry.file_name().to_string_lossy().to_string();
+			let package = out_dir_package(&dir_name, &known_names);
+			stale_by_package
+				.entry(package.clone())
+				.or_default()
+				.push(StaleOutDir {
+					last_used: last_used(&path),
+					path,
+					package,
+					size,
+				});
+		}
+		// the most recent `keep_last` of each package and anything young enough survive,
+		// unless the size budget needs them
+		let mut survivors = Vec::new();
+		for (_, mut dirs) in stale_by_package {
+			dirs.sort_by_key(|d| std::cmp::Reverse(d.last_used));
+			for (index, dir) in dirs.into_iter().enumerate() {
+				if index >= options.keep_last && too_old(dir.last_used) {
+					if remove(
+						&mut stat,
+						&dir.path,
+						dir.size,
+						stale_reason,
+						"output folder",
+					) {
+						stat.removed_out_folders += 1;
+						total_out_size -= dir.size;
 					}
+				} else {
+					survivors.push(dir);
 				}
 			}
 		}
-		Ok(GarbageCollectionStat {
-			freed_bytes,
-			removed_out_folders,
-			removed_prepared_packages,
-			removed_sources_packages,
-		})
+		if let Some(max_size) = options.max_size {
+			survivors.sort_by(|a, b| {
+				a.last_used
+					.cmp(&b.last_used)
+					.then_with(|| a.package.cmp(&b.package))
+			});
+			for dir in survivors {
+				if total_out_size <= max_size {
+					break;
+				}
+				if remove(
+					&mut stat,
+					&dir.path,
+					dir.size,
+					"over the max_size budget",
+					"output folder",
+				) {
+					stat.removed_out_folders += 1;
+					total_out_size -= dir.size;
+				}
+			}
+		}
+		stat.removed.sort();
+		Ok(stat)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{TempDir, test_manifest};
+
+	const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+	fn dirs(cache: &TempDir) -> GcDirs {
+		let sources = cache.path().join("sources");
+		GcDirs {
+			prepared: sources.join("prepared"),
+			sources,
+			out: cache.path().join("out"),
+		}
+	}
+
+	fn set_age(path: &Path, days: u32) {
+		let time = SystemTime::now() - DAY * days;
+		std::fs::File::open(path)
+			.unwrap()
+			.set_modified(time)
+			.unwrap();
+	}
+
+	/// An out dir with `size` bytes of packages, last built `days` ago
+	fn out_dir(dirs: &GcDirs, name: &str, size: usize, days: u32) -> PathBuf {
+		let path = dirs.out.join(name);
+		std::fs::create_dir_all(&path).unwrap();
+		std::fs::write(path.join("pkg.pkg.tar.zst"), vec![0; size]).unwrap();
+		let build_time = path.join("last_successful_build_time");
+		std::fs::write(&build_time, "0").unwrap();
+		set_age(&build_time, days);
+		path
+	}
+
+	/// A file or directory in the sources, last modified `days` ago
+	fn source(dir: &Path, name: &str, is_dir: bool, days: u32) -> PathBuf {
+		let path = dir.join(name);
+		std::fs::create_dir_all(dir).unwrap();
+		if is_dir {
+			std::fs::create_dir_all(&path).unwrap();
+		} else {
+			std::fs::write(&path, "source").unwrap();
+		}
+		set_age(&path, days);
+		path
+	}
+
+	/// Collects with `options` and no roots, returning what's left of `paths`
+	fn collect(
+		dirs: &GcDirs,
+		options: GcOptions,
+		roots: &[GcRootManifest],
+		paths: &[&PathBuf],
+	) -> Vec<String> {
+		test_manifest("")
+			.garbage_collect_in(dirs, &options, roots, false)
+			.unwrap();
+		paths
+			.iter()
+			.filter(|path| path.exists())
+			.map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+			.collect()
+	}
+
+	#[test]
+	fn out_dir_packages() {
+		let known = HashSet::from(["hypr", "hyprland", "lib-foo"]);
+		assert_eq!(
+			out_dir_package("hyprland-0.45.0-1-1234", &known),
+			"hyprland"
+		);
+		assert_eq!(out_dir_package("hypr-1-1234", &known), "hypr");
+		assert_eq!(out_dir_package("lib-foo-2:1.0-1-1234", &known), "lib-foo");
+		assert_eq!(out_dir_package("gone-pkg-3.1-2-1234", &known), "gone-pkg");
+		assert_eq!(out_dir_package("no-version", &known), "no-version");
+	}
+
+	#[test]
+	fn keeps_the_last_builds_of_each_package() {
+		let cache = TempDir::new();
+		let dirs = dirs(&cache);
+		let a1 = out_dir(&dirs, "a-1-x", 10, 3);
+		let a2 = out_dir(&dirs, "a-2-x", 10, 2);
+		let a3 = out_dir(&dirs, "a-3-x", 10, 1);
+		let b1 = out_dir(&dirs, "b-1-x", 10, 30);
+		let options = GcOptions {
+			keep_last: 2,
+			..Default::default()
+		};
+		assert_eq!(
+			collect(&dirs, options, &[], &[&a1, &a2, &a3, &b1]),
+			["a-2-x", "a-3-x", "b-1-x"]
+		);
+	}
+
+	#[test]
+	fn removes_what_is_older_than_max_age_days() {
+		let cache = TempDir::new();
+		let dirs = dirs(&cache);
+		let young = out_dir(&dirs, "a-1-x", 10, 5);
+		let old = out_dir(&dirs, "a-2-x", 10, 20);
+		let young_source = source(&dirs.sources, "young.tar.gz", false, 5);
+		let old_source = source(&dirs.sources, "old.tar.gz", false, 20);
+		let young_prepared = source(&dirs.prepared, "young-1234", true, 5);
+		let old_prepared = source(&dirs.prepared, "old-1234", true, 20);
+		let options = GcOptions {
+			max_age_days: Some(10),
+			..Default::default()
+		};
+		let paths = [
+			&young,
+			&old,
+			&young_source,
+			&old_source,
+			&young_prepared,
+			&old_prepared,
+		];
+		assert_eq!(
+			collect(&dirs, options, &[], &paths),
+			["a-1-x", "young.tar.gz", "young-1234"]
+		);
+	}
+
+	#[test]
+	fn max_size_evicts_the_least_recently_used_first() {
+		let cache = TempDir::new();
+		let dirs = dirs(&cache);
+		let a = out_dir(&dirs, "a-1-x", 1000, 3);
+		let b = out_dir(&dirs, "b-1-x", 1000, 1);
+		let c = out_dir(&dirs, "c-1-x", 1000, 2);
+		let d = out_dir(&dirs, "d-1-x", 1000, 2);
+		let options = GcOptions {
+			keep_last: 10,
+			max_size: Some(2100),
+			..Default::default()
+		};
+		// c and d were last used at the same time, ties go by package name
+		assert_eq!(
+			collect(&dirs, options, &[], &[&a, &b, &c, &d]),
+			["b-1-x", "d-1-x"]
+		);
+	}
+
+	#[test]
+	fn recorded_root_paths_are_never_removed() {
+		let cache = TempDir::new();
+		let dirs = dirs(&cache);
+		let out = out_dir(&dirs, "a-1-x", 1000, 400);
+		let other_out = out_dir(&dirs, "a-2-x", 1000, 400);
+		let tarball = source(&dirs.sources, "a.tar.gz", false, 400);
+		let prepared = source(&dirs.prepared, "a-1234", true, 400);
+		let other_prepared = source(&dirs.prepared, "a-5678", true, 400);
+		let root = GcRootManifest {
+			manifest: test_manifest(""),
+			paths: vec![out.clone(), tarball.clone(), prepared.clone()],
+		};
+		let options = GcOptions {
+			max_age_days: Some(1),
+			max_size: Some(0),
+			..Default::default()
+		};
+		let paths = [&out, &other_out, &tarball, &prepared, &other_prepared];
+		assert_eq!(
+			collect(&dirs, options, &[root], &paths),
+			["a-1-x", "a.tar.gz", "a-1234"]
+		);
+	}
+
+	#[test]
+	fn partial_downloads_get_a_grace_period() {
+		let cache = TempDir::new();
+		let dirs = dirs(&cache);
+		let downloading = source(&dirs.sources, "a.tar.gz.a.partial", false, 0);
+		let staging = source(&dirs.prepared, "a-1234.partial-42", true, 0);
+		let interrupted = source(&dirs.sources, "b.tar.gz.b.partial", false, 2);
+		let interrupted_staging = source(&dirs.prepared, "b-1234.partial-42", true, 2);
+		let paths = [&downloading, &staging, &interrupted, &interrupted_staging];
+		assert_eq!(
+			collect(&dirs, GcOptions::default(), &[], &paths),
+			["a.tar.gz.a.partial", "a-1234.partial-42"]
+		);
 	}
 }
@@ -1,7 +1,10 @@
 pub mod build;
+pub mod docker_gc;
 pub mod fetch;
 pub mod gc;
+pub mod resolve;
 
-pub use build::build;
+pub use build::{BuildRequest, build};
 pub use fetch::fetch;
-pub use gc::gc_command;
+pub use gc::{auto_gc, gc_command};
+pub use resolve::resolve;
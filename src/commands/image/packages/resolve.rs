@@ -0,0 +1,246 @@
+use std::collections::{BTreeMap, HashMap};
+
+use colored::Colorize;
+use thiserror::Error;
+
+use crate::{
+	manifest::{Manifest, Package, Source},
+	pkginfo::PkgInfo,
+	srcinfo::{SrcInfo, SrcInfoError},
+};
+
+/// A `build_deps` edge that wasn't written in the manifest but found in a PKGBUILD
+pub struct InferredBuildDep {
+	pub package: String,
+	pub dependency: String,
+	/// The pkgname the PKGBUILD referenced, which may be a split package of `dependency`
+	pub pkgname: String,
+	pub kind: &'static str,
+}
+
+pub struct ResolveResult {
+	pub inferred_versions: Vec<(String, String)>,
+	pub inferred_build_deps: Vec<InferredBuildDep>,
+	pub warnings: Vec<(String, SrcInfoError)>,
+	pub errors: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+#[error("circular build dependency: {}", .0.join(" -> "))]
+pub struct DependencyCycle(pub Vec<String>);
+
+impl ResolveResult {
+	pub fn print(&self) {
+		for (package, warning) in &self.warnings {
+			eprintln!(
+				"{}: could not read .SRCINFO of {}: {}",
+				"warning".yellow().bold(),
+				package.cyan().bold(),
+				warning.to_string().dimmed()
+			);
+		}
+		for error in &self.errors {
+			eprintln!("{}: {}", "ERROR".red().bold(), error);
+		}
+	}
+	pub fn print_inferred(&self) {
+		if self.inferred_versions.is_empty() && self.inferred_build_deps.is_empty() {
+			println!("{}", "Nothing was inferred from .SRCINFO files".dimmed());
+			return;
+		}
+		for (package, version) in &self.inferred_versions {
+			println!(
+				"    {} {} {} {}",
+				"".blue(),
+				package.bold(),
+				"version inferred from .SRCINFO:".dimmed(),
+				version.cyan()
+			);
+		}
+		for dep in &self.inferred_build_deps {
+			let via = if dep.pkgname == dep.dependency {
+				String::new()
+			} else {
+				format!(" (as {})", dep.pkgname)
+			};
+			println!(
+				"    {} {} {} {}{} {}",
+				"".blue(),
+				dep.package.bold(),
+				"→".dimmed(),
+				dep.dependency.cyan(),
+				via.dimmed(),
+				format!("inferred from {}", dep.kind).dimmed()
+			);
+		}
+	}
+	pub fn exit_if_failure(&self) {
+		if !self.errors.is_empty() {
+			std::process::exit(1);
+		}
+	}
+}
+
+/// Every pkgname the given manifest package produces
+fn provided_pkgnames(pkg: &Package, srcinfo: Option<&SrcInfo>) -> Vec<String> {
+	let mut names = vec![pkg.name.clone()];
+	match &pkg.source {
+		Source::Binary { .. } => {
+			if let Ok(path) = pkg.source_tarball_path()
+				&& let Ok(info) = PkgInfo::read_from_package(&path)
+			{
+				names.push(info.pkgname);
+			}
+		}
+		Source::PkgBuildGit {
+			pick_packages_from_group,
+			..
+		}
+		| Source::PkgBuildLocal {
+			pick_packages_from_group,
+			..
+		} => {
+			if let Some(srcinfo) = srcinfo {
+				names.extend(
+					srcinfo
+						.pkgnames
+						.iter()
+						.filter(|n| {
+							pick_packages_from_group
+								.as_ref()
+								.is_none_or(|picked| picked.contains(n))
+						})
+						.cloned(),
+				);
+			}
+		}
+	}
+	names
+}
+
+/// Fills in what the manifest leaves to the PKGBUILDs: missing versions become
+/// `pkgver-pkgrel` and `makedepends`/`depends` on other manifest packages become `build_deps`.
+///
+/// Git sources have to be fetched first for their `.SRCINFO` to be readable.
+pub fn resolve(manifest: &mut Manifest) -> ResolveResult {
+	let mut result = ResolveResult {
+		inferred_versions: vec![],
+		inferred_build_deps: vec![],
+		warnings: vec![],
+		errors: vec![],
+	};
+	let mut srcinfos = HashMap::new();
+	for pkg in &manifest.packages {
+		match pkg.srcinfo() {
+			Ok(Some(srcinfo)) => {
+				srcinfos.insert(pkg.name.clone(), srcinfo);
+			}
+			Ok(None) => {}
+			Err(e) => result.warnings.push((pkg.name.clone(), e)),
+		}
+	}
+
+	let mut providers = BTreeMap::new();
+	for pkg in &manifest.packages {
+		for pkgname in provided_pkgnames(pkg, srcinfos.get(&pkg.name)) {
+			providers.entry(pkgname).or_insert_with(|| pkg.name.clone());
+		}
+	}
+
+	for pkg in manifest.packages.iter_mut() {
+		let srcinfo = srcinfos.get(&pkg.name);
+		if pkg.version.is_empty() {
+			match srcinfo {
+				Some(srcinfo) => {
+					pkg.version = srcinfo.full_version();
+					result
+						.inferred_versions
+						.push((pkg.name.clone(), pkg.version.clone()));
+				}
+				None => result.errors.push(format!(
+					"package {} has no version and it could not be inferred from a .SRCINFO",
+					pkg.name
+				)),
+			}
+		}
+		let Some(srcinfo) = srcinfo else {
+			continue;
+		};
+		let referenced = srcinfo
+			.makedepends
+			.iter()
+			.map(|d| (d, "makedepends"))
+			.chain(srcinfo.depends.iter().map(|d| (d, "depends")));
+		for (pkgname, kind) in referenced {
+			let Some(dependency) = providers.get(pkgname) else {
+				continue;
+			};
+			if *dependency == pkg.name || pkg.build_deps.contains(dependency) {
+				continue;
+			}
+			pkg.build_deps.insert(dependency.clone());
+			result.inferred_build_deps.push(InferredBuildDep {
+				package: pkg.name.clone(),
+				dependency: dependency.clone(),
+				pkgname: pkgname.clone(),
+				kind,
+			});
+		}
+	}
+	// computed from the build deps and versions filled in above
+	manifest.binary_cache_keys.take();
+
+	for pkg in &manifest.packages {
+		for dep in &pkg.build_deps {
+			if !manifest.packages.iter().any(|p| &p.name == dep) {
+				result.errors.push(format!(
+					"package {} has build dependency {dep}, which is not defined in the manifest",
+					pkg.name
+				));
+			}
+		}
+	}
+	if let Err(cycle) = manifest.build_order() {
+		result.errors.push(cycle.to_string());
+	}
+	result
+}
+
+impl Manifest {
+	/// Packages ordered so every package comes after its `build_deps`,
+	/// otherwise keeping the order they're defined in
+	pub fn build_order(&self) -> Result<Vec<&Package>, DependencyCycle> {
+		fn visit<'m>(
+			manifest: &'m Manifest,
+			pkg: &'m Package,
+			stack: &mut Vec<String>,
+			order: &mut Vec<&'m Package>,
+		) -> Result<(), DependencyCycle> {
+			if order.iter().any(|p| p.name == pkg.name) {
+				return Ok(());
+			}
+			if let Some(start) = stack.iter().position(|n| *n == pkg.name) {
+				let mut cycle = stack[start..].to_vec();
+				cycle.push(pkg.name.clone());
+				return Err(DependencyCycle(cycle));
+			}
+			stack.push(pkg.name.clone());
+			for dep in manifest
+				.packages
+				.iter()
+				.filter(|p| pkg.build_deps.contains(&p.name))
+			{
+				visit(manifest, dep, stack, order)?;
+			}
+			stack.pop();
+			order.push(pkg);
+			Ok(())
+		}
+
+		let mut order = Vec::with_capacity(self.packages.len());
+		for pkg in &self.packages {
+			visit(self, pkg, &mut vec![], &mut order)?;
+		}
+		Ok(order)
+	}
+}
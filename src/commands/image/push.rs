@@ -0,0 +1,551 @@
+//! Publishing signed images to an update repository. A repository is a plain directory
+//! tree, either local (or on a network mount) or behind an HTTP server answering GET, HEAD
+//! and PUT:
+//!
+//! ```text
+//! channels/<channel>.json           ChannelPointer, the version devices on the channel run
+//! versions/<version>/metadata.json  VersionMetadata, uploaded last: a version without it
+//!                                   isn't published yet
+//! versions/<version>/<image>        the image and its .sig
+//! versions/<version>/<image>.release.json(.sig)
+//! versions/<version>/<image>.verity(.json)  when the image has a hash tree
+//! versions/<version>/kernel, initrd
+//! versions/<version>/<image>.from-<sha256>.delta  deltas from older images, see
+//!                                   [`crate::delta`]
+//! ```
+//!
+//! Published versions are never overwritten, and channel pointers are replaced in one step
+//! so clients never read a half written one. HTTP servers have to do the same: write PUT
+//! bodies to a temporary file and rename it into place, and answer `412` to a PUT with
+//! `If-None-Match: *` when the file exists. Every file of a version is sent that way, so
+//! racing pushes of the same version can't overwrite each other's files.
+use std::{
+	io::{self, Read, Write},
+	path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::{
+	assemble::image_path,
+	delta::deltas_for,
+	release::{self, Release, ReleaseError, ReleaseFile},
+};
+use crate::{
+	hash::{Sha256Hash, hash_file},
+	manifest::Manifest,
+	size, verity,
+};
+
+const VERSION_METADATA: &str = "metadata.json";
+
+#[derive(Debug, Error)]
+pub enum PushError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("http error: {0}")]
+	Http(#[from] ureq::Error),
+	#[error("{0}")]
+	Release(#[from] ReleaseError),
+	#[error("invalid metadata in the update repository: {0}")]
+	Metadata(#[from] serde_json::Error),
+	#[error("invalid {0} name {1:?}, only letters, digits and `.-_+~` are allowed")]
+	InvalidName(&'static str, String),
+	#[error(
+		"version {version} is already published at {location} with a different image, bump the manifest's version"
+	)]
+	VersionExists { version: String, location: String },
+	#[error(
+		"{0} already exists with different contents, left by another push of this version, bump the manifest's version"
+	)]
+	Conflict(String),
+}
+
+/// `channels/<channel>.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelPointer {
+	pub channel: String,
+	pub version: String,
+	/// Path of the version's [`VersionMetadata`], relative to the repository root
+	pub metadata: String,
+	/// Unix timestamp of the push that moved the channel here
+	pub updated_at: u64,
+}
+
+/// `versions/<version>/metadata.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionMetadata {
+	pub version: String,
+	pub git: Option<String>,
+	/// Key the release was signed with
+	pub key_id: String,
+	/// The image, its release manifest is `<image name>.release.json`
+	pub image: ReleaseFile,
+	pub verity_root_hash: Option<String>,
+	/// Every file of the version, relative to `versions/<version>/`
+	pub files: Vec<ReleaseFile>,
+	/// Deltas clients running one of these older images can download instead of the image
+	#[serde(default)]
+	pub deltas: Vec<VersionDelta>,
+	/// Unix timestamp of the upload
+	pub pushed_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionDelta {
+	/// The delta, one of the version's files
+	pub file: ReleaseFile,
+	/// Image the delta applies to
+	pub from_sha256: Sha256Hash,
+	pub from_size: u64,
+	pub from_version: Option<String>,
+}
+
+/// Where `image push --to` sends things
+#[derive(Debug)]
+pub enum UpdateRepository {
+	Directory(PathBuf),
+	Http(String),
+}
+
+impl UpdateRepository {
+	/// `http://` and `https://` targets are servers, anything else is a directory
+	pub fn parse(target: &str) -> Self {
+		if target.starts_with("http://") || target.starts_with("https://") {
+			UpdateRepository::Http(target.trim_end_matches('/').to_string())
+		} else {
+			UpdateRepository::Directory(PathBuf::from(target))
+		}
+	}
+
+	/// Human readable location of a file in the repository, for messages
+	pub fn location(&self, path: &str) -> String {
+		match self {
+			UpdateRepository::Directory(root) => root.join(path).display().to_string(),
+			UpdateRepository::Http(url) => format!("{url}/{path}"),
+		}
+	}
+
+	/// Reads a file of the repository, `None` if it doesn't exist
+	fn read(&self, path: &str) -> Result<Option<String>, PushError> {
+		match self {
+			UpdateRepository::Directory(root) => match std::fs::read_to_string(root.join(path)) {
+				Ok(contents) => Ok(Some(contents)),
+				Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+				Err(e) => Err(e.into()),
+			},
+			UpdateRepository::Http(_) => match ureq::get(self.location(path)).call() {
+				Ok(response) => {
+					let mut contents = String::new();
+					response
+						.into_body()
+						.into_reader()
+						.read_to_string(&mut contents)?;
+					Ok(Some(contents))
+				}
+				Err(ureq::Error::StatusCode(404)) => Ok(None),
+				Err(e) => Err(e.into()),
+			},
+		}
+	}
+
+	fn version_metadata(&self, version: &str) -> Result<Option<VersionMetadata>, PushError> {
+		match self.read(&format!("versions/{version}/{VERSION_METADATA}"))? {
+			Some(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Uploads the files of a version, then its metadata, which fails if another push
+	/// published the version in the meantime
+	fn publish_version(
+		&self,
+		metadata: &VersionMetadata,
+		files: &[PathBuf],
+	) -> Result<(), PushError> {
+		let version = &metadata.version;
+		let metadata_json = serde_json::to_string_pretty(metadata)?;
+		let exists = || PushError::VersionExists {
+			version: version.clone(),
+			location: self.location(&format!("versions/{version}")),
+		};
+		match self {
+			UpdateRepository::Directory(root) => {
+				// staged next to the published versions and renamed into place, so the
+				// version appears with all of its files at once
+				let versions = root.join("versions");
+				let staging = versions.join(format!(".{version}.partial"));
+				if staging.exists() {
+					std::fs::remove_dir_all(&staging)?;
+				}
+				std::fs::create_dir_all(&staging)?;
+				for file in files {
+					std::fs::copy(file, staging.join(file.file_name().unwrap()))?;
+				}
+				std::fs::write(staging.join(VERSION_METADATA), metadata_json)?;
+				let published = versions.join(version);
+				if published.join(VERSION_METADATA).exists() {
+					std::fs::remove_dir_all(&staging)?;
+					return Err(exists());
+				}
+				match std::fs::rename(&staging, &published) {
+					Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+						std::fs::remove_dir_all(&staging)?;
+						Err(exists())
+					}
+					result => Ok(result?),
+				}
+			}
+			UpdateRepository::Http(_) => {
+				let version_url = self.location(&format!("versions/{version}"));
+				// never overwritten either: a push racing this one may have uploaded the
+				// file and published its metadata already
+				for file in files {
+					let name = file.file_name().unwrap().to_string_lossy();
+					let url = format!("{version_url}/{name}");
+					match ureq::put(&url)
+						.header("If-None-Match", "*")
+						.send(std::fs::File::open(file)?)
+					{
+						Err(ureq::Error::StatusCode(412)) => {
+							if self.version_metadata(version)?.is_some() {
+								return Err(exists());
+							}
+							// left by a failed push, or uploaded by one still running, which
+							// is fine as long as it's the same file
+							if remote_sha256(&url)? != hash_file(file)? {
+								return Err(PushError::Conflict(url));
+							}
+						}
+						result => {
+							result?;
+						}
+					}
+				}
+				match ureq::put(format!("{version_url}/{VERSION_METADATA}"))
+					.header("If-None-Match", "*")
+					.send(metadata_json)
+				{
+					Err(ureq::Error::StatusCode(412)) => Err(exists()),
+					result => Ok(result.map(|_| ())?),
+				}
+			}
+		}
+	}
+
+	/// Replaces the channel pointer in one step
+	fn set_channel(&self, pointer: &ChannelPointer) -> Result<(), PushError> {
+		let path = format!("channels/{}.json", pointer.channel);
+		let contents = serde_json::to_string_pretty(pointer)?;
+		match self {
+			UpdateRepository::Directory(root) => {
+				let channels = root.join("channels");
+				std::fs::create_dir_all(&channels)?;
+				let partial = channels.join(format!(".{}.json.partial", pointer.channel));
+				let mut file = std::fs::File::create(&partial)?;
+				file.write_all(contents.as_bytes())?;
+				file.sync_all()?;
+				std::fs::rename(partial, root.join(path))?;
+				Ok(())
+			}
+			UpdateRepository::Http(_) => {
+				ureq::put(self.location(&path)).send(contents)?;
+				Ok(())
+			}
+		}
+	}
+}
+
+fn remote_sha256(url: &str) -> Result<Sha256Hash, PushError> {
+	let mut hasher = Sha256::new();
+	io::copy(
+		&mut ureq::get(url).call()?.into_body().into_reader(),
+		&mut hasher,
+	)?;
+	Ok(format!("{:X}", hasher.finalize()).into())
+}
+
+/// Channel and version names end up in paths and URLs
+fn check_name(what: &'static str, name: &str) -> Result<(), PushError> {
+	let valid = !name.is_empty()
+		&& !name.starts_with('.')
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || ".-_+~".contains(c));
+	if valid {
+		Ok(())
+	} else {
+		Err(PushError::InvalidName(what, name.to_string()))
+	}
+}
+
+pub struct PushResult {
+	pub release: Release,
+	pub channel: String,
+	pub repository: String,
+	/// Version the channel pointed to before
+	pub previous: Option<String>,
+	/// What was uploaded, `None` when the version was already published
+	pub published: Option<VersionMetadata>,
+}
+
+/// Publishes the signed image (along with the kernel and initrd its release manifest
+/// describes) as a new version of the repository, then points `channel` at it. Pushing a
+/// version that's already published with the same image only moves the channel.
+pub fn push(
+	manifest: &Manifest,
+	repository: &UpdateRepository,
+	channel: &str,
+	kernel_path: &Path,
+	initrd_path: &Path,
+) -> Result<PushResult, PushError> {
+	check_name("channel", channel)?;
+	let now = std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap()
+		.as_secs();
+	let image = image_path(manifest);
+	if !image.exists() {
+		return Err(ReleaseError::NotAssembled(image).into());
+	}
+	let release = release::read_release(&image)?;
+	check_name("version", &release.version)?;
+	// catch images reassembled (or kernels rebuilt) since `image sign`
+	release::check_file("image", &image, &release.image)?;
+	release::check_file("kernel", kernel_path, &release.kernel)?;
+	release::check_file("initrd", initrd_path, &release.initrd)?;
+
+	let published = match repository.version_metadata(&release.version)? {
+		Some(published) if published.image.sha256 == release.image.sha256 => None,
+		Some(_) => {
+			return Err(PushError::VersionExists {
+				version: release.version.clone(),
+				location: repository.location(&format!("versions/{}", release.version)),
+			});
+		}
+		None => {
+			let release_path = release::release_path(&image);
+			let mut files = vec![
+				image.clone(),
+				release::signature_path(&image),
+				release_path.clone(),
+				release::signature_path(&release_path),
+			];
+			if let Some(info) = verity::read_info(&image) {
+				files.push(verity::info_path(&image));
+				files.extend(info.hash_file);
+			}
+			files.push(kernel_path.to_path_buf());
+			files.push(initrd_path.to_path_buf());
+			let mut deltas = Vec::new();
+			for (path, header) in deltas_for(&image, &release.image.sha256) {
+				deltas.push(VersionDelta {
+					file: ReleaseFile::describe(&path)?,
+					from_sha256: header.old.sha256,
+					from_size: header.old.size,
+					from_version: header.old.version,
+				});
+				files.push(path);
+			}
+			let mut described = Vec::new();
+			for path in &files {
+				described.push(ReleaseFile::describe(path).map_err(|e| match e.kind() {
+					io::ErrorKind::NotFound => ReleaseError::NotSigned(path.clone()).into(),
+					_ => PushError::Io(e),
+				})?);
+			}
+			let metadata = VersionMetadata {
+				version: release.version.clone(),
+				git: release.git.clone(),
+				key_id: release.key_id.clone(),
+				image: ReleaseFile::describe(&image)?,
+				verity_root_hash: release.verity_root_hash.clone(),
+				files: described,
+				deltas,
+				pushed_at: now,
+			};
+			repository.publish_version(&metadata, &files)?;
+			Some(metadata)
+		}
+	};
+
+	let previous = match repository.read(&format!("channels/{channel}.json"))? {
+		Some(contents) => Some(serde_json::from_str::<ChannelPointer>(&contents)?.version),
+		None => None,
+	};
+	repository.set_channel(&ChannelPointer {
+		channel: channel.to_string(),
+		version: release.version.clone(),
+		metadata: format!("versions/{}/{VERSION_METADATA}", release.version),
+		updated_at: now,
+	})?;
+	Ok(PushResult {
+		release,
+		channel: channel.to_string(),
+		repository: repository.location("").trim_end_matches('/').to_string(),
+		previous,
+		published,
+	})
+}
+
+impl PushResult {
+	pub fn print(&self) {
+		match &self.published {
+			Some(metadata) => {
+				println!(
+					"{} {} {} {}",
+					"󰅧 Published".green().bold(),
+					format!("hyprside {}", self.release.version).bold(),
+					format!("to {}", self.repository).dimmed(),
+					size::human_readable_size(metadata.files.iter().map(|file| file.size).sum()).cyan()
+				);
+				for delta in &metadata.deltas {
+					println!(
+						"    {} {} {}",
+						"Delta from".green(),
+						delta
+							.from_version
+							.clone()
+							.unwrap_or_else(|| delta.from_sha256.as_str()[..12].to_lowercase())
+							.bold(),
+						size::human_readable_size(delta.file.size).cyan()
+					);
+				}
+			}
+			None => println!(
+				"{} {}",
+				format!("hyprside {}", self.release.version).bold(),
+				"is already published, only moving the channel".dimmed()
+			),
+		}
+		let previous = match &self.previous {
+			Some(previous) if *previous == self.release.version => "unchanged".to_string(),
+			Some(previous) => format!("was {previous}"),
+			None => "new channel".to_string(),
+		};
+		println!(
+			"    {} {} {} {}",
+			"Channel".green(),
+			self.channel.cyan().bold(),
+			format!("→ {}", self.release.version).bold(),
+			format!("({previous})").dimmed()
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{FileServer, TempDir};
+
+	/// A version with an image and a kernel holding `image`, written under `dir`
+	fn version(dir: &Path, version: &str, image: &str) -> (VersionMetadata, Vec<PathBuf>) {
+		let files = [("hyprside.squashfs", image), ("vmlinuz", "kernel")]
+			.map(|(name, contents)| {
+				let path = dir.join(version).join(name);
+				std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+				std::fs::write(&path, contents).unwrap();
+				path
+			})
+			.to_vec();
+		let metadata = VersionMetadata {
+			version: version.to_string(),
+			git: None,
+			key_id: "key".to_string(),
+			image: ReleaseFile::describe(&files[0]).unwrap(),
+			verity_root_hash: None,
+			files: files
+				.iter()
+				.map(|path| ReleaseFile::describe(path).unwrap())
+				.collect(),
+			deltas: Vec::new(),
+			pushed_at: 0,
+		};
+		(metadata, files)
+	}
+
+	fn published_image(repository: &UpdateRepository, version: &str) -> Option<String> {
+		repository
+			.read(&format!("versions/{version}/hyprside.squashfs"))
+			.unwrap()
+	}
+
+	/// Publishing, refusing to publish a version twice and moving a channel
+	fn check_repository(repository: &UpdateRepository, work: &Path) {
+		let (metadata, files) = version(work, "1.0", "first");
+		repository.publish_version(&metadata, &files).unwrap();
+		let published = repository.version_metadata("1.0").unwrap().unwrap();
+		assert_eq!(published.image.sha256, metadata.image.sha256);
+		assert_eq!(published_image(repository, "1.0").as_deref(), Some("first"));
+
+		let (metadata, files) = version(&work.join("again"), "1.0", "second");
+		assert!(matches!(
+			repository.publish_version(&metadata, &files),
+			Err(PushError::VersionExists { .. })
+		));
+		assert_eq!(published_image(repository, "1.0").as_deref(), Some("first"));
+
+		repository
+			.set_channel(&ChannelPointer {
+				channel: "stable".to_string(),
+				version: "1.0".to_string(),
+				metadata: format!("versions/1.0/{VERSION_METADATA}"),
+				updated_at: 0,
+			})
+			.unwrap();
+		let pointer: ChannelPointer =
+			serde_json::from_str(&repository.read("channels/stable.json").unwrap().unwrap()).unwrap();
+		assert_eq!(pointer.version, "1.0");
+		assert!(repository.read("channels/beta.json").unwrap().is_none());
+	}
+
+	#[test]
+	fn directory_repository() {
+		let (work, root) = (TempDir::new(), TempDir::new());
+		check_repository(
+			&UpdateRepository::Directory(root.path().into()),
+			work.path(),
+		);
+		// nothing staged is left behind
+		let versions = std::fs::read_dir(root.path().join("versions"))
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name())
+			.collect::<Vec<_>>();
+		assert_eq!(versions, ["1.0"]);
+	}
+
+	#[test]
+	fn http_repository() {
+		let (work, server) = (TempDir::new(), FileServer::start());
+		check_repository(&UpdateRepository::parse(&server.url), work.path());
+	}
+
+	#[test]
+	fn http_keeps_files_of_other_pushes() {
+		let (work, server) = (TempDir::new(), FileServer::start());
+		let repository = UpdateRepository::parse(&format!("{}/", server.url));
+		let version_dir = server.root.path().join("versions/1.0");
+		std::fs::create_dir_all(&version_dir).unwrap();
+
+		// uploaded by a push of a different image that hasn't published yet
+		std::fs::write(version_dir.join("hyprside.squashfs"), "theirs").unwrap();
+		let (metadata, files) = version(work.path(), "1.0", "ours");
+		assert!(matches!(
+			repository.publish_version(&metadata, &files),
+			Err(PushError::Conflict(_))
+		));
+		assert_eq!(
+			published_image(&repository, "1.0").as_deref(),
+			Some("theirs")
+		);
+		assert!(repository.version_metadata("1.0").unwrap().is_none());
+
+		// left by a failed push of the same image
+		std::fs::write(version_dir.join("hyprside.squashfs"), "ours").unwrap();
+		repository.publish_version(&metadata, &files).unwrap();
+		assert!(repository.version_metadata("1.0").unwrap().is_some());
+	}
+}
@@ -0,0 +1,452 @@
+//! Signed releases: a detached Ed25519ph signature over the image and a release manifest
+//! describing it (and the kernel and initrd it boots with), itself signed
+use std::{
+	fs::File,
+	io,
+	path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use colored::Colorize;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use thiserror::Error;
+
+use super::assemble::{get_git_commit_hash, image_path};
+use crate::{
+	commands::keys::{self, KeyError},
+	fs_utils,
+	hash::{Sha256Hash, hash_file},
+	manifest::{ImageFormat, Manifest},
+	size, verity,
+};
+
+/// Domain separation for the image signature, so it can't be passed off as a signature
+/// over anything else
+const IMAGE_SIGNATURE_CONTEXT: &[u8] = b"hyprside image";
+
+#[derive(Debug, Error)]
+pub enum ReleaseError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{0}")]
+	Key(#[from] KeyError),
+	#[error("no assembled image found at {}, run `image assemble` first", .0.display())]
+	NotAssembled(PathBuf),
+	#[error("{} is missing, was the image signed with `image sign`?", .0.display())]
+	NotSigned(PathBuf),
+	#[error("{} is not a valid release manifest: {}", .0.display(), .1)]
+	InvalidRelease(PathBuf, serde_json::Error),
+	#[error("{} is not a valid signature", .0.display())]
+	MalformedSignature(PathBuf),
+	#[error("bad signature over {0}, it was modified or signed with another key")]
+	BadSignature(String),
+	#[error("{what} doesn't match the release manifest: expected {expected}, found {actual}")]
+	Mismatch {
+		what: String,
+		expected: String,
+		actual: String,
+	},
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReleaseFile {
+	/// File name, without directories
+	pub name: String,
+	pub sha256: Sha256Hash,
+	pub size: u64,
+}
+
+impl ReleaseFile {
+	pub fn describe(path: &Path) -> io::Result<Self> {
+		Ok(Self {
+			name: path
+				.file_name()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.into_owned(),
+			sha256: hash_file(path)?,
+			size: std::fs::metadata(path)?.len(),
+		})
+	}
+}
+
+/// `<image>.release.json`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Release {
+	pub version: String,
+	/// Short hash of the commit the image was built from
+	pub git: Option<String>,
+	/// [`keys::key_id`] of the signing key
+	pub key_id: String,
+	pub format: ImageFormat,
+	pub image: ReleaseFile,
+	/// Root hash of the image's dm-verity hash tree, if it has one
+	pub verity_root_hash: Option<String>,
+	pub kernel: ReleaseFile,
+	pub initrd: ReleaseFile,
+}
+
+pub fn release_path(image_path: &Path) -> PathBuf {
+	fs_utils::with_suffix(image_path, ".release.json")
+}
+
+pub fn signature_path(path: &Path) -> PathBuf {
+	fs_utils::with_suffix(path, ".sig")
+}
+
+/// sha512 of a file, the prehash Ed25519ph signs so images don't have to fit in memory
+fn prehash_file(path: &Path) -> io::Result<Sha512> {
+	let mut hasher = Sha512::new();
+	io::copy(&mut File::open(path)?, &mut hasher)?;
+	Ok(hasher)
+}
+
+fn write_signature(path: &Path, signature: &Signature) -> io::Result<()> {
+	std::fs::write(path, format!("{}\n", BASE64.encode(signature.to_bytes())))
+}
+
+fn read_signature(path: &Path) -> Result<Signature, ReleaseError> {
+	let text = std::fs::read_to_string(path).map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => ReleaseError::NotSigned(path.to_path_buf()),
+		_ => ReleaseError::Io(e),
+	})?;
+	BASE64
+		.decode(text.trim())
+		.ok()
+		.and_then(|bytes| Signature::from_slice(&bytes).ok())
+		.ok_or_else(|| ReleaseError::MalformedSignature(path.to_path_buf()))
+}
+
+pub struct SignResult {
+	pub image: PathBuf,
+	pub signature: PathBuf,
+	pub release: PathBuf,
+	pub release_signature: PathBuf,
+	pub key_id: String,
+}
+
+/// Signs the assembled image and writes its release manifest, signed as well
+pub fn sign(
+	manifest: &Manifest,
+	key_path: &Path,
+	kernel_path: &Path,
+	initrd_path: &Path,
+) -> Result<SignResult, ReleaseError> {
+	let signing_key: SigningKey = keys::read_signing_key(key_path)?;
+	let key_id = keys::key_id(&signing_key.verifying_key());
+	let image = image_path(manifest);
+	if !image.exists() {
+		return Err(ReleaseError::NotAssembled(image));
+	}
+
+	let signature = signature_path(&image);
+	let image_signature = signing_key
+		.sign_prehashed(prehash_file(&image)?, Some(IMAGE_SIGNATURE_CONTEXT))
+		.expect("the context is shorter than 256 bytes");
+	write_signature(&signature, &image_signature)?;
+
+	let release = Release {
+		version: manifest.version.clone(),
+		git: get_git_commit_hash(),
+		key_id: key_id.clone(),
+		format: manifest.image.format,
+		image: ReleaseFile::describe(&image)?,
+		verity_root_hash: verity::read_info(&image).map(|info| info.root_hash),
+		kernel: ReleaseFile::describe(kernel_path)?,
+		initrd: ReleaseFile::describe(initrd_path)?,
+	};
+	let release_json = serde_json::to_string_pretty(&release).unwrap();
+	let release_path = release_path(&image);
+	std::fs::write(&release_path, &release_json)?;
+	let release_signature = signature_path(&release_path);
+	write_signature(
+		&release_signature,
+		&signing_key.sign(release_json.as_bytes()),
+	)?;
+
+	Ok(SignResult {
+		image,
+		signature,
+		release: release_path,
+		release_signature,
+		key_id,
+	})
+}
+
+pub struct VerifyResult {
+	pub release: Release,
+	/// Kernel and initrd files that were checked against the release manifest
+	pub checked: Vec<PathBuf>,
+}
+
+/// Checks that the file at `path` is the one the release manifest describes
+pub fn check_file(what: &str, path: &Path, expected: &ReleaseFile) -> Result<(), ReleaseError> {
+	let actual = ReleaseFile::describe(path)?;
+	if actual.size != expected.size || actual.sha256 != expected.sha256 {
+		return Err(ReleaseError::Mismatch {
+			what: format!("{what} {}", path.display()),
+			expected: format!("sha256 {} ({} bytes)", expected.sha256, expected.size),
+			actual: format!("sha256 {} ({} bytes)", actual.sha256, actual.size),
+		});
+	}
+	Ok(())
+}
+
+/// Reads an image's release manifest without checking its signature
+pub fn read_release(image: &Path) -> Result<Release, ReleaseError> {
+	let release_path = release_path(image);
+	let release_json = std::fs::read(&release_path).map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => ReleaseError::NotSigned(release_path.clone()),
+		_ => ReleaseError::Io(e),
+	})?;
+	serde_json::from_slice(&release_json).map_err(|e| ReleaseError::InvalidRelease(release_path, e))
+}
+
+/// Checks the release manifest's signature, then the image's signature and that it's the
+/// image the manifest describes. The kernel and initrd are checked when given.
+pub fn verify(
+	image: &Path,
+	key_path: &Path,
+	kernel_path: Option<&Path>,
+	initrd_path: Option<&Path>,
+) -> Result<VerifyResult, ReleaseError> {
+	let verifying_key: VerifyingKey = keys::read_verifying_key(key_path)?;
+	if !image.exists() {
+		return Err(ReleaseError::NotAssembled(image.to_path_buf()));
+	}
+
+	let release_path = release_path(image);
+	let release_json = std::fs::read(&release_path).map_err(|e| match e.kind() {
+		io::ErrorKind::NotFound => ReleaseError::NotSigned(release_path.clone()),
+		_ => ReleaseError::Io(e),
+	})?;
+	let release_signature = read_signature(&signature_path(&release_path))?;
+	verifying_key
+		.verify(&release_json, &release_signature)
+		.map_err(|_| ReleaseError::BadSignature(release_path.display().to_string()))?;
+	let release = serde_json::from_slice::<Release>(&release_json)
+		.map_err(|e| ReleaseError::InvalidRelease(release_path.clone(), e))?;
+
+	let image_signature = read_signature(&signature_path(image))?;
+	verifying_key
+		.verify_prehashed(
+			prehash_file(image)?,
+			Some(IMAGE_SIGNATURE_CONTEXT),
+			&image_signature,
+		)
+		.map_err(|_| ReleaseError::BadSignature(image.display().to_string()))?;
+	check_file("image", image, &release.image)?;
+
+	let mut checked = Vec::new();
+	for (what, path, expected) in [
+		("kernel", kernel_path, &release.kernel),
+		("initrd", initrd_path, &release.initrd),
+	] {
+		if let Some(path) = path {
+			check_file(what, path, expected)?;
+			checked.push(path.to_path_buf());
+		}
+	}
+	Ok(VerifyResult { release, checked })
+}
+
+impl SignResult {
+	pub fn print(&self) {
+		println!(
+			"{} {} {} {}",
+			"✍ Signed".green().bold(),
+			self.image.display().to_string().green().bold(),
+			"with key".green(),
+			self.key_id.cyan()
+		);
+		for (label, path) in [
+			("Image signature:   ", &self.signature),
+			("Release manifest:  ", &self.release),
+			("Release signature: ", &self.release_signature),
+		] {
+			println!(
+				"    {} {}",
+				label.green(),
+				path.display().to_string().bold()
+			);
+		}
+	}
+}
+
+impl VerifyResult {
+	pub fn print(&self) {
+		let release = &self.release;
+		println!(
+			"{} {} {}",
+			"✔ Good signature from key".green().bold(),
+			release.key_id.cyan(),
+			format!(
+				"(hyprside {}{})",
+				release.version,
+				release
+					.git
+					.as_ref()
+					.map(|git| format!(", {git}"))
+					.unwrap_or_default()
+			)
+			.dimmed()
+		);
+		println!(
+			"    {} {} {}",
+			"Image:".green(),
+			release.image.name.bold(),
+			size::human_readable_size(release.image.size).cyan()
+		);
+		if let Some(root_hash) = &release.verity_root_hash {
+			println!("    {} {}", "dm-verity root hash:".green(), root_hash);
+		}
+		for path in &self.checked {
+			println!(
+				"    {} {}",
+				"Matches:".green(),
+				path.display().to_string().bold()
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{TempDir, noise, test_manifest};
+
+	struct Signed {
+		dir: TempDir,
+		keys: keys::GeneratedKeys,
+		image: PathBuf,
+		kernel: PathBuf,
+		initrd: PathBuf,
+	}
+
+	fn signed() -> Signed {
+		let dir = TempDir::new();
+		let manifest = test_manifest(&format!(
+			"[image]\nname = \"test\"\noutput_dir = {:?}\n",
+			dir.path().join("image"),
+		));
+		let keys = keys::generate(&dir.path().join("keys/release"), false).unwrap();
+		let image = image_path(&manifest);
+		let kernel = dir.path().join("vmlinuz");
+		let initrd = dir.path().join("initrd.img");
+		std::fs::create_dir_all(image.parent().unwrap()).unwrap();
+		std::fs::write(&image, noise(100_000, 1)).unwrap();
+		std::fs::write(&kernel, noise(1000, 2)).unwrap();
+		std::fs::write(&initrd, noise(1000, 3)).unwrap();
+		let result = sign(&manifest, &keys.private_key, &kernel, &initrd).unwrap();
+		assert_eq!(result.image, image);
+		assert_eq!(result.key_id, keys.key_id);
+		Signed {
+			dir,
+			keys,
+			image,
+			kernel,
+			initrd,
+		}
+	}
+
+	impl Signed {
+		fn verify(&self, key: &Path) -> Result<VerifyResult, ReleaseError> {
+			verify(&self.image, key, Some(&self.kernel), Some(&self.initrd))
+		}
+	}
+
+	/// Overwrites a byte in the middle of the file
+	fn tamper(path: &Path) {
+		let mut contents = std::fs::read(path).unwrap();
+		let middle = contents.len() / 2;
+		contents[middle] ^= 1;
+		std::fs::write(path, contents).unwrap();
+	}
+
+	#[test]
+	fn signed_images_verify() {
+		let signed = signed();
+		for key in [&signed.keys.public_key, &signed.keys.private_key] {
+			let result = signed.verify(key).unwrap();
+			assert_eq!(result.release.key_id, signed.keys.key_id);
+			assert_eq!(result.release.version, "1.0");
+			assert_eq!(
+				result.checked,
+				[signed.kernel.clone(), signed.initrd.clone()]
+			);
+		}
+		assert!(
+			verify(&signed.image, &signed.keys.public_key, None, None)
+				.unwrap()
+				.checked
+				.is_empty()
+		);
+	}
+
+	#[test]
+	fn tampered_images_are_rejected() {
+		let signed = signed();
+		tamper(&signed.image);
+		assert!(matches!(
+			signed.verify(&signed.keys.public_key),
+			Err(ReleaseError::BadSignature(what)) if what == signed.image.display().to_string()
+		));
+	}
+
+	#[test]
+	fn tampered_releases_are_rejected() {
+		let signed = signed();
+		let release = release_path(&signed.image);
+		let json = std::fs::read_to_string(&release).unwrap();
+		std::fs::write(&release, json.replace("\"1.0\"", "\"1.1\"")).unwrap();
+		assert!(matches!(
+			signed.verify(&signed.keys.public_key),
+			Err(ReleaseError::BadSignature(what)) if what == release.display().to_string()
+		));
+	}
+
+	#[test]
+	fn tampered_signatures_are_rejected() {
+		for signature in [
+			|image: &Path| signature_path(image),
+			|image: &Path| signature_path(&release_path(image)),
+		] {
+			let signed = signed();
+			let signature = signature(&signed.image);
+			let mut bytes = read_signature(&signature).unwrap().to_bytes();
+			bytes[0] ^= 1;
+			write_signature(&signature, &Signature::from_bytes(&bytes)).unwrap();
+			assert!(matches!(
+				signed.verify(&signed.keys.public_key),
+				Err(ReleaseError::BadSignature(_))
+			));
+		}
+	}
+
+	#[test]
+	fn other_kernels_are_rejected() {
+		let signed = signed();
+		tamper(&signed.kernel);
+		assert!(matches!(
+			signed.verify(&signed.keys.public_key),
+			Err(ReleaseError::Mismatch { what, .. }) if what.starts_with("kernel")
+		));
+		std::fs::write(&signed.initrd, "other initrd").unwrap();
+		assert!(matches!(
+			verify(&signed.image, &signed.keys.public_key, None, Some(&signed.initrd)),
+			Err(ReleaseError::Mismatch { what, .. }) if what.starts_with("initrd")
+		));
+	}
+
+	#[test]
+	fn other_keys_are_rejected() {
+		let signed = signed();
+		let other = keys::generate(&signed.dir.path().join("keys/other"), false).unwrap();
+		assert!(matches!(
+			signed.verify(&other.public_key),
+			Err(ReleaseError::BadSignature(_))
+		));
+	}
+}
@@ -0,0 +1,378 @@
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	fs_utils,
+	manifest::{ImageFormat, Manifest},
+	size,
+	squashfs::{InodeKind, ReadError, Reader},
+};
+
+/// Owner of the files hyprpacker itself adds to the image, like `/etc/credits.json`
+pub const GENERATED: &str = "(generated)";
+
+/// `<image>.size.json`, where [`SizeReport`] is saved for the next assembly to compare with
+pub fn size_report_path(image_path: &Path) -> PathBuf {
+	fs_utils::with_suffix(image_path, ".size.json")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageSize {
+	pub name: String,
+	pub files: usize,
+	/// Bytes of its files in the sysroot
+	pub installed: u64,
+	/// Bytes of its file data in the image, only known for squashfs images
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub compressed: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileSize {
+	/// Absolute path in the image
+	pub path: PathBuf,
+	pub package: String,
+	pub installed: u64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub compressed: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SizeReport {
+	pub image: PathBuf,
+	pub size: u64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_size: Option<u64>,
+	/// Largest first
+	pub packages: Vec<PackageSize>,
+	/// What isn't file data: inodes, directories, lookup tables and an appended dm-verity
+	/// hash tree. Only known for squashfs images
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub metadata: Option<u64>,
+	pub largest_files: Vec<FileSize>,
+	/// The image this one is compared with
+	#[serde(skip)]
+	pub previous: Option<PreviousImage>,
+}
+
+#[derive(Debug)]
+pub struct PreviousImage {
+	pub path: PathBuf,
+	pub size: u64,
+	/// From its size report, when it has one
+	pub packages: Option<Vec<PackageSize>>,
+}
+
+/// The most recently written image in the output directory, the one about to be replaced
+/// included when the image name doesn't change. Must be called before the new image is
+/// written.
+pub fn previous_image(manifest: &Manifest) -> Option<PreviousImage> {
+	let extension = manifest.image.format.extension();
+	let (path, size) = std::fs::read_dir(&manifest.image.output_dir)
+		.ok()?
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| entry.path().extension().is_some_and(|e| e == extension))
+		.filter_map(|entry| {
+			let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+			Some((metadata.modified().ok()?, entry.path(), metadata.len()))
+		})
+		.max_by(|a, b| a.0.cmp(&b.0))
+		.map(|(_, path, size)| (path, size))?;
+	let packages = std::fs::read_to_string(size_report_path(&path))
+		.ok()
+		.and_then(|report| serde_json::from_str::<SizeReport>(&report).ok())
+		// a report left behind by an older image of the same name
+		.filter(|report| report.size == size)
+		.map(|report| report.packages);
+	Some(PreviousImage {
+		path,
+		size,
+		packages,
+	})
+}
+
+/// Bytes each file's data takes in a squashfs image, by absolute path. Files sharing
+/// blocks with an earlier one (duplicates) get nothing, and each fragment block is split
+/// between the tails in it by their length.
+fn stored_sizes(image_path: &Path) -> Result<HashMap<PathBuf, u64>, ReadError> {
+	let mut reader = Reader::open(image_path)?;
+	let mut files = Vec::new();
+	reader.walk(Path::new("/"), &mut |path, inode| {
+		if let InodeKind::File(file) = &inode.kind {
+			files.push((path.to_path_buf(), file.clone()));
+		}
+		Ok(())
+	})?;
+
+	let mut seen_tails = HashSet::new();
+	let mut fragment_use: HashMap<u32, u64> = HashMap::new();
+	for (_, file) in &files {
+		if let Some(tail) = reader.stored_fragment(file)
+			&& seen_tails.insert((tail.index, tail.offset))
+		{
+			*fragment_use.entry(tail.index).or_default() += tail.len;
+		}
+	}
+	seen_tails.clear();
+	let mut seen_blocks = HashSet::new();
+	let mut sizes = HashMap::new();
+	for (path, file) in files {
+		let mut stored = 0;
+		let (start, blocks) = reader.stored_blocks(&file);
+		if blocks > 0 && seen_blocks.insert(start) {
+			stored += blocks;
+		}
+		if let Some(tail) = reader.stored_fragment(&file)
+			&& seen_tails.insert((tail.index, tail.offset))
+		{
+			let used = fragment_use[&tail.index].max(1);
+			stored += tail.len * tail.block_stored_size / used;
+		}
+		sizes.insert(path, stored);
+	}
+	Ok(sizes)
+}
+
+/// Sizes of the assembled image, per package and for its largest files. `installed` maps
+/// the image's files (relative to its root) to their package and size in the sysroot.
+pub fn generate(
+	manifest: &Manifest,
+	image_path: &Path,
+	installed: &BTreeMap<PathBuf, (String, u64)>,
+	previous: Option<PreviousImage>,
+) -> std::io::Result<SizeReport> {
+	let size = std::fs::metadata(image_path)?.len();
+	let stored = match manifest.image.format {
+		ImageFormat::Squashfs => match stored_sizes(image_path) {
+			Ok(stored) => Some(stored),
+			Err(e) => {
+				eprintln!(
+					"{}: could not read the image for the size report: {e}",
+					"warning".yellow().bold()
+				);
+				None
+			}
+		},
+		// erofs only compresses when asked to, and ext4 never does
+		ImageFormat::Erofs | ImageFormat::Ext4 => None,
+	};
+
+	let mut packages: BTreeMap<&str, PackageSize> = BTreeMap::new();
+	let mut files = Vec::new();
+	for (relative, (package, len)) in installed {
+		let path = Path::new("/").join(relative);
+		let compressed = stored
+			.as_ref()
+			.map(|stored| stored.get(&path).copied().unwrap_or_default());
+		let entry = packages.entry(package).or_insert_with(|| PackageSize {
+			name: package.clone(),
+			files: 0,
+			installed: 0,
+			compressed: stored.as_ref().map(|_| 0),
+		});
+		entry.files += 1;
+		entry.installed += len;
+		if let (Some(total), Some(compressed)) = (&mut entry.compressed, compressed) {
+			*total += compressed;
+		}
+		files.push(FileSize {
+			path,
+			package: package.clone(),
+			installed: *len,
+			compressed,
+		});
+	}
+	let metadata = stored
+		.as_ref()
+		.map(|stored| size.saturating_sub(stored.values().sum()));
+
+	let mut packages = packages.into_values().collect::<Vec<_>>();
+	packages.sort_by(|a, b| {
+		(b.compressed, b.installed, &a.name).cmp(&(a.compressed, a.installed, &b.name))
+	});
+	files.sort_by(|a, b| (b.installed, &a.path).cmp(&(a.installed, &b.path)));
+	files.truncate(manifest.image.largest_files);
+	Ok(SizeReport {
+		image: image_path.to_path_buf(),
+		size,
+		max_size: manifest.image.max_size,
+		packages,
+		metadata,
+		largest_files: files,
+		previous,
+	})
+}
+
+/// `+1.20 MB`, `-512 bytes`
+fn size_change(change: i64) -> String {
+	let sign = if change < 0 { "-" } else { "+" };
+	format!("{sign}{}", size::human_readable_size(change.unsigned_abs()))
+}
+
+fn colored_change(change: i64) -> String {
+	match change {
+		0 => "±0".dimmed().to_string(),
+		c if c > 0 => size_change(c).red().to_string(),
+		c => size_change(c).green().to_string(),
+	}
+}
+
+fn optional_size(size: Option<u64>) -> String {
+	size
+		.map(size::human_readable_size)
+		.unwrap_or_else(|| "-".to_string())
+}
+
+impl SizeReport {
+	pub fn is_over_budget(&self) -> bool {
+		self.max_size.is_some_and(|max_size| self.size > max_size)
+	}
+
+	pub fn print(&self) {
+		let mut summary = format!(
+			"     {} {}",
+			"󰋊 Image size".yellow().bold(),
+			size::human_readable_size(self.size).cyan()
+		);
+		if let Some(previous) = &self.previous {
+			let name = previous
+				.path
+				.file_name()
+				.unwrap_or_default()
+				.to_string_lossy();
+			summary += &format!(
+				", {} {}",
+				colored_change(self.size as i64 - previous.size as i64),
+				if previous.path == self.image {
+					"since the last assembly".to_string()
+				} else {
+					format!("since {name}")
+				}
+				.dimmed()
+			);
+		}
+		match self.max_size {
+			Some(max_size) if self.is_over_budget() => {
+				summary += &format!(
+					", {}",
+					format!(
+						"{} over the {} budget",
+						size::human_readable_size(self.size - max_size),
+						size::human_readable_size(max_size)
+					)
+					.red()
+					.bold()
+				)
+			}
+			Some(max_size) => {
+				summary += &format!(
+					"{}",
+					format!(
+						", {} left of the {} budget",
+						size::human_readable_size(max_size - self.size),
+						size::human_readable_size(max_size)
+					)
+					.dimmed()
+				)
+			}
+			None => {}
+		}
+		println!("{summary}");
+
+		// growth per package, by what it takes in the image when both reports know
+		let previous = self
+			.previous
+			.as_ref()
+			.and_then(|previous| previous.packages.as_ref())
+			.map(|packages| {
+				packages
+					.iter()
+					.map(|p| (p.name.as_str(), p))
+					.collect::<HashMap<_, _>>()
+			});
+		let growth = |package: &PackageSize| -> String {
+			let Some(previous) = &previous else {
+				return String::new();
+			};
+			let change = match previous.get(package.name.as_str()) {
+				Some(before) => match (package.compressed, before.compressed) {
+					(Some(now), Some(before)) => colored_change(now as i64 - before as i64),
+					_ => colored_change(package.installed as i64 - before.installed as i64),
+				},
+				None => "new".red().to_string(),
+			};
+			format!("  {change}")
+		};
+		let removed = previous
+			.iter()
+			.flat_map(|previous| previous.values())
+			.filter(|before| !self.packages.iter().any(|p| p.name == before.name))
+			.collect::<Vec<_>>();
+		let width = self
+			.packages
+			.iter()
+			.map(|p| p.name.len())
+			.chain(removed.iter().map(|p| p.name.len()))
+			.chain([7, "metadata".len()])
+			.max()
+			.unwrap_or_default();
+		println!(
+			"       {}",
+			format!(
+				"{:width$}  {:>10}  {:>10}  {:>7}",
+				"package", "installed", "compressed", "files"
+			)
+			.dimmed()
+		);
+		for package in &self.packages {
+			println!(
+				"       {}  {:>10}  {:>10}  {:>7}{}",
+				format!("{:width$}", package.name).bold(),
+				size::human_readable_size(package.installed),
+				optional_size(package.compressed).cyan(),
+				package.files,
+				growth(package)
+			);
+		}
+		for package in removed {
+			println!(
+				"       {}  {:>10}  {:>10}  {:>7}  {} {}",
+				format!("{:width$}", package.name).dimmed().strikethrough(),
+				"-",
+				"-",
+				"-",
+				"removed".green(),
+				match package.compressed {
+					Some(before) => colored_change(-(before as i64)),
+					None => colored_change(-(package.installed as i64)),
+				}
+			);
+		}
+		if let Some(metadata) = self.metadata {
+			println!(
+				"       {}  {:>10}  {:>10}",
+				format!("{:width$}", "metadata").dimmed(),
+				"",
+				size::human_readable_size(metadata).cyan()
+			);
+		}
+
+		if !self.largest_files.is_empty() {
+			println!("     {}", "󰋊 Largest files".yellow().bold());
+			for file in &self.largest_files {
+				println!(
+					"       {:>10}  {:>10}  {} {}",
+					size::human_readable_size(file.installed),
+					optional_size(file.compressed).cyan(),
+					file.path.display(),
+					format!("({})", file.package).dimmed()
+				);
+			}
+		}
+	}
+}
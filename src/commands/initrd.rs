@@ -1,7 +1,7 @@
 use std::{
 	collections::HashMap,
 	fs,
-	io::{BufRead, BufReader},
+	io::BufReader,
 	path::{Path, PathBuf},
 	process::{Command, ExitStatus, Stdio},
 	time::UNIX_EPOCH,
@@ -10,7 +10,7 @@ use std::{
 use colored::Colorize;
 use thiserror::Error;
 
-use crate::manifest::Manifest;
+use crate::{manifest::Manifest, prefix_commands};
 
 // ===============================
 //        Error definitions
@@ -56,7 +56,7 @@ pub fn run_command_with_tag_and_collect_dependencies(
 		// STDERR thread (just prints)
 		s.spawn(|| {
 			let buf_reader = BufReader::new(stderr);
-			for line in buf_reader.lines().filter_map(Result::ok) {
+			for line in prefix_commands::lossy_lines(buf_reader) {
 				eprintln!("{}", format!("{tag}{}", tag_line(&line, &tag)).dimmed());
 			}
 		});
@@ -64,7 +64,7 @@ pub fn run_command_with_tag_and_collect_dependencies(
 		// STDOUT thread (prints + dependency collection)
 		s.spawn(|| {
 			let buf_reader = BufReader::new(stdout);
-			for line in buf_reader.lines().filter_map(Result::ok) {
+			for line in prefix_commands::lossy_lines(buf_reader) {
 				let trimmed_line = line.trim();
 				if trimmed_line.starts_with("DEPENDENCY ") {
 					dependencies.push(trimmed_line.trim_start_matches("DEPENDENCY ").to_string());
@@ -9,18 +9,17 @@ use colored::Colorize;
 use thiserror::Error;
 
 use crate::{
-    hash::hash_file, manifest::{KernelOptionValue, Manifest}, prefix_commands
+    hash::hash_file, manifest::{ArchSnapshot, KernelOptionValue, Manifest}, patch::{PatchError, apply_patches, expand_patch_globs}, prefix_commands
 };
 
-const KERNEL_IMAGE_NAME: &str = "hyprpacker-kernel-builder:latest";
+const KERNEL_IMAGE_REPOSITORY: &str = "hyprpacker-kernel-builder";
 const KERNEL_DOCKERFILE_CONTENT: &str = include_str!("../../../docker/kernel.Dockerfile");
-const BUILD_SCRIPT: &str = r##"set -euo pipefail
+/// Unpacks the sources, the manifest's patches are applied on the host afterwards
+const PREPARE_SCRIPT: &str = r##"set -euo pipefail
 
 
 DOWNLOADS="/kernel/downloads"
 SRC="/kernel/src"
-OUT="/kernel/out"
-CONFIG="/kernel/config/options.config"
 
 TARBALL="$(find "${DOWNLOADS}" -maxdepth 1 -type f | head -n1)"
 if [[ -z "${TARBALL}" ]]; then
@@ -65,11 +64,26 @@ fi
 # Extrai nova source por cima (preserva ficheiros ignorados)
 tar -xf "${TARBALL}" -C "${SRC}"
 
+# the host patches the tree next
+chown -R "${HOST_UID}:${HOST_GID}" "${SRC}"
+"##;
+
+/// Builds the (patched) tree at `KERNEL_TREE`
+const BUILD_SCRIPT: &str = r##"set -euo pipefail
+
+
+OUT="/kernel/out"
+CONFIG="/kernel/config/options.config"
+
 # ==========================================
 # Build kernel
 # ==========================================
-NEW_TREE="$(find "${SRC}" -mindepth 1 -maxdepth 1 -type d -name 'linux-*' | head -n1)"
-pushd "${NEW_TREE}" >/dev/null
+pushd "${KERNEL_TREE}" >/dev/null
+
+# the build timestamp ends up in the kernel's version string
+if [[ -n "${SOURCE_DATE_EPOCH:-}" ]]; then
+  export KBUILD_BUILD_TIMESTAMP="$(LC_ALL=C date -u -d "@${SOURCE_DATE_EPOCH}")"
+fi
 
 make olddefconfig
 KCONFIG_FILE=".config"
@@ -136,6 +150,10 @@ pub enum KernelBuildError {
     DockerRunFailed(Option<i32>),
     #[error("kernel artifact not produced at {0}")]
     MissingArtifact(PathBuf),
+    #[error("no linux-* source tree unpacked from {0}")]
+    MissingTree(String),
+    #[error("invalid kernel patches: {0}")]
+    Patch(#[from] PatchError),
 }
 
 pub struct KernelBuildResult {
@@ -165,8 +183,9 @@ pub fn build(manifest: &Manifest) -> Result<KernelBuildResult, KernelBuildError>
     fs::create_dir_all(&src_dir)?;
     fs::create_dir_all(&out_dir)?;
     fs::create_dir_all(&config_dir)?;
+    let patches = expand_patch_globs(&kernel.patches)?;
 
-    let tarball_name = extract_filename(&kernel.url).unwrap_or_else(|| "kernel.tar".to_string());
+    let tarball_name = tarball_name(&kernel.url);
     let tarball_path = downloads_dir.join(&tarball_name);
 
     // --- Download if needed ---
@@ -202,7 +221,7 @@ pub fn build(manifest: &Manifest) -> Result<KernelBuildResult, KernelBuildError>
             .arg("--mime-type")
             .arg(&tarball_path)
             .output()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to run `file`"))?;
+            .map_err(|_| io::Error::other("failed to run `file`"))?;
 
         let mime = String::from_utf8_lossy(&output.stdout).trim().to_string();
         if !mime.contains("gzip")
@@ -230,8 +249,14 @@ pub fn build(manifest: &Manifest) -> Result<KernelBuildResult, KernelBuildError>
         );
     }
 
-    // --- Calculate tarball hash ---
-    let current_hash = hash_file(&tarball_path)?.to_string();
+    // --- Calculate tarball hash (plus the pinned toolchain and patches, if any) ---
+    let mut current_hash = hash_file(&tarball_path)?.to_string();
+    if let Some(snapshot) = &manifest.arch_snapshot {
+        current_hash.push_str(&format!("+{}", snapshot.tag()));
+    }
+    for patch in &patches {
+        current_hash.push_str(&format!("+{}", hash_file(patch)?));
+    }
 
     let hash_path = out_dir.join("kernel.hash");
     if hash_path.exists() {
@@ -254,7 +279,8 @@ pub fn build(manifest: &Manifest) -> Result<KernelBuildResult, KernelBuildError>
     // --- Build Docker image if needed ---
     let dockerfile_path = kernel_root.join("kernel.Dockerfile");
     fs::write(&dockerfile_path, KERNEL_DOCKERFILE_CONTENT)?;
-    ensure_kernel_builder_image(&dockerfile_path)?;
+    let image_name = kernel_image_name(manifest);
+    ensure_kernel_builder_image(&dockerfile_path, &image_name, manifest.arch_snapshot.as_ref())?;
 
     // --- Canonical paths ---
     let downloads_dir = canonicalize(&downloads_dir)?;
@@ -262,32 +288,62 @@ pub fn build(manifest: &Manifest) -> Result<KernelBuildResult, KernelBuildError>
     let out_dir = canonicalize(&out_dir)?;
     let config_dir = canonicalize(&config_dir)?;
 
-    println!("{}", "🐧 Building kernel inside container".blue().bold());
-    let mut command = Command::new("docker");
-    command
-        .arg("run")
-        .arg("--rm")
-        .arg("-v")
-        .arg(format!("{}:/kernel/downloads:ro", downloads_dir.display()))
-        .arg("-v")
-        .arg(format!("{}:/kernel/src", src_dir.display()))
-        .arg("-v")
-        .arg(format!("{}:/kernel/out", out_dir.display()))
-        .arg("-v")
-        .arg(format!("{}:/kernel/config:ro", config_dir.display()))
-        .arg(KERNEL_IMAGE_NAME)
-        .arg("bash")
-        .arg("-c")
-        .arg(BUILD_SCRIPT);
-
-    let status = prefix_commands::run_command_with_tag(
-        command,
-        "       [ 🐧 kernel-build ] ".blue().to_string(),
-    )?;
-    if !status.success() {
-        return Err(KernelBuildError::DockerRunFailed(status.code()));
+    let run_script = |script: &str, env: &[String]| -> Result<(), KernelBuildError> {
+        let mut command = Command::new("docker");
+        command
+            .arg("run")
+            .arg("--rm")
+            .arg("-v")
+            .arg(format!("{}:/kernel/downloads:ro", downloads_dir.display()))
+            .arg("-v")
+            .arg(format!("{}:/kernel/src", src_dir.display()))
+            .arg("-v")
+            .arg(format!("{}:/kernel/out", out_dir.display()))
+            .arg("-v")
+            .arg(format!("{}:/kernel/config:ro", config_dir.display()))
+            .arg("-e")
+            .arg("KBUILD_BUILD_USER=hyprpacker")
+            .arg("-e")
+            .arg("KBUILD_BUILD_HOST=hyprpacker");
+        if let Some(epoch) = manifest.source_date_epoch() {
+            command.arg("-e").arg(format!("SOURCE_DATE_EPOCH={epoch}"));
+        }
+        for var in env {
+            command.arg("-e").arg(var);
+        }
+        command
+            .arg(&image_name)
+            .arg("bash")
+            .arg("-c")
+            .arg(script);
+
+        let status = prefix_commands::run_command_with_tag(
+            command,
+            "       [ 🐧 kernel-build ] ".blue().to_string(),
+        )?;
+        if !status.success() {
+            return Err(KernelBuildError::DockerRunFailed(status.code()));
+        }
+        Ok(())
+    };
+
+    println!("{}", "󰈸 Unpacking kernel sources inside container".blue().bold());
+    // SAFETY: getuid and getgid can't fail
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    run_script(PREPARE_SCRIPT, &[format!("HOST_UID={uid}"), format!("HOST_GID={gid}")])?;
+
+    // --- Patch with the same applier as the packages, so both accept the same patches ---
+    let tree = locate_tree(&src_dir, &tarball_name)?
+        .ok_or_else(|| KernelBuildError::MissingTree(tarball_name.clone()))?;
+    for patch in &patches {
+        println!("{} {}", "󰷈 Applying".blue().bold(), patch.display());
+        apply_patches(&tree, std::slice::from_ref(patch))?;
     }
 
+    println!("{}", "🐧 Building kernel inside container".blue().bold());
+    let tree_name = tree.file_name().unwrap_or_default().to_string_lossy();
+    run_script(BUILD_SCRIPT, &[format!("KERNEL_TREE=/kernel/src/{tree_name}")])?;
+
     let artifact_path = locate_artifact(&out_dir)?;
     fs::write(&hash_path, &current_hash)?;
     Ok(KernelBuildResult { artifact_path })
@@ -318,8 +374,7 @@ fn normalize_option_key(raw: &str) -> String {
     trimmed
         .trim_start_matches("config_")
         .trim_start_matches("Config_")
-        .replace('-', "_")
-        .replace('.', "_")
+        .replace(['-', '.'], "_")
         .to_uppercase()
 }
 
@@ -333,9 +388,21 @@ fn extract_filename(url: &str) -> Option<String> {
         .map(|s| s.to_string())
 }
 
-fn ensure_kernel_builder_image(dockerfile_path: &Path) -> Result<(), KernelBuildError> {
+/// The kernel builder image is tagged per arch_snapshot so changing the snapshot rebuilds it
+pub fn kernel_image_name(manifest: &Manifest) -> String {
+    match &manifest.arch_snapshot {
+        Some(snapshot) => format!("{KERNEL_IMAGE_REPOSITORY}:{}", snapshot.tag()),
+        None => format!("{KERNEL_IMAGE_REPOSITORY}:latest"),
+    }
+}
+
+fn ensure_kernel_builder_image(
+    dockerfile_path: &Path,
+    image_name: &str,
+    snapshot: Option<&ArchSnapshot>,
+) -> Result<(), KernelBuildError> {
     let inspect_status = Command::new("docker")
-        .args(["image", "inspect", KERNEL_IMAGE_NAME])
+        .args(["image", "inspect", image_name])
         .stdout(Stdio::null())
         .status()?;
     if inspect_status.success() {
@@ -348,6 +415,14 @@ fn ensure_kernel_builder_image(dockerfile_path: &Path) -> Result<(), KernelBuild
         .map(Path::to_path_buf)
         .unwrap_or_else(|| PathBuf::from("."));
 
+    // the Dockerfile always bind-mounts the "mirror" context, point it somewhere harmless
+    // when there's no local mirror
+    let mirror_context = match snapshot {
+        Some(snapshot) => snapshot.local_mirror_path()?,
+        None => None,
+    }
+    .unwrap_or_else(|| build_context.clone());
+
     println!("{}", "󱌢 Building kernel builder Docker image".blue().bold());
     let mut command = Command::new("docker");
     command
@@ -355,8 +430,15 @@ fn ensure_kernel_builder_image(dockerfile_path: &Path) -> Result<(), KernelBuild
         .arg("-f")
         .arg(&dockerfile_path)
         .arg("-t")
-        .arg(KERNEL_IMAGE_NAME)
-        .arg(&build_context);
+        .arg(image_name)
+        .arg("--build-context")
+        .arg(format!("mirror={}", mirror_context.display()));
+    if let Some(snapshot) = snapshot {
+        command
+            .arg("--build-arg")
+            .arg(format!("ARCH_MIRROR={}", snapshot.pacman_server()));
+    }
+    command.arg(&build_context);
     let status = prefix_commands::run_command_with_tag(
         command,
         "       [ 🐧 kernel-image ] ".blue().to_string(),
@@ -378,3 +460,66 @@ fn locate_artifact(out_dir: &Path) -> Result<PathBuf, KernelBuildError> {
     }
     Err(KernelBuildError::MissingArtifact(kernel_path))
 }
+
+/// The tarball `[kernel] url` is downloaded to
+pub(crate) fn tarball_name(url: &str) -> String {
+    extract_filename(url).unwrap_or_else(|| "kernel.tar".to_string())
+}
+
+/// The `linux-*` tree unpacked from `tarball_name`. Older versions stay in `src_dir` after a
+/// `[kernel] url` bump, so a `linux-<version>` tarball only matches its own tree, other
+/// tarball names only when a single tree was unpacked.
+pub(crate) fn locate_tree(src_dir: &Path, tarball_name: &str) -> io::Result<Option<PathBuf>> {
+    if !src_dir.exists() {
+        return Ok(None);
+    }
+    let expected = tarball_name.split(".tar").next().unwrap_or(tarball_name);
+    let mut trees = Vec::new();
+    for entry in fs::read_dir(src_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() && name.starts_with("linux-") {
+            trees.push(name);
+        }
+    }
+    let tree = if expected.starts_with("linux-") {
+        trees.into_iter().find(|name| name == expected)
+    } else if trees.len() == 1 {
+        trees.pop()
+    } else {
+        None
+    };
+    Ok(tree.map(|tree| src_dir.join(tree)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn locates_the_tree_of_the_tarball() {
+        let dir = TempDir::new();
+        let tree = |name: &str| locate_tree(dir.path(), &tarball_name(name)).unwrap();
+        assert_eq!(tree("https://cdn.kernel.org/linux-6.9.tar.xz"), None);
+
+        fs::create_dir(dir.path().join("linux-6.8")).unwrap();
+        assert_eq!(
+            tree("https://cdn.kernel.org/linux-6.8.tar.xz"),
+            Some(dir.path().join("linux-6.8"))
+        );
+        // the url was bumped but the new kernel wasn't built yet
+        assert_eq!(tree("https://cdn.kernel.org/linux-6.9.tar.xz"), None);
+        assert_eq!(
+            tree("https://example.com/archive/v6.8.tar.gz"),
+            Some(dir.path().join("linux-6.8"))
+        );
+
+        fs::create_dir(dir.path().join("linux-6.9")).unwrap();
+        assert_eq!(
+            tree("https://cdn.kernel.org/linux-6.9.tar.xz"),
+            Some(dir.path().join("linux-6.9"))
+        );
+        assert_eq!(tree("https://example.com/archive/v6.9.tar.gz"), None);
+    }
+}
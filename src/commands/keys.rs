@@ -0,0 +1,189 @@
+//! Ed25519 signing keys for releases, stored as PKCS#8 (private) and SPKI (public) PEM
+//! files so `openssl pkey` can read them too
+use std::{
+	io::{self, Write},
+	os::unix::fs::OpenOptionsExt,
+	path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use colored::Colorize;
+use ed25519_dalek::{
+	SigningKey, VerifyingKey,
+	pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey},
+};
+use thiserror::Error;
+
+use crate::fs_utils;
+
+const PRIVATE_KEY_LABEL: &str = "PRIVATE KEY";
+const PUBLIC_KEY_LABEL: &str = "PUBLIC KEY";
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{} already exists, pass --force to overwrite it", .0.display())]
+	AlreadyExists(PathBuf),
+	#[error("failed to gather randomness: {0}")]
+	Random(getrandom::Error),
+	#[error("{} is not an ed25519 {} key in PEM format", .0.display(), .1)]
+	InvalidKey(PathBuf, &'static str),
+}
+
+pub struct GeneratedKeys {
+	pub private_key: PathBuf,
+	pub public_key: PathBuf,
+	pub key_id: String,
+}
+
+/// Short fingerprint of a public key, the first 8 bytes of its sha256 in hex
+pub fn key_id(key: &VerifyingKey) -> String {
+	use sha2::{Digest, Sha256};
+	Sha256::digest(key.as_bytes())[..8]
+		.iter()
+		.map(|b| format!("{b:02x}"))
+		.collect()
+}
+
+fn pem_encode(label: &str, der: &[u8]) -> String {
+	let encoded = BASE64.encode(der);
+	let mut pem = format!("-----BEGIN {label}-----\n");
+	for line in encoded.as_bytes().chunks(64) {
+		pem.push_str(std::str::from_utf8(line).unwrap());
+		pem.push('\n');
+	}
+	pem.push_str(&format!("-----END {label}-----\n"));
+	pem
+}
+
+fn pem_decode(label: &str, pem: &str) -> Option<Vec<u8>> {
+	let body = pem
+		.trim()
+		.strip_prefix(&format!("-----BEGIN {label}-----"))?
+		.strip_suffix(&format!("-----END {label}-----"))?;
+	BASE64
+		.decode(body.split_whitespace().collect::<String>())
+		.ok()
+}
+
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, KeyError> {
+	let pem = std::fs::read_to_string(path)?;
+	pem_decode(PRIVATE_KEY_LABEL, &pem)
+		.and_then(|der| SigningKey::from_pkcs8_der(&der).ok())
+		.ok_or_else(|| KeyError::InvalidKey(path.to_path_buf(), "private"))
+}
+
+/// Reads a public key, or the public half of a private key
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, KeyError> {
+	let pem = std::fs::read_to_string(path)?;
+	if let Some(der) = pem_decode(PUBLIC_KEY_LABEL, &pem) {
+		return VerifyingKey::from_public_key_der(&der)
+			.map_err(|_| KeyError::InvalidKey(path.to_path_buf(), "public"));
+	}
+	read_signing_key(path).map(|key| key.verifying_key())
+}
+
+/// Writes a new key pair to `<path>.key` (readable only by the owner) and `<path>.pub`
+pub fn generate(path: &Path, force: bool) -> Result<GeneratedKeys, KeyError> {
+	let private_key = fs_utils::with_suffix(path, ".key");
+	let public_key = fs_utils::with_suffix(path, ".pub");
+	if !force {
+		for existing in [&private_key, &public_key] {
+			if existing.exists() {
+				return Err(KeyError::AlreadyExists(existing.clone()));
+			}
+		}
+	}
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+
+	let mut seed = [0u8; 32];
+	getrandom::getrandom(&mut seed).map_err(KeyError::Random)?;
+	let signing_key = SigningKey::from_bytes(&seed);
+	let private_der = signing_key
+		.to_pkcs8_der()
+		.expect("ed25519 keys always encode");
+	let public_der = signing_key
+		.verifying_key()
+		.to_public_key_der()
+		.expect("ed25519 keys always encode");
+
+	std::fs::remove_file(&private_key).ok();
+	std::fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(&private_key)?
+		.write_all(pem_encode(PRIVATE_KEY_LABEL, private_der.as_bytes()).as_bytes())?;
+	std::fs::write(
+		&public_key,
+		pem_encode(PUBLIC_KEY_LABEL, public_der.as_bytes()),
+	)?;
+	Ok(GeneratedKeys {
+		private_key,
+		public_key,
+		key_id: key_id(&signing_key.verifying_key()),
+	})
+}
+
+impl GeneratedKeys {
+	pub fn print(&self) {
+		println!(
+			"{} {}",
+			"🔑 Generated signing key".green().bold(),
+			self.key_id.cyan()
+		);
+		println!(
+			"    {} {} {}",
+			"Private key:".green(),
+			self.private_key.display().to_string().bold(),
+			"(keep it secret, it signs releases)".dimmed()
+		);
+		println!(
+			"    {} {}",
+			"Public key: ".green(),
+			self.public_key.display().to_string().bold()
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+
+	#[test]
+	fn both_key_files_verify() {
+		let dir = TempDir::new();
+		let keys = generate(&dir.path().join("keys/release"), false).unwrap();
+		let signing_key = read_signing_key(&keys.private_key).unwrap();
+		for path in [&keys.public_key, &keys.private_key] {
+			let key = read_verifying_key(path).unwrap();
+			assert_eq!(key, signing_key.verifying_key());
+			assert_eq!(key_id(&key), keys.key_id);
+		}
+		assert!(matches!(
+			read_signing_key(&keys.public_key),
+			Err(KeyError::InvalidKey(_, "private"))
+		));
+	}
+
+	#[test]
+	fn generate_keeps_existing_keys() {
+		let dir = TempDir::new();
+		let path = dir.path().join("release");
+		let keys = generate(&path, false).unwrap();
+		assert!(matches!(
+			generate(&path, false),
+			Err(KeyError::AlreadyExists(_))
+		));
+		assert_eq!(
+			read_verifying_key(&keys.public_key).unwrap(),
+			read_verifying_key(&keys.private_key).unwrap()
+		);
+		let regenerated = generate(&path, true).unwrap();
+		assert_ne!(regenerated.key_id, keys.key_id);
+	}
+}
@@ -1,4 +1,7 @@
+pub mod cache;
 pub mod image;
 pub mod initrd;
 pub mod kernel;
+pub mod keys;
+pub mod verify;
 pub mod vm;
@@ -0,0 +1,379 @@
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+
+use crate::{
+	archive_diff::{MemberDiff, diff_packages, diff_trees},
+	binary_cache::built_package_files,
+	cache_root,
+	commands::image::{
+		assemble::{assemble, sysroot_path},
+		packages::BuildRequest,
+	},
+	hash::hash_file,
+	manifest::{Manifest, Package, Source},
+};
+
+/// Where the rebuilds are made and kept, next to the out dirs
+fn work_dir() -> PathBuf {
+	cache_root::out_dir().with_file_name("reproducibility")
+}
+
+/// How many differing members are listed per file before the rest is summarized
+const SHOWN_MEMBERS: usize = 20;
+
+pub struct PackageVerification {
+	name: String,
+	version: String,
+	identical: Vec<String>,
+	/// Package files that differ, with the members that differ inside them
+	differing: Vec<(String, Vec<MemberDiff>)>,
+	only_in_reference: Vec<String>,
+	only_in_rebuild: Vec<String>,
+}
+
+pub struct ImageVerification {
+	image: PathBuf,
+	identical: bool,
+	/// Empty when the sysroots match and only the image itself differs
+	sysroot_diff: Vec<MemberDiff>,
+}
+
+pub struct VerifyResult {
+	packages: Vec<PackageVerification>,
+	image: Option<ImageVerification>,
+	errors: Vec<String>,
+}
+
+impl PackageVerification {
+	fn is_reproducible(&self) -> bool {
+		self.differing.is_empty()
+			&& self.only_in_reference.is_empty()
+			&& self.only_in_rebuild.is_empty()
+	}
+}
+
+fn print_member_diffs(diffs: &[MemberDiff], indent: &str) {
+	for diff in diffs.iter().take(SHOWN_MEMBERS) {
+		match diff {
+			MemberDiff::OnlyInReference(path) => println!(
+				"{indent}{} {} {}",
+				"-".red(),
+				path,
+				"only in reference".dimmed()
+			),
+			MemberDiff::OnlyInRebuild(path) => println!(
+				"{indent}{} {} {}",
+				"+".green(),
+				path,
+				"only in rebuild".dimmed()
+			),
+			MemberDiff::Changed { path, changes } => {
+				println!("{indent}{} {}", "~".yellow(), path);
+				for change in changes {
+					println!("{indent}    {}", change.to_string().dimmed());
+				}
+			}
+		}
+	}
+	if diffs.len() > SHOWN_MEMBERS {
+		println!(
+			"{indent}{}",
+			format!("... and {} more", diffs.len() - SHOWN_MEMBERS).dimmed()
+		);
+	}
+}
+
+impl VerifyResult {
+	pub fn print(&self) {
+		println!();
+		for pkg in &self.packages {
+			if pkg.is_reproducible() {
+				println!(
+					"{} {} {} {}",
+					"✔".green(),
+					pkg.name.bold(),
+					pkg.version.dimmed(),
+					format!("is reproducible ({} identical files)", pkg.identical.len()).green()
+				);
+				continue;
+			}
+			println!(
+				"{} {} {} {}",
+				"✘".red(),
+				pkg.name.bold(),
+				pkg.version.dimmed(),
+				"is not reproducible".red()
+			);
+			for (file, diffs) in &pkg.differing {
+				println!("    {} {}", file.bold(), "differs:".yellow());
+				print_member_diffs(diffs, "        ");
+			}
+			for file in &pkg.only_in_reference {
+				println!(
+					"    {} {}",
+					file.bold(),
+					"was only built by the reference".yellow()
+				);
+			}
+			for file in &pkg.only_in_rebuild {
+				println!(
+					"    {} {}",
+					file.bold(),
+					"was only built by the rebuild".yellow()
+				);
+			}
+		}
+		if let Some(image) = &self.image {
+			let name = image.image.display().to_string();
+			if image.identical {
+				println!(
+					"{} {} {}",
+					"✔".green(),
+					name.bold(),
+					"is reproducible".green()
+				);
+			} else {
+				println!(
+					"{} {} {}",
+					"✘".red(),
+					name.bold(),
+					"is not reproducible".red()
+				);
+				if image.sysroot_diff.is_empty() {
+					println!(
+						"    {}",
+						"the sysroots are identical, the difference comes from the image itself".yellow()
+					);
+				} else {
+					println!("    {}", "sysroot differences:".yellow());
+					print_member_diffs(&image.sysroot_diff, "        ");
+				}
+			}
+		}
+		for error in &self.errors {
+			eprintln!("{}: {}", "ERROR".red().bold(), error);
+		}
+		let reproducible = self.packages.iter().filter(|p| p.is_reproducible()).count();
+		println!(
+			"{} {} {} {} {}",
+			"󰑓".green(),
+			reproducible.to_string().cyan(),
+			"of".green(),
+			self.packages.len().to_string().cyan(),
+			"rebuilt packages are reproducible".green()
+		);
+		println!(
+			"    {} {}",
+			"Rebuilt files:".green(),
+			work_dir().join("rebuild").display().to_string().bold()
+		);
+	}
+	pub fn exit_if_failure(&self) {
+		if !self.errors.is_empty()
+			|| self.packages.iter().any(|p| !p.is_reproducible())
+			|| self.image.as_ref().is_some_and(|i| !i.identical)
+		{
+			std::process::exit(1);
+		}
+	}
+}
+
+/// Compares the package files of two out dirs, by their path relative to the out dir
+fn compare_packages(
+	pkg: &Package,
+	reference_dir: &Path,
+	rebuild_dir: &Path,
+) -> std::io::Result<PackageVerification> {
+	let relative = |dir: &Path| -> std::io::Result<Vec<String>> {
+		Ok(
+			built_package_files(dir)?
+				.into_iter()
+				.map(|p| p.strip_prefix(dir).unwrap().to_string_lossy().to_string())
+				.collect(),
+		)
+	};
+	let reference = relative(reference_dir)?;
+	let rebuild = relative(rebuild_dir)?;
+	let mut verification = PackageVerification {
+		name: pkg.name.clone(),
+		version: pkg.version.clone(),
+		identical: vec![],
+		differing: vec![],
+		only_in_reference: vec![],
+		only_in_rebuild: rebuild
+			.iter()
+			.filter(|f| !reference.contains(f))
+			.cloned()
+			.collect(),
+	};
+	for file in reference {
+		if !rebuild.contains(&file) {
+			verification.only_in_reference.push(file);
+			continue;
+		}
+		let (a, b) = (reference_dir.join(&file), rebuild_dir.join(&file));
+		if hash_file(&a)? == hash_file(&b)? {
+			verification.identical.push(file);
+		} else {
+			verification.differing.push((file, diff_packages(&a, &b)?));
+		}
+	}
+	Ok(verification)
+}
+
+/// Assembles the image again from the rebuilt packages and compares it to the reference image
+fn verify_image(
+	manifest: &Manifest,
+	rebuild_manifest: &Manifest,
+	reference_image: &Path,
+) -> Result<ImageVerification, String> {
+	let rebuild_image =
+		assemble(rebuild_manifest).map_err(|e| format!("failed to assemble the rebuilt image: {e}"))?;
+	let identical = hash_file(reference_image)
+		.and_then(|a| Ok(a == hash_file(&rebuild_image)?))
+		.map_err(|e| format!("failed to hash the images: {e}"))?;
+	let sysroot_diff = if identical {
+		vec![]
+	} else {
+		diff_trees(&sysroot_path(manifest), &sysroot_path(rebuild_manifest))
+			.map_err(|e| format!("failed to compare the sysroots: {e}"))?
+	};
+	Ok(ImageVerification {
+		image: reference_image.to_path_buf(),
+		identical,
+		sysroot_diff,
+	})
+}
+
+/// Rebuilds the selected packages (and with no selection, the image) into fresh out dirs
+/// next to the existing builds and compares them byte for byte, leaving the existing builds
+/// untouched.
+/// The existing builds must be up-to-date, see [`crate::commands::image::packages::build`].
+pub fn verify_reproducible(manifest: &Manifest, names: &[String]) -> VerifyResult {
+	let mut result = VerifyResult {
+		packages: vec![],
+		image: None,
+		errors: vec![],
+	};
+	let request = BuildRequest {
+		packages: names.to_vec(),
+		..Default::default()
+	};
+	let (selected, build_order) = match (request.selected(manifest), manifest.build_order()) {
+		(Ok(selected), Ok(order)) => (selected, order),
+		(Err(e), _) => {
+			result.errors.push(e);
+			return result;
+		}
+		(_, Err(cycle)) => {
+			result.errors.push(cycle.to_string());
+			return result;
+		}
+	};
+	// binary packages are downloaded, not built
+	let packages = build_order
+		.into_iter()
+		.filter(|p| selected.iter().any(|s| s.name == p.name))
+		.filter(|p| !matches!(p.source, Source::Binary { .. }))
+		.collect::<Vec<_>>();
+
+	// only rebuilds live here, the existing builds are never moved
+	let rebuild_dir = work_dir().join("rebuild");
+	if let Err(e) = std::fs::remove_dir_all(&rebuild_dir)
+		.or_else(|e| match e.kind() {
+			std::io::ErrorKind::NotFound => Ok(()),
+			_ => Err(e),
+		})
+		.and_then(|_| std::fs::create_dir_all(rebuild_dir.join("out")))
+	{
+		result
+			.errors
+			.push(format!("failed to create {}: {e}", rebuild_dir.display()));
+		return result;
+	}
+
+	let reference_image = if names.is_empty() {
+		println!("{}", "  Assembling the reference image".blue().bold());
+		match assemble(manifest) {
+			Ok(image) => Some(image),
+			Err(e) => {
+				result
+					.errors
+					.push(format!("failed to assemble the reference image: {e}"));
+				return result;
+			}
+		}
+	} else {
+		None
+	};
+
+	// a cache hit would just hand back the reference build
+	let mut uncached = manifest.clone();
+	uncached.binary_cache = None;
+	uncached.rebuild_dir = Some(rebuild_dir.clone());
+	uncached.image.output_dir = rebuild_dir.clone();
+
+	// the packages that aren't rebuilt are used as they are, by the rebuilds that depend
+	// on them and by the image
+	for pkg in &manifest.packages {
+		if packages.iter().any(|p| p.name == pkg.name) {
+			continue;
+		}
+		let out_dir = pkg.get_out_dir(manifest);
+		if let Err(e) = std::path::absolute(&out_dir)
+			.and_then(|out_dir| std::os::unix::fs::symlink(out_dir, pkg.get_out_dir(&uncached)))
+		{
+			result.errors.push(format!(
+				"failed to link {} into the rebuild: {e}",
+				out_dir.display()
+			));
+			return result;
+		}
+	}
+
+	let mut failed: Vec<String> = Vec::new();
+	for pkg in &packages {
+		if let Some(dep) = pkg.build_deps.iter().find(|d| failed.contains(d)) {
+			result.errors.push(format!(
+				"{} was not rebuilt because {dep} failed to rebuild",
+				pkg.name
+			));
+			failed.push(pkg.name.clone());
+			continue;
+		}
+		let reference_dir = pkg.get_out_dir(manifest);
+		println!(
+			"    {} {} {}",
+			"󰑓  Rebuilding".green().bold(),
+			pkg.name,
+			pkg.version.dimmed()
+		);
+		// the out dirs are keyed by the source date epoch, so the reference was built with
+		// the same one
+		if let Err(e) = pkg.build(&uncached, true) {
+			result
+				.errors
+				.push(format!("failed to rebuild {}: {e}", pkg.name));
+			failed.push(pkg.name.clone());
+			continue;
+		}
+		match compare_packages(pkg, &reference_dir, &pkg.get_out_dir(&uncached)) {
+			Ok(verification) => result.packages.push(verification),
+			Err(e) => result
+				.errors
+				.push(format!("failed to compare the builds of {}: {e}", pkg.name)),
+		}
+	}
+
+	if let Some(reference_image) = reference_image
+		&& failed.is_empty()
+	{
+		println!("{}", "  Assembling the rebuilt image".blue().bold());
+		match verify_image(manifest, &uncached, &reference_image) {
+			Ok(verification) => result.image = Some(verification),
+			Err(e) => result.errors.push(e),
+		}
+	}
+	result
+}
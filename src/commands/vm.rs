@@ -17,6 +17,8 @@ use std::{
 use colored::Colorize;
 use thiserror::Error;
 
+use crate::verity;
+
 // -----------------------------
 // Opções e erros
 // -----------------------------
@@ -31,7 +33,8 @@ pub struct RunCommandOptions {
 	pub ovmf_vars_path: PathBuf,
 	/// Kernel (vmlinuz)
 	pub kernel_path: PathBuf,
-	/// Caminho para image squashfs gerada pelo assemble
+	/// Caminho para a imagem gerada pelo assemble, copiada sem alterações para a partição do sistema
+	/// como `system.<extensão>` (`system.squashfs`, `system.erofs` ou `system.ext4`)
 	pub image_path: PathBuf,
 	/// Initramfs (retornado por initrd::build_initrd)
 	pub initrd_path: PathBuf,
@@ -122,6 +125,34 @@ pub fn run_privileged_script(commands: &[&str]) -> io::Result<ExitStatus> {
 
 pub fn run_command(opts: RunCommandOptions) -> Result<(), RunCommandError> {
 	let system_disk = Path::new("build/vm/system.qcow2");
+	let system_image = format!(
+		"system.{}",
+		opts
+			.image_path
+			.extension()
+			.and_then(|e| e.to_str())
+			.unwrap_or("squashfs")
+	);
+	// o initrd abre a imagem com dm-verity quando o assemble gerou a hash tree
+	let verity = verity::read_info(&opts.image_path);
+	let mut verity_cmdline = String::new();
+	let mut copy_verity_hash = String::new();
+	if let Some(info) = &verity {
+		verity_cmdline = format!(" roothash={} systemd.verity=1", info.root_hash);
+		match &info.hash_file {
+			Some(hash_file) => {
+				verity_cmdline.push_str(" system_image_verity=system.verity");
+				copy_verity_hash = format!(
+					" && cp {} /mnt/hyprside-vm/system.verity",
+					hash_file.display()
+				);
+			}
+			None => verity_cmdline.push_str(&format!(
+				" systemd.verity_root_options=hash-offset={}",
+				info.hash_offset
+			)),
+		}
+	}
 	let user_disk = opts.user_disk_path.as_path();
 
 	// valida artefactos obrigatórios
@@ -178,18 +209,20 @@ pub fn run_command(opts: RunCommandOptions) -> Result<(), RunCommandError> {
 			),
 			"SYSTEM_PARTITION=$(blkid -s PARTUUID -o value /dev/nbd0p2)",
 			"USER_PARTITION=$(blkid -s PARTUUID -o value /dev/nbd1p1 || true)",
-			"cat > /mnt/hyprside-vm/limine.conf <<EOF
+			&format!(
+				"cat > /mnt/hyprside-vm/limine.conf <<EOF
 timeout: 0
 /Hyprside
     protocol: linux
     path: boot():/vmlinuz
-    cmdline: console=ttyS0 system_partition=UUID=$SYSTEM_PARTITION user_partition=UUID=$USER_PARTITION
+    cmdline: console=ttyS0 system_partition=UUID=$SYSTEM_PARTITION user_partition=UUID=$USER_PARTITION system_image={system_image}{verity_cmdline}
     module_path: boot():/initramfs.img
-EOF",
+EOF"
+			),
 			"umount /mnt/hyprside-vm || true",
 			"mount /dev/nbd0p2 /mnt/hyprside-vm",
 			&format!(
-				"cp {} /mnt/hyprside-vm/system.squashfs",
+				"cp {} /mnt/hyprside-vm/{system_image}{copy_verity_hash}",
 				opts.image_path.display()
 			),
 			"umount /mnt/hyprside-vm || true",
@@ -294,7 +327,7 @@ pub fn reset_vm() -> Result<PathBuf, RunCommandError> {
 		),
 		&format!("chmod 777 {}", user_disk.display()),
 		"chmod 777 build/vm -R",
-		&format!("qemu-nbd --disconnect /dev/nbd1"),
+		"qemu-nbd --disconnect /dev/nbd1",
 		&format!("qemu-nbd --connect /dev/nbd1 {}", user_disk.display()),
 		"parted -s /dev/nbd1 mklabel gpt",
 		"parted -s /dev/nbd1 mkpart USER btrfs 1MiB 100%",
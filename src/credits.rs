@@ -1,63 +1,62 @@
-use crate::manifest::Package;
-use serde::Serialize;
+use crate::manifest::{Manifest, Package};
+use crate::pkginfo::PkgInfo;
+use serde::{Deserialize, Serialize};
 use std::fs;
 
-#[derive(Serialize)]
+/// An entry of the image's `/etc/credits.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageCredit {
 	pub name: String,
 	pub author: String,
+	/// `[epoch:]pkgver-pkgrel` of the installed package, missing in images from before
+	/// it was recorded
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub version: Option<String>,
 }
 
-fn parse_pkginfo(contents: &str) -> Option<(String, String)> {
-	let mut pkgname: Option<String> = None;
-	let mut packager: Option<String> = None;
+fn package_credit(pkg: &Package, manifest: &Manifest) -> Option<PackageCredit> {
+	let pkginfo_path = pkg.get_out_unpacked_dir(manifest).join(".PKGINFO");
+	let pkginfo = fs::read_to_string(pkginfo_path)
+		.ok()
+		.and_then(|contents| PkgInfo::parse(&contents));
+	let version = Some(
+		pkginfo
+			.as_ref()
+			.map_or_else(|| pkg.version.clone(), |info| info.pkgver.clone()),
+	);
 
-	for line in contents.lines() {
-		if line.starts_with("pkgname = ") {
-			pkgname = Some(
-				line["pkgname = ".len()..]
-					.trim()
-					.trim_matches('"')
-					.to_string(),
-			);
-		}
-		if line.starts_with("packager = ") {
-			packager = Some(
-				line["packager = ".len()..]
-					.trim()
-					.trim_matches('"')
-					.to_string(),
-			);
-		}
-	}
-
-	match (pkgname, packager) {
-		(Some(p), Some(a)) => Some((p, a)),
-		_ => None,
-	}
-}
-
-fn package_credit(pkg: &Package) -> Option<PackageCredit> {
 	if let Some(author) = &pkg.author {
 		return Some(PackageCredit {
 			name: pkg.name.clone(),
 			author: author.clone(),
+			version,
 		});
 	}
 
-	let pkginfo_path = pkg.get_out_unpacked_dir().join(".PKGINFO");
-	if let Ok(contents) = fs::read_to_string(pkginfo_path) {
-		if let Some((name, author)) = parse_pkginfo(&contents) {
-			return Some(PackageCredit { name, author });
-		}
+	if let Some(PkgInfo {
+		pkgname,
+		packager: Some(author),
+		..
+	}) = pkginfo
+	{
+		return Some(PackageCredit {
+			name: pkgname,
+			author,
+			version,
+		});
 	}
 
 	Some(PackageCredit {
 		name: pkg.name.clone(),
 		author: "Unknown".into(),
+		version,
 	})
 }
 
-pub fn generate_credits(packages: &[Package]) -> Vec<PackageCredit> {
-	packages.iter().filter_map(package_credit).collect()
+pub fn generate_credits(manifest: &Manifest) -> Vec<PackageCredit> {
+	manifest
+		.packages
+		.iter()
+		.filter_map(|pkg| package_credit(pkg, manifest))
+		.collect()
 }
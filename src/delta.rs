@@ -0,0 +1,502 @@
+//! Binary deltas between two images. Both are cut into content defined chunks with a gear
+//! rolling hash (the casync/desync approach, so data shifted by a changed file still lines
+//! up), chunks the old image has are copied from it and everything else is stored zstd
+//! compressed. Memory use only depends on the number of chunks, not the image size.
+//!
+//! A delta file is `HSDELTA1`, a little endian u32 length and a JSON [`DeltaHeader`],
+//! followed by a zstd stream of operations: `0` copy (u64 offset, u64 length into the old
+//! image), `1` insert (u64 length and the bytes) and `2` end.
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+	fs_utils,
+	hash::{Sha256Hash, hash_file},
+};
+
+const MAGIC: &[u8; 8] = b"HSDELTA1";
+/// The JSON header only holds hashes, sizes and versions, a longer one isn't a delta we wrote
+const MAX_HEADER: usize = 64 << 10;
+const MIN_CHUNK: usize = 16 << 10;
+const MAX_CHUNK: usize = 256 << 10;
+/// 16 bits set, for 64K chunks on average past the minimum. The high bits are used since
+/// they depend on the last 64 bytes, the low ones only on the last few.
+const CUT_MASK: u64 = 0xffff << 48;
+const READ_SIZE: usize = 4 << 20;
+/// Inserts are flushed once they get this big, so they don't have to fit in memory
+const MAX_INSERT: usize = 4 << 20;
+
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+const OP_END: u8 = 2;
+
+/// Random values for the gear hash, fixed forever since they decide where chunks are cut
+const GEAR: [u64; 256] = {
+	let mut table = [0u64; 256];
+	// splitmix64
+	let mut state = 0x6879_7072_7369_6465u64;
+	let mut i = 0;
+	while i < 256 {
+		state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		table[i] = z ^ (z >> 31);
+		i += 1;
+	}
+	table
+};
+
+#[derive(Debug, Error)]
+pub enum DeltaError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{} is not an image delta", .0.display())]
+	NotADelta(PathBuf),
+	#[error("{} is corrupted", .0.display())]
+	Corrupt(PathBuf),
+	#[error("{what} doesn't match the delta: expected sha256 {expected}, found {actual}")]
+	Mismatch {
+		what: String,
+		expected: Sha256Hash,
+		actual: Sha256Hash,
+	},
+}
+
+/// An image a delta starts from or produces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaImage {
+	pub sha256: Sha256Hash,
+	pub size: u64,
+	/// From the image's release manifest, when it was signed
+	pub version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaHeader {
+	pub old: DeltaImage,
+	pub new: DeltaImage,
+}
+
+pub struct DeltaStats {
+	pub header: DeltaHeader,
+	/// Bytes of the new image copied from the old one
+	pub copied: u64,
+	/// Bytes of the new image stored in the delta, before compression
+	pub inserted: u64,
+}
+
+/// How long the chunk at the start of `data` is. Only looks at the first [`MAX_CHUNK`]
+/// bytes, so where chunks are cut doesn't depend on how the input is read.
+fn chunk_len(data: &[u8]) -> usize {
+	if data.len() <= MIN_CHUNK {
+		return data.len();
+	}
+	let end = data.len().min(MAX_CHUNK);
+	let mut hash = 0u64;
+	for (i, &byte) in data[..end].iter().enumerate().skip(MIN_CHUNK - 64) {
+		hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+		if i >= MIN_CHUNK && hash & CUT_MASK == 0 {
+			return i + 1;
+		}
+	}
+	end
+}
+
+/// Calls `f` with the offset and contents of every chunk of `reader`
+fn for_each_chunk(
+	mut reader: impl Read,
+	mut f: impl FnMut(u64, &[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+	let mut buf = vec![0u8; READ_SIZE];
+	let mut filled = 0;
+	let mut offset = 0u64;
+	loop {
+		let mut eof = false;
+		while filled < buf.len() {
+			match reader.read(&mut buf[filled..])? {
+				0 => {
+					eof = true;
+					break;
+				}
+				n => filled += n,
+			}
+		}
+		let mut start = 0;
+		while filled - start >= MAX_CHUNK || (eof && start < filled) {
+			let len = chunk_len(&buf[start..filled]);
+			f(offset, &buf[start..start + len])?;
+			offset += len as u64;
+			start += len;
+		}
+		if eof {
+			return Ok(());
+		}
+		buf.copy_within(start..filled, 0);
+		filled -= start;
+	}
+}
+
+fn chunk_hash(chunk: &[u8]) -> [u8; 32] {
+	Sha256::digest(chunk).into()
+}
+
+fn sha256_hash(hasher: Sha256) -> Sha256Hash {
+	format!("{:X}", hasher.finalize()).into()
+}
+
+/// Buffers the operations of a delta, merging adjacent copies and inserts
+struct OpWriter<W: Write> {
+	out: W,
+	copy: Option<(u64, u64)>,
+	insert: Vec<u8>,
+}
+
+impl<W: Write> OpWriter<W> {
+	fn copy(&mut self, offset: u64, len: u64) -> io::Result<()> {
+		self.flush_insert()?;
+		match &mut self.copy {
+			Some((start, copy_len)) if *start + *copy_len == offset => *copy_len += len,
+			_ => {
+				self.flush_copy()?;
+				self.copy = Some((offset, len));
+			}
+		}
+		Ok(())
+	}
+
+	fn insert(&mut self, data: &[u8]) -> io::Result<()> {
+		self.flush_copy()?;
+		self.insert.extend_from_slice(data);
+		if self.insert.len() >= MAX_INSERT {
+			self.flush_insert()?;
+		}
+		Ok(())
+	}
+
+	fn flush_copy(&mut self) -> io::Result<()> {
+		if let Some((offset, len)) = self.copy.take() {
+			self.out.write_all(&[OP_COPY])?;
+			self.out.write_all(&offset.to_le_bytes())?;
+			self.out.write_all(&len.to_le_bytes())?;
+		}
+		Ok(())
+	}
+
+	fn flush_insert(&mut self) -> io::Result<()> {
+		if !self.insert.is_empty() {
+			self.out.write_all(&[OP_INSERT])?;
+			self
+				.out
+				.write_all(&(self.insert.len() as u64).to_le_bytes())?;
+			self.out.write_all(&self.insert)?;
+			self.insert.clear();
+		}
+		Ok(())
+	}
+
+	fn finish(mut self) -> io::Result<W> {
+		self.flush_copy()?;
+		self.flush_insert()?;
+		self.out.write_all(&[OP_END])?;
+		Ok(self.out)
+	}
+}
+
+/// Writes the delta turning `old` into `new` to `output`. The versions are only recorded
+/// in the header.
+pub fn generate(
+	old: &Path,
+	new: &Path,
+	output: &Path,
+	old_version: Option<String>,
+	new_version: Option<String>,
+) -> Result<DeltaStats, DeltaError> {
+	let mut chunks = HashMap::new();
+	let mut old_hasher = Sha256::new();
+	let mut old_size = 0;
+	for_each_chunk(File::open(old)?, |offset, chunk| {
+		old_hasher.update(chunk);
+		old_size += chunk.len() as u64;
+		chunks
+			.entry(chunk_hash(chunk))
+			.or_insert((offset, chunk.len() as u64));
+		Ok(())
+	})?;
+	let header = DeltaHeader {
+		old: DeltaImage {
+			sha256: sha256_hash(old_hasher),
+			size: old_size,
+			version: old_version,
+		},
+		new: DeltaImage {
+			sha256: hash_file(new)?,
+			size: std::fs::metadata(new)?.len(),
+			version: new_version,
+		},
+	};
+
+	let partial = fs_utils::with_suffix(output, ".partial");
+	let mut file = BufWriter::new(File::create(&partial)?);
+	let header_json = serde_json::to_vec(&header).unwrap();
+	file.write_all(MAGIC)?;
+	file.write_all(&(header_json.len() as u32).to_le_bytes())?;
+	file.write_all(&header_json)?;
+	let mut ops = OpWriter {
+		out: zstd::stream::write::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+		copy: None,
+		insert: Vec::new(),
+	};
+	let (mut copied, mut inserted) = (0, 0);
+	for_each_chunk(File::open(new)?, |_, chunk| {
+		match chunks.get(&chunk_hash(chunk)) {
+			Some(&(offset, len)) => {
+				copied += len;
+				ops.copy(offset, len)
+			}
+			None => {
+				inserted += chunk.len() as u64;
+				ops.insert(chunk)
+			}
+		}
+	})?;
+	ops
+		.finish()?
+		.finish()?
+		.into_inner()
+		.map_err(|e| e.into_error())?
+		.sync_all()?;
+	std::fs::rename(partial, output)?;
+	Ok(DeltaStats {
+		header,
+		copied,
+		inserted,
+	})
+}
+
+/// Reads the header of a delta, leaving `file` at the start of its operations
+fn read_header_from(file: &mut File, path: &Path) -> Result<DeltaHeader, DeltaError> {
+	let not_a_delta = || DeltaError::NotADelta(path.to_path_buf());
+	let mut magic = [0u8; 8];
+	let mut len = [0u8; 4];
+	file
+		.read_exact(&mut magic)
+		.and_then(|_| file.read_exact(&mut len))
+		.map_err(|_| not_a_delta())?;
+	if &magic != MAGIC {
+		return Err(not_a_delta());
+	}
+	let len = u32::from_le_bytes(len) as usize;
+	if len > MAX_HEADER {
+		return Err(not_a_delta());
+	}
+	let mut header = vec![0u8; len];
+	file.read_exact(&mut header).map_err(|_| not_a_delta())?;
+	serde_json::from_slice(&header).map_err(|_| not_a_delta())
+}
+
+pub fn read_header(path: &Path) -> Result<DeltaHeader, DeltaError> {
+	read_header_from(&mut File::open(path)?, path)
+}
+
+/// Hashes what's written through it
+struct HashingWriter<W: Write> {
+	out: W,
+	hasher: Sha256,
+	written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let n = self.out.write(buf)?;
+		self.hasher.update(&buf[..n]);
+		self.written += n as u64;
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.out.flush()
+	}
+}
+
+/// Rebuilds the new image of `delta` from `old` into `output`, checking both against the
+/// hashes in the header. `output` is only created once it's been checked.
+pub fn apply(old: &Path, delta: &Path, output: &Path) -> Result<DeltaHeader, DeltaError> {
+	let mut delta_file = File::open(delta)?;
+	let header = read_header_from(&mut delta_file, delta)?;
+	let old_sha256 = hash_file(old)?;
+	if old_sha256 != header.old.sha256 {
+		return Err(DeltaError::Mismatch {
+			what: format!("old image {}", old.display()),
+			expected: header.old.sha256,
+			actual: old_sha256,
+		});
+	}
+
+	let corrupt = || DeltaError::Corrupt(delta.to_path_buf());
+	let mut ops = zstd::stream::read::Decoder::new(delta_file)?;
+	let mut old_file = File::open(old)?;
+	let partial = fs_utils::with_suffix(output, ".partial");
+	let mut out = HashingWriter {
+		out: BufWriter::new(File::create(&partial)?),
+		hasher: Sha256::new(),
+		written: 0,
+	};
+	let result = (|| {
+		let mut word = [0u8; 8];
+		let mut read_u64 = |ops: &mut dyn Read| -> Result<u64, DeltaError> {
+			ops.read_exact(&mut word).map_err(|_| corrupt())?;
+			Ok(u64::from_le_bytes(word))
+		};
+		loop {
+			let mut op = [0u8];
+			ops.read_exact(&mut op).map_err(|_| corrupt())?;
+			match op[0] {
+				OP_COPY => {
+					let offset = read_u64(&mut ops)?;
+					let len = read_u64(&mut ops)?;
+					old_file.seek(SeekFrom::Start(offset))?;
+					if io::copy(&mut (&old_file).take(len), &mut out)? != len {
+						return Err(corrupt());
+					}
+				}
+				OP_INSERT => {
+					let len = read_u64(&mut ops)?;
+					if io::copy(&mut (&mut ops).take(len), &mut out)? != len {
+						return Err(corrupt());
+					}
+				}
+				OP_END => break,
+				_ => return Err(corrupt()),
+			}
+		}
+		out.flush()?;
+		let actual = sha256_hash(out.hasher.clone());
+		if out.written != header.new.size || actual != header.new.sha256 {
+			return Err(DeltaError::Mismatch {
+				what: format!("result {}", output.display()),
+				expected: header.new.sha256.clone(),
+				actual,
+			});
+		}
+		Ok(())
+	})();
+	if let Err(e) = result {
+		std::fs::remove_file(&partial).ok();
+		return Err(e);
+	}
+	std::fs::rename(partial, output)?;
+	Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{TempDir, noise};
+
+	/// Generates the delta from `old` to `new` and applies it back
+	fn round_trip(dir: &TempDir, old: &[u8], new: &[u8]) -> DeltaStats {
+		let (old_path, new_path) = (dir.path().join("old"), dir.path().join("new"));
+		let (delta, output) = (dir.path().join("delta"), dir.path().join("output"));
+		std::fs::write(&old_path, old).unwrap();
+		std::fs::write(&new_path, new).unwrap();
+		let stats = generate(
+			&old_path,
+			&new_path,
+			&delta,
+			Some("1.0".to_string()),
+			Some("1.1".to_string()),
+		)
+		.unwrap();
+		let header = apply(&old_path, &delta, &output).unwrap();
+		assert_eq!(std::fs::read(&output).unwrap(), new);
+		assert_eq!(header.new.size, new.len() as u64);
+		assert_eq!(header.old.version.as_deref(), Some("1.0"));
+		assert_eq!(stats.copied + stats.inserted, new.len() as u64);
+		stats
+	}
+
+	#[test]
+	fn shifted_data_is_copied() {
+		let dir = TempDir::new();
+		let old = noise(4 << 20, 1);
+		// bytes inserted near the start shift everything after them, a region in the
+		// middle changes and the end grows
+		let mut new = old[..100_000].to_vec();
+		new.extend(noise(5000, 2));
+		new.extend_from_slice(&old[100_000..2 << 20]);
+		new.extend(noise(300_000, 3));
+		new.extend_from_slice(&old[(2 << 20) + 300_000..]);
+		new.extend(noise(70_000, 4));
+		let stats = round_trip(&dir, &old, &new);
+		assert!(
+			stats.copied > new.len() as u64 * 3 / 4,
+			"{} copied",
+			stats.copied
+		);
+		// only the chunks around the changes are stored
+		assert!(stats.inserted < 2 << 20, "{} inserted", stats.inserted);
+	}
+
+	#[test]
+	fn edge_cases() {
+		let dir = TempDir::new();
+		let old = noise(1 << 20, 5);
+		let stats = round_trip(&dir, &old, &old);
+		assert_eq!(stats.inserted, 0);
+		round_trip(&dir, &old, b"");
+		round_trip(&dir, b"", &old);
+		round_trip(&dir, b"", b"");
+		// smaller than a chunk
+		round_trip(&dir, b"old", b"new");
+	}
+
+	#[test]
+	fn rejects_the_wrong_old_image() {
+		let dir = TempDir::new();
+		let old = noise(1 << 20, 6);
+		let mut new = old.clone();
+		new[1000] ^= 1;
+		round_trip(&dir, &old, &new);
+		let other = dir.path().join("other");
+		std::fs::write(&other, noise(1 << 20, 7)).unwrap();
+		let output = dir.path().join("from-other");
+		assert!(matches!(
+			apply(&other, &dir.path().join("delta"), &output),
+			Err(DeltaError::Mismatch { .. })
+		));
+		assert!(!output.exists());
+	}
+
+	#[test]
+	fn rejects_corrupt_deltas() {
+		let dir = TempDir::new();
+		let old = noise(1 << 20, 8);
+		let new = noise(1 << 20, 9);
+		round_trip(&dir, &old, &new);
+		let (old_path, delta) = (dir.path().join("old"), dir.path().join("delta"));
+		let output = dir.path().join("from-corrupt");
+
+		assert!(matches!(
+			read_header(&old_path),
+			Err(DeltaError::NotADelta(_))
+		));
+		// a header length that would allocate 4 GiB
+		let huge = dir.path().join("huge");
+		std::fs::write(&huge, [&MAGIC[..], &u32::MAX.to_le_bytes()].concat()).unwrap();
+		assert!(matches!(read_header(&huge), Err(DeltaError::NotADelta(_))));
+		// cut short in the middle of the operations
+		let bytes = std::fs::read(&delta).unwrap();
+		std::fs::write(&delta, &bytes[..bytes.len() / 2]).unwrap();
+		assert!(apply(&old_path, &delta, &output).is_err());
+		assert!(!output.exists());
+	}
+}
@@ -1,4 +1,8 @@
-use std::{fs::DirEntry, path::Path, time::SystemTime};
+use std::{
+	fs::DirEntry,
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
 
 pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Result<()> {
 	copy_dir_all_with_filter(src, dst, |_| true)
@@ -9,8 +13,10 @@ pub fn copy_dir_all_with_filter(
 	filter: impl Fn(&DirEntry) -> bool,
 ) -> std::io::Result<()> {
 	std::fs::create_dir_all(&dst)?;
-	for entry in std::fs::read_dir(src)? {
-		let entry = entry?;
+	// sorted, so the copies are created in the same order every time
+	let mut entries = std::fs::read_dir(src)?.collect::<Result<Vec<_>, _>>()?;
+	entries.sort_by_key(|e| e.file_name());
+	for entry in entries {
 		if !filter(&entry) {
 			continue;
 		}
@@ -24,6 +30,19 @@ pub fn copy_dir_all_with_filter(
 	Ok(())
 }
 
+/// Hardlinks `src` to `dst`, or copies it when that isn't possible (e.g. across
+/// filesystems). Symlinks are recreated, not followed.
+pub fn link_or_copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+	if std::fs::hard_link(src, dst).is_ok() {
+		return Ok(());
+	}
+	if std::fs::symlink_metadata(src)?.is_symlink() {
+		std::os::unix::fs::symlink(std::fs::read_link(src)?, dst)
+	} else {
+		std::fs::copy(src, dst).map(|_| ())
+	}
+}
+
 pub fn has_file_newer_than(dir: &Path, timestamp: SystemTime) -> std::io::Result<bool> {
 	if !dir.exists() {
 		return Ok(false);
@@ -38,12 +57,43 @@ pub fn has_file_newer_than(dir: &Path, timestamp: SystemTime) -> std::io::Result
 			if has_file_newer_than(&path, timestamp)? {
 				return Ok(true);
 			}
-		} else if let Ok(modified) = metadata.modified() {
-			if modified > timestamp {
-				return Ok(true);
-			}
+		} else if let Ok(modified) = metadata.modified()
+			&& modified > timestamp
+		{
+			return Ok(true);
 		}
 	}
 
 	Ok(false)
 }
+
+/// Feeds the relative path and contents of every file under `dir` into `hasher`,
+/// in a stable order. `.git` directories are skipped.
+pub fn hash_dir_contents(dir: &Path, hasher: &mut impl std::hash::Hasher) -> std::io::Result<()> {
+	use std::hash::Hash;
+	fn visit(root: &Path, dir: &Path, hasher: &mut impl std::hash::Hasher) -> std::io::Result<()> {
+		let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+		entries.sort_by_key(|e| e.file_name());
+		for entry in entries {
+			if entry.file_name() == ".git" {
+				continue;
+			}
+			let path = entry.path();
+			if entry.file_type()?.is_dir() {
+				visit(root, &path, hasher)?;
+			} else {
+				path.strip_prefix(root).unwrap_or(&path).hash(hasher);
+				std::fs::read(&path)?.hash(hasher);
+			}
+		}
+		Ok(())
+	}
+	visit(dir, dir, hasher)
+}
+
+/// `path` with `suffix` appended to its file name, e.g. `image.squashfs` → `image.squashfs.sig`
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut name = path.as_os_str().to_owned();
+	name.push(suffix);
+	PathBuf::from(name)
+}
@@ -12,6 +12,30 @@ pub fn hash_file(path: impl Into<PathBuf>) -> io::Result<Sha256Hash> {
 	let hash_bytes = hasher.finalize();
 	Ok(format!("{:X}", hash_bytes).into())
 }
+/// A [`std::hash::Hasher`] backed by SHA-256, for keys that have to match between machines
+/// (unlike `DefaultHasher`, whose output may change between Rust releases)
+#[derive(Default)]
+pub struct StableHasher(Sha256);
+
+impl StableHasher {
+	pub fn new() -> Self {
+		StableHasher(Sha256::new())
+	}
+	pub fn finalize(self) -> Sha256Hash {
+		format!("{:X}", self.0.finalize()).into()
+	}
+}
+
+impl std::hash::Hasher for StableHasher {
+	fn write(&mut self, bytes: &[u8]) {
+		self.0.update(bytes);
+	}
+	fn finish(&self) -> u64 {
+		let digest = self.0.clone().finalize();
+		u64::from_be_bytes(digest[..8].try_into().unwrap())
+	}
+}
+
 pub fn default_hash<T: From<String>>() -> T {
 	"A".repeat(64).into()
 }
@@ -19,7 +43,7 @@ pub fn default_hash<T: From<String>>() -> T {
 use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt;
 
-#[derive(Debug, Clone, Hash, Eq, Serialize)]
+#[derive(Debug, Clone, Eq, Serialize)]
 pub struct Sha256Hash(String);
 
 impl PartialEq for Sha256Hash {
@@ -28,6 +52,12 @@ impl PartialEq for Sha256Hash {
 	}
 }
 
+impl std::hash::Hash for Sha256Hash {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.0.to_uppercase().hash(state);
+	}
+}
+
 impl Sha256Hash {
 	pub fn as_str(&self) -> &str {
 		&self.0
@@ -1,21 +1,38 @@
+mod arch_snapshot;
+mod archive_diff;
+mod binary_cache;
+mod cache_root;
 mod commands;
 mod credits;
+mod delta;
 mod fs_utils;
 mod hash;
 mod manifest;
 mod ovmf_download;
+mod patch;
+mod pkginfo;
 mod prefix_commands;
 mod privilage_escalation;
+mod reproducible;
 mod size;
 mod sources;
+mod squashfs;
+mod srcinfo;
+#[cfg(test)]
+mod test_utils;
+mod verity;
 use clap::{Parser, Subcommand};
 use colored::Colorize;
-use std::{io::ErrorKind, path::PathBuf};
+use std::{
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
 mod bootloader;
 use crate::{
 	commands::{
+		cache,
 		image::{self, packages},
-		initrd, kernel, vm,
+		initrd, kernel, keys, verify, vm,
 	},
 	privilage_escalation::ensure_root,
 };
@@ -29,6 +46,9 @@ struct Cli {
 	command: Commands,
 	#[arg(default_value = "manifest.toml", short)]
 	manifest: PathBuf,
+	/// Shared cache for sources and package outputs, e.g. for several worktrees
+	#[arg(long, global = true, env = "HYPRPACKER_CACHE")]
+	cache_dir: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -53,10 +73,63 @@ enum Commands {
 		#[command(subcommand)]
 		command: InitrdCommands,
 	},
+	/// Binary cache of built packages
+	Cache {
+		#[command(subcommand)]
+		command: CacheCommands,
+	},
+	/// Rebuilds packages (or with none given, the whole image) from scratch and checks the
+	/// result is byte for byte identical to the existing build
+	VerifyReproducible {
+		/// Package names or globs to rebuild, defaults to all packages and the image
+		packages: Vec<String>,
+	},
+	/// Manifest helpers
+	Manifest {
+		#[command(subcommand)]
+		command: ManifestCommands,
+	},
+	/// Release signing keys
+	Keys {
+		#[command(subcommand)]
+		command: KeysCommands,
+	},
 	/// Cleans up the build directory
 	Clean,
 }
 
+#[derive(Subcommand, Debug)]
+enum KeysCommands {
+	/// Generates an ed25519 key pair for `image sign`, as `<path>.key` and `<path>.pub`
+	Generate {
+		#[arg(default_value = "keys/release")]
+		path: PathBuf,
+		/// Overwrite an existing key pair
+		#[arg(long)]
+		force: bool,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommands {
+	/// Uploads built packages to the manifest's binary cache
+	Push {
+		/// Packages to upload, defaults to all of them
+		packages: Vec<String>,
+	},
+	/// Downloads packages that need a rebuild from the manifest's binary cache
+	Pull {
+		/// Packages to download, defaults to all of them
+		packages: Vec<String>,
+	},
+}
+
+#[derive(Subcommand, Debug)]
+enum ManifestCommands {
+	/// Validates the manifest and reports what was inferred from the PKGBUILDs
+	Check,
+}
+
 #[derive(Subcommand, Debug)]
 enum KernelCommands {
 	/// Builds the Linux kernel defined in the manifest
@@ -86,23 +159,292 @@ enum ImageCommands {
 		#[command(subcommand)]
 		command: PackageCommands,
 	},
-	/// UNIMPLEMENTED!!! Pushes the image to the update server
-	Push,
+	/// Collects the debug symbols of the assembled image and kernel into a .build-id tree and archive
+	Debuginfo,
+	/// Signs the assembled image and writes a signed release manifest with the hashes of the
+	/// image, kernel and initrd
+	Sign {
+		/// Private key from `keys generate`
+		#[arg(long)]
+		key: PathBuf,
+	},
+	/// Checks the signatures of an image and its release manifest
+	Verify {
+		/// Public (or private) key the release was signed with
+		#[arg(long)]
+		key: PathBuf,
+		/// Image to check, defaults to the manifest's assembled image
+		image: Option<PathBuf>,
+		/// Also check this kernel against the release manifest
+		#[arg(long)]
+		kernel: Option<PathBuf>,
+		/// Also check this initrd against the release manifest
+		#[arg(long)]
+		initrd: Option<PathBuf>,
+	},
+	/// Compares two images, or an image and the sysroot: packages, then files added,
+	/// removed or changed
+	Diff {
+		old: PathBuf,
+		/// Image or directory to compare with, defaults to the sysroot
+		new: Option<PathBuf>,
+		/// Print the differences as JSON
+		#[arg(long)]
+		json: bool,
+	},
+	/// Generates a binary delta turning one image into another
+	Delta {
+		old: PathBuf,
+		new: PathBuf,
+		/// Where to write the delta, defaults to `<new>.from-<old sha256>.delta` which
+		/// `image push` picks up
+		#[arg(short, long)]
+		output: Option<PathBuf>,
+	},
+	/// Rebuilds an image from the one a delta was generated against
+	ApplyDelta {
+		old: PathBuf,
+		delta: PathBuf,
+		output: PathBuf,
+	},
+	/// Lists a directory of an image, or a single file, without mounting it. Exits with an
+	/// error if the path doesn't exist
+	Ls {
+		image: PathBuf,
+		#[arg(default_value = "/")]
+		path: PathBuf,
+		/// Show the type, mode, owner, size and mtime of each entry
+		#[arg(short, long)]
+		long: bool,
+		/// List everything under the directory, with full paths
+		#[arg(short = 'R', long)]
+		recursive: bool,
+		/// Print the entries as JSON
+		#[arg(long)]
+		json: bool,
+	},
+	/// Writes a file of an image to stdout, following symlinks
+	Cat { image: PathBuf, path: PathBuf },
+	/// Copies a file or directory out of an image, keeping modes, mtimes and (as root)
+	/// owners. Device nodes, fifos, sockets and xattrs aren't extracted
+	Extract {
+		image: PathBuf,
+		path: PathBuf,
+		/// Where to create it, or an existing directory to create it in
+		dest: PathBuf,
+	},
+	/// Publishes the signed image, kernel and initrd to an update repository and points a
+	/// channel at them
+	Push {
+		/// Channel to move to this version, e.g. stable
+		#[arg(long)]
+		channel: String,
+		/// Repository directory, or the URL of an update server
+		#[arg(long)]
+		to: String,
+	},
 }
 
 #[derive(Subcommand, Debug)]
 enum PackageCommands {
-	/// Removes unused source tarballs from the sources directory
+	/// Removes sources and build outputs no manifest references, following the `[gc]` policies
 	#[command(alias = "gc")]
-	GarbageCollect,
+	GarbageCollect {
+		/// List what would be removed without removing anything
+		#[arg(long)]
+		dry_run: bool,
+		/// Unreferenced builds kept per package, most recent first (overrides `[gc] keep_last`)
+		#[arg(long)]
+		keep_last: Option<usize>,
+		/// Keep unreferenced builds and sources used within this many days
+		#[arg(long)]
+		max_age_days: Option<u64>,
+		/// Size budget for build/out, e.g. 50G
+		#[arg(long, value_parser = parse_size_arg)]
+		max_size: Option<u64>,
+		/// Another manifest whose packages must be kept, can be repeated
+		#[arg(long = "root")]
+		roots: Vec<PathBuf>,
+		/// Also remove builder Docker images no manifest uses (overrides `[gc] docker_images`)
+		#[arg(long)]
+		docker_images: bool,
+	},
 	/// Pre-downloads sources for packages
 	Fetch,
-	/// Builds all packages without building the image
-	Build,
+	/// Builds packages without building the image
+	Build {
+		/// Package names or globs to build (along with their build_deps), defaults to all
+		packages: Vec<String>,
+		/// Rebuild the selected packages even if they're up-to-date or in the binary cache
+		#[arg(long)]
+		force: bool,
+		/// Also rebuild the packages that have the selected ones in their build_deps
+		#[arg(long)]
+		rebuild_dependents: bool,
+		/// Keep building packages that don't depend on a failed one (default)
+		#[arg(long, conflicts_with = "fail_fast")]
+		keep_going: bool,
+		/// Stop at the first package that fails to build
+		#[arg(long)]
+		fail_fast: bool,
+	},
+}
+
+/// Records what this project uses for garbage collection, failing only costs that protection
+fn register_gc_root(manifest: &manifest::Manifest, manifest_path: &Path) {
+	if let Err(e) = cache_root::register_gc_root(manifest, manifest_path) {
+		eprintln!(
+			"{}: could not register the project's GC root: {e}",
+			"warning".yellow().bold()
+		);
+	}
+}
+
+/// Builds (or reuses) the kernel and initrd, returning their paths
+fn build_boot_files(manifest: &manifest::Manifest) -> (PathBuf, PathBuf) {
+	let initrd_path = match initrd::build_initrd(manifest) {
+		Ok(path) => path,
+		Err(e) => {
+			eprintln!("{}: Failed to build initrd: {}", "ERROR".red().bold(), e);
+			std::process::exit(1);
+		}
+	};
+	let kernel_path = match kernel::build(manifest) {
+		Ok(result) => {
+			result.print();
+			result.artifact_path
+		}
+		Err(e) => {
+			eprintln!("{}: Failed to build kernel: {}", "ERROR".red().bold(), e);
+			std::process::exit(1);
+		}
+	};
+	(kernel_path, initrd_path)
+}
+
+/// Compares two images, or an image and a directory seen through `overrides`
+fn diff_images(overrides: Option<&[manifest::EntryOverride]>, old: &Path, new: &Path, json: bool) {
+	match image::diff::diff(overrides, old, new) {
+		Ok(result) if json => println!("{}", serde_json::to_string_pretty(&result).unwrap()),
+		Ok(result) => result.print(),
+		Err(e) => {
+			eprintln!("{}: Failed to compare images: {}", "ERROR".red().bold(), e);
+			std::process::exit(1);
+		}
+	}
+}
+
+fn parse_size_arg(size: &str) -> Result<u64, String> {
+	size::parse_size(size).ok_or_else(|| format!("invalid size {size:?}, expected e.g. 50G"))
 }
 
 fn main() {
 	let cli = Cli::parse();
+	// commands that don't need a manifest
+	match &cli.command {
+		Commands::Keys {
+			command: KeysCommands::Generate { path, force },
+		} => {
+			match keys::generate(path, *force) {
+				Ok(result) => result.print(),
+				Err(e) => {
+					eprintln!(
+						"{}: Failed to generate signing key: {}",
+						"ERROR".red().bold(),
+						e
+					);
+					std::process::exit(1);
+				}
+			}
+			return;
+		}
+		// directories are compared through the manifest's overrides, two images need nothing
+		Commands::Image {
+			command: ImageCommands::Diff {
+				old,
+				new: Some(new),
+				json,
+			},
+		} if !old.is_dir() && !new.is_dir() => {
+			diff_images(None, old, new, *json);
+			return;
+		}
+		Commands::Image {
+			command: ImageCommands::Delta { old, new, output },
+		} => {
+			match image::delta::delta(old, new, output.clone()) {
+				Ok(result) => result.print(),
+				Err(e) => {
+					eprintln!("{}: Failed to generate delta: {}", "ERROR".red().bold(), e);
+					std::process::exit(1);
+				}
+			}
+			return;
+		}
+		Commands::Image {
+			command: ImageCommands::ApplyDelta { old, delta, output },
+		} => {
+			match image::delta::apply_delta(old, delta, output) {
+				Ok(result) => result.print(),
+				Err(e) => {
+					eprintln!("{}: Failed to apply delta: {}", "ERROR".red().bold(), e);
+					std::process::exit(1);
+				}
+			}
+			return;
+		}
+		Commands::Image {
+			command: ImageCommands::Ls {
+				image,
+				path,
+				long,
+				recursive,
+				json,
+			},
+		} => {
+			let printed = image::inspect::ls(image, path, *recursive).map(|result| {
+				if *json {
+					println!("{}", serde_json::to_string_pretty(&result).unwrap());
+				} else if let Err(e) = result.print(*long)
+					&& e.kind() != ErrorKind::BrokenPipe
+				{
+					eprintln!("{}: {e}", "ERROR".red().bold());
+					std::process::exit(1);
+				}
+			});
+			if let Err(e) = printed {
+				eprintln!("{}: Failed to list image: {}", "ERROR".red().bold(), e);
+				std::process::exit(1);
+			}
+			return;
+		}
+		Commands::Image {
+			command: ImageCommands::Cat { image, path },
+		} => {
+			if let Err(e) = image::inspect::cat(image, path) {
+				eprintln!("{}: Failed to read from image: {}", "ERROR".red().bold(), e);
+				std::process::exit(1);
+			}
+			return;
+		}
+		Commands::Image {
+			command: ImageCommands::Extract { image, path, dest },
+		} => {
+			match image::inspect::extract(image, path, dest) {
+				Ok(result) => result.print(),
+				Err(e) => {
+					eprintln!(
+						"{}: Failed to extract from image: {}",
+						"ERROR".red().bold(),
+						e
+					);
+					std::process::exit(1);
+				}
+			}
+			return;
+		}
+		_ => {}
+	}
 	let manifest = match std::fs::read_to_string(&cli.manifest) {
 		Ok(manifest) => manifest,
 		Err(e) => {
@@ -114,7 +456,15 @@ fn main() {
 			std::process::exit(1);
 		}
 	};
-	let manifest = match toml::from_str::<manifest::Manifest>(&manifest) {
+	if let Err(e) = cache_root::init(cli.cache_dir.clone()) {
+		eprintln!(
+			"{}: Failed to open the cache directory {}: {e}",
+			"ERROR".red().bold(),
+			cli.cache_dir.unwrap_or_default().display()
+		);
+		std::process::exit(1);
+	}
+	let mut manifest = match toml::from_str::<manifest::Manifest>(&manifest) {
 		Ok(manifest) => manifest,
 		Err(e) => {
 			eprintln!(
@@ -128,11 +478,15 @@ fn main() {
 	match cli.command {
 		Commands::Image { command } => match command {
 			ImageCommands::Assemble => {
-				packages::gc_command(&manifest);
 				let fetch_result = packages::fetch(&manifest);
 				fetch_result.print();
 				fetch_result.exit_if_failure();
-				let build_result = packages::build(&manifest);
+				let resolve_result = packages::resolve(&mut manifest);
+				resolve_result.print();
+				resolve_result.exit_if_failure();
+				register_gc_root(&manifest, &cli.manifest);
+				packages::auto_gc(&manifest);
+				let build_result = packages::build(&manifest, &packages::BuildRequest::default());
 				build_result.print();
 				build_result.exit_if_failure();
 				println!("{}", "  Assembling image".blue().bold());
@@ -145,78 +499,148 @@ fn main() {
 							image_path.display().to_string().green().bold()
 						);
 					}
-					Err(image::AssembleError::CopyError {
-						package: pkg,
-						error,
-					}) => {
-						eprintln!(
-							"  {} {} {} {}: {}",
-							" 󱁥  Failed copying ".bold().red(),
-							pkg.name.red().bold(),
-							pkg.version.dimmed(),
-							"to sysroot".red(),
-							error.to_string().red()
-						);
-					}
-					Err(image::AssembleError::SquashfsError(e)) => {
-						eprintln!();
-						eprintln!(
-							"    {}: {}",
-							" 󱁥  Failed to create image".bold().red(),
-							e.to_string().red()
-						);
-						eprintln!();
-						fn hyperlink(link: impl core::fmt::Display, text: impl core::fmt::Display) -> String {
-							format!("\x1b]8;;{link}\x1b\\{text}\x1b]8;;\x1b\\")
-						}
-						if let image::SquashFsError::CommandError(e) = e {
-							if let ErrorKind::NotFound = e.kind() {
-								eprintln!(
-									"    {}: This is likely due to {} not being installed. {}",
-									"help".bold().cyan(),
-									hyperlink(
-										"https://github.com/plougher/squashfs-tools",
-										"squashfs-tools".bold().underline()
-									),
-									"Make sure it is installed and try again.".bold()
-								);
-								eprintln!();
-							}
-						}
-					}
-					Err(image::AssembleError::Io(e)) => {
-						eprintln!();
-						eprintln!(
-							"    {}: {}",
-							" 󱁥  Failed to create image due to an IO error"
-								.bold()
-								.red(),
-							e.to_string().red().dimmed()
-						);
-						eprintln!();
+					Err(e) => {
+						e.print();
+						std::process::exit(1);
 					}
 				}
 			}
 			ImageCommands::Packages { command } => match command {
-				PackageCommands::GarbageCollect => packages::gc_command(&manifest),
+				PackageCommands::GarbageCollect {
+					dry_run,
+					keep_last,
+					max_age_days,
+					max_size,
+					roots,
+					docker_images,
+				} => {
+					// the out dirs are named after the (possibly inferred) versions
+					let resolve_result = packages::resolve(&mut manifest);
+					resolve_result.print();
+					resolve_result.exit_if_failure();
+					register_gc_root(&manifest, &cli.manifest);
+					let mut options = manifest.gc.clone();
+					options.keep_last = keep_last.unwrap_or(options.keep_last);
+					options.max_age_days = max_age_days.or(options.max_age_days);
+					options.max_size = max_size.or(options.max_size);
+					options.roots.extend(roots);
+					options.docker_images |= docker_images;
+					packages::gc_command(&manifest, &options, dry_run);
+				}
 				PackageCommands::Fetch => {
-					packages::gc_command(&manifest);
 					let result = packages::fetch(&manifest);
 					result.print();
 					result.exit_if_failure();
+					let resolve_result = packages::resolve(&mut manifest);
+					resolve_result.print();
+					resolve_result.exit_if_failure();
+					register_gc_root(&manifest, &cli.manifest);
+					packages::auto_gc(&manifest);
 				}
-				PackageCommands::Build => {
-					packages::gc_command(&manifest);
+				PackageCommands::Build {
+					packages: names,
+					force,
+					rebuild_dependents,
+					keep_going: _,
+					fail_fast,
+				} => {
+					let request = packages::BuildRequest {
+						packages: names,
+						force,
+						rebuild_dependents,
+						fail_fast,
+					};
+					// catch typos before fetching anything
+					if let Err(e) = request.selected(&manifest) {
+						eprintln!("{}: {}", "ERROR".red().bold(), e);
+						std::process::exit(1);
+					}
 					let fetch_result = packages::fetch(&manifest);
 					fetch_result.print();
 					fetch_result.exit_if_failure();
-					let build_result = packages::build(&manifest);
+					let resolve_result = packages::resolve(&mut manifest);
+					resolve_result.print();
+					resolve_result.exit_if_failure();
+					register_gc_root(&manifest, &cli.manifest);
+					packages::auto_gc(&manifest);
+					let build_result = packages::build(&manifest, &request);
 					build_result.print();
 					build_result.exit_if_failure();
 				}
 			},
-			ImageCommands::Push => {
-				todo!("push command")
+			ImageCommands::Debuginfo => {
+				// the debug stores live in the out dirs, which are named after the inferred versions
+				let fetch_result = packages::fetch(&manifest);
+				fetch_result.print();
+				fetch_result.exit_if_failure();
+				let resolve_result = packages::resolve(&mut manifest);
+				resolve_result.print();
+				resolve_result.exit_if_failure();
+				register_gc_root(&manifest, &cli.manifest);
+				match image::debuginfo(&manifest) {
+					Ok(result) => result.print(),
+					Err(e) => {
+						eprintln!(
+							"{}: Failed to collect debug info: {}",
+							"ERROR".red().bold(),
+							e
+						);
+						std::process::exit(1);
+					}
+				}
+			}
+			ImageCommands::Sign { key } => {
+				// the release records the kernel and initrd the image boots with
+				let (kernel_path, initrd_path) = build_boot_files(&manifest);
+				match image::release::sign(&manifest, &key, &kernel_path, &initrd_path) {
+					Ok(result) => result.print(),
+					Err(e) => {
+						eprintln!("{}: Failed to sign image: {}", "ERROR".red().bold(), e);
+						std::process::exit(1);
+					}
+				}
+			}
+			ImageCommands::Verify {
+				key,
+				image: image_path,
+				kernel,
+				initrd,
+			} => {
+				let image_path = image_path.unwrap_or_else(|| image::assemble::image_path(&manifest));
+				match image::release::verify(&image_path, &key, kernel.as_deref(), initrd.as_deref()) {
+					Ok(result) => result.print(),
+					Err(e) => {
+						eprintln!(
+							"{}: Failed to verify {}: {}",
+							"ERROR".red().bold(),
+							image_path.display(),
+							e
+						);
+						std::process::exit(1);
+					}
+				}
+			}
+			ImageCommands::Diff { old, new, json } => {
+				let new = new.unwrap_or_else(|| image::assemble::sysroot_path(&manifest));
+				diff_images(Some(&manifest.image.overrides), &old, &new, json);
+			}
+			ImageCommands::Delta { .. }
+			| ImageCommands::ApplyDelta { .. }
+			| ImageCommands::Ls { .. }
+			| ImageCommands::Cat { .. }
+			| ImageCommands::Extract { .. } => {
+				unreachable!("handled before reading the manifest")
+			}
+			ImageCommands::Push { channel, to } => {
+				let (kernel_path, initrd_path) = build_boot_files(&manifest);
+				let repository = image::push::UpdateRepository::parse(&to);
+				match image::push::push(&manifest, &repository, &channel, &kernel_path, &initrd_path) {
+					Ok(result) => result.print(),
+					Err(e) => {
+						eprintln!("{}: Failed to push image: {}", "ERROR".red().bold(), e);
+						std::process::exit(1);
+					}
+				}
 			}
 		},
 		Commands::Kernel { command } => match command {
@@ -228,6 +652,66 @@ fn main() {
 				}
 			},
 		},
+		Commands::Cache { command } => {
+			// cache keys depend on the inferred versions
+			let fetch_result = packages::fetch(&manifest);
+			fetch_result.print();
+			fetch_result.exit_if_failure();
+			let resolve_result = packages::resolve(&mut manifest);
+			resolve_result.print();
+			resolve_result.exit_if_failure();
+			register_gc_root(&manifest, &cli.manifest);
+			let result = match command {
+				CacheCommands::Push { packages } => cache::push(&manifest, &packages),
+				CacheCommands::Pull { packages } => cache::pull(&manifest, &packages),
+			};
+			result.print();
+			result.exit_if_failure();
+		}
+		Commands::VerifyReproducible { packages: names } => {
+			let request = packages::BuildRequest {
+				packages: names.clone(),
+				..Default::default()
+			};
+			if let Err(e) = request.selected(&manifest) {
+				eprintln!("{}: {}", "ERROR".red().bold(), e);
+				std::process::exit(1);
+			}
+			let fetch_result = packages::fetch(&manifest);
+			fetch_result.print();
+			fetch_result.exit_if_failure();
+			let resolve_result = packages::resolve(&mut manifest);
+			resolve_result.print();
+			resolve_result.exit_if_failure();
+			register_gc_root(&manifest, &cli.manifest);
+			packages::auto_gc(&manifest);
+			// the reference build, which may come from the binary cache
+			let build_result = packages::build(&manifest, &request);
+			build_result.print();
+			build_result.exit_if_failure();
+			let result = verify::verify_reproducible(&manifest, &names);
+			result.print();
+			result.exit_if_failure();
+		}
+		Commands::Manifest {
+			command: ManifestCommands::Check,
+		} => {
+			let fetch_result = packages::fetch(&manifest);
+			fetch_result.print();
+			fetch_result.exit_if_failure();
+			let resolve_result = packages::resolve(&mut manifest);
+			resolve_result.print();
+			resolve_result.print_inferred();
+			resolve_result.exit_if_failure();
+			register_gc_root(&manifest, &cli.manifest);
+			println!(
+				"{} {} {}",
+				"✔ Manifest is valid:".green().bold(),
+				manifest.packages.len().to_string().cyan(),
+				"packages".green()
+			);
+		}
+		Commands::Keys { .. } => unreachable!("handled before reading the manifest"),
 		Commands::Clean => {
 			std::fs::remove_dir_all("build").unwrap_or_else(|e| {
 				if let ErrorKind::NotFound = e.kind() {
@@ -278,11 +762,15 @@ fn main() {
 			}
 
 			// Assemble the image (run package fetch/build then assemble)
-			packages::gc_command(&manifest);
 			let fetch_result = packages::fetch(&manifest);
 			fetch_result.print();
 			fetch_result.exit_if_failure();
-			let build_result = packages::build(&manifest);
+			let resolve_result = packages::resolve(&mut manifest);
+			resolve_result.print();
+			resolve_result.exit_if_failure();
+			register_gc_root(&manifest, &cli.manifest);
+			packages::auto_gc(&manifest);
+			let build_result = packages::build(&manifest, &packages::BuildRequest::default());
 			build_result.print();
 			build_result.exit_if_failure();
 			println!("{}", "  Assembling image".blue().bold());
@@ -296,57 +784,8 @@ fn main() {
 					);
 					image_path
 				}
-				Err(image::AssembleError::CopyError {
-					package: pkg,
-					error,
-				}) => {
-					eprintln!(
-						"  {} {} {} {}: {}",
-						" 󱁥  Failed copying ".bold().red(),
-						pkg.name.red().bold(),
-						pkg.version.dimmed(),
-						"to sysroot".red(),
-						error.to_string().red()
-					);
-					std::process::exit(1);
-				}
-				Err(image::AssembleError::SquashfsError(e)) => {
-					eprintln!();
-					eprintln!(
-						"    {}: {}",
-						" 󱁥  Failed to create image".bold().red(),
-						e.to_string().red()
-					);
-					eprintln!();
-					fn hyperlink(link: impl core::fmt::Display, text: impl core::fmt::Display) -> String {
-						format!("\x1b]8;;{link}\x1b\\{text}\x1b]8;;\x1b\\")
-					}
-					if let image::SquashFsError::CommandError(e) = e {
-						if let ErrorKind::NotFound = e.kind() {
-							eprintln!(
-								"    {}: This is likely due to {} not being installed. {}",
-								"help".bold().cyan(),
-								hyperlink(
-									"https://github.com/plougher/squashfs-tools",
-									"squashfs-tools".bold().underline()
-								),
-								"Make sure it is installed and try again.".bold()
-							);
-							eprintln!();
-						}
-					}
-					std::process::exit(1);
-				}
-				Err(image::AssembleError::Io(e)) => {
-					eprintln!();
-					eprintln!(
-						"    {}: {}",
-						" 󱁥  Failed to create image due to an IO error"
-							.bold()
-							.red(),
-						e.to_string().red().dimmed()
-					);
-					eprintln!();
+				Err(e) => {
+					e.print();
 					std::process::exit(1);
 				}
 			};
@@ -408,7 +847,9 @@ fn main() {
 				}
 			}
 		}
-		Commands::Vm { command: VMCommands::Reset } => {
+		Commands::Vm {
+			command: VMCommands::Reset,
+		} => {
 			match vm::reset_vm() {
 				Ok(p) => p,
 				Err(e) => {
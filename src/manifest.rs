@@ -1,7 +1,9 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::OnceLock;
 
 use crate::hash::Sha256Hash;
+use crate::patch::PatchError;
 use serde::Deserialize;
 use serde::Serialize;
 use thiserror::Error;
@@ -9,10 +11,450 @@ use thiserror::Error;
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Manifest {
 	pub version: String,
+	/// Pins pacman inside the build containers to a fixed Arch Linux snapshot
+	#[serde(default, deserialize_with = "deserialize_arch_snapshot")]
+	pub arch_snapshot: Option<ArchSnapshot>,
+	/// Where built packages are looked up before running makepkg and uploaded after
+	#[serde(default)]
+	pub binary_cache: Option<BinaryCache>,
+	/// Unix timestamp used instead of the current time in builds and the image,
+	/// defaults to the time of the last git commit
+	#[serde(default)]
+	pub source_date_epoch: Option<u64>,
+	#[serde(default)]
+	pub gc: GcOptions,
+	#[serde(default)]
+	pub image: ImageOptions,
 	pub kernel: Kernel,
 	pub initrd: InitrdOptions,
 	#[serde(rename = "package", default = "Vec::new")]
 	pub packages: Vec<Package>,
+	/// [`Package::binary_cache_key`] of every package by name, computed on first use
+	#[serde(skip)]
+	pub binary_cache_keys: OnceLock<HashMap<String, String>>,
+	/// Takes the place of `build/` (and the cache root) for the out dirs and the sysroot,
+	/// so `verify-reproducible` can rebuild without touching the existing builds
+	#[serde(skip)]
+	pub rebuild_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Hash)]
+#[serde(untagged)]
+pub enum ArchSnapshot {
+	/// A date in the Arch Linux Archive, e.g. "2026/10/01"
+	Date(String),
+	/// A local directory laid out like an Arch mirror (`<repo>/os/<arch>`)
+	Local { path: PathBuf },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BinaryCache {
+	#[serde(flatten)]
+	pub backend: BinaryCacheBackend,
+	/// Uploads packages after they're built, turn off for read-only caches
+	#[serde(default = "default_true")]
+	pub upload: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum BinaryCacheBackend {
+	/// A directory, e.g. a shared network mount
+	Directory { path: PathBuf },
+	/// A plain HTTP server answering GET and PUT under this URL
+	Http { url: String },
+}
+
+/// Retention policy for the out dirs and sources (`build/` or the shared cache root). Whatever the manifest (or one of
+/// the `roots`) references is always kept, the policies only decide about the rest.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct GcOptions {
+	/// Collect garbage before every fetch, build and assemble instead of only on `packages gc`
+	#[serde(default)]
+	pub auto: bool,
+	/// Unreferenced out dirs kept per package, most recently built first
+	#[serde(default)]
+	pub keep_last: usize,
+	/// Unreferenced out dirs and sources used within this many days are kept
+	#[serde(default)]
+	pub max_age_days: Option<u64>,
+	/// Size budget for the out dirs (bytes, or a string like "50G"), the oldest
+	/// unreferenced out dirs are removed until it fits
+	#[serde(default, deserialize_with = "crate::size::deserialize_size")]
+	pub max_size: Option<u64>,
+	/// Other manifests whose packages must survive garbage collection, e.g. other branches
+	#[serde(default)]
+	pub roots: Vec<PathBuf>,
+	/// Also remove the `hyprpacker-*` builder Docker images no manifest uses anymore
+	#[serde(default)]
+	pub docker_images: bool,
+}
+
+/// How the system image is written
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ImageOptions {
+	/// File name of the image without extension, with `{version}`, `{git}` (short commit
+	/// hash), `{date}` (YYYYMMDD) and `{epoch}` (both from the source date epoch) filled in
+	#[serde(
+		default = "default_image_name",
+		deserialize_with = "deserialize_name_template"
+	)]
+	pub name: String,
+	/// Where images and the files generated alongside them are written
+	#[serde(default = "default_image_output_dir")]
+	pub output_dir: PathBuf,
+	#[serde(default)]
+	pub format: ImageFormat,
+	#[serde(default)]
+	pub squashfs: SquashfsOptions,
+	#[serde(default)]
+	pub erofs: ErofsOptions,
+	/// Globs of absolute image paths, when set only matching files (and everything under
+	/// matching directories) are installed
+	#[serde(default, deserialize_with = "deserialize_globs")]
+	pub include: Vec<String>,
+	/// Globs of absolute image paths left out of the image, along with everything under them.
+	/// Defaults to the package metadata at the top of packages (`.PKGINFO`, `.MTREE`...).
+	#[serde(
+		default = "default_image_exclude",
+		deserialize_with = "deserialize_globs"
+	)]
+	pub exclude: Vec<String>,
+	#[serde(default)]
+	pub strip: StripOptions,
+	/// Generates a dm-verity hash tree of the image after assembling it when set
+	#[serde(default)]
+	pub verity: Option<VerityOptions>,
+	/// Ownership, permissions and xattrs of image entries, applied in order over the
+	/// defaults (root owned, the sysroot's permissions, no xattrs)
+	#[serde(default)]
+	pub overrides: Vec<EntryOverride>,
+	/// Assembly fails when the image ends up bigger than this (bytes, or a string like
+	/// "2G"), after printing the size report
+	#[serde(default, deserialize_with = "crate::size::deserialize_size")]
+	pub max_size: Option<u64>,
+	/// Files listed in the size report printed after assembly, largest first
+	#[serde(default = "default_largest_files")]
+	pub largest_files: usize,
+}
+
+impl Default for ImageOptions {
+	fn default() -> Self {
+		Self {
+			name: default_image_name(),
+			output_dir: default_image_output_dir(),
+			format: ImageFormat::default(),
+			squashfs: SquashfsOptions::default(),
+			erofs: ErofsOptions::default(),
+			include: vec![],
+			exclude: default_image_exclude(),
+			strip: StripOptions::default(),
+			verity: None,
+			overrides: vec![],
+			max_size: None,
+			largest_files: default_largest_files(),
+		}
+	}
+}
+
+fn default_image_name() -> String {
+	"hyprside-{version}-{git}".to_string()
+}
+
+fn default_image_output_dir() -> PathBuf {
+	PathBuf::from("build/images")
+}
+
+fn default_largest_files() -> usize {
+	10
+}
+
+fn default_image_exclude() -> Vec<String> {
+	vec!["/.*".to_string()]
+}
+
+/// Variables of the image name template
+pub const IMAGE_NAME_VARIABLES: [&str; 4] = ["version", "git", "date", "epoch"];
+
+fn deserialize_name_template<'de, D: serde::Deserializer<'de>>(
+	deserializer: D,
+) -> Result<String, D::Error> {
+	let template = String::deserialize(deserializer)?;
+	let mut rest = template.as_str();
+	while let Some(start) = rest.find('{') {
+		let Some(end) = rest[start..].find('}') else {
+			return Err(serde::de::Error::custom(format!(
+				"unclosed {{ in image name {template:?}"
+			)));
+		};
+		let variable = &rest[start + 1..start + end];
+		if !IMAGE_NAME_VARIABLES.contains(&variable) {
+			return Err(serde::de::Error::custom(format!(
+				"unknown variable {{{variable}}} in image name {template:?}, expected one of {}",
+				IMAGE_NAME_VARIABLES.map(|v| format!("{{{v}}}")).join(", ")
+			)));
+		}
+		rest = &rest[start + end + 1..];
+	}
+	if template.is_empty() || template.contains('/') {
+		return Err(serde::de::Error::custom(format!(
+			"invalid image name {template:?}, expected a file name"
+		)));
+	}
+	Ok(template)
+}
+
+fn deserialize_globs<'de, D: serde::Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+	let globs = Vec::<String>::deserialize(deserializer)?;
+	for glob in &globs {
+		if !glob.starts_with('/') {
+			return Err(serde::de::Error::custom(format!(
+				"invalid glob {glob:?}, expected an absolute image path"
+			)));
+		}
+		glob::Pattern::new(glob)
+			.map_err(|e| serde::de::Error::custom(format!("invalid glob {glob:?}: {e}")))?;
+	}
+	Ok(globs)
+}
+
+/// Whether `date` is laid out like the Arch Linux Archive's `YYYY/MM/DD`
+fn is_archive_date(date: &str) -> bool {
+	let parts: Vec<&str> = date.split('/').collect();
+	let [year, month, day] = parts.as_slice() else {
+		return false;
+	};
+	let number = |part: &str, len: usize| {
+		(part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+			.then(|| part.parse::<u32>().unwrap())
+	};
+	number(year, 4).is_some()
+		&& number(month, 2).is_some_and(|month| (1..=12).contains(&month))
+		&& number(day, 2).is_some_and(|day| (1..=31).contains(&day))
+}
+
+fn deserialize_arch_snapshot<'de, D: serde::Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<ArchSnapshot>, D::Error> {
+	let snapshot = Option::<ArchSnapshot>::deserialize(deserializer)?;
+	if let Some(ArchSnapshot::Date(date)) = &snapshot
+		&& !is_archive_date(date)
+	{
+		return Err(serde::de::Error::custom(format!(
+			"invalid arch_snapshot date {date:?}, expected YYYY/MM/DD like the Arch Linux Archive"
+		)));
+	}
+	Ok(snapshot)
+}
+
+/// What's left out of the image to make it smaller. Only files the packages install are
+/// stripped, never generated ones like `/etc/credits.json`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct StripOptions {
+	/// `/usr/share/doc`, `/usr/share/info` and `/usr/share/gtk-doc`
+	#[serde(default)]
+	pub docs: bool,
+	/// `/usr/share/man`
+	#[serde(default)]
+	pub man_pages: bool,
+	/// Locales kept in `/usr/share/locale`, e.g. `["en_US", "pt"]` (which keeps `pt_BR` too).
+	/// All of them are kept when not set.
+	#[serde(default)]
+	pub locales: Option<Vec<String>>,
+	/// `*.a` archives under `/usr/lib`
+	#[serde(default)]
+	pub static_libs: bool,
+	/// `/usr/include`
+	#[serde(default)]
+	pub headers: bool,
+	/// `/usr/lib/pkgconfig` and `/usr/share/pkgconfig`
+	#[serde(default)]
+	pub pkgconfig: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+	#[default]
+	Squashfs,
+	/// Faster random reads than squashfs, needs erofs-utils 1.7 or newer
+	Erofs,
+	/// A read-only ext4, needs e2fsprogs 1.47.1 or newer
+	Ext4,
+}
+
+impl ImageFormat {
+	/// Extension of the image file, also used in its name on the system partition
+	pub fn extension(self) -> &'static str {
+		match self {
+			ImageFormat::Squashfs => "squashfs",
+			ImageFormat::Erofs => "erofs",
+			ImageFormat::Ext4 => "ext4",
+		}
+	}
+}
+
+impl std::fmt::Display for ImageFormat {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.extension())
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+	None,
+	Gzip,
+	Lzo,
+	Lz4,
+	Lz4hc,
+	Xz,
+	Lzma,
+	Deflate,
+	Zstd,
+}
+
+impl std::fmt::Display for CompressionAlgorithm {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			CompressionAlgorithm::None => "none",
+			CompressionAlgorithm::Gzip => "gzip",
+			CompressionAlgorithm::Lzo => "lzo",
+			CompressionAlgorithm::Lz4 => "lz4",
+			CompressionAlgorithm::Lz4hc => "lz4hc",
+			CompressionAlgorithm::Xz => "xz",
+			CompressionAlgorithm::Lzma => "lzma",
+			CompressionAlgorithm::Deflate => "deflate",
+			CompressionAlgorithm::Zstd => "zstd",
+		})
+	}
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SquashfsOptions {
+	#[serde(default)]
+	pub backend: SquashfsBackend,
+	/// zstd (the only one the builtin writer supports), gzip, lzo, lz4 or xz
+	#[serde(default = "default_squashfs_compression")]
+	pub compression: CompressionAlgorithm,
+	/// Defaults to 15 for zstd, the compressor's default otherwise
+	#[serde(default)]
+	pub level: Option<i32>,
+	/// Data block size (bytes, or a string like "128K"), a power of two from 4K to 1M.
+	/// Defaults to 1M.
+	#[serde(default, deserialize_with = "crate::size::deserialize_size")]
+	pub block_size: Option<u64>,
+}
+
+impl Default for SquashfsOptions {
+	fn default() -> Self {
+		Self {
+			backend: SquashfsBackend::default(),
+			compression: default_squashfs_compression(),
+			level: None,
+			block_size: None,
+		}
+	}
+}
+
+fn default_squashfs_compression() -> CompressionAlgorithm {
+	CompressionAlgorithm::Zstd
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErofsOptions {
+	/// lz4, lz4hc, lzma, deflate, zstd or none
+	#[serde(default = "default_erofs_compression")]
+	pub compression: CompressionAlgorithm,
+	/// The compressor's default when not set
+	#[serde(default)]
+	pub level: Option<i32>,
+}
+
+impl Default for ErofsOptions {
+	fn default() -> Self {
+		Self {
+			compression: default_erofs_compression(),
+			level: None,
+		}
+	}
+}
+
+fn default_erofs_compression() -> CompressionAlgorithm {
+	CompressionAlgorithm::Lz4hc
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct VerityOptions {
+	#[serde(default)]
+	pub hash_tree: VerityHashTree,
+	/// Hex encoded, up to 256 bytes. Defaults to one derived from the image's file name.
+	#[serde(default)]
+	pub salt: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerityHashTree {
+	/// After the image's data, which is zero padded to 4K
+	#[default]
+	Append,
+	/// In `<image stem>.verity`
+	Separate,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SquashfsBackend {
+	/// hyprpacker's own writer, needs neither squashfs-tools nor a root owned sysroot
+	#[default]
+	Builtin,
+	/// `mksquashfs` from squashfs-tools (4.6 or newer for xattr overrides)
+	Mksquashfs,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EntryOverride {
+	/// Glob of absolute paths in the image, e.g. "/usr/bin/sudo" or "/home/**"
+	pub path: String,
+	#[serde(default)]
+	pub uid: Option<u32>,
+	#[serde(default)]
+	pub gid: Option<u32>,
+	/// Permission bits, an integer or an octal string like "4755"
+	#[serde(default, deserialize_with = "deserialize_mode")]
+	pub mode: Option<u32>,
+	/// e.g. `"security.capability" = "0x0100000200200000..."`, values starting with 0x are hex
+	#[serde(default)]
+	pub xattrs: BTreeMap<String, String>,
+}
+
+fn deserialize_mode<'de, D: serde::Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Mode {
+		Number(u32),
+		Octal(String),
+	}
+	let mode = match Option::<Mode>::deserialize(deserializer)? {
+		None => return Ok(None),
+		Some(Mode::Number(mode)) => mode,
+		Some(Mode::Octal(text)) => {
+			u32::from_str_radix(text.trim_start_matches("0o"), 8).map_err(|_| {
+				serde::de::Error::custom(format!("invalid mode {text:?}, expected e.g. \"0755\""))
+			})?
+		}
+	};
+	if mode > 0o7777 {
+		return Err(serde::de::Error::custom(format!(
+			"invalid mode {mode:o}, only permission bits can be set"
+		)));
+	}
+	Ok(Some(mode))
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -20,6 +462,9 @@ pub struct Kernel {
 	pub url: String,
 	#[serde(default)]
 	pub options: KernelOptions,
+	/// Globs of unified diffs applied in order to the kernel tree before `make olddefconfig`
+	#[serde(default)]
+	pub patches: Vec<String>,
 }
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(untagged)]
@@ -32,6 +477,8 @@ pub type KernelOptions = BTreeMap<String, KernelOptionValue>;
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Package {
 	pub name: String,
+	/// Defaults to `[epoch:]pkgver-pkgrel` from the PKGBUILD's .SRCINFO
+	#[serde(default)]
 	pub version: String,
 	pub author: Option<String>,
 	pub source: Source,
@@ -39,6 +486,38 @@ pub struct Package {
 	pub docker: DockerSettings,
 	#[serde(default)]
 	pub build_deps: HashSet<String>,
+	/// Globs of unified diffs applied in order to the prepared sources, e.g. `patches/foo/*.patch`
+	#[serde(default)]
+	pub patches: Vec<String>,
+	#[serde(flatten)]
+	pub build_options: BuildOptions,
+}
+
+/// Per-package knobs for the makepkg container, all of them part of the package's cache key
+#[derive(Debug, Deserialize, Serialize, Clone, Hash)]
+pub struct BuildOptions {
+	/// Extra environment variables passed into the build container
+	#[serde(default)]
+	pub env: BTreeMap<String, String>,
+	/// Extra arguments appended to the makepkg invocation
+	#[serde(default)]
+	pub makepkg_args: Vec<String>,
+	/// A makepkg.conf that replaces the container's /etc/makepkg.conf
+	pub makepkg_conf: Option<PathBuf>,
+	/// Runs the PKGBUILD's check() function, `false` is the same as passing `--nocheck`
+	#[serde(default = "default_true")]
+	pub check: bool,
+	pub cflags: Option<String>,
+	pub ldflags: Option<String>,
+	pub makeflags: Option<String>,
+	/// Container CPU limit (docker run --cpus)
+	pub cpus: Option<u32>,
+	/// Container memory limit (docker run --memory), e.g. "8g"
+	pub memory: Option<String>,
+}
+
+fn default_true() -> bool {
+	true
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -109,6 +588,8 @@ pub enum SourceFetchError {
 	},
 	#[error("invalid source: {0}")]
 	InvalidSource(#[from] InvalidSourceError),
+	#[error("failed to apply patches: {0}")]
+	Patch(#[from] PatchError),
 }
 
 pub struct GarbageCollectionStat {
@@ -116,4 +597,43 @@ pub struct GarbageCollectionStat {
 	pub removed_out_folders: usize,
 	pub removed_prepared_packages: usize,
 	pub removed_sources_packages: usize,
+	/// Every removed (or with a dry run, removable) path with its size and the reason
+	pub removed: Vec<(PathBuf, u64, &'static str)>,
+	pub dry_run: bool,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn arch_snapshot(value: &str) -> Result<Option<ArchSnapshot>, toml::de::Error> {
+		toml::from_str::<Manifest>(&format!(
+			"version = \"1.0\"\narch_snapshot = {value}\n[kernel]\nurl = \"https://example.com/linux-1.0.tar.xz\"\n[initrd]\nbuild_script = \"initrd.sh\"\n"
+		))
+		.map(|manifest| manifest.arch_snapshot)
+	}
+
+	#[test]
+	fn arch_snapshot_dates() {
+		assert!(matches!(
+			arch_snapshot("\"2026/10/01\""),
+			Ok(Some(ArchSnapshot::Date(date))) if date == "2026/10/01"
+		));
+		assert!(matches!(
+			arch_snapshot("{ path = \"/srv/arch-mirror\" }"),
+			Ok(Some(ArchSnapshot::Local { .. }))
+		));
+		for date in [
+			"2026-10-01",
+			"2026/10/1",
+			"26/10/01",
+			"2026/13/01",
+			"2026/10/00",
+			"2026/10/01/",
+			"latest",
+		] {
+			let error = arch_snapshot(&format!("{date:?}")).unwrap_err();
+			assert!(error.to_string().contains("expected YYYY/MM/DD"), "{error}");
+		}
+	}
 }
@@ -0,0 +1,551 @@
+//! Minimal unified diff support for the `patches` manifest option.
+//!
+//! Only plain text hunks are supported (what `git format-patch` and `diff -u` produce),
+//! applied with one leading path component stripped, like `patch -p1`.
+use std::{
+	collections::BTreeMap,
+	hash::Hasher,
+	path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Debug)]
+pub struct HunkFailure {
+	pub file: PathBuf,
+	/// 1-based index of the hunk inside its file
+	pub hunk: usize,
+	pub header: String,
+}
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+	#[error("io error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("invalid patch glob {0}: {1}")]
+	InvalidGlob(String, glob::PatternError),
+	#[error("patch glob {0} doesn't match any file")]
+	NoMatches(String),
+	#[error("malformed patch {}: {reason}", patch.display())]
+	Malformed { patch: PathBuf, reason: String },
+	#[error("{} does not apply:{}", patch.display(), format_hunk_failures(failures))]
+	HunksFailed {
+		patch: PathBuf,
+		failures: Vec<HunkFailure>,
+	},
+}
+
+fn format_hunk_failures(failures: &[HunkFailure]) -> String {
+	failures
+		.iter()
+		.map(|f| {
+			format!(
+				"\n        {}: hunk #{} FAILED ({})",
+				f.file.display(),
+				f.hunk,
+				f.header
+			)
+		})
+		.collect()
+}
+
+/// Expands the manifest's patch globs, keeping the listed order and sorting the
+/// matches of each glob by name (so `0001-*.patch` series apply in order)
+pub fn expand_patch_globs(patterns: &[String]) -> Result<Vec<PathBuf>, PatchError> {
+	let mut patches = Vec::new();
+	for pattern in patterns {
+		let mut matches = glob::glob(pattern)
+			.map_err(|e| PatchError::InvalidGlob(pattern.clone(), e))?
+			.filter_map(Result::ok)
+			.filter(|p| p.is_file())
+			.collect::<Vec<_>>();
+		if matches.is_empty() {
+			return Err(PatchError::NoMatches(pattern.clone()));
+		}
+		matches.sort();
+		patches.extend(matches);
+	}
+	Ok(patches)
+}
+
+/// Feeds the contents of every patch matched by `patterns` into `hasher`, so patches
+/// can be part of rebuild keys. Globs that fail to expand are hashed as written.
+pub fn hash_patches(patterns: &[String], hasher: &mut impl Hasher) {
+	use std::hash::Hash;
+	match expand_patch_globs(patterns) {
+		Ok(patches) => {
+			for patch in patches {
+				std::fs::read(&patch).ok().hash(hasher);
+			}
+		}
+		Err(_) => patterns.hash(hasher),
+	}
+}
+
+struct Hunk {
+	header: String,
+	old_start: usize,
+	/// Without line endings, so CRLF files still match patches with LF lines (and back)
+	old_lines: Vec<String>,
+	/// With their line endings, the last one has none after `\ No newline at end of file`
+	new_lines: Vec<String>,
+	/// For each new line, the index of the old line it is context for
+	context: Vec<Option<usize>>,
+}
+
+/// A target file being patched
+struct PatchedFile {
+	/// With their line endings
+	lines: Vec<String>,
+	/// The ending of added lines, the first line's for existing files. New files keep the
+	/// endings written in the patch.
+	ending: Option<&'static str>,
+}
+
+struct FilePatch {
+	old_path: Option<PathBuf>,
+	new_path: Option<PathBuf>,
+	hunks: Vec<Hunk>,
+}
+
+/// Parses the `path` part of `--- a/path\t2024-01-01 ...`, stripping the first component.
+/// `diff -N` marks missing files with the epoch timestamp instead of `/dev/null`.
+fn parse_path(raw: &str) -> Option<PathBuf> {
+	let (raw, timestamp) = raw.split_once('\t').unwrap_or((raw, ""));
+	let raw = raw.trim();
+	if raw == "/dev/null" || timestamp.starts_with("1970-01-01 00:00:00") {
+		return None;
+	}
+	let path = Path::new(raw);
+	let mut components = path.components();
+	components.next();
+	Some(components.as_path().to_path_buf())
+}
+
+/// `@@ -12,7 +12,8 @@ fn foo()` -> (old_start, old_count, new_count)
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+	let ranges = line.strip_prefix("@@ ")?.split(" @@").next()?;
+	let (old, new) = ranges.split_once(' ')?;
+	let parse_range = |range: &str| -> Option<(usize, usize)> {
+		match range.split_once(',') {
+			Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+			None => Some((range.parse().ok()?, 1)),
+		}
+	};
+	let (old_start, old_count) = parse_range(old.strip_prefix('-')?)?;
+	let (_, new_count) = parse_range(new.strip_prefix('+')?)?;
+	Some((old_start, old_count, new_count))
+}
+
+/// `line` without its `\n` or `\r\n`
+fn strip_ending(line: &str) -> &str {
+	match line.strip_suffix('\n') {
+		Some(line) => line.strip_suffix('\r').unwrap_or(line),
+		None => line,
+	}
+}
+
+/// The `\n` or `\r\n` at the end of `line`, empty for a last line without newline
+fn line_ending(line: &str) -> &str {
+	&line[strip_ending(line).len()..]
+}
+
+fn parse_patch(patch_path: &Path, contents: &str) -> Result<Vec<FilePatch>, PatchError> {
+	let malformed = |reason: String| PatchError::Malformed {
+		patch: patch_path.to_path_buf(),
+		reason,
+	};
+	let raw_lines = contents.split_inclusive('\n').collect::<Vec<_>>();
+	let lines = raw_lines
+		.iter()
+		.map(|l| strip_ending(l))
+		.collect::<Vec<_>>();
+	let mut files: Vec<FilePatch> = Vec::new();
+	let mut i = 0;
+	while i < lines.len() {
+		let line = lines[i];
+		if let Some(old) = line.strip_prefix("--- ")
+			&& let Some(new) = lines.get(i + 1).and_then(|l| l.strip_prefix("+++ "))
+		{
+			files.push(FilePatch {
+				old_path: parse_path(old),
+				new_path: parse_path(new),
+				hunks: vec![],
+			});
+			i += 2;
+			continue;
+		}
+		if line.starts_with("@@ ") {
+			let file = files
+				.last_mut()
+				.ok_or_else(|| malformed(format!("hunk before any file header: {line}")))?;
+			let (old_start, mut old_remaining, mut new_remaining) =
+				parse_hunk_header(line).ok_or_else(|| malformed(format!("bad hunk header: {line}")))?;
+			let mut hunk = Hunk {
+				header: line.to_string(),
+				old_start,
+				old_lines: vec![],
+				new_lines: vec![],
+				context: vec![],
+			};
+			// `\ No newline at end of file` applies to the line before it, only the new
+			// side's lines keep their endings
+			let mark_missing_newline = |hunk: &mut Hunk, previous: &str| {
+				if (previous.starts_with('+') || previous.starts_with(' '))
+					&& let Some(last) = hunk.new_lines.last_mut()
+				{
+					last.truncate(strip_ending(last).len());
+				}
+			};
+			i += 1;
+			while old_remaining > 0 || new_remaining > 0 {
+				let Some(raw) = raw_lines.get(i) else {
+					return Err(malformed(format!("truncated hunk {}", hunk.header)));
+				};
+				// some editors strip the trailing space of empty context lines
+				let (tag, text) = if lines[i].is_empty() {
+					(' ', *raw)
+				} else {
+					(raw.chars().next().unwrap(), &raw[1..])
+				};
+				match tag {
+					' ' => {
+						hunk.context.push(Some(hunk.old_lines.len()));
+						hunk.old_lines.push(strip_ending(text).to_string());
+						hunk.new_lines.push(text.to_string());
+						old_remaining = old_remaining.saturating_sub(1);
+						new_remaining = new_remaining.saturating_sub(1);
+					}
+					'-' => {
+						hunk.old_lines.push(strip_ending(text).to_string());
+						old_remaining = old_remaining.saturating_sub(1);
+					}
+					'+' => {
+						hunk.context.push(None);
+						hunk.new_lines.push(text.to_string());
+						new_remaining = new_remaining.saturating_sub(1);
+					}
+					'\\' => mark_missing_newline(&mut hunk, lines[i - 1]),
+					_ => {
+						return Err(malformed(format!(
+							"unexpected line in hunk {}",
+							hunk.header
+						)));
+					}
+				}
+				i += 1;
+			}
+			if let Some(marker) = lines.get(i)
+				&& marker.starts_with('\\')
+			{
+				mark_missing_newline(&mut hunk, lines[i - 1]);
+				i += 1;
+			}
+			file.hunks.push(hunk);
+			continue;
+		}
+		i += 1;
+	}
+	if files.is_empty() {
+		return Err(malformed("no file headers found".to_string()));
+	}
+	Ok(files)
+}
+
+fn find_hunk(lines: &[String], hunk: &Hunk, expected: usize) -> Option<usize> {
+	let len = hunk.old_lines.len();
+	if len > lines.len() {
+		return None;
+	}
+	let last_start = lines.len() - len;
+	let matches_at = |pos: usize| {
+		pos <= last_start
+			&& lines[pos..pos + len]
+				.iter()
+				.zip(&hunk.old_lines)
+				.all(|(line, old)| strip_ending(line) == old)
+	};
+	let expected = expected.min(last_start);
+	(0..=last_start.max(expected)).find_map(|distance| {
+		let after = expected + distance;
+		if matches_at(after) {
+			return Some(after);
+		}
+		let before = expected.checked_sub(distance)?;
+		matches_at(before).then_some(before)
+	})
+}
+
+/// Applies `patches` in order to the tree at `dir`. Every patch is applied as a whole:
+/// if any hunk fails nothing from that patch is written and all failing hunks are reported.
+/// Patched files keep their line endings, added lines get the ending of the file's first line.
+pub fn apply_patches(dir: &Path, patches: &[PathBuf]) -> Result<(), PatchError> {
+	for patch_path in patches {
+		let contents = std::fs::read_to_string(patch_path)?;
+		let file_patches = parse_patch(patch_path, &contents)?;
+		// None for deletions
+		let mut results: BTreeMap<PathBuf, Option<PatchedFile>> = BTreeMap::new();
+		let mut failures = Vec::new();
+
+		for file_patch in file_patches {
+			let Some(target) = file_patch.new_path.clone().or(file_patch.old_path.clone()) else {
+				continue;
+			};
+			let PatchedFile { mut lines, ending } = match results.remove(&target).flatten() {
+				Some(file) => file,
+				None if file_patch.old_path.is_none() => PatchedFile {
+					lines: vec![],
+					ending: None,
+				},
+				None => {
+					let original = std::fs::read_to_string(dir.join(&target))?;
+					let lines = original
+						.split_inclusive('\n')
+						.map(str::to_string)
+						.collect::<Vec<_>>();
+					let ending = match lines.first().map(|l| line_ending(l)) {
+						Some("\r\n") => "\r\n",
+						_ => "\n",
+					};
+					PatchedFile {
+						lines,
+						ending: Some(ending),
+					}
+				}
+			};
+
+			let mut shift = 0isize;
+			for (index, hunk) in file_patch.hunks.iter().enumerate() {
+				// for pure insertions old_start is the line *after which* to insert
+				let old_start = if hunk.old_lines.is_empty() {
+					hunk.old_start
+				} else {
+					hunk.old_start.saturating_sub(1)
+				};
+				let expected = (old_start as isize + shift).max(0) as usize;
+				match find_hunk(&lines, hunk, expected) {
+					Some(pos) => {
+						let removed = hunk.old_lines.len();
+						// context lines stay as they are, added ones get the file's ending
+						let new_lines = hunk
+							.new_lines
+							.iter()
+							.zip(&hunk.context)
+							.map(|(line, context)| match (context, ending) {
+								_ if line_ending(line).is_empty() => line.clone(),
+								(Some(old), _) => lines[pos + old].clone(),
+								(None, Some(ending)) => format!("{}{ending}", strip_ending(line)),
+								(None, None) => line.clone(),
+							})
+							.collect::<Vec<_>>();
+						lines.splice(pos..pos + removed, new_lines);
+						shift =
+							pos as isize - old_start as isize + hunk.new_lines.len() as isize - removed as isize;
+					}
+					None => failures.push(HunkFailure {
+						file: target.clone(),
+						hunk: index + 1,
+						header: hunk.header.clone(),
+					}),
+				}
+			}
+			let deleted = file_patch.new_path.is_none() && lines.is_empty();
+			results.insert(target, (!deleted).then_some(PatchedFile { lines, ending }));
+		}
+
+		if !failures.is_empty() {
+			return Err(PatchError::HunksFailed {
+				patch: patch_path.clone(),
+				failures,
+			});
+		}
+		for (target, result) in results {
+			let path = dir.join(&target);
+			match result {
+				Some(PatchedFile { lines, .. }) => {
+					if let Some(parent) = path.parent() {
+						std::fs::create_dir_all(parent)?;
+					}
+					std::fs::write(&path, lines.concat())?;
+				}
+				None => std::fs::remove_file(&path)?,
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+
+	const ORIGINAL: &str = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+
+	/// Writes `files` into a fresh tree and applies `patch` to it
+	fn apply(files: &[(&str, &str)], patch: &str) -> (TempDir, Result<(), PatchError>) {
+		let dir = TempDir::new();
+		let tree = dir.path().join("tree");
+		for (path, contents) in files {
+			let path = tree.join(path);
+			std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+			std::fs::write(path, contents).unwrap();
+		}
+		std::fs::create_dir_all(&tree).unwrap();
+		let patch_path = dir.path().join("0001-test.patch");
+		std::fs::write(&patch_path, patch).unwrap();
+		let result = apply_patches(&tree, &[patch_path]);
+		(dir, result)
+	}
+
+	fn read(dir: &TempDir, path: &str) -> String {
+		std::fs::read_to_string(dir.path().join("tree").join(path)).unwrap()
+	}
+
+	const TWO_HUNKS: &str = "\
+--- a/numbers
++++ b/numbers
+@@ -2,3 +2,3 @@
+ two
+-three
++THREE
+ four
+@@ -8,3 +8,4 @@
+ eight
+ nine
++nine and a half
+ ten
+";
+
+	#[test]
+	fn applies_at_the_recorded_lines() {
+		let (dir, result) = apply(&[("numbers", ORIGINAL)], TWO_HUNKS);
+		result.unwrap();
+		assert_eq!(
+			read(&dir, "numbers"),
+			"one\ntwo\nTHREE\nfour\nfive\nsix\nseven\neight\nnine\nnine and a half\nten\n"
+		);
+	}
+
+	#[test]
+	fn finds_moved_hunks() {
+		// lines added above the first hunk and removed between the two
+		let moved = "zero\nhalf\none\ntwo\nthree\nfour\nfive\nseven\neight\nnine\nten\n";
+		let (dir, result) = apply(&[("numbers", moved)], TWO_HUNKS);
+		result.unwrap();
+		assert_eq!(
+			read(&dir, "numbers"),
+			"zero\nhalf\none\ntwo\nTHREE\nfour\nfive\nseven\neight\nnine\nnine and a half\nten\n"
+		);
+	}
+
+	#[test]
+	fn failing_hunks_leave_the_tree_alone() {
+		// the second hunk's context is gone, the first one applies but isn't written
+		let changed = ORIGINAL.replace("nine", "NINE");
+		let (dir, result) = apply(&[("numbers", &changed)], TWO_HUNKS);
+		let Err(PatchError::HunksFailed { failures, .. }) = result else {
+			panic!("the patch should not apply: {result:?}");
+		};
+		assert_eq!(failures.len(), 1);
+		assert_eq!(failures[0].file, Path::new("numbers"));
+		assert_eq!(failures[0].hunk, 2);
+		assert_eq!(failures[0].header, "@@ -8,3 +8,4 @@");
+		assert_eq!(read(&dir, "numbers"), changed);
+	}
+
+	#[test]
+	fn creates_and_deletes_files() {
+		let patch = "\
+diff --git a/old b/old
+deleted file mode 100644
+--- a/old
++++ /dev/null
+@@ -1,2 +0,0 @@
+-going
+-away
+diff --git a/dir/new b/dir/new
+new file mode 100644
+--- /dev/null
++++ b/dir/new
+@@ -0,0 +1,2 @@
++brand
++new
+";
+		let (dir, result) = apply(&[("old", "going\naway\n")], patch);
+		result.unwrap();
+		assert!(!dir.path().join("tree/old").exists());
+		assert_eq!(read(&dir, "dir/new"), "brand\nnew\n");
+	}
+
+	#[test]
+	fn missing_newline_at_the_end() {
+		let patch = "\
+--- a/file
++++ b/file
+@@ -1,2 +1,2 @@
+ first
+-last
+\\ No newline at end of file
++last, with a newline
+";
+		let (dir, result) = apply(&[("file", "first\nlast")], patch);
+		result.unwrap();
+		assert_eq!(read(&dir, "file"), "first\nlast, with a newline\n");
+
+		let patch = "\
+--- a/file
++++ b/file
+@@ -1,2 +1,2 @@
+ first
+-last
++last, without one
+\\ No newline at end of file
+";
+		let (dir, result) = apply(&[("file", "first\nlast\n")], patch);
+		result.unwrap();
+		assert_eq!(read(&dir, "file"), "first\nlast, without one");
+	}
+
+	#[test]
+	fn keeps_line_endings() {
+		// a CRLF file patched with LF lines, like a patch that went through git's autocrlf
+		let patch = "\
+--- a/dos.txt
++++ b/dos.txt
+@@ -1,3 +1,4 @@
+ one
+-two
++TWO
++two and a half
+ three
+";
+		let mixed = "one\r\ntwo\r\nthree\n";
+		let (dir, result) = apply(&[("dos.txt", mixed)], patch);
+		result.unwrap();
+		assert_eq!(
+			read(&dir, "dos.txt"),
+			"one\r\nTWO\r\ntwo and a half\r\nthree\n"
+		);
+
+		// and the other way around, a CRLF patch for an LF file
+		let patch = patch.replace("dos.txt", "unix.txt").replace('\n', "\r\n");
+		let (dir, result) = apply(&[("unix.txt", "one\ntwo\nthree\n")], &patch);
+		result.unwrap();
+		assert_eq!(read(&dir, "unix.txt"), "one\nTWO\ntwo and a half\nthree\n");
+
+		let patch = "--- /dev/null\r\n+++ b/new.bat\r\n@@ -0,0 +1,2 @@\r\n+@echo off\r\n+exit /b 0\r\n";
+		let (dir, result) = apply(&[], patch);
+		result.unwrap();
+		assert_eq!(read(&dir, "new.bat"), "@echo off\r\nexit /b 0\r\n");
+	}
+
+	#[test]
+	fn rejects_malformed_patches() {
+		let (_, result) = apply(&[("file", "")], "not a patch\n");
+		assert!(matches!(result, Err(PatchError::Malformed { .. })));
+		let truncated = "--- a/file\n+++ b/file\n@@ -1,3 +1,3 @@\n one\n";
+		let (_, result) = apply(&[("file", "one\ntwo\nthree\n")], truncated);
+		assert!(matches!(result, Err(PatchError::Malformed { .. })));
+	}
+}
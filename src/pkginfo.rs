@@ -0,0 +1,91 @@
+use std::{
+	io::{self, Read},
+	path::Path,
+};
+
+/// The fields hyprpacker cares about from a package's `.PKGINFO`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PkgInfo {
+	pub pkgname: String,
+	pub pkgbase: Option<String>,
+	/// Full version as written by makepkg: `[epoch:]pkgver-pkgrel`
+	pub pkgver: String,
+	pub packager: Option<String>,
+	/// Unix timestamp, `SOURCE_DATE_EPOCH` when the build had one
+	pub builddate: Option<u64>,
+}
+
+impl PkgInfo {
+	pub fn parse(contents: &str) -> Option<Self> {
+		let mut pkgname = None;
+		let mut pkgbase = None;
+		let mut pkgver = None;
+		let mut packager = None;
+		let mut builddate = None;
+
+		for line in contents.lines() {
+			let Some((key, value)) = line.split_once(" = ") else {
+				continue;
+			};
+			let value = value.trim().trim_matches('"').to_string();
+			match key.trim() {
+				"pkgname" => pkgname = Some(value),
+				"pkgbase" => pkgbase = Some(value),
+				"pkgver" => pkgver = Some(value),
+				"packager" => packager = Some(value),
+				"builddate" => builddate = value.parse().ok(),
+				_ => {}
+			}
+		}
+
+		Some(PkgInfo {
+			pkgname: pkgname?,
+			pkgbase,
+			pkgver: pkgver?,
+			packager,
+			builddate,
+		})
+	}
+
+	/// Reads `.PKGINFO` straight out of a `.pkg.tar.zst` without unpacking the rest
+	pub fn read_from_package(path: &Path) -> io::Result<Self> {
+		let decoder = zstd::Decoder::new(std::fs::File::open(path)?)?;
+		let mut archive = tar::Archive::new(decoder);
+		for entry in archive.entries()? {
+			let mut entry = entry?;
+			if entry.path()?.as_os_str() != ".PKGINFO" {
+				continue;
+			}
+			let mut contents = String::new();
+			entry.read_to_string(&mut contents)?;
+			return Self::parse(&contents).ok_or_else(|| {
+				io::Error::new(
+					io::ErrorKind::InvalidData,
+					format!("malformed .PKGINFO in {}", path.display()),
+				)
+			});
+		}
+		Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("no .PKGINFO in {}", path.display()),
+		))
+	}
+
+	/// Whether this is the detached debug symbols package makepkg splits off (`<pkgbase>-debug`)
+	pub fn is_debug(&self) -> bool {
+		match &self.pkgbase {
+			Some(pkgbase) => self.pkgname == format!("{pkgbase}-debug"),
+			None => self.pkgname.ends_with("-debug"),
+		}
+	}
+
+	/// Whether a free-form manifest version refers to this package's version,
+	/// either exactly or without the `-pkgrel` suffix
+	pub fn version_matches(&self, version: &str) -> bool {
+		self.pkgver == version
+			|| self
+				.pkgver
+				.rsplit_once('-')
+				.is_some_and(|(without_pkgrel, _)| without_pkgrel == version)
+	}
+}
@@ -5,6 +5,23 @@ use std::{
 
 use colored::Colorize;
 
+/// Lines of a child's output like [`BufRead::lines`], but invalid UTF-8 is replaced instead
+/// of ending the stream, which would leave the child blocked on a full pipe
+pub fn lossy_lines(mut reader: impl BufRead) -> impl Iterator<Item = String> {
+	let mut buf = Vec::new();
+	std::iter::from_fn(move || {
+		buf.clear();
+		match reader.read_until(b'\n', &mut buf) {
+			Ok(0) | Err(_) => None,
+			Ok(_) => {
+				let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+				let line = line.strip_suffix(b"\r").unwrap_or(line);
+				Some(String::from_utf8_lossy(line).into_owned())
+			}
+		}
+	})
+}
+
 /// Runs commands but adds a tag to each log line the process prints to the stdout/stderr
 pub fn run_command_with_tag(
 	mut command: Command,
@@ -19,7 +36,7 @@ pub fn run_command_with_tag(
 	std::thread::scope(|s| {
 		s.spawn(|| {
 			let buf_reader = BufReader::new(stderr);
-			for line in buf_reader.lines().filter_map(Result::ok) {
+			for line in lossy_lines(buf_reader) {
 				let line = line
 					.replace("\r\n", "\n")
 					.replace("\r", &format!("\r{tag}"))
@@ -29,7 +46,7 @@ pub fn run_command_with_tag(
 		});
 		s.spawn(|| {
 			let buf_reader = BufReader::new(stdout);
-			for line in buf_reader.lines().filter_map(Result::ok) {
+			for line in lossy_lines(buf_reader) {
 				let line = line
 					.replace("\r\n", "\n")
 					.replace("\r", &format!("\r{tag}"))
@@ -41,3 +58,15 @@ pub fn run_command_with_tag(
 	let status = child.wait()?;
 	Ok(status)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lossy_lines_keep_reading_after_invalid_utf8() {
+		let output: &[u8] = b"first\n\xff\xfe broken\r\nlast";
+		let lines = lossy_lines(output).collect::<Vec<_>>();
+		assert_eq!(lines, ["first", "\u{fffd}\u{fffd} broken", "last"]);
+	}
+}
@@ -24,10 +24,10 @@ pub fn reexec_with_elevation() -> io::Result<Infallible> {
 					exit(0);
 				} else {
 					// command ran but returned non-zero -> treat as error
-					return Err(io::Error::new(
-						io::ErrorKind::Other,
-						format!("{} returned non-zero exit code", cmd),
-					));
+					return Err(io::Error::other(format!(
+						"{} returned non-zero exit code",
+						cmd
+					)));
 				}
 			}
 			Err(e) => {
@@ -88,13 +88,10 @@ fn try_run_with(cmd: &str, exe: &std::path::Path, args: &[String]) -> io::Result
 			.spawn()
 	};
 
-	let mut child = match child {
-		Ok(c) => c,
-		Err(e) => return Err(e),
-	};
+	let mut child = child?;
 
 	let status = child.wait()?;
-	return Ok(status.success());
+	Ok(status.success())
 }
 
 /// Ensure the current process runs as root. If already root, returns normally.
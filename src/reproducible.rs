@@ -0,0 +1,28 @@
+//! `SOURCE_DATE_EPOCH` handling, see <https://reproducible-builds.org/specs/source-date-epoch/>
+use std::{process::Command, sync::OnceLock};
+
+use crate::manifest::Manifest;
+
+/// Commit time of HEAD in the current directory's git repository
+fn git_commit_time() -> Option<u64> {
+	let output = Command::new("git")
+		.args(["log", "-1", "--format=%ct"])
+		.output()
+		.ok()?;
+	if !output.status.success() {
+		return None;
+	}
+	String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+}
+
+impl Manifest {
+	/// The timestamp every build and the image should use instead of the current time:
+	/// `source_date_epoch` from the manifest, or the time of the last git commit.
+	/// `None` outside of a git repository, builds then keep using the current time.
+	pub fn source_date_epoch(&self) -> Option<u64> {
+		static GIT_COMMIT_TIME: OnceLock<Option<u64>> = OnceLock::new();
+		self
+			.source_date_epoch
+			.or_else(|| *GIT_COMMIT_TIME.get_or_init(git_commit_time))
+	}
+}
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Deserializer};
+
 pub fn human_readable_size(bytes: u64) -> String {
 	const KB: u64 = 1024;
 	const MB: u64 = KB * 1024;
@@ -12,3 +14,94 @@ pub fn human_readable_size(bytes: u64) -> String {
 		b => format!("{} bytes", b),
 	}
 }
+
+/// Parses sizes like `512M`, `20G` or `1.5T` (powers of 1024), plain numbers are bytes
+pub fn parse_size(size: &str) -> Option<u64> {
+	let size = size.trim();
+	let (number, unit) = size.split_at(
+		size
+			.find(|c: char| !c.is_ascii_digit() && c != '.')
+			.unwrap_or(size.len()),
+	);
+	let unit = unit.trim().to_ascii_uppercase();
+	let unit = unit.strip_suffix('B').unwrap_or(&unit);
+	let unit = match unit.strip_suffix('I') {
+		Some(prefix) if !prefix.is_empty() => prefix,
+		_ => unit,
+	};
+	let multiplier: u64 = match unit {
+		"" => 1,
+		"K" => 1024,
+		"M" => 1024 * 1024,
+		"G" => 1024 * 1024 * 1024,
+		"T" => 1024 * 1024 * 1024 * 1024,
+		_ => return None,
+	};
+	let number = number.parse::<f64>().ok()?;
+	let bytes = number * multiplier as f64;
+	(bytes < u64::MAX as f64).then_some(bytes as u64)
+}
+
+/// Manifest sizes, either a number of bytes or a string for [`parse_size`]
+pub fn deserialize_size<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Size {
+		Bytes(u64),
+		Text(String),
+	}
+	match Option::<Size>::deserialize(deserializer)? {
+		None => Ok(None),
+		Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+		Some(Size::Text(text)) => parse_size(&text).map(Some).ok_or_else(|| {
+			serde::de::Error::custom(format!("invalid size {text:?}, expected e.g. \"50G\""))
+		}),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn units() {
+		assert_eq!(parse_size("0"), Some(0));
+		assert_eq!(parse_size("4096"), Some(4096));
+		assert_eq!(parse_size("12B"), Some(12));
+		assert_eq!(parse_size("1K"), Some(1024));
+		assert_eq!(parse_size("512M"), Some(512 << 20));
+		assert_eq!(parse_size("20G"), Some(20 << 30));
+		assert_eq!(parse_size("2T"), Some(2 << 40));
+		assert_eq!(parse_size("1.5T"), Some(3 << 39));
+		assert_eq!(parse_size(".5K"), Some(512));
+		assert_eq!(parse_size("0.1K"), Some(102));
+	}
+
+	#[test]
+	fn spelling() {
+		for size in [
+			"50G", "50g", "50GB", "50gb", "50GiB", "50gib", " 50 G ", "50 GiB",
+		] {
+			assert_eq!(parse_size(size), Some(50 << 30), "{size:?}");
+		}
+	}
+
+	#[test]
+	fn invalid() {
+		for size in [
+			"", " ", "G", ".", "1.2.3G", "-1G", "1e3", "1X", "1 G B", "1GG", "1IB", "1BI", "1I", "1KiBB",
+			"inf", "NaN",
+		] {
+			assert_eq!(parse_size(size), None, "{size:?}");
+		}
+	}
+
+	#[test]
+	fn overflow() {
+		assert_eq!(parse_size("16777215T"), Some(16777215 << 40));
+		assert_eq!(parse_size("16777216T"), None);
+		assert_eq!(parse_size("99999999999999999999"), None);
+	}
+}
@@ -1,23 +1,31 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use colored::Colorize;
 use serde::Deserialize;
 
 use crate::{
-	fs_utils::copy_dir_all,
+	cache_root,
+	fs_utils::{self, copy_dir_all, hash_dir_contents},
 	hash::{Sha256Hash, hash_file},
 	manifest::{InvalidSourceError, Package, Source, SourceFetchError},
+	patch::{apply_patches, expand_patch_globs, hash_patches},
 };
 
 #[derive(Debug, Deserialize, Clone)]
 pub enum SourceType {
-	Tarball { url: String, sha256: Sha256Hash },
-	LocalFolder { path: PathBuf },
+	Tarball {
+		url: String,
+		sha256: Sha256Hash,
+	},
+	LocalFolder {
+		#[allow(dead_code)]
+		path: PathBuf,
+	},
 }
 
 impl Package {
-	pub const fn sources_path() -> &'static str {
-		"build/sources"
+	pub fn sources_path() -> PathBuf {
+		cache_root::sources_dir()
 	}
 
 	pub fn create_sources_dir() -> std::io::Result<()> {
@@ -25,9 +33,7 @@ impl Package {
 	}
 
 	pub fn prepared_sources_dir() -> PathBuf {
-		let mut path = PathBuf::from(Self::sources_path());
-		path.push("prepared");
-		path
+		Self::sources_path().join("prepared")
 	}
 
 	pub fn create_prepared_sources_dir() -> std::io::Result<()> {
@@ -53,7 +59,7 @@ impl Package {
 					let repo = repo_url.trim_end_matches(".git");
 					let repo_name = repo
 						.split('/')
-						.last()
+						.next_back()
 						.ok_or(InvalidSourceError::InvalidGitSourceUrl)?;
 					Some(format!("{repo}/-/archive/{rev}/{repo_name}-{rev}.tar.gz"))
 				} else {
@@ -67,19 +73,16 @@ impl Package {
 		}
 	}
 
+	/// Content addressed by the source's sha256, so projects sharing a cache root share downloads
 	pub fn source_tarball_path(&self) -> Result<PathBuf, InvalidSourceError> {
-		let mut path = PathBuf::from(Self::sources_path());
-		let t = self.source_type()?;
-		match t {
-			SourceType::Tarball { .. } => {
-				use std::collections::hash_map::DefaultHasher;
-				use std::hash::{Hash, Hasher};
-
-				let mut hasher = DefaultHasher::new();
-				self.source.hash(&mut hasher);
-				let hash = hasher.finish();
-				path.push(format!("{:x}.tar.gz", hash));
-				Ok(path)
+		match self.source_type()? {
+			SourceType::Tarball { sha256, .. } => {
+				// binary packages keep their extension, build containers look for *.pkg.tar.zst
+				let extension = match self.source {
+					Source::Binary { .. } => "pkg.tar.zst",
+					_ => "tar.gz",
+				};
+				Ok(Self::sources_path().join(format!("{}.{extension}", sha256.as_str().to_lowercase())))
 			}
 			SourceType::LocalFolder { .. } => Err(InvalidSourceError::UnsupportedSourceType),
 		}
@@ -105,41 +108,74 @@ impl Package {
 		}
 	}
 
+	/// Keyed by the source and patches rather than the version, since the version may only be
+	/// known after reading the prepared PKGBUILD
 	pub fn get_package_prepared_dir(&self) -> PathBuf {
+		use std::collections::hash_map::DefaultHasher;
+		use std::hash::{Hash, Hasher};
+
+		let mut hasher = DefaultHasher::new();
+		self.source.hash(&mut hasher);
+		hash_patches(&self.patches, &mut hasher);
+		// the same relative path holds different files in different worktrees
+		if let Source::PkgBuildLocal { path, .. } = &self.source {
+			hash_dir_contents(path, &mut hasher).ok();
+		}
 		let mut d = Self::prepared_sources_dir();
-		d.push(format!("{}-{}", self.name, self.version));
+		d.push(format!("{}-{:x}", self.name, hasher.finish()));
 		d
 	}
 
+	/// Unpacks git sources (or copies local ones that have patches) into the prepared
+	/// dir and applies the package's patches on top. The tree is prepared next to the
+	/// prepared dir and only moved into place once every patch applied, so a failed patch
+	/// never leaves a half patched tree behind for the next run to build. A prepared dir
+	/// that already exists is kept.
 	pub fn prepare_sources(&self) -> Result<PathBuf, SourceFetchError> {
+		// expand first so a bad glob doesn't leave a half prepared tree behind
+		let patches = expand_patch_globs(&self.patches)?;
+		let stage = |unpack: &dyn Fn(&Path) -> Result<PathBuf, SourceFetchError>| {
+			Self::create_prepared_sources_dir()?;
+			let prepared_dir = self.get_package_prepared_dir();
+			// per process, another project may be preparing the same sources in a shared cache
+			let staging_dir =
+				fs_utils::with_suffix(&prepared_dir, &format!(".partial-{}", std::process::id()));
+			if staging_dir.exists() {
+				std::fs::remove_dir_all(&staging_dir)?;
+			}
+			std::fs::create_dir(&staging_dir)?;
+			let result = unpack(&staging_dir).and_then(|tree| {
+				apply_patches(&tree, &patches)?;
+				// the dir is named after the sources and patches, so one that's already there
+				// holds the same tree, and other worktrees may be building from it
+				match std::fs::rename(&tree, &prepared_dir) {
+					Err(_) if prepared_dir.is_dir() => Ok(prepared_dir),
+					result => result.map(|_| prepared_dir).map_err(Into::into),
+				}
+			});
+			std::fs::remove_dir_all(&staging_dir).ok();
+			result
+		};
 		match &self.source {
-			Source::PkgBuildGit { repo_url, rev, .. } => {
-				// Ensure prepared dir exists
-				Self::create_prepared_sources_dir()?;
+			Source::PkgBuildGit { repo_url, rev, .. } => stage(&|staging_dir| {
 				let tarball_path = self.source_tarball_path()?;
-				let prepared_dir = self.get_package_prepared_dir();
-
-				// If already unpacked, skip
-				if prepared_dir.exists() {
-					std::fs::remove_dir_all(&prepared_dir)?;
-				}
-
-				// Unpack tarball
 				let tar_gz = std::fs::File::open(&tarball_path)?;
 				let decompressor = flate2::read::GzDecoder::new(tar_gz);
 				let mut archive = tar::Archive::new(decompressor);
-				let repo_name = repo_url.split('/').last().unwrap().trim_end_matches(".git");
-				let folder_name = format!("{repo_name}-{rev}");
-				let tmp = std::env::temp_dir();
-				std::fs::create_dir_all(&tmp)?;
-				archive.unpack(&tmp)?;
-				let extracted_dir = tmp.join(folder_name);
-				std::fs::rename(&extracted_dir, &prepared_dir).or_else(|_| {
-					copy_dir_all(&extracted_dir, &prepared_dir)?;
-					std::fs::remove_dir_all(&extracted_dir)
-				})?;
-				Ok(prepared_dir)
-			}
+				let repo_name = repo_url
+					.split('/')
+					.next_back()
+					.unwrap()
+					.trim_end_matches(".git");
+				archive.unpack(staging_dir)?;
+				Ok(staging_dir.join(format!("{repo_name}-{rev}")))
+			}),
+			// never patch the user's own folder, work on a copy
+			Source::PkgBuildLocal { path, .. } if !patches.is_empty() => stage(&|staging_dir| {
+				let tree = staging_dir.join("tree");
+				copy_dir_all(path, &tree)?;
+				Ok(tree)
+			}),
 			Source::PkgBuildLocal { path, .. } => Ok(PathBuf::from(path)),
 			Source::Binary { .. } => self
 				.source_tarball_path()
@@ -149,7 +185,7 @@ impl Package {
 	pub fn fetch_sources(&self) -> Result<(), SourceFetchError> {
 		let t = self.source_type()?;
 		match t {
-			SourceType::Tarball { url, .. } => {
+			SourceType::Tarball { url, sha256 } => {
 				let tarball_path = self.source_tarball_path()?;
 				let needs_download = self.assert_source_tarball_matches_hash().is_err();
 				if needs_download {
@@ -163,8 +199,19 @@ impl Package {
 						.call()
 						.map_err(SourceFetchError::FetchError)?;
 					let mut reader = resp.into_body().into_reader();
-					let mut file = std::fs::File::create(&tarball_path).map_err(SourceFetchError::Io)?;
+					// only verified downloads get their content address, other projects may be reading it
+					let partial = tarball_path.with_extension(format!("{}.partial", self.name));
+					let mut file = std::fs::File::create(&partial).map_err(SourceFetchError::Io)?;
 					std::io::copy(&mut reader, &mut file).map_err(SourceFetchError::Io)?;
+					let actual = hash_file(&partial)?;
+					if actual != sha256 {
+						std::fs::remove_file(&partial)?;
+						return Err(SourceFetchError::HashMismatch {
+							expected: sha256,
+							actual,
+						});
+					}
+					std::fs::rename(&partial, &tarball_path)?;
 				}
 				self.assert_source_tarball_matches_hash()?;
 				Ok(())
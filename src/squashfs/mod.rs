@@ -0,0 +1,42 @@
+//! SquashFS 4.0 images, see <https://dr-emann.github.io/squashfs/> for the format
+mod reader;
+mod writer;
+
+pub use reader::{FileLayout, Inode, InodeKind, ReadError, Reader, symlink_target};
+pub use writer::{Overrides, WriteError, WriterOptions, encode_device, write_image};
+
+const MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: usize = 96;
+const COMPRESSION_ZSTD: u16 = 6;
+
+/// Uncompressed size of a metadata block
+const METADATA_SIZE: usize = 8192;
+/// Set in a metadata block's header when it's stored uncompressed
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Set in a data block's or fragment's size when it's stored uncompressed
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+
+const NO_FRAGMENT: u32 = u32::MAX;
+const NO_XATTRS: u32 = u32::MAX;
+const NO_TABLE: u64 = u64::MAX;
+
+const FLAG_NO_XATTRS: u16 = 0x0200;
+const FLAG_DUPLICATES: u16 = 0x0040;
+
+const BASIC_DIR: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const BASIC_SYMLINK: u16 = 3;
+const BASIC_BLOCK_DEV: u16 = 4;
+const BASIC_CHAR_DEV: u16 = 5;
+const BASIC_FIFO: u16 = 6;
+const BASIC_SOCKET: u16 = 7;
+/// Extended inode types are the basic ones plus this
+const EXTENDED: u16 = 7;
+
+/// Directory entries per directory header
+const DIR_HEADER_ENTRIES: usize = 256;
+const MAX_NAME_LEN: usize = 256;
+
+const XATTR_USER: u16 = 0;
+const XATTR_TRUSTED: u16 = 1;
+const XATTR_SECURITY: u16 = 2;
@@ -0,0 +1,825 @@
+use std::{
+	collections::HashMap,
+	ffi::{OsStr, OsString},
+	fs::File,
+	io::{self, Read, Seek, SeekFrom, Write},
+	os::unix::ffi::{OsStrExt, OsStringExt},
+	path::{Component, Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::*;
+
+const COMPRESSION_GZIP: u16 = 1;
+/// Set in xattr keys whose value is stored elsewhere, as a reference
+const XATTR_OUT_OF_LINE: u16 = 0x0100;
+
+#[derive(Debug, Error)]
+pub enum ReadError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("not a squashfs 4.0 image")]
+	NotSquashfs,
+	#[error("unsupported compression {0}, only gzip and zstd images can be read")]
+	UnsupportedCompression(&'static str),
+	#[error("corrupted image: {0}")]
+	Corrupt(&'static str),
+	#[error("no such file or directory in the image: {0}")]
+	NotFound(String),
+	#[error("not a directory in the image: {0}")]
+	NotADirectory(String),
+	#[error("too many levels of symbolic links in the image: {0}")]
+	SymlinkLoop(String),
+}
+
+/// Where a directory's listing is in the directory table
+#[derive(Debug, Clone)]
+pub struct DirListing {
+	block: u32,
+	offset: u16,
+	/// Size of the listing plus 3, like the kernel expects
+	size: u32,
+}
+
+/// Where a file's contents are
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+	blocks_start: u64,
+	size: u64,
+	block_sizes: Vec<u32>,
+	fragment: u32,
+	fragment_offset: u32,
+}
+
+/// A file's tail, packed with other tails into a fragment block
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentTail {
+	pub index: u32,
+	/// Where the tail is in the uncompressed fragment block
+	pub offset: u32,
+	pub len: u64,
+	/// What the whole fragment block takes in the image
+	pub block_stored_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum InodeKind {
+	Dir(DirListing),
+	File(FileLayout),
+	Symlink(Vec<u8>),
+	/// Old 32 bit encoding of the device number
+	BlockDev(u32),
+	CharDev(u32),
+	Fifo,
+	Socket,
+}
+
+#[derive(Debug, Clone)]
+pub struct Inode {
+	pub kind: InodeKind,
+	/// Permission bits only
+	pub mode: u16,
+	pub uid: u32,
+	pub gid: u32,
+	/// Modification time, seconds since the epoch
+	pub mtime: u32,
+	xattrs: u32,
+}
+
+impl Inode {
+	/// File size, 0 for anything but files and symlinks
+	pub fn size(&self) -> u64 {
+		match &self.kind {
+			InodeKind::File(file) => file.size,
+			InodeKind::Symlink(target) => target.len() as u64,
+			_ => 0,
+		}
+	}
+
+	/// `ls -l` style type character
+	pub fn type_char(&self) -> char {
+		match self.kind {
+			InodeKind::Dir(_) => 'd',
+			InodeKind::File(_) => '-',
+			InodeKind::Symlink(_) => 'l',
+			InodeKind::BlockDev(_) => 'b',
+			InodeKind::CharDev(_) => 'c',
+			InodeKind::Fifo => 'p',
+			InodeKind::Socket => 's',
+		}
+	}
+}
+
+/// Reads the fixed size little endian fields of an on-disk structure
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+	fn take(&mut self, n: usize) -> Result<&[u8], ReadError> {
+		if self.0.len() < n {
+			return Err(ReadError::Corrupt("truncated metadata"));
+		}
+		let (taken, rest) = self.0.split_at(n);
+		self.0 = rest;
+		Ok(taken)
+	}
+
+	fn u16(&mut self) -> Result<u16, ReadError> {
+		Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+	}
+
+	fn u32(&mut self) -> Result<u32, ReadError> {
+		Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+	}
+
+	fn u64(&mut self) -> Result<u64, ReadError> {
+		Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+	}
+}
+
+struct Superblock {
+	block_size: u32,
+	fragment_count: u32,
+	compression: u16,
+	id_count: u16,
+	root_inode: u64,
+	bytes_used: u64,
+	id_table_start: u64,
+	xattr_id_table_start: u64,
+	inode_table_start: u64,
+	directory_table_start: u64,
+	fragment_table_start: u64,
+}
+
+/// Reads squashfs images written by [`super::write_image`] or `mksquashfs`, without
+/// mounting them
+pub struct Reader {
+	file: File,
+	superblock: Superblock,
+	ids: Vec<u32>,
+	/// Start and size of every fragment block
+	fragments: Vec<(u64, u32)>,
+	/// Decompressed metadata blocks and where the next one starts, by position
+	metadata_cache: HashMap<u64, (Vec<u8>, u64)>,
+	/// The last fragment block read, by index
+	fragment_cache: Option<(u32, Vec<u8>)>,
+}
+
+impl Reader {
+	pub fn open(path: &Path) -> Result<Self, ReadError> {
+		let mut file = File::open(path)?;
+		let mut bytes = [0u8; SUPERBLOCK_SIZE];
+		file
+			.read_exact(&mut bytes)
+			.map_err(|_| ReadError::NotSquashfs)?;
+		let mut fields = Fields(&bytes);
+		if fields.u32()? != MAGIC {
+			return Err(ReadError::NotSquashfs);
+		}
+		let _inode_count = fields.u32()?;
+		let _creation_time = fields.u32()?;
+		let block_size = fields.u32()?;
+		let fragment_count = fields.u32()?;
+		let compression = fields.u16()?;
+		let _block_log = fields.u16()?;
+		let _flags = fields.u16()?;
+		let id_count = fields.u16()?;
+		let (major, _minor) = (fields.u16()?, fields.u16()?);
+		if major != 4 {
+			return Err(ReadError::NotSquashfs);
+		}
+		let superblock = Superblock {
+			block_size,
+			fragment_count,
+			compression,
+			id_count,
+			root_inode: fields.u64()?,
+			bytes_used: fields.u64()?,
+			id_table_start: fields.u64()?,
+			xattr_id_table_start: fields.u64()?,
+			inode_table_start: fields.u64()?,
+			directory_table_start: fields.u64()?,
+			fragment_table_start: fields.u64()?,
+		};
+		match compression {
+			COMPRESSION_GZIP | COMPRESSION_ZSTD => {}
+			2 => return Err(ReadError::UnsupportedCompression("lzma")),
+			3 => return Err(ReadError::UnsupportedCompression("lzo")),
+			4 => return Err(ReadError::UnsupportedCompression("xz")),
+			5 => return Err(ReadError::UnsupportedCompression("lz4")),
+			_ => return Err(ReadError::Corrupt("unknown compression")),
+		}
+
+		let mut reader = Self {
+			file,
+			superblock,
+			ids: vec![],
+			fragments: vec![],
+			metadata_cache: HashMap::new(),
+			fragment_cache: None,
+		};
+		let id_table = reader.read_lookup_table(
+			reader.superblock.id_table_start,
+			reader.superblock.id_count as usize * 4,
+		)?;
+		reader.ids = id_table
+			.chunks_exact(4)
+			.map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+			.collect();
+		if reader.superblock.fragment_count > 0 {
+			let fragment_table = reader.read_lookup_table(
+				reader.superblock.fragment_table_start,
+				reader.superblock.fragment_count as usize * 16,
+			)?;
+			reader.fragments = fragment_table
+				.chunks_exact(16)
+				.map(|entry| {
+					let mut fields = Fields(entry);
+					(fields.u64().unwrap(), fields.u32().unwrap())
+				})
+				.collect();
+		}
+		Ok(reader)
+	}
+
+	fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, ReadError> {
+		let corrupt = |_| ReadError::Corrupt("block doesn't decompress");
+		match self.superblock.compression {
+			COMPRESSION_ZSTD => zstd::bulk::decompress(data, max_size).map_err(corrupt),
+			_ => {
+				let mut decompressed = Vec::with_capacity(max_size);
+				flate2::read::ZlibDecoder::new(data)
+					.take(max_size as u64)
+					.read_to_end(&mut decompressed)
+					.map_err(corrupt)?;
+				Ok(decompressed)
+			}
+		}
+	}
+
+	fn read_at(&mut self, position: u64, len: usize) -> Result<Vec<u8>, ReadError> {
+		if position + len as u64 > self.superblock.bytes_used {
+			return Err(ReadError::Corrupt("reference past the end of the image"));
+		}
+		let mut data = vec![0u8; len];
+		self.file.seek(SeekFrom::Start(position))?;
+		self.file.read_exact(&mut data)?;
+		Ok(data)
+	}
+
+	/// The metadata block at `position` and where the next one starts
+	fn metadata_block(&mut self, position: u64) -> Result<(&[u8], u64), ReadError> {
+		if !self.metadata_cache.contains_key(&position) {
+			let header = u16::from_le_bytes(self.read_at(position, 2)?.try_into().unwrap());
+			let size = (header & !METADATA_UNCOMPRESSED) as usize;
+			let data = self.read_at(position + 2, size)?;
+			let data = if header & METADATA_UNCOMPRESSED != 0 {
+				data
+			} else {
+				self.decompress(&data, METADATA_SIZE)?
+			};
+			let next = position + 2 + size as u64;
+			self.metadata_cache.insert(position, (data, next));
+		}
+		let (data, next) = &self.metadata_cache[&position];
+		Ok((data, *next))
+	}
+
+	/// `len` bytes of metadata starting `offset` bytes past the start of the block at
+	/// `position`, which may be in one of the following blocks
+	fn read_metadata(
+		&mut self,
+		mut position: u64,
+		mut offset: usize,
+		len: usize,
+	) -> Result<Vec<u8>, ReadError> {
+		let mut data = Vec::with_capacity(len);
+		while data.len() < len {
+			let (block, next) = self.metadata_block(position)?;
+			if block.is_empty() {
+				return Err(ReadError::Corrupt("empty metadata block"));
+			}
+			if offset < block.len() {
+				let n = (block.len() - offset).min(len - data.len());
+				data.extend_from_slice(&block[offset..offset + n]);
+				offset = 0;
+			} else {
+				offset -= block.len();
+			}
+			position = next;
+		}
+		Ok(data)
+	}
+
+	/// A table of fixed size entries behind a list of metadata block positions
+	fn read_lookup_table(&mut self, lookup_start: u64, len: usize) -> Result<Vec<u8>, ReadError> {
+		if len == 0 {
+			return Ok(vec![]);
+		}
+		let first_block = u64::from_le_bytes(self.read_at(lookup_start, 8)?.try_into().unwrap());
+		self.read_metadata(first_block, 0, len)
+	}
+
+	fn id(&self, index: u16) -> Result<u32, ReadError> {
+		self
+			.ids
+			.get(index as usize)
+			.copied()
+			.ok_or(ReadError::Corrupt("uid or gid index out of bounds"))
+	}
+
+	/// Reads the inode at an inode reference, as found in directory entries
+	pub fn inode(&mut self, reference: u64) -> Result<Inode, ReadError> {
+		let position = self.superblock.inode_table_start + (reference >> 16);
+		let offset = (reference & 0xffff) as usize;
+		let header = self.read_metadata(position, offset, 16)?;
+		let mut fields = Fields(&header);
+		let inode_type = fields.u16()?;
+		let mode = fields.u16()?;
+		let uid = self.id(fields.u16()?)?;
+		let gid = self.id(fields.u16()?)?;
+		let mtime = fields.u32()?;
+		let _number = fields.u32()?;
+
+		let (basic_type, extended) = if inode_type > EXTENDED {
+			(inode_type - EXTENDED, true)
+		} else {
+			(inode_type, false)
+		};
+		let block_size = self.superblock.block_size as u64;
+		let mut read = |len: usize, at: usize| self.read_metadata(position, offset + at, len);
+		let mut xattrs = NO_XATTRS;
+		let kind = match basic_type {
+			BASIC_DIR if extended => {
+				let body = read(24, 16)?;
+				let mut fields = Fields(&body);
+				let _links = fields.u32()?;
+				let size = fields.u32()?;
+				let block = fields.u32()?;
+				let _parent = fields.u32()?;
+				let _index_count = fields.u16()?;
+				let offset = fields.u16()?;
+				xattrs = fields.u32()?;
+				InodeKind::Dir(DirListing {
+					block,
+					offset,
+					size,
+				})
+			}
+			BASIC_DIR => {
+				let body = read(16, 16)?;
+				let mut fields = Fields(&body);
+				let block = fields.u32()?;
+				let _links = fields.u32()?;
+				let size = fields.u16()? as u32;
+				let offset = fields.u16()?;
+				InodeKind::Dir(DirListing {
+					block,
+					offset,
+					size,
+				})
+			}
+			BASIC_FILE => {
+				let fixed = if extended { 40 } else { 16 };
+				let body = read(fixed, 16)?;
+				let mut fields = Fields(&body);
+				let (blocks_start, size, fragment, fragment_offset) = if extended {
+					let blocks_start = fields.u64()?;
+					let size = fields.u64()?;
+					let _sparse = fields.u64()?;
+					let _links = fields.u32()?;
+					let fragment = fields.u32()?;
+					let fragment_offset = fields.u32()?;
+					xattrs = fields.u32()?;
+					(blocks_start, size, fragment, fragment_offset)
+				} else {
+					let blocks_start = fields.u32()? as u64;
+					let fragment = fields.u32()?;
+					let fragment_offset = fields.u32()?;
+					(
+						blocks_start,
+						fields.u32()? as u64,
+						fragment,
+						fragment_offset,
+					)
+				};
+				let block_count = if fragment == NO_FRAGMENT {
+					size.div_ceil(block_size)
+				} else {
+					size / block_size
+				};
+				let sizes = read(block_count as usize * 4, 16 + fixed)?;
+				InodeKind::File(FileLayout {
+					blocks_start,
+					size,
+					block_sizes: sizes
+						.chunks_exact(4)
+						.map(|size| u32::from_le_bytes(size.try_into().unwrap()))
+						.collect(),
+					fragment,
+					fragment_offset,
+				})
+			}
+			BASIC_SYMLINK => {
+				let body = read(8, 16)?;
+				let mut fields = Fields(&body);
+				let _links = fields.u32()?;
+				let target_size = fields.u32()? as usize;
+				let target = read(target_size, 24)?;
+				if extended {
+					xattrs = u32::from_le_bytes(read(4, 24 + target_size)?.try_into().unwrap());
+				}
+				InodeKind::Symlink(target)
+			}
+			BASIC_BLOCK_DEV | BASIC_CHAR_DEV | BASIC_FIFO | BASIC_SOCKET => {
+				let with_device = matches!(basic_type, BASIC_BLOCK_DEV | BASIC_CHAR_DEV);
+				let len = 4 + if with_device { 4 } else { 0 } + if extended { 4 } else { 0 };
+				let body = read(len, 16)?;
+				let mut fields = Fields(&body);
+				let _links = fields.u32()?;
+				let device = if with_device { fields.u32()? } else { 0 };
+				if extended {
+					xattrs = fields.u32()?;
+				}
+				match basic_type {
+					BASIC_BLOCK_DEV => InodeKind::BlockDev(device),
+					BASIC_CHAR_DEV => InodeKind::CharDev(device),
+					BASIC_FIFO => InodeKind::Fifo,
+					_ => InodeKind::Socket,
+				}
+			}
+			_ => return Err(ReadError::Corrupt("unknown inode type")),
+		};
+		Ok(Inode {
+			kind,
+			mode: mode & 0o7777,
+			uid,
+			gid,
+			mtime,
+			xattrs,
+		})
+	}
+
+	pub fn root(&mut self) -> Result<Inode, ReadError> {
+		self.inode(self.superblock.root_inode)
+	}
+
+	/// Names and inode references of a directory's entries, in the image's (sorted) order
+	pub fn read_dir(&mut self, listing: &DirListing) -> Result<Vec<(OsString, u64)>, ReadError> {
+		let position = self.superblock.directory_table_start + listing.block as u64;
+		let data = self.read_metadata(
+			position,
+			listing.offset as usize,
+			listing.size.saturating_sub(3) as usize,
+		)?;
+		let mut fields = Fields(&data);
+		let mut entries = Vec::new();
+		while !fields.0.is_empty() {
+			let count = fields.u32()? + 1;
+			let start = fields.u32()? as u64;
+			let _base = fields.u32()?;
+			for _ in 0..count {
+				let offset = fields.u16()? as u64;
+				let _number_delta = fields.u16()?;
+				let _inode_type = fields.u16()?;
+				let name_size = fields.u16()? as usize + 1;
+				let name = fields.take(name_size)?.to_vec();
+				// names are joined onto paths, these would escape the directory (they can't
+				// be empty, the size is stored minus one)
+				if matches!(&name[..], b"." | b"..") || name.contains(&b'/') || name.contains(&0) {
+					return Err(ReadError::Corrupt("invalid name in a directory"));
+				}
+				if entries
+					.last()
+					.is_some_and(|(last, _): &(OsString, u64)| last.as_bytes() == &name[..])
+				{
+					return Err(ReadError::Corrupt("duplicate name in a directory"));
+				}
+				entries.push((OsString::from_vec(name), (start << 16) | offset));
+			}
+		}
+		Ok(entries)
+	}
+
+	/// The inode at an absolute path like `/usr/bin`, without following symlinks
+	pub fn lookup(&mut self, path: &Path) -> Result<Inode, ReadError> {
+		let mut inode = self.root()?;
+		let mut walked = PathBuf::from("/");
+		for component in path.components() {
+			let name = match component {
+				Component::Normal(name) => name,
+				Component::RootDir | Component::CurDir => continue,
+				Component::ParentDir | Component::Prefix(_) => {
+					return Err(ReadError::NotFound(path.display().to_string()));
+				}
+			};
+			inode = self.child(&inode, &walked, name)?;
+			walked.push(name);
+		}
+		Ok(inode)
+	}
+
+	/// The inode at an absolute path, following symlinks (and `..`) inside the image like
+	/// the kernel would with the image as `/`. Returns the resolved path too
+	pub fn resolve(&mut self, path: &Path) -> Result<(PathBuf, Inode), ReadError> {
+		const MAX_SYMLINKS: usize = 40;
+		let root = self.root()?;
+		// the inodes of the directories walked so far, for `..`
+		let mut parents = Vec::new();
+		let mut inode = root.clone();
+		let mut walked = PathBuf::from("/");
+		let mut pending = path
+			.components()
+			.map(|c| c.as_os_str().to_os_string())
+			.rev()
+			.collect::<Vec<_>>();
+		let mut symlinks = 0;
+		while let Some(name) = pending.pop() {
+			match name.as_bytes() {
+				b"/" | b"." => continue,
+				b".." => {
+					if let Some(parent) = parents.pop() {
+						inode = parent;
+						walked.pop();
+					}
+					continue;
+				}
+				_ => {}
+			}
+			let child = self.child(&inode, &walked, &name)?;
+			let InodeKind::Symlink(target) = &child.kind else {
+				parents.push(std::mem::replace(&mut inode, child));
+				walked.push(name);
+				continue;
+			};
+			symlinks += 1;
+			if symlinks > MAX_SYMLINKS {
+				return Err(ReadError::SymlinkLoop(path.display().to_string()));
+			}
+			let target = symlink_target(target);
+			if target.is_absolute() {
+				parents.clear();
+				inode = root.clone();
+				walked = PathBuf::from("/");
+			}
+			pending.extend(
+				target
+					.components()
+					.map(|c| c.as_os_str().to_os_string())
+					.rev(),
+			);
+		}
+		Ok((walked, inode))
+	}
+
+	/// The entry `name` of the directory `dir`, which is at `path`
+	fn child(&mut self, dir: &Inode, path: &Path, name: &OsStr) -> Result<Inode, ReadError> {
+		let InodeKind::Dir(listing) = &dir.kind else {
+			return Err(ReadError::NotADirectory(path.display().to_string()));
+		};
+		let reference = self
+			.read_dir(listing)?
+			.into_iter()
+			.find(|(entry, _)| entry.as_os_str() == name)
+			.map(|(_, reference)| reference)
+			.ok_or_else(|| ReadError::NotFound(path.join(name).display().to_string()))?;
+		self.inode(reference)
+	}
+
+	/// Calls `f` with the absolute path and inode of everything under `path` (itself
+	/// included), parents before their children
+	pub fn walk(
+		&mut self,
+		path: &Path,
+		f: &mut impl FnMut(&Path, &Inode) -> Result<(), ReadError>,
+	) -> Result<(), ReadError> {
+		let inode = self.lookup(path)?;
+		self.walk_inode(&Path::new("/").join(path), &inode, f)
+	}
+
+	fn walk_inode(
+		&mut self,
+		path: &Path,
+		inode: &Inode,
+		f: &mut impl FnMut(&Path, &Inode) -> Result<(), ReadError>,
+	) -> Result<(), ReadError> {
+		f(path, inode)?;
+		if let InodeKind::Dir(listing) = &inode.kind {
+			for (name, reference) in self.read_dir(listing)? {
+				let child = self.inode(reference)?;
+				self.walk_inode(&path.join(name), &child, f)?;
+			}
+		}
+		Ok(())
+	}
+
+	fn fragment_block(&mut self, index: u32) -> Result<&[u8], ReadError> {
+		if self.fragment_cache.as_ref().map(|(i, _)| *i) != Some(index) {
+			let &(start, size) = self
+				.fragments
+				.get(index as usize)
+				.ok_or(ReadError::Corrupt("fragment index out of bounds"))?;
+			let data = self.read_data_block(start, size)?;
+			self.fragment_cache = Some((index, data));
+		}
+		Ok(&self.fragment_cache.as_ref().unwrap().1)
+	}
+
+	fn read_data_block(&mut self, position: u64, size: u32) -> Result<Vec<u8>, ReadError> {
+		let data = self.read_at(position, (size & !DATA_UNCOMPRESSED) as usize)?;
+		if size & DATA_UNCOMPRESSED != 0 {
+			Ok(data)
+		} else {
+			self.decompress(&data, self.superblock.block_size as usize)
+		}
+	}
+
+	/// Writes a file's contents to `out`
+	pub fn read_file(&mut self, file: &FileLayout, out: &mut impl Write) -> Result<(), ReadError> {
+		let block_size = self.superblock.block_size as u64;
+		let mut position = file.blocks_start;
+		let mut remaining = file.size;
+		for &size in &file.block_sizes {
+			let len = remaining.min(block_size) as usize;
+			if size == 0 {
+				// sparse
+				out.write_all(&vec![0u8; len])?;
+			} else {
+				let block = self.read_data_block(position, size)?;
+				if block.len() < len {
+					return Err(ReadError::Corrupt("data block too short"));
+				}
+				out.write_all(&block[..len])?;
+				position += (size & !DATA_UNCOMPRESSED) as u64;
+			}
+			remaining -= len as u64;
+		}
+		if remaining > 0 {
+			if file.fragment == NO_FRAGMENT {
+				return Err(ReadError::Corrupt("file is missing blocks"));
+			}
+			let start = file.fragment_offset as usize;
+			let block = self.fragment_block(file.fragment)?;
+			let tail = block
+				.get(start..start + remaining as usize)
+				.ok_or(ReadError::Corrupt("fragment too short"))?;
+			out.write_all(tail)?;
+		}
+		Ok(())
+	}
+
+	/// Where a file's data blocks start and how many bytes they take in the image
+	pub fn stored_blocks(&self, file: &FileLayout) -> (u64, u64) {
+		let stored = file
+			.block_sizes
+			.iter()
+			.map(|&size| (size & !DATA_UNCOMPRESSED) as u64)
+			.sum();
+		(file.blocks_start, stored)
+	}
+
+	/// The fragment block holding a file's tail, if it has one
+	pub fn stored_fragment(&self, file: &FileLayout) -> Option<FragmentTail> {
+		if file.fragment == NO_FRAGMENT {
+			return None;
+		}
+		let &(_, size) = self.fragments.get(file.fragment as usize)?;
+		let in_blocks = file.block_sizes.len() as u64 * self.superblock.block_size as u64;
+		Some(FragmentTail {
+			index: file.fragment,
+			offset: file.fragment_offset,
+			len: file.size.saturating_sub(in_blocks),
+			block_stored_size: (size & !DATA_UNCOMPRESSED) as u64,
+		})
+	}
+
+	/// The extended attributes of an inode, by full name
+	pub fn xattrs(&mut self, inode: &Inode) -> Result<Vec<(String, Vec<u8>)>, ReadError> {
+		if inode.xattrs == NO_XATTRS || self.superblock.xattr_id_table_start == NO_TABLE {
+			return Ok(vec![]);
+		}
+		let header = self.read_at(self.superblock.xattr_id_table_start, 16)?;
+		let mut fields = Fields(&header);
+		let table_start = fields.u64()?;
+		let count = fields.u32()?;
+		if inode.xattrs >= count {
+			return Err(ReadError::Corrupt("xattr index out of bounds"));
+		}
+		// 16 byte entries, 512 per metadata block
+		let lookup = self.superblock.xattr_id_table_start + 16 + (inode.xattrs as u64 / 512) * 8;
+		let block = u64::from_le_bytes(self.read_at(lookup, 8)?.try_into().unwrap());
+		let entry = self.read_metadata(block, (inode.xattrs as usize % 512) * 16, 16)?;
+		let mut fields = Fields(&entry);
+		let reference = fields.u64()?;
+		let pairs = fields.u32()?;
+
+		let position = table_start + (reference >> 16);
+		let mut offset = (reference & 0xffff) as usize;
+		let mut read = |reader: &mut Self, len: usize| {
+			let data = reader.read_metadata(position, offset, len);
+			offset += len;
+			data
+		};
+		let mut xattrs = Vec::new();
+		for _ in 0..pairs {
+			let key = read(self, 4)?;
+			let mut fields = Fields(&key);
+			let key_type = fields.u16()?;
+			let name_size = fields.u16()? as usize;
+			let name = String::from_utf8_lossy(&read(self, name_size)?).into_owned();
+			let prefix = match key_type & !XATTR_OUT_OF_LINE {
+				XATTR_USER => "user.",
+				XATTR_TRUSTED => "trusted.",
+				XATTR_SECURITY => "security.",
+				_ => return Err(ReadError::Corrupt("unknown xattr prefix")),
+			};
+			let value_size = u32::from_le_bytes(read(self, 4)?.try_into().unwrap()) as usize;
+			let mut value = read(self, value_size)?;
+			if key_type & XATTR_OUT_OF_LINE != 0 {
+				let value_reference = u64::from_le_bytes(
+					value
+						.as_slice()
+						.try_into()
+						.map_err(|_| ReadError::Corrupt("bad out of line xattr"))?,
+				);
+				let position = table_start + (value_reference >> 16);
+				let offset = (value_reference & 0xffff) as usize;
+				let size = self.read_metadata(position, offset, 4)?;
+				let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+				value = self.read_metadata(position, offset + 4, size)?;
+			}
+			xattrs.push((format!("{prefix}{name}"), value));
+		}
+		Ok(xattrs)
+	}
+}
+
+/// Symlink targets and names are bytes, like paths
+pub fn symlink_target(target: &[u8]) -> PathBuf {
+	PathBuf::from(OsStr::from_bytes(target))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		squashfs::{WriterOptions, write_image},
+		test_utils::TempDir,
+	};
+
+	/// Writes `files` (path, contents) into a tree and makes an image of it
+	fn image_of(dir: &TempDir, files: &[(&str, &[u8])]) -> PathBuf {
+		let root = dir.path().join("root");
+		for (path, contents) in files {
+			let path = root.join(path);
+			std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+			std::fs::write(path, contents).unwrap();
+		}
+		std::fs::create_dir_all(&root).unwrap();
+		let image = dir.path().join("image.squashfs");
+		let options = WriterOptions {
+			block_size: 128 * 1024,
+			compression_level: 3,
+			mtime: Some(1_700_000_000),
+			overrides: &[],
+		};
+		write_image(&root, &image, &options).unwrap();
+		image
+	}
+
+	/// Renames the entry `QQ` of an image by patching its (uncompressed, since it's tiny)
+	/// directory table
+	fn rename_entry(image: &Path, name: &[u8; 2]) {
+		let mut bytes = std::fs::read(image).unwrap();
+		let at = bytes
+			.windows(2)
+			.position(|window| window == b"QQ")
+			.expect("the directory table should be stored uncompressed");
+		bytes[at..at + 2].copy_from_slice(name);
+		std::fs::write(image, bytes).unwrap();
+	}
+
+	#[test]
+	fn rejects_names_escaping_their_directory() {
+		for (name, valid) in [
+			(b"QR", true),
+			(b"..", false),
+			(b"a/", false),
+			(b"/a", false),
+		] {
+			let dir = TempDir::new();
+			let image = image_of(&dir, &[("QQ", b"contents")]);
+			rename_entry(&image, name);
+			let mut reader = Reader::open(&image).unwrap();
+			let InodeKind::Dir(root) = reader.root().unwrap().kind else {
+				panic!("the root should be a directory");
+			};
+			let entries = reader.read_dir(&root);
+			if valid {
+				assert_eq!(entries.unwrap()[0].0, OsStr::from_bytes(name));
+			} else {
+				assert!(matches!(entries, Err(ReadError::Corrupt(_))));
+			}
+		}
+	}
+}
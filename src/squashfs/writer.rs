@@ -0,0 +1,1048 @@
+use std::{
+	collections::{BTreeMap, HashMap},
+	ffi::OsString,
+	fs::File,
+	io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+	os::unix::{
+		ffi::OsStrExt,
+		fs::{FileTypeExt, MetadataExt},
+	},
+	path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::*;
+use crate::manifest::EntryOverride;
+
+#[derive(Debug, Error)]
+pub enum WriteError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("invalid block size {0}, expected a power of two from 4K to 1M")]
+	InvalidBlockSize(u64),
+	#[error("invalid override path {0:?}: {1}")]
+	InvalidPattern(String, glob::PatternError),
+	#[error("invalid xattr {0:?}: only user., trusted. and security. xattrs are supported")]
+	UnsupportedXattr(String),
+	#[error("invalid value for xattr {0:?}: {1:?} is not valid hex")]
+	InvalidXattrValue(String, String),
+	#[error("file name longer than 256 bytes: {}", .0.display())]
+	NameTooLong(PathBuf),
+	#[error("more than 65536 different uids and gids")]
+	TooManyIds,
+}
+
+pub struct WriterOptions<'a> {
+	/// Data block size, a power of two from 4K to 1M
+	pub block_size: u64,
+	/// zstd compression level
+	pub compression_level: i32,
+	/// Used for every entry and as the image's creation time instead of the sysroot's
+	/// mtimes and the current time
+	pub mtime: Option<u32>,
+	pub overrides: &'a [EntryOverride],
+}
+
+enum Kind {
+	Dir(Vec<usize>),
+	File { source: PathBuf, size: u64 },
+	Symlink(Vec<u8>),
+	BlockDev(u32),
+	CharDev(u32),
+	Fifo,
+	Socket,
+}
+
+struct Node {
+	name: OsString,
+	kind: Kind,
+	/// Permission bits only, the type comes from the inode type
+	mode: u16,
+	uid: u16,
+	gid: u16,
+	mtime: u32,
+	xattrs: u32,
+}
+
+impl Node {
+	fn basic_type(&self) -> u16 {
+		match self.kind {
+			Kind::Dir(_) => BASIC_DIR,
+			Kind::File { .. } => BASIC_FILE,
+			Kind::Symlink(_) => BASIC_SYMLINK,
+			Kind::BlockDev(_) => BASIC_BLOCK_DEV,
+			Kind::CharDev(_) => BASIC_CHAR_DEV,
+			Kind::Fifo => BASIC_FIFO,
+			Kind::Socket => BASIC_SOCKET,
+		}
+	}
+}
+
+/// Where a file's contents ended up
+#[derive(Clone, Default)]
+struct FileData {
+	blocks_start: u64,
+	block_sizes: Vec<u32>,
+	/// Fragment index and offset in it of the file's tail
+	fragment: Option<(u32, u32)>,
+}
+
+/// Compresses `data`, or keeps it as is when that doesn't make it smaller
+fn compress(data: &[u8], level: i32) -> io::Result<(Vec<u8>, bool)> {
+	let compressed = zstd::bulk::compress(data, level)?;
+	Ok(if compressed.len() < data.len() {
+		(compressed, true)
+	} else {
+		(data.to_vec(), false)
+	})
+}
+
+/// [`compress`] for many blocks at once, on every core, keeping their order
+fn compress_all(blocks: &[Vec<u8>], level: i32) -> io::Result<Vec<(Vec<u8>, bool)>> {
+	let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+	let chunk_size = blocks.len().div_ceil(threads).max(1);
+	std::thread::scope(|scope| {
+		let workers = blocks
+			.chunks(chunk_size)
+			.map(|chunk| {
+				scope.spawn(move || {
+					chunk
+						.iter()
+						.map(|block| compress(block, level))
+						.collect::<io::Result<Vec<_>>>()
+				})
+			})
+			.collect::<Vec<_>>();
+		let mut compressed = Vec::with_capacity(blocks.len());
+		for worker in workers {
+			compressed.extend(worker.join().expect("compression thread panicked")?);
+		}
+		Ok(compressed)
+	})
+}
+
+/// Builds a table out of 8K metadata blocks, each prefixed by its size
+struct MetadataWriter {
+	level: i32,
+	output: Vec<u8>,
+	buffer: Vec<u8>,
+	/// Offset of every block in `output`, for the lookup tables
+	block_starts: Vec<u64>,
+}
+
+impl MetadataWriter {
+	fn new(level: i32) -> Self {
+		Self {
+			level,
+			output: vec![],
+			buffer: Vec::with_capacity(METADATA_SIZE),
+			block_starts: vec![],
+		}
+	}
+
+	/// Offset of the current block in the table and the position in it
+	fn position(&self) -> (u64, u16) {
+		(self.output.len() as u64, self.buffer.len() as u16)
+	}
+
+	/// A reference to the current position, as stored in inode and xattr references
+	fn reference(&self) -> u64 {
+		let (block, offset) = self.position();
+		(block << 16) | offset as u64
+	}
+
+	fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+		while !data.is_empty() {
+			let n = (METADATA_SIZE - self.buffer.len()).min(data.len());
+			self.buffer.extend_from_slice(&data[..n]);
+			data = &data[n..];
+			if self.buffer.len() == METADATA_SIZE {
+				self.flush()?;
+			}
+		}
+		Ok(())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		if self.buffer.is_empty() {
+			return Ok(());
+		}
+		self.block_starts.push(self.output.len() as u64);
+		let (block, compressed) = compress(&self.buffer, self.level)?;
+		let mut header = block.len() as u16;
+		if !compressed {
+			header |= METADATA_UNCOMPRESSED;
+		}
+		self.output.extend_from_slice(&header.to_le_bytes());
+		self.output.extend_from_slice(&block);
+		self.buffer.clear();
+		Ok(())
+	}
+
+	fn finish(mut self) -> io::Result<(Vec<u8>, Vec<u64>)> {
+		self.flush()?;
+		Ok((self.output, self.block_starts))
+	}
+}
+
+/// The image file, tracking the write position
+struct Output {
+	file: BufWriter<File>,
+	position: u64,
+}
+
+impl Output {
+	fn write(&mut self, data: &[u8]) -> io::Result<()> {
+		self.file.write_all(data)?;
+		self.position += data.len() as u64;
+		Ok(())
+	}
+
+	/// Writes a table of fixed size entries and the lookup table of its metadata blocks,
+	/// returning where the lookup table starts
+	fn write_lookup_table(&mut self, table: MetadataWriter) -> io::Result<u64> {
+		let (bytes, block_starts) = table.finish()?;
+		let table_start = self.position;
+		self.write(&bytes)?;
+		let lookup_start = self.position;
+		for start in block_starts {
+			self.write(&(table_start + start).to_le_bytes())?;
+		}
+		Ok(lookup_start)
+	}
+}
+
+/// Prefix id, name without the prefix and value of each xattr of an entry
+type XattrSet = Vec<(u16, Vec<u8>, Vec<u8>)>;
+
+struct Writer<'a> {
+	options: &'a WriterOptions<'a>,
+	overrides: Overrides<'a>,
+	nodes: Vec<Node>,
+	ids: Vec<u32>,
+	xattr_sets: Vec<XattrSet>,
+	fragment_buffer: Vec<u8>,
+	/// Start and size of every written fragment block
+	fragments: Vec<(u64, u32)>,
+	/// Contents already written, by hash
+	written: HashMap<[u8; 32], FileData>,
+	duplicates: bool,
+}
+
+/// Decodes an xattr value, `0x` followed by hex or plain text
+fn xattr_value(name: &str, value: &str) -> Result<Vec<u8>, WriteError> {
+	let Some(hex) = value.strip_prefix("0x") else {
+		return Ok(value.as_bytes().to_vec());
+	};
+	let invalid = || WriteError::InvalidXattrValue(name.to_string(), value.to_string());
+	if hex.len() % 2 != 0 {
+		return Err(invalid());
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid()))
+		.collect()
+}
+
+/// The manifest's `[[image.overrides]]`, ready to be matched
+pub struct Overrides<'a>(Vec<(glob::Pattern, &'a EntryOverride)>);
+
+/// Ownership, permission bits and xattrs of an image entry
+pub struct EntryAttributes {
+	pub mode: u32,
+	pub uid: u32,
+	pub gid: u32,
+	pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl<'a> Overrides<'a> {
+	pub fn new(overrides: &'a [EntryOverride]) -> Result<Self, WriteError> {
+		overrides
+			.iter()
+			.map(|o| {
+				glob::Pattern::new(&o.path)
+					.map(|p| (p, o))
+					.map_err(|e| WriteError::InvalidPattern(o.path.clone(), e))
+			})
+			.collect::<Result<_, _>>()
+			.map(Self)
+	}
+
+	/// What the entry at `image_path` (absolute, e.g. "/usr/bin/sudo") ends up with,
+	/// starting from root ownership and the sysroot's permission bits `mode`
+	pub fn apply(&self, image_path: &str, mode: u32) -> Result<EntryAttributes, WriteError> {
+		let mut attributes = EntryAttributes {
+			mode,
+			uid: 0,
+			gid: 0,
+			xattrs: BTreeMap::new(),
+		};
+		let match_options = glob::MatchOptions {
+			require_literal_separator: true,
+			..Default::default()
+		};
+		for (pattern, entry_override) in &self.0 {
+			if !pattern.matches_with(image_path, match_options) {
+				continue;
+			}
+			attributes.mode = entry_override.mode.unwrap_or(attributes.mode);
+			attributes.uid = entry_override.uid.unwrap_or(attributes.uid);
+			attributes.gid = entry_override.gid.unwrap_or(attributes.gid);
+			for (name, value) in &entry_override.xattrs {
+				attributes
+					.xattrs
+					.insert(name.clone(), xattr_value(name, value)?);
+			}
+		}
+		Ok(attributes)
+	}
+}
+
+/// Linux's old 32 bit device number encoding, which squashfs uses
+pub fn encode_device(rdev: u64) -> u32 {
+	let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+	(minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+impl<'a> Writer<'a> {
+	fn id_index(&mut self, id: u32) -> Result<u16, WriteError> {
+		let index = match self.ids.iter().position(|i| *i == id) {
+			Some(index) => index,
+			None => {
+				self.ids.push(id);
+				self.ids.len() - 1
+			}
+		};
+		u16::try_from(index).map_err(|_| WriteError::TooManyIds)
+	}
+
+	fn xattr_index(&mut self, xattrs: &BTreeMap<String, Vec<u8>>) -> Result<u32, WriteError> {
+		if xattrs.is_empty() {
+			return Ok(NO_XATTRS);
+		}
+		let mut set = Vec::new();
+		for (name, value) in xattrs {
+			let (prefix, suffix) = if let Some(suffix) = name.strip_prefix("user.") {
+				(XATTR_USER, suffix)
+			} else if let Some(suffix) = name.strip_prefix("trusted.") {
+				(XATTR_TRUSTED, suffix)
+			} else if let Some(suffix) = name.strip_prefix("security.") {
+				(XATTR_SECURITY, suffix)
+			} else {
+				return Err(WriteError::UnsupportedXattr(name.clone()));
+			};
+			set.push((prefix, suffix.as_bytes().to_vec(), value.clone()));
+		}
+		let index = match self.xattr_sets.iter().position(|s| *s == set) {
+			Some(index) => index,
+			None => {
+				self.xattr_sets.push(set);
+				self.xattr_sets.len() - 1
+			}
+		};
+		Ok(index as u32)
+	}
+
+	/// Reads `path` and everything under it into `nodes`, returning its index
+	fn scan(&mut self, path: &Path, image_path: &str, name: OsString) -> Result<usize, WriteError> {
+		if name.len() > MAX_NAME_LEN {
+			return Err(WriteError::NameTooLong(path.to_path_buf()));
+		}
+		let metadata = std::fs::symlink_metadata(path)?;
+		let file_type = metadata.file_type();
+		let kind = if file_type.is_dir() {
+			let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+			entries.sort_by_key(|e| e.file_name());
+			let mut children = Vec::with_capacity(entries.len());
+			for entry in entries {
+				let child_path = format!(
+					"{}/{}",
+					image_path.trim_end_matches('/'),
+					entry.file_name().to_string_lossy()
+				);
+				children.push(self.scan(&entry.path(), &child_path, entry.file_name())?);
+			}
+			Kind::Dir(children)
+		} else if file_type.is_file() {
+			Kind::File {
+				source: path.to_path_buf(),
+				size: metadata.len(),
+			}
+		} else if file_type.is_symlink() {
+			Kind::Symlink(std::fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+		} else if file_type.is_block_device() {
+			Kind::BlockDev(encode_device(metadata.rdev()))
+		} else if file_type.is_char_device() {
+			Kind::CharDev(encode_device(metadata.rdev()))
+		} else if file_type.is_fifo() {
+			Kind::Fifo
+		} else {
+			Kind::Socket
+		};
+
+		let attributes = self.overrides.apply(image_path, metadata.mode() & 0o7777)?;
+		let node = Node {
+			name,
+			kind,
+			mode: attributes.mode as u16,
+			uid: self.id_index(attributes.uid)?,
+			gid: self.id_index(attributes.gid)?,
+			mtime: self
+				.options
+				.mtime
+				.unwrap_or_else(|| metadata.mtime().clamp(0, u32::MAX as i64) as u32),
+			xattrs: self.xattr_index(&attributes.xattrs)?,
+		};
+		self.nodes.push(node);
+		Ok(self.nodes.len() - 1)
+	}
+
+	/// Node indices children first, the order inodes are numbered and written in
+	fn inode_order(&self, node: usize, order: &mut Vec<usize>) {
+		if let Kind::Dir(children) = &self.nodes[node].kind {
+			for child in children {
+				self.inode_order(*child, order);
+			}
+		}
+		order.push(node);
+	}
+
+	fn flush_fragment(&mut self, output: &mut Output) -> io::Result<()> {
+		if self.fragment_buffer.is_empty() {
+			return Ok(());
+		}
+		let (block, compressed) = compress(&self.fragment_buffer, self.options.compression_level)?;
+		let mut size = block.len() as u32;
+		if !compressed {
+			size |= DATA_UNCOMPRESSED;
+		}
+		self.fragments.push((output.position, size));
+		output.write(&block)?;
+		self.fragment_buffer.clear();
+		Ok(())
+	}
+
+	/// Writes a file's full blocks and puts its tail in a fragment
+	fn write_file(&mut self, output: &mut Output, source: &Path, size: u64) -> io::Result<FileData> {
+		if size == 0 {
+			return Ok(FileData::default());
+		}
+		let mut hasher = Sha256::new();
+		io::copy(&mut File::open(source)?, &mut hasher)?;
+		let hash: [u8; 32] = hasher.finalize().into();
+		if let Some(data) = self.written.get(&hash) {
+			self.duplicates = true;
+			return Ok(data.clone());
+		}
+
+		let block_size = self.options.block_size;
+		let mut file = File::open(source)?;
+		let mut data = FileData {
+			blocks_start: output.position,
+			..Default::default()
+		};
+		// enough blocks at once to keep every core busy
+		const BATCH: u64 = 64;
+		let mut remaining_blocks = size / block_size;
+		while remaining_blocks > 0 {
+			let batch = remaining_blocks.min(BATCH);
+			let mut blocks = Vec::with_capacity(batch as usize);
+			for _ in 0..batch {
+				let mut block = vec![0; block_size as usize];
+				file.read_exact(&mut block)?;
+				blocks.push(block);
+			}
+			for (block, compressed) in compress_all(&blocks, self.options.compression_level)? {
+				let mut size = block.len() as u32;
+				if !compressed {
+					size |= DATA_UNCOMPRESSED;
+				}
+				data.block_sizes.push(size);
+				output.write(&block)?;
+			}
+			remaining_blocks -= batch;
+		}
+
+		let tail_size = (size % block_size) as usize;
+		if tail_size > 0 {
+			let mut tail = vec![0; tail_size];
+			file.read_exact(&mut tail)?;
+			if self.fragment_buffer.len() + tail_size > block_size as usize {
+				self.flush_fragment(output)?;
+			}
+			data.fragment = Some((
+				self.fragments.len() as u32,
+				self.fragment_buffer.len() as u32,
+			));
+			self.fragment_buffer.extend_from_slice(&tail);
+		}
+		self.written.insert(hash, data.clone());
+		Ok(data)
+	}
+}
+
+/// Writes the `u16`/`u32`/`u64` fields of an on-disk structure
+macro_rules! le_bytes {
+	($($field:expr),* $(,)?) => {{
+		let mut bytes = Vec::new();
+		$(bytes.extend_from_slice(&$field.to_le_bytes());)*
+		bytes
+	}};
+}
+
+/// Writes the directory listing of `children` (already in the inode table) and returns its size
+fn write_listing(
+	directory_table: &mut MetadataWriter,
+	children: &[(&[u8], u64, u32, u16)],
+) -> io::Result<u32> {
+	let mut size = 0;
+	let mut rest = children;
+	while let Some((_, first_reference, base, _)) = rest.first() {
+		// entries under one header share the inode block and are numbered close to its base
+		let block = first_reference >> 16;
+		let count = rest
+			.iter()
+			.take(DIR_HEADER_ENTRIES)
+			.take_while(|(_, reference, number, _)| {
+				reference >> 16 == block && (*number as i64 - *base as i64).abs() <= i16::MAX as i64
+			})
+			.count();
+		directory_table.write(&le_bytes!(count as u32 - 1, block as u32, *base))?;
+		size += 12;
+		for (name, reference, number, inode_type) in &rest[..count] {
+			directory_table.write(&le_bytes!(
+				(reference & 0xffff) as u16,
+				(*number as i64 - *base as i64) as i16,
+				*inode_type,
+				name.len() as u16 - 1
+			))?;
+			directory_table.write(name)?;
+			size += 8 + name.len() as u32;
+		}
+		rest = &rest[count..];
+	}
+	Ok(size)
+}
+
+/// Writes the contents of `source_dir` as a zstd compressed squashfs image. The same tree
+/// and options always give the same bytes: entries are sorted and nothing depends on the
+/// time unless `mtime` is `None`.
+pub fn write_image(
+	source_dir: &Path,
+	image_path: &Path,
+	options: &WriterOptions,
+) -> Result<(), WriteError> {
+	let block_size = options.block_size;
+	if !block_size.is_power_of_two() || !(4096..=1024 * 1024).contains(&block_size) {
+		return Err(WriteError::InvalidBlockSize(block_size));
+	}
+	let mut writer = Writer {
+		options,
+		overrides: Overrides::new(options.overrides)?,
+		nodes: vec![],
+		ids: vec![],
+		xattr_sets: vec![],
+		fragment_buffer: Vec::with_capacity(block_size as usize),
+		fragments: vec![],
+		written: HashMap::new(),
+		duplicates: false,
+	};
+	let root = writer.scan(source_dir, "/", OsString::new())?;
+	let mut order = Vec::with_capacity(writer.nodes.len());
+	writer.inode_order(root, &mut order);
+	let mut numbers = vec![0u32; writer.nodes.len()];
+	let mut parents = vec![order.len() as u32 + 1; writer.nodes.len()];
+	for (position, node) in order.iter().enumerate() {
+		numbers[*node] = position as u32 + 1;
+	}
+	for node in &order {
+		if let Kind::Dir(children) = &writer.nodes[*node].kind {
+			for child in children {
+				parents[*child] = numbers[*node];
+			}
+		}
+	}
+
+	let mut output = Output {
+		file: BufWriter::new(File::create(image_path)?),
+		position: 0,
+	};
+	output.write(&[0; SUPERBLOCK_SIZE])?;
+
+	let mut file_data = HashMap::new();
+	for node in &order {
+		if let Kind::File { source, size } = &writer.nodes[*node].kind {
+			let (source, size) = (source.clone(), *size);
+			file_data.insert(*node, writer.write_file(&mut output, &source, size)?);
+		}
+	}
+	writer.flush_fragment(&mut output)?;
+
+	let level = options.compression_level;
+	let mut inode_table = MetadataWriter::new(level);
+	let mut directory_table = MetadataWriter::new(level);
+	let mut references = vec![0u64; writer.nodes.len()];
+	for &index in &order {
+		let node = &writer.nodes[index];
+		references[index] = inode_table.reference();
+		let extended = node.xattrs != NO_XATTRS;
+		let mut inode_type = node.basic_type();
+		let body = match &node.kind {
+			Kind::Dir(children) => {
+				let entries = children
+					.iter()
+					.map(|c| {
+						let child = &writer.nodes[*c];
+						(
+							child.name.as_bytes(),
+							references[*c],
+							numbers[*c],
+							child.basic_type(),
+						)
+					})
+					.collect::<Vec<_>>();
+				let (block, offset) = directory_table.position();
+				let listing_size = write_listing(&mut directory_table, &entries)? + 3;
+				let links = 2
+					+ children
+						.iter()
+						.filter(|c| matches!(writer.nodes[**c].kind, Kind::Dir(_)))
+						.count() as u32;
+				if extended || listing_size > u16::MAX as u32 {
+					inode_type += EXTENDED;
+					le_bytes!(
+						links,
+						listing_size,
+						block as u32,
+						parents[index],
+						0u16,
+						offset,
+						node.xattrs
+					)
+				} else {
+					le_bytes!(
+						block as u32,
+						links,
+						listing_size as u16,
+						offset,
+						parents[index]
+					)
+				}
+			}
+			Kind::File { size, .. } => {
+				let data = &file_data[&index];
+				let (fragment, fragment_offset) = data.fragment.unwrap_or((NO_FRAGMENT, 0));
+				let mut body = if extended || data.blocks_start > u32::MAX as u64 || *size > u32::MAX as u64
+				{
+					inode_type += EXTENDED;
+					le_bytes!(
+						data.blocks_start,
+						*size,
+						0u64,
+						1u32,
+						fragment,
+						fragment_offset,
+						node.xattrs
+					)
+				} else {
+					le_bytes!(
+						data.blocks_start as u32,
+						fragment,
+						fragment_offset,
+						*size as u32
+					)
+				};
+				for size in &data.block_sizes {
+					body.extend_from_slice(&size.to_le_bytes());
+				}
+				body
+			}
+			Kind::Symlink(target) => {
+				let mut body = le_bytes!(1u32, target.len() as u32);
+				body.extend_from_slice(target);
+				if extended {
+					inode_type += EXTENDED;
+					body.extend_from_slice(&node.xattrs.to_le_bytes());
+				}
+				body
+			}
+			Kind::BlockDev(device) | Kind::CharDev(device) => {
+				let mut body = le_bytes!(1u32, *device);
+				if extended {
+					inode_type += EXTENDED;
+					body.extend_from_slice(&node.xattrs.to_le_bytes());
+				}
+				body
+			}
+			Kind::Fifo | Kind::Socket => {
+				let mut body = le_bytes!(1u32);
+				if extended {
+					inode_type += EXTENDED;
+					body.extend_from_slice(&node.xattrs.to_le_bytes());
+				}
+				body
+			}
+		};
+		inode_table.write(&le_bytes!(
+			inode_type,
+			node.mode,
+			node.uid,
+			node.gid,
+			node.mtime,
+			numbers[index]
+		))?;
+		inode_table.write(&body)?;
+	}
+
+	// the order of the tables matters, the kernel checks each one ends where the next begins
+	let inode_table_start = output.position;
+	output.write(&inode_table.finish()?.0)?;
+	let directory_table_start = output.position;
+	output.write(&directory_table.finish()?.0)?;
+
+	let mut fragment_table = MetadataWriter::new(level);
+	for (start, size) in &writer.fragments {
+		fragment_table.write(&le_bytes!(*start, *size, 0u32))?;
+	}
+	let fragment_table_start = output.write_lookup_table(fragment_table)?;
+
+	let mut id_table = MetadataWriter::new(level);
+	for id in &writer.ids {
+		id_table.write(&id.to_le_bytes())?;
+	}
+	let id_table_start = output.write_lookup_table(id_table)?;
+
+	let xattr_id_table_start = if writer.xattr_sets.is_empty() {
+		NO_TABLE
+	} else {
+		let mut pairs = MetadataWriter::new(level);
+		let mut ids = MetadataWriter::new(level);
+		for set in &writer.xattr_sets {
+			let reference = pairs.reference();
+			let mut size = 0;
+			for (prefix, name, value) in set {
+				pairs.write(&le_bytes!(*prefix, name.len() as u16))?;
+				pairs.write(name)?;
+				pairs.write(&(value.len() as u32).to_le_bytes())?;
+				pairs.write(value)?;
+				size += name.len() + value.len();
+			}
+			ids.write(&le_bytes!(reference, set.len() as u32, size as u32))?;
+		}
+		let xattr_table_start = output.position;
+		output.write(&pairs.finish()?.0)?;
+		let (ids, block_starts) = ids.finish()?;
+		let ids_start = output.position;
+		output.write(&ids)?;
+		let xattr_id_table_start = output.position;
+		output.write(&le_bytes!(
+			xattr_table_start,
+			writer.xattr_sets.len() as u32,
+			0u32
+		))?;
+		for start in block_starts {
+			output.write(&(ids_start + start).to_le_bytes())?;
+		}
+		xattr_id_table_start
+	};
+
+	let bytes_used = output.position;
+	// block devices, loop ones included, want whole 4K sectors
+	let padding = bytes_used.next_multiple_of(4096) - bytes_used;
+	output.write(&vec![0; padding as usize])?;
+
+	let mut flags = 0;
+	if writer.xattr_sets.is_empty() {
+		flags |= FLAG_NO_XATTRS;
+	}
+	if writer.duplicates {
+		flags |= FLAG_DUPLICATES;
+	}
+	let creation_time = options.mtime.unwrap_or_else(|| {
+		std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map_or(0, |d| d.as_secs() as u32)
+	});
+	let superblock = le_bytes!(
+		MAGIC,
+		order.len() as u32,
+		creation_time,
+		block_size as u32,
+		writer.fragments.len() as u32,
+		COMPRESSION_ZSTD,
+		block_size.trailing_zeros() as u16,
+		flags,
+		writer.ids.len() as u16,
+		4u16,
+		0u16,
+		references[root],
+		bytes_used,
+		id_table_start,
+		xattr_id_table_start,
+		inode_table_start,
+		directory_table_start,
+		fragment_table_start,
+		NO_TABLE,
+	);
+	let mut file = output.file.into_inner().map_err(|e| e.into_error())?;
+	file.seek(SeekFrom::Start(0))?;
+	file.write_all(&superblock)?;
+	file.sync_all()?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::BTreeMap, ffi::CString, os::unix::fs::PermissionsExt};
+
+	use super::*;
+	use crate::test_utils::{TempDir, noise};
+
+	const BLOCK_SIZE: u64 = 4096;
+	const MTIME: u32 = 1_700_000_000;
+
+	fn options(overrides: &[EntryOverride]) -> WriterOptions<'_> {
+		WriterOptions {
+			block_size: BLOCK_SIZE,
+			compression_level: 3,
+			mtime: Some(MTIME),
+			overrides,
+		}
+	}
+
+	fn write(path: &Path, contents: &[u8]) {
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		std::fs::write(path, contents).unwrap();
+	}
+
+	/// What the reader sees under `/`, by path: type, mode, owner and contents (or target)
+	fn read_back(image: &Path) -> BTreeMap<PathBuf, (char, u16, u32, u32, Vec<u8>)> {
+		let mut reader = Reader::open(image).unwrap();
+		let mut inodes = Vec::new();
+		reader
+			.walk(Path::new("/"), &mut |path, inode| {
+				inodes.push((path.to_path_buf(), inode.clone()));
+				Ok(())
+			})
+			.unwrap();
+		let mut entries = BTreeMap::new();
+		for (path, inode) in inodes {
+			assert_eq!(inode.mtime, MTIME, "{}", path.display());
+			let contents = match &inode.kind {
+				InodeKind::File(file) => {
+					let mut contents = Vec::new();
+					reader.read_file(file, &mut contents).unwrap();
+					contents
+				}
+				InodeKind::Symlink(target) => target.clone(),
+				InodeKind::BlockDev(dev) | InodeKind::CharDev(dev) => dev.to_le_bytes().to_vec(),
+				_ => Vec::new(),
+			};
+			let entry = (
+				inode.type_char(),
+				inode.mode,
+				inode.uid,
+				inode.gid,
+				contents,
+			);
+			entries.insert(path, entry);
+		}
+		entries
+	}
+
+	#[test]
+	fn round_trip() {
+		let dir = TempDir::new();
+		let root = dir.path().join("root");
+		let small = noise(100, 1);
+		let tail = noise(3 * BLOCK_SIZE as usize + 123, 2);
+		let whole = noise(2 * BLOCK_SIZE as usize, 3);
+		write(&root.join("etc/small"), &small);
+		write(&root.join("usr/lib/tail.so"), &tail);
+		write(&root.join("usr/lib/whole.bin"), &whole);
+		write(&root.join("usr/lib/copy.so"), &tail);
+		write(&root.join("usr/share/small-copy"), &small);
+		write(&root.join("empty-file"), b"");
+		std::fs::create_dir_all(root.join("empty-dir")).unwrap();
+		std::os::unix::fs::symlink("../etc/small", root.join("usr/link")).unwrap();
+		// more entries than fit under one directory header
+		for i in 0..300 {
+			write(
+				&root.join(format!("many/{i:03}")),
+				format!("{i}").as_bytes(),
+			);
+		}
+		let fifo = CString::new(root.join("fifo").as_os_str().as_bytes()).unwrap();
+		assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+		std::fs::set_permissions(root.join("etc/small"), PermissionsExt::from_mode(0o600)).unwrap();
+
+		let image = dir.path().join("image.squashfs");
+		write_image(&root, &image, &options(&[])).unwrap();
+		let entries = read_back(&image);
+
+		let file = |contents: &[u8], mode| ('-', mode, 0, 0, contents.to_vec());
+		assert_eq!(entries[Path::new("/etc/small")], file(&small, 0o600));
+		assert_eq!(entries[Path::new("/usr/lib/tail.so")], file(&tail, 0o644));
+		assert_eq!(
+			entries[Path::new("/usr/lib/whole.bin")],
+			file(&whole, 0o644)
+		);
+		assert_eq!(entries[Path::new("/usr/lib/copy.so")], file(&tail, 0o644));
+		assert_eq!(
+			entries[Path::new("/usr/share/small-copy")],
+			file(&small, 0o644)
+		);
+		assert_eq!(entries[Path::new("/empty-file")], file(b"", 0o644));
+		assert_eq!(entries[Path::new("/empty-dir")].0, 'd');
+		assert_eq!(entries[Path::new("/usr/link")].0, 'l');
+		assert_eq!(entries[Path::new("/usr/link")].4, b"../etc/small");
+		assert_eq!(entries[Path::new("/fifo")].0, 'p');
+		assert_eq!(entries[Path::new("/fifo")].1, 0o640);
+		for i in 0..300 {
+			let path = PathBuf::from(format!("/many/{i:03}"));
+			assert_eq!(entries[&path].4, format!("{i}").as_bytes());
+		}
+		// the root, 6 directories, 6 files, a symlink, a fifo and the 300 files in `many`
+		assert_eq!(entries.len(), 1 + 6 + 6 + 1 + 1 + 300);
+
+		// duplicates share their blocks and fragment
+		let mut reader = Reader::open(&image).unwrap();
+		let layout =
+			|reader: &mut Reader, path: &str| match reader.lookup(Path::new(path)).unwrap().kind {
+				InodeKind::File(file) => file,
+				_ => panic!("{path} should be a file"),
+			};
+		let (tail, copy) = (
+			layout(&mut reader, "/usr/lib/tail.so"),
+			layout(&mut reader, "/usr/lib/copy.so"),
+		);
+		assert_eq!(reader.stored_blocks(&tail), reader.stored_blocks(&copy));
+		let (tail, copy) = (
+			reader.stored_fragment(&tail).unwrap(),
+			reader.stored_fragment(&copy).unwrap(),
+		);
+		assert_eq!((tail.index, tail.offset), (copy.index, copy.offset));
+		let whole = layout(&mut reader, "/usr/lib/whole.bin");
+		assert_eq!(reader.stored_blocks(&whole).1, 2 * BLOCK_SIZE);
+		assert!(reader.stored_fragment(&whole).is_none());
+
+		// the same tree always gives the same bytes
+		let again = dir.path().join("again.squashfs");
+		write_image(&root, &again, &options(&[])).unwrap();
+		assert_eq!(
+			std::fs::read(&image).unwrap(),
+			std::fs::read(&again).unwrap()
+		);
+	}
+
+	#[test]
+	fn overrides_and_xattrs() {
+		let dir = TempDir::new();
+		let root = dir.path().join("root");
+		write(&root.join("usr/bin/sudo"), b"sudo");
+		write(&root.join("usr/bin/ping"), b"ping");
+		write(&root.join("home/user/file"), b"file");
+		let overrides = [
+			EntryOverride {
+				path: "/usr/bin/sudo".to_string(),
+				uid: None,
+				gid: None,
+				mode: Some(0o4755),
+				xattrs: BTreeMap::new(),
+			},
+			EntryOverride {
+				path: "/usr/bin/ping".to_string(),
+				uid: None,
+				gid: None,
+				mode: None,
+				xattrs: BTreeMap::from([
+					(
+						"security.capability".to_string(),
+						"0x0100000200200000".to_string(),
+					),
+					("user.comment".to_string(), "plain text".to_string()),
+				]),
+			},
+			EntryOverride {
+				path: "/home/**".to_string(),
+				uid: Some(1000),
+				gid: Some(100),
+				mode: None,
+				xattrs: BTreeMap::new(),
+			},
+		];
+		let image = dir.path().join("image.squashfs");
+		write_image(&root, &image, &options(&overrides)).unwrap();
+
+		let entries = read_back(&image);
+		assert_eq!(entries[Path::new("/usr/bin/sudo")].1, 0o4755);
+		assert_eq!(entries[Path::new("/home")].2, 0);
+		assert_eq!(entries[Path::new("/home/user")].2, 1000);
+		assert_eq!(entries[Path::new("/home/user/file")].3, 100);
+
+		let mut reader = Reader::open(&image).unwrap();
+		let ping = reader.lookup(Path::new("/usr/bin/ping")).unwrap();
+		assert_eq!(
+			reader.xattrs(&ping).unwrap(),
+			[
+				(
+					"security.capability".to_string(),
+					vec![0x01, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00]
+				),
+				("user.comment".to_string(), b"plain text".to_vec()),
+			]
+		);
+		let sudo = reader.lookup(Path::new("/usr/bin/sudo")).unwrap();
+		assert!(reader.xattrs(&sudo).unwrap().is_empty());
+
+		let invalid = [EntryOverride {
+			path: "/usr/bin/ping".to_string(),
+			uid: None,
+			gid: None,
+			mode: None,
+			xattrs: BTreeMap::from([("system.acl".to_string(), String::new())]),
+		}];
+		assert!(matches!(
+			write_image(&root, &image, &options(&invalid)),
+			Err(WriteError::UnsupportedXattr(_))
+		));
+	}
+
+	#[test]
+	fn devices() {
+		// creating device nodes takes CAP_MKNOD
+		if unsafe { libc::geteuid() } != 0 {
+			return;
+		}
+		let dir = TempDir::new();
+		let root = dir.path().join("root");
+		std::fs::create_dir_all(&root).unwrap();
+		for (name, kind, major, minor) in [
+			("null", libc::S_IFCHR, 1, 3),
+			("loop0", libc::S_IFBLK, 7, 0),
+			("big", libc::S_IFCHR, 300, 70000),
+		] {
+			let path = CString::new(root.join(name).as_os_str().as_bytes()).unwrap();
+			let dev = libc::makedev(major, minor);
+			assert_eq!(unsafe { libc::mknod(path.as_ptr(), kind | 0o600, dev) }, 0);
+		}
+		let image = dir.path().join("image.squashfs");
+		write_image(&root, &image, &options(&[])).unwrap();
+
+		let entries = read_back(&image);
+		let device = |path: &str| {
+			let (kind, _, _, _, dev) = &entries[Path::new(path)];
+			let dev = u32::from_le_bytes(dev[..4].try_into().unwrap());
+			(
+				*kind,
+				(dev >> 8) & 0xfff,
+				(dev & 0xff) | ((dev >> 12) & 0xfff00),
+			)
+		};
+		assert_eq!(device("/null"), ('c', 1, 3));
+		assert_eq!(device("/loop0"), ('b', 7, 0));
+		assert_eq!(device("/big"), ('c', 300, 70000));
+	}
+}
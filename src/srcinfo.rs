@@ -0,0 +1,156 @@
+use std::{
+	collections::BTreeSet,
+	hash::{DefaultHasher, Hash, Hasher},
+	path::PathBuf,
+	process::Command,
+};
+
+use thiserror::Error;
+
+use crate::{
+	commands::image::packages::build::BuildDockerImageError,
+	manifest::{Package, Source},
+};
+
+/// The parts of a PKGBUILD's `.SRCINFO` hyprpacker uses for inference
+#[derive(Debug, Clone, Default)]
+pub struct SrcInfo {
+	pub pkgbase: String,
+	pub pkgver: String,
+	pub pkgrel: String,
+	pub epoch: Option<String>,
+	pub pkgnames: Vec<String>,
+	/// `makedepends` and `checkdepends` of the whole PKGBUILD, without version constraints
+	pub makedepends: BTreeSet<String>,
+	/// `depends` of every split package, without version constraints
+	pub depends: BTreeSet<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum SrcInfoError {
+	#[error("io error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("failed to build docker image: {0}")]
+	Docker(#[from] BuildDockerImageError),
+	#[error("makepkg --printsrcinfo exited with non-zero code: {0}")]
+	Non0ExitCode(i32),
+	#[error("malformed .SRCINFO: missing {0}")]
+	Malformed(&'static str),
+}
+
+/// Strips version constraints and descriptions, `foo>=1.0: bar` -> `foo`
+fn dependency_name(value: &str) -> String {
+	value
+		.split(['<', '>', '=', ':'])
+		.next()
+		.unwrap_or(value)
+		.trim()
+		.to_string()
+}
+
+impl SrcInfo {
+	pub fn parse(contents: &str) -> Result<Self, SrcInfoError> {
+		let mut info = SrcInfo::default();
+		for line in contents.lines() {
+			let Some((key, value)) = line.trim().split_once(" = ") else {
+				continue;
+			};
+			let value = value.trim();
+			// architecture specific keys look like `depends_x86_64`
+			let key = key
+				.trim()
+				.trim_end_matches("_x86_64")
+				.trim_end_matches("_any");
+			match key {
+				"pkgbase" => info.pkgbase = value.to_string(),
+				"pkgname" => info.pkgnames.push(value.to_string()),
+				"pkgver" => info.pkgver = value.to_string(),
+				"pkgrel" => info.pkgrel = value.to_string(),
+				"epoch" => info.epoch = Some(value.to_string()),
+				"makedepends" | "checkdepends" => {
+					info.makedepends.insert(dependency_name(value));
+				}
+				"depends" => {
+					info.depends.insert(dependency_name(value));
+				}
+				_ => {}
+			}
+		}
+		if info.pkgbase.is_empty() {
+			return Err(SrcInfoError::Malformed("pkgbase"));
+		}
+		if info.pkgver.is_empty() {
+			return Err(SrcInfoError::Malformed("pkgver"));
+		}
+		if info.pkgrel.is_empty() {
+			return Err(SrcInfoError::Malformed("pkgrel"));
+		}
+		Ok(info)
+	}
+
+	/// The version makepkg will give the built packages: `[epoch:]pkgver-pkgrel`
+	pub fn full_version(&self) -> String {
+		match &self.epoch {
+			Some(epoch) if epoch != "0" => format!("{epoch}:{}-{}", self.pkgver, self.pkgrel),
+			_ => format!("{}-{}", self.pkgver, self.pkgrel),
+		}
+	}
+}
+
+impl Package {
+	fn generated_srcinfo_dir() -> PathBuf {
+		PathBuf::from("build/srcinfo")
+	}
+
+	/// Reads the package's `.SRCINFO`. PKGBUILDs that don't ship one get it generated with
+	/// `makepkg --printsrcinfo` inside the package's build container, cached by PKGBUILD contents.
+	///
+	/// Returns `None` for binary packages and for git packages whose sources weren't fetched yet.
+	pub fn srcinfo(&self) -> Result<Option<SrcInfo>, SrcInfoError> {
+		if let Source::Binary { .. } = &self.source {
+			return Ok(None);
+		}
+		let src_root = self.get_this_package_src_root();
+		let pkgbuild_path = src_root.join("PKGBUILD");
+		if !pkgbuild_path.exists() {
+			return Ok(None);
+		}
+		let shipped_srcinfo = src_root.join(".SRCINFO");
+		if shipped_srcinfo.exists() {
+			return SrcInfo::parse(&std::fs::read_to_string(shipped_srcinfo)?).map(Some);
+		}
+
+		let mut hasher = DefaultHasher::new();
+		std::fs::read(&pkgbuild_path)?.hash(&mut hasher);
+		let cached_srcinfo =
+			Self::generated_srcinfo_dir().join(format!("{}-{:x}.SRCINFO", self.name, hasher.finish()));
+		if cached_srcinfo.exists() {
+			return SrcInfo::parse(&std::fs::read_to_string(cached_srcinfo)?).map(Some);
+		}
+
+		let docker_image_name = self.build_docker_image_if_needed()?;
+		// makepkg refuses to run as root, even just to print the .SRCINFO
+		let output = Command::new("docker")
+			.arg("run")
+			.arg("--rm")
+			.arg("-v")
+			.arg(format!("{}:/src:ro", src_root.canonicalize()?.display()))
+			.arg("-w")
+			.arg("/src")
+			.arg(docker_image_name)
+			.arg("bash")
+			.arg("-c")
+			.arg("useradd builduser -m && runuser -u builduser -- makepkg --printsrcinfo")
+			.output()?;
+		if !output.status.success() {
+			return Err(SrcInfoError::Non0ExitCode(
+				output.status.code().unwrap_or(-1),
+			));
+		}
+		let contents = String::from_utf8_lossy(&output.stdout).to_string();
+		let srcinfo = SrcInfo::parse(&contents)?;
+		std::fs::create_dir_all(Self::generated_srcinfo_dir())?;
+		std::fs::write(cached_srcinfo, contents)?;
+		Ok(Some(srcinfo))
+	}
+}
@@ -0,0 +1,208 @@
+//! Helpers shared by the unit tests
+use std::{
+	io::{self, BufRead, BufReader, Read, Write},
+	net::{TcpListener, TcpStream},
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::manifest::Manifest;
+
+/// A fresh directory under the system temp dir, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	pub fn new() -> Self {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!(
+			"hyprpacker-test-{}-{}",
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		std::fs::create_dir_all(&path).unwrap();
+		Self(path)
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		std::fs::remove_dir_all(&self.0).ok();
+	}
+}
+
+/// Deterministic bytes that don't compress and have no repeated chunks
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+	let mut state = seed.wrapping_mul(6364136223846793005) | 1;
+	(0..len)
+		.map(|_| {
+			state = state
+				.wrapping_mul(6364136223846793005)
+				.wrapping_add(1442695040888963407);
+			(state >> 56) as u8
+		})
+		.collect()
+}
+
+/// A manifest with the required `[kernel]` and `[initrd]` tables and `extra` on top
+pub fn test_manifest(extra: &str) -> Manifest {
+	toml::from_str(&format!(
+		"version = \"1.0\"\n{extra}\n[kernel]\nurl = \"https://example.com/linux-1.0.tar.xz\"\n[initrd]\nbuild_script = \"initrd.sh\"\n"
+	))
+	.unwrap()
+}
+
+/// Writes a `.pkg.tar.zst` like makepkg's into `dir`, with a `.PKGINFO` and one file holding
+/// `contents`
+pub fn write_package(dir: &Path, name: &str, version: &str, contents: &[u8]) -> PathBuf {
+	let mut archive = tar::Builder::new(Vec::new());
+	let pkginfo = format!("pkgname = {name}\npkgver = {version}\nbuilddate = 1700000000\n");
+	for (path, data) in [
+		(".PKGINFO".to_string(), pkginfo.as_bytes()),
+		(format!("usr/share/{name}/data"), contents),
+	] {
+		let mut header = tar::Header::new_gnu();
+		header.set_size(data.len() as u64);
+		header.set_mode(0o644);
+		header.set_cksum();
+		archive.append_data(&mut header, path, data).unwrap();
+	}
+	let tarball = archive.into_inner().unwrap();
+	std::fs::create_dir_all(dir).unwrap();
+	let path = dir.join(format!("{name}-{version}-x86_64.pkg.tar.zst"));
+	std::fs::write(&path, zstd::encode_all(&tarball[..], 0).unwrap()).unwrap();
+	path
+}
+
+/// A stand-in for the HTTP servers update repositories and binary caches live on: serves
+/// a directory with GET and HEAD, and stores PUT bodies in it the way those servers must,
+/// through a temporary file renamed into place, answering `412` to a PUT with
+/// `If-None-Match: *` when the file exists. Runs until the test process exits.
+pub struct FileServer {
+	pub url: String,
+	pub root: TempDir,
+}
+
+impl FileServer {
+	pub fn start() -> Self {
+		let root = TempDir::new();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		let dir = root.path().to_path_buf();
+		std::thread::spawn(move || {
+			for stream in listener.incoming().flatten() {
+				let dir = dir.clone();
+				std::thread::spawn(move || serve_connection(&dir, stream));
+			}
+		});
+		Self { url, root }
+	}
+}
+
+fn serve_connection(root: &Path, stream: TcpStream) {
+	let mut reader = BufReader::new(stream.try_clone().unwrap());
+	let mut stream = stream;
+	// keep-alive connections carry several requests
+	while let Ok(Some((status, body))) = serve_request(root, &mut reader) {
+		let head = format!(
+			"HTTP/1.1 {status} -\r\nContent-Length: {}\r\n\r\n",
+			body.len()
+		);
+		if stream.write_all(head.as_bytes()).is_err() || stream.write_all(&body).is_err() {
+			break;
+		}
+	}
+}
+
+/// Handles one request, `None` once the client closed the connection
+fn serve_request(
+	root: &Path,
+	reader: &mut BufReader<TcpStream>,
+) -> io::Result<Option<(u16, Vec<u8>)>> {
+	let mut request_line = String::new();
+	if reader.read_line(&mut request_line)? == 0 {
+		return Ok(None);
+	}
+	let mut parts = request_line.split_whitespace();
+	let method = parts.next().unwrap_or_default().to_string();
+	let target = parts.next().unwrap_or_default().to_string();
+	let (mut content_length, mut chunked, mut if_none_match) = (0, false, false);
+	loop {
+		let mut line = String::new();
+		reader.read_line(&mut line)?;
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+		let Some((name, value)) = line.split_once(':') else {
+			continue;
+		};
+		let value = value.trim();
+		match name.to_ascii_lowercase().as_str() {
+			"content-length" => content_length = value.parse().unwrap_or_default(),
+			"transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+			"if-none-match" => if_none_match = value == "*",
+			_ => {}
+		}
+	}
+	let mut body = Vec::new();
+	if chunked {
+		loop {
+			let mut size = String::new();
+			reader.read_line(&mut size)?;
+			let size = usize::from_str_radix(size.trim().split(';').next().unwrap_or("0"), 16)
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+			let mut chunk = vec![0; size + 2];
+			reader.read_exact(&mut chunk)?;
+			if size == 0 {
+				break;
+			}
+			body.extend_from_slice(&chunk[..size]);
+		}
+	} else {
+		body.resize(content_length, 0);
+		reader.read_exact(&mut body)?;
+	}
+
+	let relative = target.trim_start_matches('/');
+	if relative
+		.split('/')
+		.any(|part| part == ".." || part.is_empty())
+	{
+		return Ok(Some((400, Vec::new())));
+	}
+	let path = root.join(relative);
+	Ok(Some(match method.as_str() {
+		"GET" | "HEAD" => match std::fs::read(&path) {
+			Ok(contents) if method == "GET" => (200, contents),
+			Ok(_) => (200, Vec::new()),
+			Err(_) => (404, Vec::new()),
+		},
+		"PUT" => {
+			std::fs::create_dir_all(path.parent().unwrap())?;
+			static PARTIALS: AtomicUsize = AtomicUsize::new(0);
+			let partial = path.with_file_name(format!(
+				".{}.{}.partial",
+				path.file_name().unwrap().to_string_lossy(),
+				PARTIALS.fetch_add(1, Ordering::Relaxed)
+			));
+			std::fs::write(&partial, body)?;
+			// linking fails when the file exists, so racing PUTs can't both succeed
+			let stored = if if_none_match {
+				std::fs::hard_link(&partial, &path)
+			} else {
+				std::fs::rename(&partial, &path)
+			};
+			std::fs::remove_file(partial).ok();
+			match stored {
+				Ok(()) => (201, Vec::new()),
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (412, Vec::new()),
+				Err(e) => return Err(e),
+			}
+		}
+		_ => (405, Vec::new()),
+	}))
+}
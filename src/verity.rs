@@ -0,0 +1,276 @@
+//! dm-verity hash trees, laid out the way `veritysetup format` writes them (format version 1,
+//! sha256, 4K data and hash blocks), see
+//! <https://gitlab.com/cryptsetup/cryptsetup/-/wikis/DMVerity>
+use std::{
+	fs::{File, OpenOptions},
+	io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+	fs_utils,
+	manifest::{VerityHashTree, VerityOptions},
+};
+
+const BLOCK_SIZE: u64 = 4096;
+const DIGEST_SIZE: usize = 32;
+/// Digests per hash block, a power of two
+const HASHES_PER_BLOCK: u64 = BLOCK_SIZE / DIGEST_SIZE as u64;
+const SUPERBLOCK_SIZE: usize = 512;
+const MAX_SALT_SIZE: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum VerityError {
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("invalid salt {0:?}, expected up to 256 hex encoded bytes")]
+	InvalidSalt(String),
+}
+
+/// What's needed to open the image with dm-verity, written next to it as
+/// `<image>.verity.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerityInfo {
+	/// Hex encoded
+	pub root_hash: String,
+	/// Hex encoded
+	pub salt: String,
+	pub data_blocks: u64,
+	pub data_block_size: u64,
+	pub hash_block_size: u64,
+	pub hash_algorithm: String,
+	/// Where the verity superblock starts in the hash device, the image itself when the
+	/// tree is appended
+	pub hash_offset: u64,
+	/// The separate hash device, `None` when the tree is appended to the image
+	pub hash_file: Option<PathBuf>,
+}
+
+pub fn info_path(image_path: &Path) -> PathBuf {
+	fs_utils::with_suffix(image_path, ".verity.json")
+}
+
+/// Reads what [`generate`] recorded about an image's hash tree, `None` when it has none
+pub fn read_info(image_path: &Path) -> Option<VerityInfo> {
+	let contents = std::fs::read_to_string(info_path(image_path)).ok()?;
+	serde_json::from_str(&contents).ok()
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+	if !text.len().is_multiple_of(2) {
+		return None;
+	}
+	(0..text.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+fn hash_block(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+	let mut hasher = Sha256::new();
+	hasher.update(salt);
+	hasher.update(block);
+	hasher.finalize().into()
+}
+
+/// Hashes `digests` into hash blocks of the next level, each zero padded to the block size
+fn hash_level(salt: &[u8], digests: &[[u8; DIGEST_SIZE]]) -> (Vec<u8>, Vec<[u8; DIGEST_SIZE]>) {
+	let mut blocks = Vec::new();
+	let mut next = Vec::new();
+	for chunk in digests.chunks(HASHES_PER_BLOCK as usize) {
+		let mut block = vec![0u8; BLOCK_SIZE as usize];
+		for (i, digest) in chunk.iter().enumerate() {
+			block[i * DIGEST_SIZE..(i + 1) * DIGEST_SIZE].copy_from_slice(digest);
+		}
+		next.push(hash_block(salt, &block));
+		blocks.extend_from_slice(&block);
+	}
+	(blocks, next)
+}
+
+fn superblock(uuid: [u8; 16], data_blocks: u64, salt: &[u8]) -> [u8; BLOCK_SIZE as usize] {
+	let mut sb = [0u8; BLOCK_SIZE as usize];
+	let mut w = &mut sb[..SUPERBLOCK_SIZE];
+	w.write_all(b"verity\0\0").unwrap();
+	w.write_all(&1u32.to_le_bytes()).unwrap(); // version
+	w.write_all(&1u32.to_le_bytes()).unwrap(); // hash type, 1 is the normal one
+	w.write_all(&uuid).unwrap();
+	let mut algorithm = [0u8; 32];
+	algorithm[..6].copy_from_slice(b"sha256");
+	w.write_all(&algorithm).unwrap();
+	w.write_all(&(BLOCK_SIZE as u32).to_le_bytes()).unwrap(); // data block size
+	w.write_all(&(BLOCK_SIZE as u32).to_le_bytes()).unwrap(); // hash block size
+	w.write_all(&data_blocks.to_le_bytes()).unwrap();
+	w.write_all(&(salt.len() as u16).to_le_bytes()).unwrap();
+	w.write_all(&[0u8; 6]).unwrap();
+	w.write_all(salt).unwrap();
+	sb
+}
+
+/// Computes the hash tree of `image_path`, zero padding it to whole blocks first, and
+/// appends it (superblock first) or writes it to `<image stem>.verity`. Salt and UUID are
+/// derived from the image's file name unless the salt is set, so the root hash only
+/// depends on the contents and name.
+pub fn generate(image_path: &Path, options: &VerityOptions) -> Result<VerityInfo, VerityError> {
+	let file_name = image_path
+		.file_name()
+		.unwrap_or_default()
+		.as_encoded_bytes();
+	let salt = match &options.salt {
+		Some(salt) => parse_hex(salt)
+			.filter(|salt| salt.len() <= MAX_SALT_SIZE)
+			.ok_or_else(|| VerityError::InvalidSalt(salt.clone()))?,
+		None => Sha256::digest([b"salt:", file_name].concat()).to_vec(),
+	};
+	let mut uuid: [u8; 16] = Sha256::digest([b"uuid:", file_name].concat())[..16]
+		.try_into()
+		.unwrap();
+	// version 4, RFC 4122 variant
+	uuid[6] = (uuid[6] & 0x0f) | 0x40;
+	uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+	let mut image = OpenOptions::new().read(true).write(true).open(image_path)?;
+	let len = image.metadata()?.len();
+	let data_size = len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+	image.set_len(data_size)?;
+	let data_blocks = data_size / BLOCK_SIZE;
+
+	let mut digests = Vec::with_capacity(data_blocks as usize);
+	let mut reader = BufReader::with_capacity(1 << 20, &mut image);
+	let mut block = vec![0u8; BLOCK_SIZE as usize];
+	for _ in 0..data_blocks {
+		reader.read_exact(&mut block)?;
+		digests.push(hash_block(&salt, &block));
+	}
+	drop(reader);
+
+	// level 0 hashes the data blocks, every level above hashes the one below until a single
+	// hash block is left, so there's always at least one level, even for a single data
+	// block. The kernel expects the top level first.
+	let mut levels = Vec::new();
+	loop {
+		let (blocks, next) = hash_level(&salt, &digests);
+		levels.push(blocks);
+		digests = next;
+		if digests.len() <= 1 {
+			break;
+		}
+	}
+	let root_hash = digests.first().copied().unwrap_or_default();
+
+	let (hash_offset, hash_file, mut output) = match options.hash_tree {
+		VerityHashTree::Append => {
+			image.seek(SeekFrom::Start(data_size))?;
+			(data_size, None, BufWriter::new(image))
+		}
+		VerityHashTree::Separate => {
+			let hash_file = image_path.with_extension("verity");
+			let file = File::create(&hash_file)?;
+			(0, Some(hash_file), BufWriter::new(file))
+		}
+	};
+	output.write_all(&superblock(uuid, data_blocks, &salt))?;
+	for level in levels.iter().rev() {
+		output.write_all(level)?;
+	}
+	output
+		.into_inner()
+		.map_err(|e| e.into_error())?
+		.sync_all()?;
+
+	let info = VerityInfo {
+		root_hash: hex(&root_hash),
+		salt: hex(&salt),
+		data_blocks,
+		data_block_size: BLOCK_SIZE,
+		hash_block_size: BLOCK_SIZE,
+		hash_algorithm: "sha256".to_string(),
+		hash_offset,
+		hash_file,
+	};
+	std::fs::write(
+		info_path(image_path),
+		serde_json::to_string_pretty(&info).unwrap(),
+	)?;
+	Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::TempDir;
+
+	const SALT: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+	/// `veritysetup format --salt=<SALT> --data-block-size=4096 --hash-block-size=4096 image
+	/// image.verity` of `blocks` blocks of a fixed pattern: the root hash, and the sha256 of
+	/// the hash device after its superblock. The expected values were computed from the
+	/// on-disk format independently of this module.
+	fn check(blocks: usize, root_hash: &str, tree_size: u64, tree_sha256: &str) {
+		let dir = TempDir::new();
+		let image = dir.path().join("image");
+		let data = (0..blocks * BLOCK_SIZE as usize)
+			.map(|i| ((i * 7 + i / BLOCK_SIZE as usize) % 251) as u8)
+			.collect::<Vec<_>>();
+		std::fs::write(&image, &data).unwrap();
+		let info = generate(
+			&image,
+			&VerityOptions {
+				hash_tree: VerityHashTree::Separate,
+				salt: Some(SALT.to_string()),
+			},
+		)
+		.unwrap();
+		assert_eq!(info.root_hash, root_hash);
+		assert_eq!(info.data_blocks, blocks as u64);
+		assert_eq!(std::fs::read(&image).unwrap(), data);
+
+		let hash_device = std::fs::read(info.hash_file.unwrap()).unwrap();
+		let (sb, tree) = hash_device.split_at(BLOCK_SIZE as usize);
+		assert_eq!(&sb[..8], b"verity\0\0");
+		assert_eq!(&sb[72..80], &(blocks as u64).to_le_bytes());
+		assert_eq!(&sb[80..82], &32u16.to_le_bytes());
+		assert_eq!(hex(&sb[88..120]), SALT);
+		assert_eq!(tree.len() as u64, tree_size);
+		assert_eq!(hex(&Sha256::digest(tree)), tree_sha256);
+	}
+
+	#[test]
+	fn single_block_has_a_hash_block() {
+		check(
+			1,
+			"aee2946a8ca7eb57b9a05f4fc8918e279751463763cecd19d0b3377b7fa36cc9",
+			BLOCK_SIZE,
+			"39d16e94f120b265f47b52dafc2afda3232453c24ca78073000adca02ff85b8b",
+		);
+	}
+
+	#[test]
+	fn one_level() {
+		check(
+			2,
+			"f0fcc38c7d8998c18d746dc12889ff714b5227f0fe689d367f99f642998e0f8b",
+			BLOCK_SIZE,
+			"d56fac39659e1a1700231fa90c965d639a5f175f7ebcca35f6f3da50ca6464ca",
+		);
+	}
+
+	#[test]
+	fn two_levels() {
+		// 129 digests take two hash blocks, hashed by a third one at the top
+		check(
+			129,
+			"9e1e479bc82edaa0688c38a182d02a59f8df6c84255706ac0e3fadf51eba22c1",
+			3 * BLOCK_SIZE,
+			"162ec1dfc6350885b8bf2057eacbd5fd988039e51937f8136589de5a99f4de2b",
+		);
+	}
+}